@@ -0,0 +1,29 @@
+use std::fs;
+use std::process::Command;
+
+/// Runs a command and returns what it printed, if it succeeded.
+fn output_of(program: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(program).args(args).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    Some(String::from_utf8(output.stdout).ok()?.trim().to_string())
+}
+
+fn main() {
+    // Builds from a source tarball have no repository, and may have no `date` either.
+    let git_hash =
+        output_of("git", &["rev-parse", "--short", "HEAD"]).unwrap_or("unknown".to_string());
+    let build_date = output_of("date", &["-u", "+%Y-%m-%d"]).unwrap_or("unknown".to_string());
+    println!("cargo:rustc-env=USERSERVERSD_GIT_HASH={git_hash}");
+    println!("cargo:rustc-env=USERSERVERSD_BUILD_DATE={build_date}");
+
+    // Runs again whenever a commit is made or checked out, rather than on every build.
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    if let Some(head) = fs::read_to_string(".git/HEAD").ok()
+        && let Some(reference) = head.trim().strip_prefix("ref: ")
+    {
+        println!("cargo:rerun-if-changed=.git/{reference}");
+    }
+}
@@ -0,0 +1,782 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, BufReader};
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
+
+use super::ipc;
+use super::ipc::command::Command;
+use super::ipc::response::{
+    ConfigReload, DaemonEnvironment, DaemonInfo, HistoryEntry, Response, ResponseKind,
+    ResponseStatus, ServiceEvent, ServiceList, ServiceStatus, ServiceSummary, SnapshotInfo,
+    SnapshotRestore,
+};
+use super::user_context;
+
+const BUSY_RETRIES: u32 = 5;
+const BUSY_RETRY_DELAY: Duration = Duration::from_millis(200);
+
+#[derive(Debug)]
+pub enum Error {
+    /// The location of the socket of the daemon couldn't be determined.
+    SocketPath(io::Error),
+    Connect(io::Error),
+    Send(io::Error),
+    Receive(io::Error),
+    ConnectionClosed,
+    /// The daemon refused or failed to run the command.
+    Command(ResponseStatus),
+    /// The daemon answered with something that doesn't fit the command.
+    UnexpectedResponse,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        match self {
+            Self::SocketPath(err) => write!(fmt, "failed to get socket path: {err}"),
+            Self::Connect(err) => write!(fmt, "failed to connect to socket: {err}"),
+            Self::Send(err) => write!(fmt, "failed to send command to server: {err}"),
+            Self::Receive(err) => write!(fmt, "failed to receive response from server: {err}"),
+            Self::ConnectionClosed => write!(fmt, "connection with server unexpectedly closed"),
+            Self::Command(status) => write!(fmt, "command execution failed: {status}"),
+            Self::UnexpectedResponse => write!(fmt, "got unexpected response from server"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Which services `Client::list_services` returns. The default filter matches every service.
+#[derive(Default)]
+pub struct ServiceFilter {
+    /// Only keeps the services of this group.
+    pub group: Option<String>,
+    /// Only keeps the services that are running, or only the ones that aren't.
+    pub running: Option<bool>,
+    /// Only keeps the services that have all of these labels.
+    pub labels: HashMap<String, String>,
+}
+
+/// What the client traces, as it sends or receives it.
+pub enum Traced<'a> {
+    Command(&'a Command),
+    Response(&'a Response),
+}
+
+type Trace = Box<dyn FnMut(Traced<'_>) + Send>;
+
+/// A blocking connection to the daemon.
+///
+/// Commands the daemon turns away for the time being, because it has too many clients or is
+/// reloading its configuration, are sent again a few times before failing with the busy status.
+pub struct Client {
+    socket_path: PathBuf,
+    // The connection, which is opened again before the next command once it is closed, such as
+    // when a stream is dropped before its end.
+    connection: Option<BufReader<UnixStream>>,
+    warnings: Vec<String>,
+    trace: Option<Trace>,
+}
+
+impl Client {
+    /// Connects to the daemon of the current user.
+    pub fn connect() -> Result<Self, Error> {
+        let socket_path = user_context::get()
+            .socket_path()
+            .map_err(Error::SocketPath)?;
+        Self::connect_to(socket_path)
+    }
+
+    pub fn connect_to<P: Into<PathBuf>>(socket_path: P) -> Result<Self, Error> {
+        let mut client = Self {
+            socket_path: socket_path.into(),
+            connection: None,
+            warnings: Vec::new(),
+            trace: None,
+        };
+        client.reconnect()?;
+        Ok(client)
+    }
+
+    /// Has `trace` called with every command the client sends and every response it receives.
+    pub fn set_trace<F: FnMut(Traced<'_>) + Send + 'static>(&mut self, trace: F) {
+        self.trace = Some(Box::new(trace));
+    }
+
+    fn trace(&mut self, traced: Traced<'_>) {
+        if let Some(trace) = &mut self.trace {
+            trace(traced);
+        }
+    }
+
+    fn reconnect(&mut self) -> Result<(), Error> {
+        let socket = UnixStream::connect(&self.socket_path).map_err(Error::Connect)?;
+        self.connection = Some(BufReader::new(socket));
+        Ok(())
+    }
+
+    /// Sends a command over the connection, opening it again first if it was closed.
+    fn write_command(&mut self, command: &Command) -> Result<(), Error> {
+        if self.connection.is_none() {
+            self.reconnect()?;
+        }
+        self.trace(Traced::Command(command));
+        let connection = self.connection.as_mut().unwrap();
+        command
+            .write_to_stream(connection.get_mut())
+            .map_err(Error::Send)
+    }
+
+    /// Reads the next response, closing the connection if there is none to be read.
+    fn read_response(&mut self) -> Result<Response, Error> {
+        let Some(connection) = &mut self.connection else {
+            return Err(Error::ConnectionClosed);
+        };
+        let result = match Response::read_from_stream(connection) {
+            Ok(Some(response)) => Ok(response),
+            Ok(None) => Err(Error::ConnectionClosed),
+            Err(err) => Err(Error::Receive(err)),
+        };
+        match &result {
+            Ok(response) => self.trace(Traced::Response(response)),
+            Err(_) => self.connection = None,
+        }
+        result
+    }
+
+    fn send_once(&mut self, command: &Command) -> Result<Response, Error> {
+        let sent = match self.write_command(command) {
+            Err(Error::Connect(err)) => return Err(Error::Connect(err)),
+            sent => sent,
+        };
+
+        // The server may reject a connection (e.g. when it is busy) before reading the
+        // command, so a response can still be waiting even if sending it failed.
+        match (sent, self.read_response()) {
+            (_, Ok(response)) => Ok(response),
+            (Err(err), _) | (Ok(()), Err(err)) => Err(err),
+        }
+    }
+
+    /// Sends a command and returns the response whatever its status is.
+    pub fn send(&mut self, command: &Command) -> Result<Response, Error> {
+        let mut retries = 0;
+        loop {
+            let response = self.send_once(command)?;
+            // Commands the daemon was too busy for were never run, so sending them again is
+            // safe. A daemon that is going away isn't retried, as it may not come back.
+            if response.status != ResponseStatus::Busy || retries == BUSY_RETRIES {
+                return Ok(response);
+            }
+
+            retries += 1;
+            thread::sleep(BUSY_RETRY_DELAY * retries);
+            self.reconnect()?;
+        }
+    }
+
+    /// Runs a command, failing unless the daemon reports success. The warnings the daemon gave
+    /// are kept until they are taken with `take_warnings`.
+    pub fn run(&mut self, command: &Command) -> Result<ResponseKind, Error> {
+        let response = self.send(command)?;
+        if response.status != ResponseStatus::Ok {
+            return Err(Error::Command(response.status));
+        }
+
+        self.warnings.extend(response.warnings);
+        Ok(response.kind)
+    }
+
+    /// Returns the caveats about the commands that succeeded so far, and forgets them.
+    pub fn take_warnings(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.warnings)
+    }
+
+    fn run_without_result(&mut self, command: Command) -> Result<(), Error> {
+        self.run(&command).map(|_| ())
+    }
+
+    /// Adds a service, checking that its working directory and executables exist unless `force`
+    /// is set.
+    pub fn add_service(
+        &mut self,
+        name: &str,
+        service: ipc::Service,
+        force: bool,
+    ) -> Result<(), Error> {
+        self.run_without_result(Command::AddService {
+            name: name.to_string(),
+            service: Box::new(service),
+            force,
+        })
+    }
+
+    /// Adds several services at once, failing without changing anything if any of them is
+    /// invalid. Services that already exist are left as they are unless `replace` is set, in
+    /// which case every service that isn't imported is removed.
+    pub fn import_services(
+        &mut self,
+        services: HashMap<String, ipc::Service>,
+        replace: bool,
+        force: bool,
+    ) -> Result<(), Error> {
+        self.run_without_result(Command::ImportServices {
+            services,
+            replace,
+            force,
+        })
+    }
+
+    pub fn remove_service(&mut self, name: &str) -> Result<(), Error> {
+        self.run_without_result(Command::RemoveService {
+            name: name.to_string(),
+        })
+    }
+
+    pub fn start_service(&mut self, name: &str) -> Result<(), Error> {
+        self.run_without_result(Command::StartService {
+            name: name.to_string(),
+        })
+    }
+
+    /// Stops a service, along with the services that depend on it if `with_dependents` is set.
+    pub fn stop_service(&mut self, name: &str, with_dependents: bool) -> Result<(), Error> {
+        self.run_without_result(Command::StopService {
+            name: name.to_string(),
+            with_dependents,
+        })
+    }
+
+    pub fn restart_service(&mut self, name: &str) -> Result<(), Error> {
+        self.run_without_result(Command::RestartService {
+            name: name.to_string(),
+        })
+    }
+
+    /// Freezes a running service until it is resumed. Pausing a paused service does nothing.
+    pub fn pause_service(&mut self, name: &str) -> Result<(), Error> {
+        self.run_without_result(Command::PauseService {
+            name: name.to_string(),
+        })
+    }
+
+    /// Lets a paused service carry on. Resuming a service that isn't paused does nothing.
+    pub fn resume_service(&mut self, name: &str) -> Result<(), Error> {
+        self.run_without_result(Command::ResumeService {
+            name: name.to_string(),
+        })
+    }
+
+    /// Has a running service reload its configuration, failing with `NoReloadMethod` if it has
+    /// neither a reload signal nor a reload command.
+    pub fn reload_service(&mut self, name: &str) -> Result<(), Error> {
+        self.run_without_result(Command::ReloadService {
+            name: name.to_string(),
+        })
+    }
+
+    /// Waits until a running service is ready, or fails with `WaitTimedOut` after `timeout`.
+    pub fn wait_for_service(&mut self, name: &str, timeout: Duration) -> Result<(), Error> {
+        self.run_without_result(Command::WaitForService {
+            name: name.to_string(),
+            timeout_ms: u64::try_from(timeout.as_millis()).unwrap_or(u64::MAX),
+        })
+    }
+
+    pub fn service_status(&mut self, name: &str) -> Result<ServiceStatus, Error> {
+        self.get_service_status(name, None)
+    }
+
+    /// Returns the status of a service with at most `log_bytes` bytes of the end of its logs,
+    /// instead of the default amount.
+    pub fn service_status_with_logs(
+        &mut self,
+        name: &str,
+        log_bytes: usize,
+    ) -> Result<ServiceStatus, Error> {
+        self.get_service_status(name, Some(log_bytes))
+    }
+
+    fn get_service_status(
+        &mut self,
+        name: &str,
+        log_bytes: Option<usize>,
+    ) -> Result<ServiceStatus, Error> {
+        match self.run(&Command::GetServiceStatus {
+            name: name.to_string(),
+            log_bytes,
+        })? {
+            ResponseKind::ServiceStatus(status) => Ok(*status),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    /// Returns the last `lines` lines of the logs of a service.
+    pub fn service_logs(&mut self, name: &str, lines: usize) -> Result<String, Error> {
+        match self.run(&Command::GetServiceLogs {
+            name: name.to_string(),
+            lines,
+        })? {
+            ResponseKind::ServiceLogs { logs } => Ok(logs),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    /// Returns the whole logs of a service, which the daemon sends piece by piece.
+    pub fn stream_service_logs(&mut self, name: &str) -> Result<LogStream<'_>, Error> {
+        self.write_command(&Command::StreamServiceLogs {
+            name: name.to_string(),
+        })?;
+        Ok(LogStream {
+            client: self,
+            done: false,
+        })
+    }
+
+    /// Subscribes to the events of the given kinds, or of every kind if there are none, which
+    /// keep arriving until the stream is dropped. The client connects again for the commands
+    /// sent afterwards.
+    pub fn subscribe(&mut self, kinds: &[ipc::EventKind]) -> Result<EventStream<'_>, Error> {
+        self.write_command(&Command::Subscribe {
+            events: kinds.to_vec(),
+        })?;
+        Ok(EventStream {
+            client: self,
+            done: false,
+        })
+    }
+
+    /// Lists the services that match the filter.
+    pub fn list_services(&mut self, filter: ServiceFilter) -> Result<ServiceList, Error> {
+        match self.run(&Command::ListServices {
+            group: filter.group,
+            running: filter.running,
+            labels: filter.labels,
+        })? {
+            ResponseKind::ServiceList(list) => Ok(list),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    pub fn add_service_to_target(&mut self, target: &str, service: &str) -> Result<(), Error> {
+        self.run_without_result(Command::AddServiceToTarget {
+            target: target.to_string(),
+            service: service.to_string(),
+        })
+    }
+
+    pub fn remove_service_from_target(&mut self, target: &str, service: &str) -> Result<(), Error> {
+        self.run_without_result(Command::RemoveServiceFromTarget {
+            target: target.to_string(),
+            service: service.to_string(),
+        })
+    }
+
+    /// Starts the services of a target, also stopping the ones outside of it if `exclusive` is
+    /// set.
+    pub fn start_target(&mut self, name: &str, exclusive: bool) -> Result<(), Error> {
+        self.run_without_result(Command::StartTarget {
+            name: name.to_string(),
+            exclusive,
+        })
+    }
+
+    pub fn stop_target(&mut self, name: &str) -> Result<(), Error> {
+        self.run_without_result(Command::StopTarget {
+            name: name.to_string(),
+        })
+    }
+
+    /// Returns the state of every service at a glance, which is much lighter than getting the
+    /// status of each.
+    pub fn service_summaries(&mut self) -> Result<HashMap<String, ServiceSummary>, Error> {
+        match self.run(&Command::GetServiceSummaries)? {
+            ResponseKind::ServiceSummaries { summaries } => Ok(summaries),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    pub fn list_targets(&mut self) -> Result<HashMap<String, ipc::Target>, Error> {
+        match self.run(&Command::ListTargets)? {
+            ResponseKind::TargetList { targets } => Ok(targets),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    pub fn list_groups(&mut self) -> Result<HashMap<String, ipc::GroupInfo>, Error> {
+        match self.run(&Command::ListGroups)? {
+            ResponseKind::GroupList { groups } => Ok(groups),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    /// Sets the description of a group, removing it if `description` is empty.
+    pub fn describe_group(&mut self, name: &str, description: &str) -> Result<(), Error> {
+        self.run_without_result(Command::DescribeGroup {
+            name: name.to_string(),
+            description: description.to_string(),
+        })
+    }
+
+    /// Moves every service of a group to another one, or out of any group if `to` is `None`.
+    /// Returns the names of the services that were moved.
+    pub fn move_group(&mut self, from: &str, to: Option<&str>) -> Result<Vec<String>, Error> {
+        match self.run(&Command::MoveGroup {
+            from: from.to_string(),
+            to: to.map(str::to_string),
+        })? {
+            ResponseKind::MovedServices { services } => Ok(services),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    /// Has the daemon read its configuration file again and apply what changed in it.
+    pub fn reload_config(&mut self) -> Result<ConfigReload, Error> {
+        match self.run(&Command::ReloadConfig)? {
+            ResponseKind::ConfigReloaded(reload) => Ok(reload),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    /// Has the daemon write its configuration right away, instead of within a second of when it
+    /// changes.
+    pub fn flush(&mut self) -> Result<(), Error> {
+        self.run_without_result(Command::Flush)
+    }
+
+    /// Saves the services and which of them are running under a name, replacing any snapshot
+    /// with the same name.
+    pub fn save_snapshot(&mut self, name: &str) -> Result<SnapshotInfo, Error> {
+        match self.run(&Command::SaveSnapshot {
+            name: name.to_string(),
+        })? {
+            ResponseKind::Snapshot(snapshot) => Ok(snapshot),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    /// Puts the services back the way they were when a snapshot was taken.
+    pub fn restore_snapshot(&mut self, name: &str) -> Result<SnapshotRestore, Error> {
+        match self.run(&Command::RestoreSnapshot {
+            name: name.to_string(),
+        })? {
+            ResponseKind::SnapshotRestored(restore) => Ok(restore),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    /// Lists the snapshots, from the oldest to the newest.
+    pub fn list_snapshots(&mut self) -> Result<Vec<SnapshotInfo>, Error> {
+        match self.run(&Command::ListSnapshots)? {
+            ResponseKind::SnapshotList { snapshots } => Ok(snapshots),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    pub fn history(&mut self, limit: usize) -> Result<Vec<HistoryEntry>, Error> {
+        match self.run(&Command::GetHistory { limit })? {
+            ResponseKind::History { entries } => Ok(entries),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    pub fn ping(&mut self) -> Result<DaemonInfo, Error> {
+        match self.run(&Command::Ping)? {
+            ResponseKind::DaemonInfo(info) => Ok(info),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    pub fn daemon_environment(&mut self) -> Result<DaemonEnvironment, Error> {
+        match self.run(&Command::GetDaemonEnvironment)? {
+            ResponseKind::DaemonEnvironment(environment) => Ok(environment),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+
+    /// Asks the daemon to replace itself with the current version of its binary, and connects
+    /// to whichever instance is running once the old one let go of the connection.
+    pub fn restart_daemon(&mut self) -> Result<(), Error> {
+        self.run(&Command::RestartDaemon)?;
+
+        // The old instance closes every connection once it gets replaced.
+        while self.read_response().is_ok() {}
+
+        self.reconnect()
+    }
+
+    /// Stops every service and shuts the daemon down.
+    pub fn shutdown(&mut self) -> Result<(), Error> {
+        self.run_without_result(Command::Shutdown)
+    }
+}
+
+/// The logs of a service as they arrive from the daemon. Dropping the stream before its end
+/// closes the connection, so that the rest of the logs aren't taken for the responses to later
+/// commands.
+pub struct LogStream<'a> {
+    client: &'a mut Client,
+    done: bool,
+}
+
+impl Iterator for LogStream<'_> {
+    type Item = Result<String, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        let response = match self.client.read_response() {
+            Ok(response) => response,
+            Err(err) => {
+                self.done = true;
+                return Some(Err(err));
+            }
+        };
+        if response.status != ResponseStatus::Ok {
+            self.done = true;
+            return Some(Err(Error::Command(response.status)));
+        }
+
+        match response.kind {
+            ResponseKind::LogChunk { data } => Some(Ok(data)),
+            ResponseKind::LogEnd => {
+                self.done = true;
+                None
+            }
+            _ => {
+                self.done = true;
+                Some(Err(Error::UnexpectedResponse))
+            }
+        }
+    }
+}
+
+impl Drop for LogStream<'_> {
+    fn drop(&mut self) {
+        if !self.done {
+            self.client.connection = None;
+        }
+    }
+}
+
+/// The events the client subscribed to as they arrive from the daemon. Dropping the stream
+/// closes the connection, which ends the subscription.
+pub struct EventStream<'a> {
+    client: &'a mut Client,
+    done: bool,
+}
+
+impl Iterator for EventStream<'_> {
+    type Item = Result<ServiceEvent, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        let response = match self.client.read_response() {
+            Ok(response) => response,
+            Err(err) => {
+                self.done = true;
+                return Some(Err(err));
+            }
+        };
+        if response.status != ResponseStatus::Ok {
+            self.done = true;
+            return Some(Err(Error::Command(response.status)));
+        }
+
+        match response.kind {
+            ResponseKind::Event(event) => Some(Ok(event)),
+            _ => {
+                self.done = true;
+                Some(Err(Error::UnexpectedResponse))
+            }
+        }
+    }
+}
+
+impl Drop for EventStream<'_> {
+    fn drop(&mut self) {
+        self.client.connection = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::os::unix::net::UnixListener;
+    use std::process;
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+
+    fn listen(test: &str) -> (PathBuf, UnixListener) {
+        let path =
+            env::temp_dir().join(format!("userserversd-client-{}-{test}.sock", process::id()));
+        let _ = fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        (path, listener)
+    }
+
+    fn respond(stream: &mut UnixStream, status: ResponseStatus, kind: ResponseKind) {
+        let response = Response {
+            status,
+            kind,
+            warnings: Vec::new(),
+        };
+        response.write_to_stream(stream).unwrap();
+    }
+
+    /// Answers each connection's first command with the next of `statuses`, returning how many
+    /// connections it got.
+    fn fake_daemon(
+        test: &str,
+        statuses: Vec<ResponseStatus>,
+    ) -> (PathBuf, thread::JoinHandle<usize>) {
+        let (path, listener) = listen(test);
+        let daemon = thread::spawn(move || {
+            let count = statuses.len();
+            for status in statuses {
+                let (mut stream, _) = listener.accept().unwrap();
+                Command::read_from_stream(&mut BufReader::new(&stream))
+                    .unwrap()
+                    .unwrap();
+                respond(&mut stream, status, ResponseKind::None);
+            }
+            count
+        });
+        (path, daemon)
+    }
+
+    #[test]
+    fn busy_daemon_is_asked_again_on_a_new_connection() {
+        let statuses = vec![
+            ResponseStatus::Busy,
+            ResponseStatus::Busy,
+            ResponseStatus::Ok,
+        ];
+        let (path, daemon) = fake_daemon("busy", statuses);
+
+        let mut client = Client::connect_to(&path).unwrap();
+        let response = client.send(&Command::Ping).unwrap();
+        assert_eq!(response.status, ResponseStatus::Ok);
+        assert_eq!(daemon.join().unwrap(), 3);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn daemon_going_away_is_not_asked_again() {
+        let (path, daemon) = fake_daemon("going-away", vec![ResponseStatus::ShuttingDown]);
+
+        let mut client = Client::connect_to(&path).unwrap();
+        let err = client.run(&Command::Ping).err().unwrap();
+        assert!(matches!(err, Error::Command(ResponseStatus::ShuttingDown)));
+        assert_eq!(daemon.join().unwrap(), 1);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn trace_gets_every_command_and_response() {
+        let (path, daemon) = fake_daemon("trace", vec![ResponseStatus::Ok]);
+
+        let traced = Arc::new(Mutex::new(Vec::new()));
+        let mut client = Client::connect_to(&path).unwrap();
+        let sink = traced.clone();
+        client.set_trace(move |traced| {
+            sink.lock().unwrap().push(match traced {
+                Traced::Command(command) => {
+                    format!("> {}", serde_json::to_string(command).unwrap())
+                }
+                Traced::Response(response) => format!("< {:?}", response.status),
+            });
+        });
+        assert!(client.flush().is_ok());
+
+        assert_eq!(*traced.lock().unwrap(), ["> \"Flush\"", "< Ok"]);
+        daemon.join().unwrap();
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn commands_after_a_finished_stream_use_the_same_connection() {
+        let (path, listener) = listen("finished-stream");
+        let daemon = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            Command::read_from_stream(&mut reader).unwrap().unwrap();
+            for data in ["first\n", "second\n"] {
+                let data = data.to_string();
+                respond(
+                    &mut stream,
+                    ResponseStatus::Ok,
+                    ResponseKind::LogChunk { data },
+                );
+            }
+            respond(&mut stream, ResponseStatus::Ok, ResponseKind::LogEnd);
+
+            let mut commands = 0;
+            while let Ok(Some(_)) = Command::read_from_stream(&mut reader) {
+                commands += 1;
+                respond(&mut stream, ResponseStatus::Ok, ResponseKind::None);
+            }
+            commands
+        });
+
+        let mut client = Client::connect_to(&path).unwrap();
+        let logs = client
+            .stream_service_logs("web")
+            .unwrap()
+            .collect::<Result<String, Error>>();
+        assert_eq!(logs.unwrap(), "first\nsecond\n");
+        for _ in 0..3 {
+            assert!(client.flush().is_ok());
+        }
+        drop(client);
+
+        assert_eq!(daemon.join().unwrap(), 3);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn dropping_a_stream_midway_closes_its_connection() {
+        let (path, listener) = listen("dropped-stream");
+        let daemon = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            Command::read_from_stream(&mut reader).unwrap().unwrap();
+            for data in ["first\n", "second\n", "third\n"] {
+                let data = data.to_string();
+                respond(
+                    &mut stream,
+                    ResponseStatus::Ok,
+                    ResponseKind::LogChunk { data },
+                );
+            }
+            let closed = matches!(Command::read_from_stream(&mut reader), Ok(None));
+
+            let (mut stream, _) = listener.accept().unwrap();
+            let command = Command::read_from_stream(&mut BufReader::new(&stream))
+                .unwrap()
+                .unwrap();
+            assert!(matches!(command, Command::Flush));
+            respond(&mut stream, ResponseStatus::Ok, ResponseKind::None);
+            closed
+        });
+
+        let mut client = Client::connect_to(&path).unwrap();
+        let mut logs = client.stream_service_logs("web").unwrap();
+        assert_eq!(logs.next().unwrap().unwrap(), "first\n");
+        drop(logs);
+        // Without closing the connection, the next chunk would be read as the response.
+        assert!(client.flush().is_ok());
+
+        assert!(daemon.join().unwrap());
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,131 @@
+use std::ops::Deref;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+/// Where the daemon reads the time from.
+///
+/// The monotonic clock is for everything that gets waited on or scheduled: ticks, timer runs,
+/// health check intervals and debounces. It is never set and doesn't move while the machine is
+/// suspended, so neither a change of the wall clock nor a resume makes any of them fire early or
+/// holds them back. The wall clock is only for times that are shown or outlive the daemon, such
+/// as when a service started, and how long ago one of those was reads as zero if the wall clock
+/// has since gone back past it.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+
+    fn wall(&self) -> SystemTime;
+
+    /// How long ago a time of the wall clock was, or zero if it is still to come.
+    fn since(&self, time: SystemTime) -> Duration {
+        self.wall().duration_since(time).unwrap_or_default()
+    }
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn wall(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// A clock that can be handed around, which is the system clock unless another one is given.
+#[derive(Clone)]
+pub struct SharedClock(Arc<dyn Clock>);
+
+impl SharedClock {
+    pub fn new<C: Clock + 'static>(clock: C) -> Self {
+        Self(Arc::new(clock))
+    }
+}
+
+impl Default for SharedClock {
+    fn default() -> Self {
+        Self::new(SystemClock)
+    }
+}
+
+impl Deref for SharedClock {
+    type Target = dyn Clock;
+
+    fn deref(&self) -> &Self::Target {
+        &*self.0
+    }
+}
+
+/// A clock that only moves when told to, and whose wall clock can be set apart from its
+/// monotonic one like an NTP correction or a suspend would.
+#[cfg(test)]
+#[derive(Clone)]
+pub struct TestClock {
+    start: Instant,
+    state: Arc<std::sync::Mutex<(Duration, SystemTime)>>,
+}
+
+#[cfg(test)]
+impl TestClock {
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            state: Arc::new(std::sync::Mutex::new((Duration::ZERO, SystemTime::now()))),
+        }
+    }
+
+    pub fn shared(&self) -> SharedClock {
+        SharedClock::new(self.clone())
+    }
+
+    /// Lets time pass on both clocks.
+    pub fn advance(&self, by: Duration) {
+        let mut state = self.state.lock().unwrap();
+        state.0 += by;
+        state.1 += by;
+    }
+
+    /// Moves the wall clock alone, forward for a positive number of seconds and back otherwise.
+    pub fn shift_wall(&self, secs: i64) {
+        let mut state = self.state.lock().unwrap();
+        let by = Duration::from_secs(secs.unsigned_abs());
+        state.1 = if secs >= 0 {
+            state.1 + by
+        } else {
+            state.1 - by
+        };
+    }
+}
+
+#[cfg(test)]
+impl Clock for TestClock {
+    fn now(&self) -> Instant {
+        self.start + self.state.lock().unwrap().0
+    }
+
+    fn wall(&self) -> SystemTime {
+        self.state.lock().unwrap().1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn time_since_a_moment_the_wall_clock_went_back_past_is_zero() {
+        let clock = TestClock::new();
+        let started_at = clock.wall();
+        clock.advance(Duration::from_secs(30));
+        assert_eq!(clock.since(started_at), Duration::from_secs(30));
+
+        let now = clock.now();
+        clock.shift_wall(-3600);
+        assert_eq!(clock.since(started_at), Duration::ZERO);
+        assert_eq!(clock.now(), now);
+
+        clock.shift_wall(3600 + 90);
+        assert_eq!(clock.since(started_at), Duration::from_secs(120));
+    }
+}
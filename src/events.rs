@@ -0,0 +1,309 @@
+use std::fmt;
+use std::sync::{Arc, Mutex, mpsc};
+use std::time::SystemTime;
+
+use super::ipc::{EventKind, ServiceState};
+
+#[derive(Clone, Debug)]
+pub enum EventCause {
+    Requested,
+    Exited {
+        code: Option<i32>,
+        signal: Option<i32>,
+    },
+    StartFailed {
+        error: String,
+    },
+    StopFailed {
+        error: String,
+    },
+    HealthCheckFailed {
+        code: Option<i32>,
+    },
+    HealthCheckPassed,
+    FileChanged {
+        path: String,
+    },
+    /// The service was added, removed or had its definition changed, rather than changing state.
+    Added,
+    Removed,
+    Edited,
+}
+
+impl fmt::Display for EventCause {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        match self {
+            Self::Requested => write!(fmt, "requested"),
+            Self::Exited {
+                code: Some(code), ..
+            } => write!(fmt, "exited with code {code}"),
+            Self::Exited {
+                signal: Some(signal),
+                ..
+            } => write!(fmt, "killed by signal {signal}"),
+            Self::Exited { .. } => write!(fmt, "exited"),
+            Self::StartFailed { error } => write!(fmt, "failed to start: {error}"),
+            Self::StopFailed { error } => write!(fmt, "failed to stop: {error}"),
+            Self::HealthCheckFailed { code: Some(code) } => {
+                write!(fmt, "health check kept failing, last with code {code}")
+            }
+            Self::HealthCheckFailed { code: None } => write!(fmt, "health check kept failing"),
+            Self::HealthCheckPassed => write!(fmt, "health check passed"),
+            Self::FileChanged { path } => write!(fmt, "restarted due to file change: {path}"),
+            Self::Added => write!(fmt, "added"),
+            Self::Removed => write!(fmt, "removed"),
+            Self::Edited => write!(fmt, "edited"),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Event {
+    pub service: String,
+    pub old_state: ServiceState,
+    pub new_state: ServiceState,
+    pub cause: EventCause,
+    pub timestamp: SystemTime,
+}
+
+impl Event {
+    /// How the event is told apart by subscribers. A service is started when it goes from not
+    /// running to running and stopped the other way around, and failing takes priority over both.
+    pub fn kind(&self) -> EventKind {
+        match self.cause {
+            EventCause::Added => return EventKind::Added,
+            EventCause::Removed => return EventKind::Removed,
+            EventCause::Edited => return EventKind::Edited,
+            _ => {}
+        }
+
+        if self.new_state == ServiceState::Failed {
+            EventKind::Failed
+        } else if self.new_state.is_active() && !self.old_state.is_active() {
+            EventKind::Started
+        } else if !self.new_state.is_active() && self.old_state.is_active() {
+            EventKind::Stopped
+        } else {
+            EventKind::StateChanged
+        }
+    }
+}
+
+/// Fans out every service state transition, and every service being added, removed or edited,
+/// to all subscribers. Subscribers that went away are dropped the next time an event is emitted.
+///
+/// Services emit into the bus while holding their own runtime lock, so each transition is
+/// emitted exactly once and in order for any given service.
+#[derive(Default)]
+pub struct EventBus {
+    subscribers: Mutex<Vec<mpsc::Sender<Event>>>,
+}
+
+impl EventBus {
+    pub fn subscribe(&self) -> mpsc::Receiver<Event> {
+        let (tx, rx) = mpsc::channel();
+        self.subscribers.lock().unwrap().push(tx);
+        rx
+    }
+
+    /// Emits an event about the definition of a service, which leaves its state as it is.
+    pub fn emit_definition(&self, service: String, state: ServiceState, cause: EventCause) {
+        self.emit(Event {
+            service,
+            old_state: state,
+            new_state: state,
+            cause,
+            timestamp: SystemTime::now(),
+        });
+    }
+
+    pub fn emit(&self, event: Event) {
+        self.subscribers
+            .lock()
+            .unwrap()
+            .retain(|subscriber| subscriber.send(event.clone()).is_ok());
+    }
+}
+
+/// Emits events on behalf of a single service.
+#[derive(Clone)]
+pub struct EventSender {
+    service: String,
+    bus: Arc<EventBus>,
+}
+
+impl EventSender {
+    pub fn new(service: String, bus: Arc<EventBus>) -> Self {
+        Self { service, bus }
+    }
+
+    pub fn service(&self) -> &str {
+        &self.service
+    }
+
+    pub fn emit(&self, old_state: ServiceState, new_state: ServiceState, cause: EventCause) {
+        self.bus.emit(Event {
+            service: self.service.clone(),
+            old_state,
+            new_state,
+            cause,
+            timestamp: SystemTime::now(),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::env;
+    use std::thread;
+    use std::time::Duration;
+
+    use super::*;
+    use crate::service::{Service, ServiceKind};
+
+    fn event(old_state: ServiceState, new_state: ServiceState, cause: EventCause) -> Event {
+        Event {
+            service: "web".to_string(),
+            old_state,
+            new_state,
+            cause,
+            timestamp: SystemTime::now(),
+        }
+    }
+
+    fn shell_service(script: &str, bus: &Arc<EventBus>) -> Service {
+        let service = Service::new(
+            env::temp_dir().to_string_lossy().to_string(),
+            HashMap::new(),
+            None,
+            ServiceKind::Synchronous {
+                command: vec!["sh".to_string(), "-c".to_string(), script.to_string()],
+            },
+        );
+        service.attach_events(EventSender::new("web".to_string(), bus.clone()));
+        service
+    }
+
+    #[test]
+    fn kind_tells_starts_stops_and_failures_apart() {
+        use ServiceState::*;
+
+        let cases = [
+            (Stopped, Running, EventCause::Requested, EventKind::Started),
+            (Running, Stopped, EventCause::Requested, EventKind::Stopped),
+            (Paused, Stopped, EventCause::Requested, EventKind::Stopped),
+            (
+                Running,
+                Unhealthy,
+                EventCause::HealthCheckFailed { code: Some(1) },
+                EventKind::StateChanged,
+            ),
+            (
+                Running,
+                Paused,
+                EventCause::Requested,
+                EventKind::StateChanged,
+            ),
+            (
+                Running,
+                Failed,
+                EventCause::Exited {
+                    code: Some(1),
+                    signal: None,
+                },
+                EventKind::Failed,
+            ),
+            // A start that fails never got the service running.
+            (
+                Stopped,
+                Failed,
+                EventCause::StartFailed {
+                    error: "no".to_string(),
+                },
+                EventKind::Failed,
+            ),
+            (Running, Running, EventCause::Edited, EventKind::Edited),
+            (Stopped, Stopped, EventCause::Added, EventKind::Added),
+            (Running, Running, EventCause::Removed, EventKind::Removed),
+        ];
+        for (old_state, new_state, cause, kind) in cases {
+            let event = event(old_state, new_state, cause);
+            assert_eq!(event.kind(), kind, "{event:?}");
+        }
+    }
+
+    #[test]
+    fn subscribers_that_went_away_are_dropped() {
+        let bus = EventBus::default();
+        let kept = bus.subscribe();
+        drop(bus.subscribe());
+
+        bus.emit_definition("web".to_string(), ServiceState::Stopped, EventCause::Added);
+        assert_eq!(bus.subscribers.lock().unwrap().len(), 1);
+        let received = kept.try_recv().unwrap();
+        assert_eq!(received.kind(), EventKind::Added);
+        assert!(kept.try_recv().is_err());
+    }
+
+    #[test]
+    fn an_exit_noticed_by_many_threads_is_emitted_once() {
+        let bus = Arc::new(EventBus::default());
+        let events = bus.subscribe();
+        let service = shell_service("sleep 0.2; exit 3", &bus);
+        assert!(service.start().is_ok());
+
+        thread::scope(|scope| {
+            for _ in 0..8 {
+                scope.spawn(|| {
+                    while service.is_running() {
+                        thread::yield_now();
+                    }
+                });
+            }
+        });
+
+        let events = events.try_iter().collect::<Vec<Event>>();
+        assert_eq!(events.len(), 2, "{events:?}");
+        assert_eq!(events[0].kind(), EventKind::Started);
+        assert_eq!(events[1].kind(), EventKind::Failed);
+        assert!(matches!(
+            events[1].cause,
+            EventCause::Exited { code: Some(3), .. }
+        ));
+    }
+
+    #[test]
+    fn concurrent_starts_and_stops_emit_a_consistent_chain() {
+        let bus = Arc::new(EventBus::default());
+        let events = bus.subscribe();
+        let service = shell_service("exec sleep 1000", &bus);
+
+        thread::scope(|scope| {
+            for i in 0..6 {
+                let service = &service;
+                scope.spawn(move || {
+                    for _ in 0..5 {
+                        // Half of them fail as the service already is where they'd take it.
+                        if i % 2 == 0 {
+                            let _ = service.start();
+                        } else {
+                            let _ = service.stop();
+                        }
+                        thread::sleep(Duration::from_millis(5));
+                    }
+                });
+            }
+        });
+        let _ = service.stop();
+
+        // Every event starts from the state the previous one left the service in.
+        let events = events.try_iter().collect::<Vec<Event>>();
+        assert!(!events.is_empty());
+        assert_eq!(events[0].old_state, ServiceState::Stopped);
+        for pair in events.windows(2) {
+            assert_eq!(pair[0].new_state, pair[1].old_state, "{pair:?}");
+        }
+        assert_eq!(events.last().unwrap().new_state, ServiceState::Stopped);
+    }
+}
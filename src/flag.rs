@@ -1,11 +1,80 @@
 use std::collections::HashMap;
 use std::env;
 use std::iter::Peekable;
+use std::vec;
+
+use serde::de::DeserializeOwned;
+
+mod completions;
+
+pub use completions::Shell;
+
+const RESERVED_FLAG_NAMES: [&str; 2] = ["h", "help"];
+
+/// What the argument of a flag must be, which is checked while parsing.
+#[derive(Clone)]
+enum ArgumentType {
+    Text,
+    Int,
+    /// Parses the argument as JSON of the type the flag was added with.
+    Json(fn(&str) -> Result<serde_json::Value, String>),
+    Choice(Vec<String>),
+}
+
+/// The argument of a typed flag, once checked.
+#[derive(Clone, Debug, PartialEq)]
+pub enum FlagValue {
+    Int(u64),
+    Json(serde_json::Value),
+    Choice(String),
+}
+
+fn parse_json<T: DeserializeOwned>(json: &str) -> Result<serde_json::Value, String> {
+    serde_json::from_str::<T>(json).map_err(|err| err.to_string())?;
+    serde_json::from_str(json).map_err(|err| err.to_string())
+}
 
 #[derive(Clone)]
 struct Flag {
     name: (String, String),
     help: String,
+    takes_argument: bool,
+    repeatable: bool,
+    argument_type: ArgumentType,
+    // Stops parsing right away, so that nothing else the command expects has to be given.
+    ends_parsing: bool,
+}
+
+impl Flag {
+    /// Checks the argument of the flag against its type. Empty integers and JSON are let
+    /// through untyped, since they are how fields get cleared.
+    fn parse_argument(&self, argument: &str) -> Result<Option<FlagValue>, String> {
+        if argument.is_empty() && !matches!(self.argument_type, ArgumentType::Choice(_)) {
+            return Ok(None);
+        }
+
+        let long_name = &self.name.1;
+        match &self.argument_type {
+            ArgumentType::Text => Ok(None),
+            ArgumentType::Int => argument
+                .parse()
+                .map(|int| Some(FlagValue::Int(int)))
+                .map_err(|_| format!("--{long_name} expects a non-negative integer")),
+            ArgumentType::Json(parse) => parse(argument)
+                .map(|json| Some(FlagValue::Json(json)))
+                .map_err(|err| format!("--{long_name} expects valid JSON: {err}")),
+            ArgumentType::Choice(choices) => {
+                if choices.iter().any(|choice| choice == argument) {
+                    Ok(Some(FlagValue::Choice(argument.to_string())))
+                } else {
+                    Err(format!(
+                        "--{long_name} expects one of: {}",
+                        choices.join(", ")
+                    ))
+                }
+            }
+        }
+    }
 }
 
 #[derive(Clone)]
@@ -14,7 +83,13 @@ pub struct Command {
     help: String,
     flags: Vec<Flag>,
     positional_args: Vec<(String, String)>,
+    // Always come after the required ones.
+    optional_positional_args: Vec<(String, String)>,
+    // Takes every positional argument left after the others.
+    variadic_positional_arg: Option<(String, String)>,
     subcommands: Vec<Command>,
+    // Titles and bodies of extra sections at the end of the help.
+    help_sections: Vec<(String, String)>,
 }
 
 impl Command {
@@ -24,26 +99,233 @@ impl Command {
             help: help.to_string(),
             flags: Vec::new(),
             positional_args: Vec::new(),
+            optional_positional_args: Vec::new(),
+            variadic_positional_arg: None,
             subcommands: Vec::new(),
+            help_sections: Vec::new(),
         }
     }
 
+    fn display_name(&self) -> &str {
+        self.name.as_deref().unwrap_or("root")
+    }
+
+    // Mistakes in the command definitions are programming errors, so they panic right away
+    // instead of silently breaking parsing later on.
+    fn check_flag_names(&self, short_name: &str, long_name: &str) {
+        let command_name = self.display_name();
+
+        assert!(
+            !short_name.is_empty() && !long_name.is_empty(),
+            "flags of the `{command_name}` command must have both a short and a long name"
+        );
+        assert!(
+            short_name.len() <= long_name.len(),
+            "the short name of the flag --{long_name} of the `{command_name}` command is longer than its long name"
+        );
+        assert!(
+            !RESERVED_FLAG_NAMES.contains(&short_name) && !RESERVED_FLAG_NAMES.contains(&long_name),
+            "the flag --{long_name} of the `{command_name}` command uses a reserved name"
+        );
+
+        for flag in &self.flags {
+            assert!(
+                flag.name.0 != short_name,
+                "the `{command_name}` command has two flags with the short name -{short_name}"
+            );
+            assert!(
+                flag.name.1 != long_name,
+                "the `{command_name}` command has two flags with the long name --{long_name}"
+            );
+        }
+    }
+
+    fn add_typed_flag(
+        &mut self,
+        short_name: &str,
+        long_name: &str,
+        help: &str,
+        argument_type: ArgumentType,
+    ) {
+        self.check_flag_names(short_name, long_name);
+        self.flags.push(Flag {
+            name: (short_name.to_string(), long_name.to_string()),
+            help: help.to_string(),
+            takes_argument: true,
+            repeatable: false,
+            argument_type,
+            ends_parsing: false,
+        })
+    }
+
     pub fn add_flag(&mut self, short_name: &str, long_name: &str, help: &str) {
+        self.add_typed_flag(short_name, long_name, help, ArgumentType::Text);
+    }
+
+    /// Adds a flag whose argument must be a non-negative integer.
+    pub fn add_int_flag(&mut self, short_name: &str, long_name: &str, help: &str) {
+        self.add_typed_flag(short_name, long_name, help, ArgumentType::Int);
+    }
+
+    /// Adds a flag whose argument must be JSON that deserializes into `T`.
+    pub fn add_json_flag<T: DeserializeOwned>(
+        &mut self,
+        short_name: &str,
+        long_name: &str,
+        help: &str,
+    ) {
+        self.add_typed_flag(
+            short_name,
+            long_name,
+            help,
+            ArgumentType::Json(parse_json::<T>),
+        );
+    }
+
+    /// Adds a flag whose argument must be one of `choices`.
+    pub fn add_choice_flag(
+        &mut self,
+        short_name: &str,
+        long_name: &str,
+        help: &str,
+        choices: &[&str],
+    ) {
+        let choices = choices.iter().map(|choice| choice.to_string()).collect();
+        self.add_typed_flag(short_name, long_name, help, ArgumentType::Choice(choices));
+    }
+
+    /// Adds a flag that can be passed several times, with all of its arguments being collected.
+    pub fn add_repeatable_flag(&mut self, short_name: &str, long_name: &str, help: &str) {
+        self.check_flag_names(short_name, long_name);
         self.flags.push(Flag {
             name: (short_name.to_string(), long_name.to_string()),
             help: help.to_string(),
+            takes_argument: true,
+            repeatable: true,
+            argument_type: ArgumentType::Text,
+            ends_parsing: false,
+        })
+    }
+
+    pub fn add_bool_flag(&mut self, short_name: &str, long_name: &str, help: &str) {
+        self.check_flag_names(short_name, long_name);
+        self.flags.push(Flag {
+            name: (short_name.to_string(), long_name.to_string()),
+            help: help.to_string(),
+            takes_argument: false,
+            repeatable: false,
+            argument_type: ArgumentType::Text,
+            ends_parsing: false,
+        })
+    }
+
+    /// Adds the -V/--version flag, which can be given without the positional arguments and
+    /// subcommand the command otherwise requires.
+    pub fn add_version_flag(&mut self, help: &str) {
+        self.check_flag_names("V", "version");
+        self.flags.push(Flag {
+            name: ("V".to_string(), "version".to_string()),
+            help: help.to_string(),
+            takes_argument: false,
+            repeatable: false,
+            argument_type: ArgumentType::Text,
+            ends_parsing: true,
         })
     }
 
     pub fn add_subcommand(&mut self, subcommand: Command) {
+        let command_name = self.display_name();
+
+        let subcommand_name = match &subcommand.name {
+            Some(name) => name,
+            None => panic!("subcommands of the `{command_name}` command must have names"),
+        };
+        assert!(
+            !self
+                .subcommands
+                .iter()
+                .any(|other| other.name.as_ref() == Some(subcommand_name)),
+            "the `{command_name}` command has two subcommands named `{subcommand_name}`"
+        );
+        assert!(
+            self.optional_positional_args.is_empty() && self.variadic_positional_arg.is_none(),
+            "the `{command_name}` command has both optional positional arguments and subcommands"
+        );
+
         self.subcommands.push(subcommand);
     }
 
+    fn check_positional_arg_name(&self, name: &str) {
+        let command_name = self.display_name();
+
+        assert!(
+            !name.is_empty(),
+            "positional arguments of the `{command_name}` command must have names"
+        );
+        assert!(
+            !self
+                .positional_args
+                .iter()
+                .chain(&self.optional_positional_args)
+                .chain(&self.variadic_positional_arg)
+                .any(|(other, _)| other == name),
+            "the `{command_name}` command has two positional arguments named `{name}`"
+        );
+    }
+
     pub fn add_positional_arg(&mut self, name: &str, help: &str) {
+        self.check_positional_arg_name(name);
+        assert!(
+            self.optional_positional_args.is_empty() && self.variadic_positional_arg.is_none(),
+            "the required positional argument `{name}` of the `{}` command comes after an optional one",
+            self.display_name()
+        );
+
         self.positional_args
             .push((name.to_string(), help.to_string()));
     }
 
+    /// Adds a positional argument that can be left out, as long as no other positional argument
+    /// comes after it.
+    pub fn add_optional_positional_arg(&mut self, name: &str, help: &str) {
+        self.check_positional_arg_name(name);
+        assert!(
+            self.subcommands.is_empty(),
+            "the `{}` command has both subcommands and the optional positional argument `{name}`",
+            self.display_name()
+        );
+        assert!(
+            self.variadic_positional_arg.is_none(),
+            "the optional positional argument `{name}` of the `{}` command comes after the variadic one",
+            self.display_name()
+        );
+
+        self.optional_positional_args
+            .push((name.to_string(), help.to_string()));
+    }
+
+    /// Adds a positional argument that takes any number of values, including none. It comes
+    /// after every other positional argument.
+    pub fn add_variadic_positional_arg(&mut self, name: &str, help: &str) {
+        self.check_positional_arg_name(name);
+        assert!(
+            self.subcommands.is_empty()
+                && self.optional_positional_args.is_empty()
+                && self.variadic_positional_arg.is_none(),
+            "the variadic positional argument `{name}` of the `{}` command must be its only optional one",
+            self.display_name()
+        );
+
+        self.variadic_positional_arg = Some((name.to_string(), help.to_string()));
+    }
+
+    /// Adds a section to the end of the help of this command. Each line of `body` is indented
+    /// under the title.
+    pub fn add_help_section(&mut self, title: &str, body: &str) {
+        self.help_sections
+            .push((title.to_string(), body.to_string()));
+    }
+
     fn generate_help_impl(&self, indentation: usize) -> String {
         let command_name = match &self.name {
             Some(name) => name,
@@ -58,13 +340,19 @@ impl Command {
         if self.name.is_none() {
             output.push_str(&format!("USAGE: {command_name}"));
         } else {
-            output.push_str(&format!("{command_name}"));
+            output.push_str(command_name);
         }
 
         // Add positional arguments
         for (arg, _) in &self.positional_args {
             output.push_str(&format!(" <{}>", arg.to_uppercase()));
         }
+        for (arg, _) in &self.optional_positional_args {
+            output.push_str(&format!(" [{}]", arg.to_uppercase()));
+        }
+        if let Some((arg, _)) = &self.variadic_positional_arg {
+            output.push_str(&format!(" [{}...]", arg.to_uppercase()));
+        }
 
         // Add optional parts
         if !self.flags.is_empty() {
@@ -78,8 +366,16 @@ impl Command {
         output.push_str(&format!("\n    {indent_str}{}\n", self.help));
 
         // Positional arguments
-        if !self.positional_args.is_empty() {
-            for (arg_name, arg_help) in &self.positional_args {
+        if !self.positional_args.is_empty()
+            || !self.optional_positional_args.is_empty()
+            || self.variadic_positional_arg.is_some()
+        {
+            for (arg_name, arg_help) in self
+                .positional_args
+                .iter()
+                .chain(&self.optional_positional_args)
+                .chain(&self.variadic_positional_arg)
+            {
                 output.push_str(&format!("\n{indent_str}{}:\n", arg_name.to_uppercase()));
                 output.push_str(&format!("{indent_str}    {arg_help}\n"));
             }
@@ -94,8 +390,14 @@ impl Command {
                 }
 
                 output.push_str(&format!(
-                    "{indent_str}    -{}, --{}  <ARGUMENT>\n",
-                    flag.name.0, flag.name.1
+                    "{indent_str}    -{}, --{}{}\n",
+                    flag.name.0,
+                    flag.name.1,
+                    match (flag.takes_argument, flag.repeatable) {
+                        (true, true) => "  <ARGUMENT>...",
+                        (true, false) => "  <ARGUMENT>",
+                        _ => "",
+                    }
                 ));
                 output.push_str(&format!("{indent_str}        {}\n", flag.help));
             }
@@ -113,29 +415,86 @@ impl Command {
             }
         }
 
+        for (title, body) in &self.help_sections {
+            output.push_str(&format!("\n{indent_str}{}:\n", title.to_uppercase()));
+            for line in body.lines() {
+                output.push_str(&format!("{indent_str}    {line}\n"));
+            }
+        }
+
         output
     }
 
     pub fn generate_help(&self) -> String {
         self.generate_help_impl(0)
     }
+
+    /// Generates a script that completes the subcommands, flags and positional arguments of
+    /// `program` in `shell`. See `completions::generate` for what `helpers` is.
+    pub fn generate_completions(
+        &self,
+        shell: Shell,
+        program: &str,
+        helpers: &[(&str, &str)],
+    ) -> String {
+        completions::generate(self, shell, program, helpers)
+    }
+}
+
+// Swapping two neighbouring characters counts as a single edit, since it is a common typo.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let a = a.chars().collect::<Vec<char>>();
+    let b = b.chars().collect::<Vec<char>>();
+
+    let mut distances = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for (i, row) in distances.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    for (j, distance) in distances[0].iter_mut().enumerate() {
+        *distance = j;
+    }
+
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let substitution = distances[i - 1][j - 1] + usize::from(a[i - 1] != b[j - 1]);
+            let mut distance = substitution
+                .min(distances[i - 1][j] + 1)
+                .min(distances[i][j - 1] + 1);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                distance = distance.min(distances[i - 2][j - 2] + 1);
+            }
+            distances[i][j] = distance;
+        }
+    }
+
+    distances[a.len()][b.len()]
+}
+
+/// Returns the candidate closest to `name`, if it is close enough to likely be a typo of it.
+fn closest_match<'a>(name: &str, candidates: impl IntoIterator<Item = &'a str>) -> Option<&'a str> {
+    let max_distance = (name.chars().count() / 3).clamp(1, 3);
+    candidates
+        .into_iter()
+        .map(|candidate| (edit_distance(name, candidate), candidate))
+        .filter(|(distance, _)| *distance <= max_distance)
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, candidate)| candidate)
+}
+
+fn suggestion(name: &str, candidates: impl IntoIterator<Item = String>) -> String {
+    let candidates = candidates.into_iter().collect::<Vec<String>>();
+    match closest_match(name, candidates.iter().map(String::as_str)) {
+        Some(candidate) => format!(" (did you mean `{candidate}`?)"),
+        None => String::new(),
+    }
 }
 
 struct Parser {
-    argv: Peekable<env::Args>,
+    argv: Peekable<vec::IntoIter<String>>,
     program_name: String,
 }
 
 impl Parser {
-    fn new() -> Self {
-        let mut parser = Self {
-            argv: env::args().peekable(),
-            program_name: String::new(),
-        };
-        parser.program_name = parser.argv.next().unwrap();
-        parser
-    }
-
     fn parse(&mut self, command: &Command) -> Result<ParsedCommand, String> {
         let mut parsed_command = ParsedCommand {
             name: match &command.name {
@@ -143,7 +502,10 @@ impl Parser {
                 None => self.program_name.clone(),
             },
             flags: HashMap::new(),
+            repeated_flags: HashMap::new(),
+            values: HashMap::new(),
             positional_args: HashMap::new(),
+            variadic_args: Vec::new(),
             subcommand: None,
         };
 
@@ -163,6 +525,19 @@ impl Parser {
             parsed_command.positional_args.insert(arg_name.clone(), arg);
         }
 
+        for (arg_name, _) in &command.optional_positional_args {
+            match self.argv.next_if(|arg| !arg.starts_with("-")) {
+                Some(arg) => parsed_command.positional_args.insert(arg_name.clone(), arg),
+                None => break,
+            };
+        }
+
+        if command.variadic_positional_arg.is_some() {
+            while let Some(arg) = self.argv.next_if(|arg| !arg.starts_with("-")) {
+                parsed_command.variadic_args.push(arg);
+            }
+        }
+
         if !command.flags.is_empty() {
             while let Some(arg) = self.argv.peek() {
                 if !arg.starts_with("-") {
@@ -173,6 +548,26 @@ impl Parser {
                 let mut flag_known = false;
                 for flag in &command.flags {
                     if format!("-{}", flag.name.0) == arg || format!("--{}", flag.name.1) == arg {
+                        if !flag.takes_argument {
+                            parsed_command
+                                .flags
+                                .insert(flag.name.1.clone(), String::new());
+                            if flag.ends_parsing {
+                                return Ok(parsed_command);
+                            }
+
+                            flag_known = true;
+                            break;
+                        }
+
+                        // A second argument would otherwise silently replace the first.
+                        if !flag.repeatable && parsed_command.flags.contains_key(&flag.name.1) {
+                            return Err(format!(
+                                "the flag --{} was given more than once",
+                                flag.name.1
+                            ));
+                        }
+
                         let flag_argument = match self.argv.next() {
                             Some(flag_argument) => flag_argument,
                             None => {
@@ -181,9 +576,20 @@ impl Parser {
                                 ));
                             }
                         };
-                        parsed_command
-                            .flags
-                            .insert(flag.name.1.clone(), flag_argument);
+                        if let Some(value) = flag.parse_argument(&flag_argument)? {
+                            parsed_command.values.insert(flag.name.1.clone(), value);
+                        }
+                        if flag.repeatable {
+                            parsed_command
+                                .repeated_flags
+                                .entry(flag.name.1.clone())
+                                .or_default()
+                                .push(flag_argument);
+                        } else {
+                            parsed_command
+                                .flags
+                                .insert(flag.name.1.clone(), flag_argument);
+                        }
 
                         flag_known = true;
                         break;
@@ -191,7 +597,17 @@ impl Parser {
                 }
 
                 if !flag_known {
-                    return Err(format!("unknown flag: {arg}"));
+                    let suggestion = suggestion(
+                        &arg,
+                        command.flags.iter().map(|flag| {
+                            if arg.starts_with("--") {
+                                format!("--{}", flag.name.1)
+                            } else {
+                                format!("-{}", flag.name.0)
+                            }
+                        }),
+                    );
+                    return Err(format!("unknown flag: {arg}{suggestion}"));
                 }
             }
         }
@@ -222,11 +638,18 @@ impl Parser {
             }
 
             if parsed_command.subcommand.is_none() {
+                let suggestion = suggestion(
+                    &arg,
+                    command
+                        .subcommands
+                        .iter()
+                        .filter_map(|subcommand| subcommand.name.clone()),
+                );
                 return match &command.name {
                     Some(name) => Err(format!(
-                        "an unknown subcommand was provided to the {name} subcommand"
+                        "an unknown subcommand was provided to the {name} subcommand: {arg}{suggestion}"
                     )),
-                    None => Err(format!("unknown subcommand: {arg}")),
+                    None => Err(format!("unknown subcommand: {arg}{suggestion}")),
                 };
             }
         }
@@ -236,7 +659,69 @@ impl Parser {
 }
 
 pub fn parse(command: &Command) -> Result<ParsedCommand, String> {
-    let mut parser = Parser::new();
+    parse_from(command, env::args().collect())
+}
+
+/// Splits a line into arguments the way a shell would, minus expansions: words are separated by
+/// whitespace, single quotes keep everything up to the next one as it is, double quotes and
+/// backslashes escape what follows them, and a `#` starting a word comments out the rest.
+pub fn split_line(line: &str) -> Result<Vec<String>, String> {
+    let mut args = Vec::new();
+    let mut arg = None::<String>;
+    let mut chars = line.chars();
+
+    while let Some(char) = chars.next() {
+        match char {
+            '#' if arg.is_none() => break,
+            char if char.is_whitespace() => args.extend(arg.take()),
+            '\\' => {
+                let escaped = chars.next().ok_or("the line ends with a lone backslash")?;
+                arg.get_or_insert_default().push(escaped);
+            }
+            '\'' => {
+                let arg = arg.get_or_insert_default();
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(char) => arg.push(char),
+                        None => return Err("a single quote is never closed".to_string()),
+                    }
+                }
+            }
+            '"' => {
+                let arg = arg.get_or_insert_default();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(char @ ('"' | '\\')) => arg.push(char),
+                            Some(char) => {
+                                arg.push('\\');
+                                arg.push(char);
+                            }
+                            None => return Err("a double quote is never closed".to_string()),
+                        },
+                        Some(char) => arg.push(char),
+                        None => return Err("a double quote is never closed".to_string()),
+                    }
+                }
+            }
+            char => arg.get_or_insert_default().push(char),
+        }
+    }
+    args.extend(arg);
+
+    Ok(args)
+}
+
+/// Parses `args` as if they were the arguments of the program, the first one being its name.
+pub fn parse_from(command: &Command, args: Vec<String>) -> Result<ParsedCommand, String> {
+    let mut args = args.into_iter();
+    let program_name = args.next().unwrap_or_default();
+    let mut parser = Parser {
+        argv: args.peekable(),
+        program_name,
+    };
     parser.parse(command)
 }
 
@@ -244,6 +729,432 @@ pub fn parse(command: &Command) -> Result<ParsedCommand, String> {
 pub struct ParsedCommand {
     pub name: String,
     pub flags: HashMap<String, String>,
+    pub repeated_flags: HashMap<String, Vec<String>>,
+    /// The checked arguments of the typed flags, unless they were empty.
+    pub values: HashMap<String, FlagValue>,
     pub positional_args: HashMap<String, String>,
+    /// The values of the variadic positional argument, if the command has one.
+    pub variadic_args: Vec<String>,
     pub subcommand: Option<Box<ParsedCommand>>,
 }
+
+impl ParsedCommand {
+    pub fn has_flag(&self, name: &str) -> bool {
+        self.flags.contains_key(name) || self.repeated_flags.contains_key(name)
+    }
+
+    pub fn get_int(&self, name: &str) -> Option<u64> {
+        match self.values.get(name)? {
+            FlagValue::Int(int) => Some(*int),
+            _ => None,
+        }
+    }
+
+    /// Returns the argument of a JSON flag as the type it was added with.
+    pub fn get_json<T: DeserializeOwned>(&self, name: &str) -> Option<T> {
+        match self.values.get(name)? {
+            // Already known to deserialize, since it was checked while parsing.
+            FlagValue::Json(json) => Some(serde_json::from_value(json.clone()).unwrap()),
+            _ => None,
+        }
+    }
+
+    /// Returns every argument passed to a repeatable flag, in order.
+    pub fn get_all(&self, name: &str) -> &[String] {
+        self.repeated_flags
+            .get(name)
+            .map(|values| values.as_slice())
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::panic;
+
+    use super::*;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(str::to_string).collect()
+    }
+
+    fn cli() -> Command {
+        let mut root = Command::new(None, "The program.");
+
+        let mut status = Command::new(Some("status"), "Shows a service or all of them.");
+        status.add_optional_positional_arg("service name", "The name of the service.");
+        status.add_bool_flag("q", "quiet", "Shows less.");
+        root.add_subcommand(status);
+
+        let mut remove = Command::new(Some("remove"), "Removes a service.");
+        remove.add_positional_arg("service name", "The name of the service.");
+        root.add_subcommand(remove);
+
+        root
+    }
+
+    #[test]
+    fn optional_positional_arg_can_be_left_out() {
+        let parsed = parse_from(&cli(), args("ctl status")).unwrap();
+        let status = parsed.subcommand.unwrap();
+        assert_eq!(status.name, "status");
+        assert_eq!(status.positional_args.get("service name"), None);
+    }
+
+    #[test]
+    fn optional_positional_arg_is_taken_when_given() {
+        let parsed = parse_from(&cli(), args("ctl status web -q")).unwrap();
+        let status = parsed.subcommand.unwrap();
+        assert_eq!(
+            status
+                .positional_args
+                .get("service name")
+                .map(String::as_str),
+            Some("web")
+        );
+        assert!(status.has_flag("quiet"));
+    }
+
+    #[test]
+    fn flag_is_not_taken_for_a_left_out_optional_positional_arg() {
+        let parsed = parse_from(&cli(), args("ctl status --quiet")).unwrap();
+        let status = parsed.subcommand.unwrap();
+        assert_eq!(status.positional_args.get("service name"), None);
+        assert!(status.has_flag("quiet"));
+    }
+
+    #[test]
+    fn missing_required_positional_arg_is_an_error() {
+        let err = parse_from(&cli(), args("ctl remove")).err().unwrap();
+        assert_eq!(err, "no service name was provided to the remove subcommand");
+    }
+
+    #[test]
+    fn help_brackets_optional_positional_args() {
+        let mut logs = Command::new(Some("logs"), "Shows logs.");
+        logs.add_positional_arg("service name", "The name of the service.");
+        logs.add_optional_positional_arg("lines", "How many lines.");
+
+        let help = logs.generate_help();
+        assert!(help.starts_with("logs <SERVICE NAME> [LINES]\n"), "{help}");
+    }
+
+    #[test]
+    #[should_panic(
+        expected = "the required positional argument `name` of the `add` command comes after an optional one"
+    )]
+    fn required_positional_arg_after_an_optional_one_panics() {
+        let mut add = Command::new(Some("add"), "Adds something.");
+        add.add_optional_positional_arg("kind", "The kind.");
+        add.add_positional_arg("name", "The name.");
+    }
+
+    #[test]
+    #[should_panic(expected = "the `status` command has two flags with the long name --quiet")]
+    fn flags_sharing_a_long_name_panic() {
+        let mut status = Command::new(Some("status"), "Shows a service.");
+        status.add_bool_flag("q", "quiet", "Shows less.");
+        status.add_flag("Q", "quiet", "Shows even less.");
+    }
+
+    #[test]
+    #[should_panic(expected = "the `root` command has two flags with the short name -v")]
+    fn flags_sharing_a_short_name_panic() {
+        let mut root = Command::new(None, "The program.");
+        root.add_bool_flag("v", "verbose", "Shows more.");
+        root.add_version_flag("Prints the version.");
+        root.add_int_flag("v", "verbosity", "How much to show.");
+    }
+
+    #[test]
+    fn reserved_names_panic_whichever_way_the_flag_is_added() {
+        let adders: [fn(&mut Command); 3] = [
+            |command| command.add_bool_flag("h", "human", "Human-readable sizes."),
+            |command| command.add_flag("H", "help", "Help with something."),
+            |command| command.add_repeatable_flag("x", "help", "Repeated help."),
+        ];
+
+        for add in adders {
+            let result = panic::catch_unwind(|| add(&mut Command::new(Some("logs"), "Logs.")));
+            let message = result.unwrap_err();
+            let message = message.downcast_ref::<String>().unwrap();
+            assert!(message.ends_with("uses a reserved name"), "{message}");
+        }
+    }
+
+    #[test]
+    #[should_panic(
+        expected = "the short name of the flag --to of the `export` command is longer than its long name"
+    )]
+    fn short_name_longer_than_the_long_one_panics() {
+        let mut export = Command::new(Some("export"), "Exports the services.");
+        export.add_flag("out", "to", "Where to write them.");
+    }
+
+    #[test]
+    #[should_panic(expected = "the `root` command has two subcommands named `start`")]
+    fn subcommands_sharing_a_name_panic() {
+        let mut root = Command::new(None, "The program.");
+        root.add_subcommand(Command::new(Some("start"), "Starts a service."));
+        root.add_subcommand(Command::new(Some("stop"), "Stops a service."));
+        root.add_subcommand(Command::new(Some("start"), "Starts it again."));
+    }
+
+    #[test]
+    #[should_panic(expected = "the `env` command has two positional arguments named `name`")]
+    fn positional_args_sharing_a_name_panic() {
+        let mut env = Command::new(Some("env"), "Sets a variable.");
+        env.add_positional_arg("name", "The name of the service.");
+        env.add_optional_positional_arg("name", "The name of the variable.");
+    }
+
+    #[test]
+    #[should_panic(
+        expected = "the `group` command has both optional positional arguments and subcommands"
+    )]
+    fn subcommands_after_an_optional_positional_arg_panic() {
+        let mut group = Command::new(Some("group"), "Manages groups.");
+        group.add_optional_positional_arg("group name", "The group to show.");
+        group.add_subcommand(Command::new(Some("list"), "Lists the groups."));
+    }
+
+    fn parse_error(line: &str) -> String {
+        parse_from(&cli(), args(line)).err().unwrap()
+    }
+
+    #[test]
+    fn edit_distance_counts_swapped_neighbours_as_one_edit() {
+        assert_eq!(edit_distance("", "list"), 4);
+        assert_eq!(edit_distance("list", "list"), 0);
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+        assert_eq!(edit_distance("stauts", "status"), 1);
+        assert_eq!(edit_distance("ab", "ba"), 1);
+        assert_eq!(edit_distance("abc", "ca"), 3);
+    }
+
+    #[test]
+    fn closest_match_allows_more_edits_for_longer_names() {
+        let candidates = ["list", "list-services", "logs", "restart", "remove"];
+        let closest = |name| closest_match(name, candidates);
+        assert_eq!(closest("lst"), Some("list"));
+        assert_eq!(closest("lgos"), Some("logs"));
+        assert_eq!(closest("lst-srvices"), Some("list-services"));
+        assert_eq!(closest("rmove"), Some("remove"));
+        // Two edits are too many for a name this short, but not for a longer one.
+        assert_eq!(closest("lx"), None);
+        assert_eq!(closest("rmovee"), Some("remove"));
+        assert_eq!(closest("deploy"), None);
+        assert_eq!(closest_match("list", []), None);
+        // The first of the equally close candidates wins.
+        assert_eq!(closest_match("rest", ["best", "test"]), Some("best"));
+    }
+
+    #[test]
+    fn unknown_subcommand_suggests_the_closest_one() {
+        assert_eq!(
+            parse_error("ctl stauts"),
+            "unknown subcommand: stauts (did you mean `status`?)"
+        );
+        assert_eq!(
+            parse_error("ctl remvoe web"),
+            "unknown subcommand: remvoe (did you mean `remove`?)"
+        );
+    }
+
+    #[test]
+    fn unknown_flag_suggests_the_closest_one_in_the_same_form() {
+        assert_eq!(
+            parse_error("ctl status --quite"),
+            "unknown flag: --quite (did you mean `--quiet`?)"
+        );
+        assert_eq!(
+            parse_error("ctl status -Q"),
+            "unknown flag: -Q (did you mean `-q`?)"
+        );
+    }
+
+    #[test]
+    fn nothing_is_suggested_when_no_name_is_close() {
+        assert_eq!(parse_error("ctl deploy"), "unknown subcommand: deploy");
+        assert_eq!(
+            parse_error("ctl status --verbose"),
+            "unknown flag: --verbose"
+        );
+        assert!(!parse_error("ctl st").contains("did you mean"));
+    }
+
+    #[test]
+    fn a_flag_given_twice_is_an_error_unless_it_is_repeatable() {
+        let mut logs = Command::new(Some("logs"), "Shows logs.");
+        logs.add_flag("s", "since", "Where to start.");
+        logs.add_repeatable_flag("g", "grep", "What to look for.");
+        let parse = |line| parse_from(&logs, args(line));
+
+        assert_eq!(
+            parse("logs --since 1h -s 2h").err().unwrap(),
+            "the flag --since was given more than once"
+        );
+        let parsed = parse("logs -g error --since 1h --grep panic").unwrap();
+        assert_eq!(parsed.flags.get("since").map(String::as_str), Some("1h"));
+        assert_eq!(parsed.get_all("grep"), ["error", "panic"]);
+    }
+
+    #[test]
+    fn typed_flags_check_their_arguments() {
+        let mut list = Command::new(Some("list"), "Lists the services.");
+        list.add_int_flag("n", "limit", "How many to list.");
+        list.add_json_flag::<Vec<u16>>("p", "ports", "The ports they listen on.");
+        list.add_choice_flag("f", "format", "How to show them.", &["table", "json"]);
+        let parse = |line| parse_from(&list, args(line));
+
+        let parsed = parse("list -n 20 --ports [80,443] -f json").unwrap();
+        assert_eq!(parsed.get_int("limit"), Some(20));
+        assert_eq!(parsed.get_json::<Vec<u16>>("ports"), Some(vec![80, 443]));
+        assert_eq!(
+            parsed.values.get("format"),
+            Some(&FlagValue::Choice("json".to_string()))
+        );
+
+        for (line, err) in [
+            ("list -n ten", "--limit expects a non-negative integer"),
+            ("list -n -1", "--limit expects a non-negative integer"),
+            ("list -f yaml", "--format expects one of: table, json"),
+        ] {
+            assert_eq!(parse(line).err().unwrap(), err, "{line}");
+        }
+        // Checked against the type of the flag, not only as JSON.
+        let err = parse(r#"list --ports ["http"]"#).err().unwrap();
+        assert!(err.starts_with("--ports expects valid JSON: "), "{err}");
+        let err = parse("list --ports [70000]").err().unwrap();
+        assert!(err.starts_with("--ports expects valid JSON: "), "{err}");
+
+        // Empty arguments clear integer and JSON fields, so they pass unchecked.
+        let parsed = parse_from(
+            &list,
+            vec!["list".to_string(), "-n".to_string(), String::new()],
+        )
+        .unwrap();
+        assert_eq!(parsed.flags.get("limit").map(String::as_str), Some(""));
+        assert_eq!(parsed.get_int("limit"), None);
+        let err = parse_from(
+            &list,
+            vec!["list".to_string(), "-f".to_string(), String::new()],
+        )
+        .err()
+        .unwrap();
+        assert_eq!(err, "--format expects one of: table, json");
+    }
+
+    #[test]
+    fn variadic_positional_arg_takes_what_is_left_until_a_flag() {
+        let mut start = Command::new(Some("start"), "Starts services.");
+        start.add_positional_arg("first", "The first service.");
+        start.add_variadic_positional_arg("others", "The other services.");
+        start.add_bool_flag("w", "wait", "Waits for them.");
+        let parse = |line| parse_from(&start, args(line));
+
+        let parsed = parse("start web db cache --wait").unwrap();
+        assert_eq!(parsed.positional_args["first"], "web");
+        assert_eq!(parsed.variadic_args, ["db", "cache"]);
+        assert!(parsed.has_flag("wait"));
+
+        let parsed = parse("start web").unwrap();
+        assert_eq!(parsed.variadic_args, Vec::<String>::new());
+        // Values after the flags aren't taken.
+        assert_eq!(
+            parse("start web --wait db").unwrap().variadic_args,
+            Vec::<String>::new()
+        );
+        assert_eq!(
+            parse("start").err().unwrap(),
+            "no first was provided to the start subcommand"
+        );
+    }
+
+    #[test]
+    fn optional_positional_args_are_filled_in_order() {
+        let mut logs = Command::new(Some("logs"), "Shows logs.");
+        logs.add_optional_positional_arg("service", "The service.");
+        logs.add_optional_positional_arg("lines", "How many lines.");
+        logs.add_bool_flag("f", "follow", "Keeps showing new lines.");
+
+        let parsed = parse_from(&logs, args("logs web -f")).unwrap();
+        assert_eq!(
+            parsed.positional_args.get("service").map(String::as_str),
+            Some("web")
+        );
+        assert_eq!(parsed.positional_args.get("lines"), None);
+        let parsed = parse_from(&logs, args("logs web 50")).unwrap();
+        assert_eq!(
+            parsed.positional_args.get("lines").map(String::as_str),
+            Some("50")
+        );
+    }
+
+    #[test]
+    fn variadic_positional_arg_must_be_the_only_optional_one() {
+        let adders: [fn(&mut Command); 3] = [
+            |command| command.add_optional_positional_arg("more", "More."),
+            |command| command.add_variadic_positional_arg("more", "More."),
+            |command| command.add_positional_arg("more", "More."),
+        ];
+
+        for add in adders {
+            let result = panic::catch_unwind(|| {
+                let mut stop = Command::new(Some("stop"), "Stops services.");
+                stop.add_variadic_positional_arg("services", "The services.");
+                add(&mut stop);
+            });
+            let message = result.unwrap_err();
+            let message = message.downcast_ref::<String>().unwrap();
+            assert!(message.contains("`stop` command"), "{message}");
+        }
+    }
+
+    #[test]
+    fn lines_are_split_like_a_shell_would() {
+        let split = |line| split_line(line).unwrap();
+        assert_eq!(split("  start \t web   db "), ["start", "web", "db"]);
+        assert_eq!(split(""), Vec::<String>::new());
+        assert_eq!(
+            split("edit sync 'my app' --group a\\ b"),
+            ["edit", "sync", "my app", "--group", "a b"]
+        );
+        // Quotes only group what they enclose, so they can start and end within a word.
+        assert_eq!(
+            split(r#"--set-env KEY='x y'"z" '' """#),
+            ["--set-env", "KEY=x yz", "", ""]
+        );
+    }
+
+    #[test]
+    fn only_double_quotes_and_backslashes_are_escaped_within_double_quotes() {
+        let split = |line| split_line(line).unwrap();
+        assert_eq!(
+            split(r#""say \"hi\"" "C:\\dir" "a\nb""#),
+            [r#"say "hi""#, r"C:\dir", r"a\nb"]
+        );
+        // Single quotes keep backslashes as they are.
+        assert_eq!(split(r"'a\b' 'it'\''s'"), [r"a\b", "it's"]);
+    }
+
+    #[test]
+    fn a_hash_starting_a_word_comments_out_the_rest() {
+        let split = |line| split_line(line).unwrap();
+        assert_eq!(split("# only a comment"), Vec::<String>::new());
+        assert_eq!(split("logs web # the last lines"), ["logs", "web"]);
+        assert_eq!(split("add web#1 '#2' \\#3"), ["add", "web#1", "#2", "#3"]);
+    }
+
+    #[test]
+    fn unfinished_quotes_and_escapes_are_errors() {
+        for (line, err) in [
+            ("start 'web", "a single quote is never closed"),
+            (r#"start "web"#, "a double quote is never closed"),
+            (r#"start "web\"#, "a double quote is never closed"),
+            (r"start web\", "the line ends with a lone backslash"),
+        ] {
+            assert_eq!(split_line(line).err().unwrap(), err, "{line}");
+        }
+    }
+}
@@ -0,0 +1,463 @@
+use std::fmt::Write;
+
+use super::Command;
+
+#[derive(Clone, Copy)]
+pub enum Shell {
+    Bash,
+    Zsh,
+    Fish,
+}
+
+impl Shell {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "bash" => Some(Self::Bash),
+            "zsh" => Some(Self::Zsh),
+            "fish" => Some(Self::Fish),
+            _ => None,
+        }
+    }
+}
+
+/// What can follow a command, flattened out of the command tree so that the scripts can look it
+/// up by the path of subcommands that led to it.
+struct Node {
+    path: String,
+    subcommands: Vec<String>,
+    argument_flags: Vec<String>,
+    bool_flags: Vec<String>,
+    // The helper subcommand that lists the values of each positional argument, if any.
+    positional_args: Vec<String>,
+}
+
+fn collect_nodes(command: &Command, path: &str, helpers: &[(&str, &str)], nodes: &mut Vec<Node>) {
+    let mut node = Node {
+        path: path.to_string(),
+        subcommands: Vec::new(),
+        argument_flags: Vec::new(),
+        bool_flags: Vec::new(),
+        positional_args: Vec::new(),
+    };
+
+    for flag in &command.flags {
+        let names = if flag.takes_argument {
+            &mut node.argument_flags
+        } else {
+            &mut node.bool_flags
+        };
+        names.push(format!("-{}", flag.name.0));
+        names.push(format!("--{}", flag.name.1));
+    }
+
+    for (name, _) in command
+        .positional_args
+        .iter()
+        .chain(&command.optional_positional_args)
+    {
+        let helper = helpers
+            .iter()
+            .find(|(arg_name, _)| arg_name == name)
+            .map(|(_, helper)| helper.to_string())
+            .unwrap_or_default();
+        node.positional_args.push(helper);
+    }
+
+    let mut children = Vec::new();
+    for subcommand in &command.subcommands {
+        let name = subcommand.name.clone().unwrap();
+        let subcommand_path = if path.is_empty() {
+            name.clone()
+        } else {
+            format!("{path} {name}")
+        };
+        node.subcommands.push(name);
+        children.push((subcommand, subcommand_path));
+    }
+
+    nodes.push(node);
+    for (subcommand, subcommand_path) in children {
+        collect_nodes(subcommand, &subcommand_path, helpers, nodes);
+    }
+}
+
+fn quoted_words(words: &[String]) -> String {
+    words
+        .iter()
+        .map(|word| format!("'{word}'"))
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+/// Generates a completion script for `program`. Positional arguments named in `helpers` are
+/// completed with the lines printed by running `program` with the helper as its only argument,
+/// and everything else that isn't a flag or subcommand is completed with file names.
+pub fn generate(
+    command: &Command,
+    shell: Shell,
+    program: &str,
+    helpers: &[(&str, &str)],
+) -> String {
+    let mut nodes = Vec::new();
+    collect_nodes(command, "", helpers, &mut nodes);
+
+    let function = format!("_{}", program.replace(|c: char| !c.is_alphanumeric(), "_"));
+    match shell {
+        Shell::Bash => generate_bash(&nodes, program, &function),
+        Shell::Zsh => generate_zsh(&nodes, program, &function),
+        Shell::Fish => generate_fish(&nodes, program, &function),
+    }
+}
+
+// The scripts of bash and zsh only differ in how they read the command line and offer
+// candidates, so they share how nodes are looked up.
+fn write_posix_nodes(output: &mut String, nodes: &[Node], function: &str) {
+    writeln!(output, "{function}_node() {{").unwrap();
+    writeln!(output, "    case \"$command_path\" in").unwrap();
+    for node in nodes {
+        writeln!(output, "    '{}')", node.path).unwrap();
+        writeln!(
+            output,
+            "        subcommands=({})",
+            quoted_words(&node.subcommands)
+        )
+        .unwrap();
+        writeln!(
+            output,
+            "        argument_flags=({})",
+            quoted_words(&node.argument_flags)
+        )
+        .unwrap();
+        writeln!(
+            output,
+            "        bool_flags=({})",
+            quoted_words(&node.bool_flags)
+        )
+        .unwrap();
+        writeln!(
+            output,
+            "        positional_args=({})",
+            quoted_words(&node.positional_args)
+        )
+        .unwrap();
+        writeln!(output, "        ;;").unwrap();
+    }
+    writeln!(output, "    esac").unwrap();
+    writeln!(output, "}}").unwrap();
+}
+
+fn generate_bash(nodes: &[Node], program: &str, function: &str) -> String {
+    let mut output = format!("# bash completion for {program}\n\n");
+    write_posix_nodes(&mut output, nodes, function);
+    write!(
+        output,
+        r#"
+{function}_contains() {{
+    local word="$1" candidate
+    shift
+    for candidate in "$@"; do
+        [[ "$candidate" == "$word" ]] && return 0
+    done
+    return 1
+}}
+
+{function}() {{
+    local cur="${{COMP_WORDS[COMP_CWORD]}}" command_path="" word helper
+    local subcommands argument_flags bool_flags positional_args
+    local i position=0 skip=0
+    {function}_node
+
+    for ((i = 1; i < COMP_CWORD; i++)); do
+        word="${{COMP_WORDS[i]}}"
+        if ((skip)); then
+            skip=0
+        elif {function}_contains "$word" "${{argument_flags[@]}}"; then
+            skip=1
+        elif [[ "$word" == -* ]]; then
+            :
+        elif ((position < ${{#positional_args[@]}})); then
+            ((position++))
+        elif {function}_contains "$word" "${{subcommands[@]}}"; then
+            command_path="${{command_path:+$command_path }}$word"
+            position=0
+            {function}_node
+        fi
+    done
+
+    COMPREPLY=()
+    if ((skip)); then
+        return
+    elif [[ "$cur" == -* ]]; then
+        COMPREPLY=($(compgen -W "${{argument_flags[*]}} ${{bool_flags[*]}}" -- "$cur"))
+    elif ((position < ${{#positional_args[@]}})); then
+        helper="${{positional_args[position]}}"
+        if [[ -n "$helper" ]]; then
+            COMPREPLY=($(compgen -W "$({program} "$helper" 2>/dev/null)" -- "$cur"))
+        fi
+    else
+        COMPREPLY=($(compgen -W "${{subcommands[*]}}" -- "$cur"))
+    fi
+}}
+
+complete -o default -F {function} {program}
+"#
+    )
+    .unwrap();
+    output
+}
+
+fn generate_zsh(nodes: &[Node], program: &str, function: &str) -> String {
+    let mut output = format!("#compdef {program}\n\n");
+    write_posix_nodes(&mut output, nodes, function);
+    write!(
+        output,
+        r#"
+{function}() {{
+    local command_path="" word helper
+    local -a subcommands argument_flags bool_flags positional_args
+    integer i position=0 skip=0
+    {function}_node
+
+    for ((i = 2; i < CURRENT; i++)); do
+        word="${{words[i]}}"
+        if ((skip)); then
+            skip=0
+        elif ((${{argument_flags[(Ie)$word]}})); then
+            skip=1
+        elif [[ "$word" == -* ]]; then
+            :
+        elif ((position < ${{#positional_args}})); then
+            ((position++))
+        elif ((${{subcommands[(Ie)$word]}})); then
+            command_path="${{command_path:+$command_path }}$word"
+            position=0
+            {function}_node
+        fi
+    done
+
+    if ((skip)); then
+        _files
+    elif [[ "$PREFIX" == -* ]]; then
+        compadd -- "${{argument_flags[@]}}" "${{bool_flags[@]}}"
+    elif ((position < ${{#positional_args}})); then
+        helper="${{positional_args[position + 1]}}"
+        if [[ -n "$helper" ]]; then
+            compadd -- ${{(f)"$({program} "$helper" 2>/dev/null)"}}
+        else
+            _files
+        fi
+    elif ((${{#subcommands}})); then
+        compadd -- "${{subcommands[@]}}"
+    else
+        _files
+    fi
+}}
+
+{function} "$@"
+"#
+    )
+    .unwrap();
+    output
+}
+
+fn generate_fish(nodes: &[Node], program: &str, function: &str) -> String {
+    let mut output = format!("# fish completion for {program}\n\n");
+
+    writeln!(
+        output,
+        "function {function}_node --argument-names command_path"
+    )
+    .unwrap();
+    writeln!(output, "    switch \"$command_path\"").unwrap();
+    for node in nodes {
+        writeln!(output, "        case '{}'", node.path).unwrap();
+        for (name, words) in [
+            ("subcommands", &node.subcommands),
+            ("argument_flags", &node.argument_flags),
+            ("bool_flags", &node.bool_flags),
+            ("positional_args", &node.positional_args),
+        ] {
+            let line = format!("set -g {function}_{name} {}", quoted_words(words));
+            writeln!(output, "            {}", line.trim_end()).unwrap();
+        }
+    }
+    writeln!(output, "    end").unwrap();
+    writeln!(output, "end").unwrap();
+
+    write!(
+        output,
+        r#"
+function {function}
+    set -l tokens (commandline -opc)
+    set -l current (commandline -ct)
+    set -l command_path ''
+    set -l position 0
+    set -l skip 0
+    {function}_node $command_path
+
+    for word in $tokens[2..-1]
+        if test $skip = 1
+            set skip 0
+        else if contains -- $word ${function}_argument_flags
+            set skip 1
+        else if string match -q -- '-*' $word
+            continue
+        else if test $position -lt (count ${function}_positional_args)
+            set position (math $position + 1)
+        else if contains -- $word ${function}_subcommands
+            set command_path (string trim -- "$command_path $word")
+            set position 0
+            {function}_node $command_path
+        end
+    end
+
+    if test $skip = 1
+        __fish_complete_path $current
+    else if string match -q -- '-*' $current
+        printf '%s\n' ${function}_argument_flags ${function}_bool_flags
+    else if test $position -lt (count ${function}_positional_args)
+        set -l helper ${function}_positional_args[(math $position + 1)]
+        if test -n "$helper"
+            {program} $helper 2>/dev/null
+        else
+            __fish_complete_path $current
+        end
+    else if test (count ${function}_subcommands) -gt 0
+        printf '%s\n' ${function}_subcommands
+    else
+        __fish_complete_path $current
+    end
+end
+
+complete -c {program} -f -a '({function})'
+"#
+    )
+    .unwrap();
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::process;
+
+    use super::*;
+
+    const HELPERS: &[(&str, &str)] = &[("service name", "__complete-service-names")];
+
+    fn cli() -> Command {
+        let mut root = Command::new(None, "The program.");
+        root.add_bool_flag("v", "verbose", "Says more.");
+
+        let mut start = Command::new(Some("start"), "Starts a service.");
+        start.add_positional_arg("service name", "The name of the service.");
+        root.add_subcommand(start);
+
+        let mut logs = Command::new(Some("logs"), "Shows the logs of a service.");
+        logs.add_int_flag("n", "lines", "How many lines to show.");
+        logs.add_positional_arg("service name", "The name of the service.");
+        root.add_subcommand(logs);
+
+        let mut add = Command::new(Some("add"), "Adds a service.");
+        let mut sync = Command::new(Some("sync"), "Adds a synchronous service.");
+        sync.add_bool_flag("sh", "shell", "Runs the command with `sh -c`.");
+        sync.add_flag("wd", "working-directory", "Where the service runs.");
+        sync.add_positional_arg("name", "The name of the service.");
+        sync.add_positional_arg("command", "What the service runs.");
+        add.add_subcommand(sync);
+        root.add_subcommand(add);
+
+        root
+    }
+
+    /// Compares the script for `shell` with the one in `testdata`, or writes it there instead
+    /// when `UPDATE_GOLDEN_FILES` is set.
+    fn assert_golden(shell: Shell, file: &str) {
+        let script = generate(&cli(), shell, "ctl", HELPERS);
+        let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("src/flag/testdata")
+            .join(file);
+        if env::var_os("UPDATE_GOLDEN_FILES").is_some() {
+            fs::write(&path, &script).unwrap();
+        }
+        let golden = fs::read_to_string(&path).unwrap();
+        assert!(
+            script == golden,
+            "the {file} script changed; run the tests with UPDATE_GOLDEN_FILES=1 if it should have"
+        );
+    }
+
+    #[test]
+    fn bash_script_matches_golden_file() {
+        assert_golden(Shell::Bash, "completions.bash");
+    }
+
+    #[test]
+    fn zsh_script_matches_golden_file() {
+        assert_golden(Shell::Zsh, "completions.zsh");
+    }
+
+    #[test]
+    fn fish_script_matches_golden_file() {
+        assert_golden(Shell::Fish, "completions.fish");
+    }
+
+    // Completes the line in bash, with `ctl` being a function that answers for the helper.
+    fn complete_in_bash(words: &[&str]) -> Vec<String> {
+        let script = generate(&cli(), Shell::Bash, "ctl", HELPERS);
+        let line = words
+            .iter()
+            .map(|word| format!("'{word}'"))
+            .collect::<Vec<String>>()
+            .join(" ");
+        let test = format!(
+            r#"{script}
+ctl() {{ [[ "$1" == __complete-service-names ]] && printf '%s\n' web worker db; }}
+COMP_WORDS=({line})
+COMP_CWORD=$((${{#COMP_WORDS[@]}} - 1))
+_ctl
+printf '%s\n' "${{COMPREPLY[@]}}"
+"#
+        );
+        let output = process::Command::new("bash")
+            .args(["--norc", "-c", &test])
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{output:?}");
+        String::from_utf8(output.stdout)
+            .unwrap()
+            .lines()
+            .filter(|line| !line.is_empty())
+            .map(str::to_string)
+            .collect()
+    }
+
+    #[test]
+    fn bash_script_completes_subcommands_flags_and_service_names() {
+        assert_eq!(complete_in_bash(&["ctl", ""]), ["start", "logs", "add"]);
+        assert_eq!(complete_in_bash(&["ctl", "s"]), ["start"]);
+        assert_eq!(complete_in_bash(&["ctl", "--v"]), ["--verbose"]);
+        assert_eq!(complete_in_bash(&["ctl", "start", "w"]), ["web", "worker"]);
+        // The value of a flag isn't taken for the service name.
+        assert_eq!(
+            complete_in_bash(&["ctl", "logs", "--lines", "10", "d"]),
+            ["db"]
+        );
+        assert_eq!(
+            complete_in_bash(&["ctl", "logs", "--lines", ""]),
+            Vec::<String>::new()
+        );
+        assert_eq!(complete_in_bash(&["ctl", "add", ""]), ["sync"]);
+        assert_eq!(
+            complete_in_bash(&["ctl", "add", "sync", "--"]),
+            ["--working-directory", "--shell"]
+        );
+        // Positional arguments without a helper are left to the shell's own completion.
+        assert_eq!(
+            complete_in_bash(&["ctl", "add", "sync", "app", ""]),
+            Vec::<String>::new()
+        );
+    }
+}
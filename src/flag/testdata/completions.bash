@@ -0,0 +1,85 @@
+# bash completion for ctl
+
+_ctl_node() {
+    case "$command_path" in
+    '')
+        subcommands=('start' 'logs' 'add')
+        argument_flags=()
+        bool_flags=('-v' '--verbose')
+        positional_args=()
+        ;;
+    'start')
+        subcommands=()
+        argument_flags=()
+        bool_flags=()
+        positional_args=('__complete-service-names')
+        ;;
+    'logs')
+        subcommands=()
+        argument_flags=('-n' '--lines')
+        bool_flags=()
+        positional_args=('__complete-service-names')
+        ;;
+    'add')
+        subcommands=('sync')
+        argument_flags=()
+        bool_flags=()
+        positional_args=()
+        ;;
+    'add sync')
+        subcommands=()
+        argument_flags=('-wd' '--working-directory')
+        bool_flags=('-sh' '--shell')
+        positional_args=('' '')
+        ;;
+    esac
+}
+
+_ctl_contains() {
+    local word="$1" candidate
+    shift
+    for candidate in "$@"; do
+        [[ "$candidate" == "$word" ]] && return 0
+    done
+    return 1
+}
+
+_ctl() {
+    local cur="${COMP_WORDS[COMP_CWORD]}" command_path="" word helper
+    local subcommands argument_flags bool_flags positional_args
+    local i position=0 skip=0
+    _ctl_node
+
+    for ((i = 1; i < COMP_CWORD; i++)); do
+        word="${COMP_WORDS[i]}"
+        if ((skip)); then
+            skip=0
+        elif _ctl_contains "$word" "${argument_flags[@]}"; then
+            skip=1
+        elif [[ "$word" == -* ]]; then
+            :
+        elif ((position < ${#positional_args[@]})); then
+            ((position++))
+        elif _ctl_contains "$word" "${subcommands[@]}"; then
+            command_path="${command_path:+$command_path }$word"
+            position=0
+            _ctl_node
+        fi
+    done
+
+    COMPREPLY=()
+    if ((skip)); then
+        return
+    elif [[ "$cur" == -* ]]; then
+        COMPREPLY=($(compgen -W "${argument_flags[*]} ${bool_flags[*]}" -- "$cur"))
+    elif ((position < ${#positional_args[@]})); then
+        helper="${positional_args[position]}"
+        if [[ -n "$helper" ]]; then
+            COMPREPLY=($(compgen -W "$(ctl "$helper" 2>/dev/null)" -- "$cur"))
+        fi
+    else
+        COMPREPLY=($(compgen -W "${subcommands[*]}" -- "$cur"))
+    fi
+}
+
+complete -o default -F _ctl ctl
@@ -0,0 +1,75 @@
+# fish completion for ctl
+
+function _ctl_node --argument-names command_path
+    switch "$command_path"
+        case ''
+            set -g _ctl_subcommands 'start' 'logs' 'add'
+            set -g _ctl_argument_flags
+            set -g _ctl_bool_flags '-v' '--verbose'
+            set -g _ctl_positional_args
+        case 'start'
+            set -g _ctl_subcommands
+            set -g _ctl_argument_flags
+            set -g _ctl_bool_flags
+            set -g _ctl_positional_args '__complete-service-names'
+        case 'logs'
+            set -g _ctl_subcommands
+            set -g _ctl_argument_flags '-n' '--lines'
+            set -g _ctl_bool_flags
+            set -g _ctl_positional_args '__complete-service-names'
+        case 'add'
+            set -g _ctl_subcommands 'sync'
+            set -g _ctl_argument_flags
+            set -g _ctl_bool_flags
+            set -g _ctl_positional_args
+        case 'add sync'
+            set -g _ctl_subcommands
+            set -g _ctl_argument_flags '-wd' '--working-directory'
+            set -g _ctl_bool_flags '-sh' '--shell'
+            set -g _ctl_positional_args '' ''
+    end
+end
+
+function _ctl
+    set -l tokens (commandline -opc)
+    set -l current (commandline -ct)
+    set -l command_path ''
+    set -l position 0
+    set -l skip 0
+    _ctl_node $command_path
+
+    for word in $tokens[2..-1]
+        if test $skip = 1
+            set skip 0
+        else if contains -- $word $_ctl_argument_flags
+            set skip 1
+        else if string match -q -- '-*' $word
+            continue
+        else if test $position -lt (count $_ctl_positional_args)
+            set position (math $position + 1)
+        else if contains -- $word $_ctl_subcommands
+            set command_path (string trim -- "$command_path $word")
+            set position 0
+            _ctl_node $command_path
+        end
+    end
+
+    if test $skip = 1
+        __fish_complete_path $current
+    else if string match -q -- '-*' $current
+        printf '%s\n' $_ctl_argument_flags $_ctl_bool_flags
+    else if test $position -lt (count $_ctl_positional_args)
+        set -l helper $_ctl_positional_args[(math $position + 1)]
+        if test -n "$helper"
+            ctl $helper 2>/dev/null
+        else
+            __fish_complete_path $current
+        end
+    else if test (count $_ctl_subcommands) -gt 0
+        printf '%s\n' $_ctl_subcommands
+    else
+        __fish_complete_path $current
+    end
+end
+
+complete -c ctl -f -a '(_ctl)'
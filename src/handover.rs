@@ -0,0 +1,106 @@
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::env;
+use std::fs;
+use std::io;
+use std::os::fd::{BorrowedFd, RawFd};
+use std::os::unix::process::CommandExt;
+use std::process;
+
+use nix::fcntl::{self, FcntlArg, FdFlag};
+
+use serde::{Deserialize, Serialize};
+
+use super::service::ServiceHandover;
+use super::user_context;
+
+/// Tells a freshly exec'd daemon where to find the state left by the instance it replaced.
+/// Only ever set across that exec, so a handover file left behind by a crash is never trusted.
+const HANDOVER_VARIABLE: &str = "USERSERVERSD_HANDOVER";
+
+/// Everything a new daemon instance needs to replace the running one without touching the
+/// services.
+#[derive(Serialize, Deserialize)]
+pub struct Handover {
+    pub listener_fd: RawFd,
+    #[serde(default)]
+    pub socket_activated: bool,
+    pub services: HashMap<String, ServiceHandover>,
+}
+
+fn set_inherited(fd: RawFd, inherited: bool) -> io::Result<()> {
+    // SAFETY: the descriptors passed in are owned by the daemon and stay open for the call.
+    let fd = unsafe { BorrowedFd::borrow_raw(fd) };
+    let flags = if inherited {
+        FdFlag::empty()
+    } else {
+        FdFlag::FD_CLOEXEC
+    };
+    fcntl::fcntl(fd, FcntlArg::F_SETFD(flags))?;
+    Ok(())
+}
+
+/// Returns the path of the daemon binary, which, after an upgrade replaced it, is the new one.
+fn daemon_binary() -> io::Result<String> {
+    let path = env::current_exe()?.to_string_lossy().to_string();
+    Ok(path
+        .strip_suffix(" (deleted)")
+        .map(|path| path.to_string())
+        .unwrap_or(path))
+}
+
+fn try_exec(handover: &Handover) -> io::Result<Infallible> {
+    let path = user_context::get().handover_file_path()?;
+    fs::write(&path, serde_json::to_vec(handover)?)?;
+
+    let fds = handover
+        .services
+        .values()
+        .flat_map(|service| service.inherited_fds())
+        .chain([handover.listener_fd])
+        .collect::<Vec<RawFd>>();
+    for fd in &fds {
+        set_inherited(*fd, true)?;
+    }
+
+    let err = process::Command::new(daemon_binary()?)
+        .args(env::args_os().skip(1))
+        .env(HANDOVER_VARIABLE, &path)
+        .exec();
+
+    // Still running, so this instance carries on as if nothing happened.
+    for fd in &fds {
+        let _ = set_inherited(*fd, false);
+    }
+    let _ = fs::remove_file(&path);
+    Err(err)
+}
+
+/// Replaces the daemon with a new instance of its binary, run with the same arguments, that
+/// takes over from this one. Only returns if that fails.
+pub fn exec(handover: &Handover) -> io::Error {
+    let Err(err) = try_exec(handover);
+    err
+}
+
+/// Reads the state left by the daemon instance this one replaced, if any.
+pub fn take() -> Option<Handover> {
+    let path = env::var(HANDOVER_VARIABLE).ok()?;
+    // SAFETY: this runs before the daemon spawns any other thread.
+    unsafe { env::remove_var(HANDOVER_VARIABLE) };
+
+    let contents = fs::read(&path);
+    let _ = fs::remove_file(&path);
+
+    match contents.map(|contents| serde_json::from_slice::<Handover>(&contents)) {
+        Ok(Ok(handover)) => Some(handover),
+        Ok(Err(err)) => {
+            warn!("Failed to parse the state left by the previous instance: {err}");
+            None
+        }
+        Err(err) => {
+            warn!("Failed to read the state left by the previous instance: {err}");
+            None
+        }
+    }
+}
@@ -0,0 +1,216 @@
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use super::ipc::command::Command;
+use super::ipc::response::{HistoryEntry, ResponseKind, ResponseStatus};
+use super::log;
+use super::service_manager::is_secret_variable;
+use super::user_context::UserContext;
+
+/// How big the history file gets before it's moved aside for a new one, which replaces the
+/// one moved aside before it.
+const MAX_HISTORY_FILE_SIZE: u64 = 1024 * 1024;
+
+/// Keeps the commands the daemon ran, along with how they went, in a file of the state directory
+/// that only ever gets appended to.
+pub struct History {
+    // Where the commands are recorded, if the daemon has a state directory.
+    path: Option<String>,
+    include_read_only: bool,
+    // Held while the file is appended to or moved aside.
+    file: Mutex<()>,
+}
+
+impl History {
+    pub fn new(context: &UserContext, include_read_only: bool) -> Self {
+        Self {
+            path: context.history_file_path(),
+            include_read_only,
+            file: Mutex::new(()),
+        }
+    }
+
+    /// Whether the command gets recorded. Commands that only look at the daemon don't, unless
+    /// the daemon was asked to record them too.
+    pub fn records(&self, command: &Command) -> bool {
+        self.include_read_only || !command.is_read_only()
+    }
+
+    pub fn record(&self, mut command: serde_json::Value, status: ResponseStatus) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        hide_secrets(&mut command);
+        let entry = HistoryEntry::new(log::format_timestamp(SystemTime::now()), command, status);
+
+        let _file = self.file.lock().unwrap();
+        if let Err(err) = append(path, &entry) {
+            warn!("Failed to record the command in `{path}`: {err}");
+        }
+    }
+
+    /// Gets the last `limit` recorded commands, from the oldest to the newest. Lines that can't
+    /// be read are skipped.
+    pub fn read(
+        &self,
+        limit: usize,
+        warnings: &mut Vec<String>,
+    ) -> Result<ResponseKind, ResponseStatus> {
+        let Some(path) = &self.path else {
+            warnings.push("the daemon has no state directory, so it keeps no history".to_string());
+            return Ok(ResponseKind::History {
+                entries: Vec::new(),
+            });
+        };
+
+        let _file = self.file.lock().unwrap();
+        let mut entries = Vec::new();
+        for path in [format!("{path}.1"), path.clone()] {
+            let contents = match fs::read_to_string(&path) {
+                Ok(contents) => contents,
+                Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+                Err(err) => {
+                    return Err(ResponseStatus::HistoryFailed {
+                        message: format!("failed to read `{path}`: {err}"),
+                    });
+                }
+            };
+            entries.extend(
+                contents
+                    .lines()
+                    .filter_map(|line| serde_json::from_str::<HistoryEntry>(line).ok()),
+            );
+        }
+        let entries = entries.split_off(entries.len().saturating_sub(limit));
+
+        Ok(ResponseKind::History { entries })
+    }
+}
+
+/// Hides the values of the variables that look like secrets wherever the command holds the
+/// environment of a service, like `daemon-env` does for the daemon's own.
+fn hide_secrets(value: &mut serde_json::Value) {
+    match value {
+        serde_json::Value::Object(fields) => {
+            for (key, value) in fields {
+                match value {
+                    serde_json::Value::Object(variables)
+                        if key == "environment" || key == "stop_environment" =>
+                    {
+                        for (name, value) in variables {
+                            if is_secret_variable(name) {
+                                *value = "<hidden>".into();
+                            }
+                        }
+                    }
+                    value => hide_secrets(value),
+                }
+            }
+        }
+        serde_json::Value::Array(values) => values.iter_mut().for_each(hide_secrets),
+        _ => {}
+    }
+}
+
+fn append(path: &str, entry: &HistoryEntry) -> io::Result<()> {
+    if let Some(state_dir) = Path::new(path).parent() {
+        fs::create_dir_all(state_dir)?;
+    }
+    if fs::metadata(path).is_ok_and(|metadata| metadata.len() >= MAX_HISTORY_FILE_SIZE) {
+        fs::rename(path, format!("{path}.1"))?;
+    }
+
+    let mut line = serde_json::to_vec(entry)?;
+    line.push(b'\n');
+    // Only the user gets to read what their services were set up with.
+    File::options()
+        .append(true)
+        .create(true)
+        .mode(0o600)
+        .open(path)?
+        .write_all(&line)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::os::unix::fs::PermissionsExt;
+
+    use super::super::ipc::{self, ServiceKind};
+    use super::super::scratch::scratch_dir;
+    use super::*;
+
+    fn history(test: &str) -> (History, String) {
+        let path = scratch_dir("history", test)
+            .join("history.jsonl")
+            .to_string_lossy()
+            .to_string();
+        let history = History {
+            path: Some(path.clone()),
+            include_read_only: false,
+            file: Mutex::new(()),
+        };
+        (history, path)
+    }
+
+    fn variables(variables: &[(&str, &str)]) -> HashMap<String, String> {
+        variables
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn secrets_in_environments_are_hidden() {
+        let (history, path) = history("secrets_in_environments_are_hidden");
+        let mut service = ipc::Service::new(
+            "/srv/web",
+            ServiceKind::Asynchronous {
+                start_command: vec!["web".to_string(), "start".to_string()],
+                stop_command: vec!["web".to_string(), "stop".to_string()],
+                stop_environment: Some(variables(&[("DB_PASSWORD", "hunter2")])),
+                stop_working_directory: None,
+                pidfile: None,
+                command_timeout_secs: None,
+            },
+        );
+        service.environment = variables(&[("PORT", "8080"), ("GitHub_Token", "ghp_123")]);
+        let command = Command::ImportServices {
+            services: HashMap::from([("web".to_string(), service)]),
+            replace: false,
+            force: false,
+        };
+        history.record(serde_json::to_value(&command).unwrap(), ResponseStatus::Ok);
+
+        let contents = fs::read_to_string(&path).unwrap();
+        assert!(!contents.contains("hunter2") && !contents.contains("ghp_123"));
+        let ResponseKind::History { entries } = history.read(10, &mut Vec::new()).unwrap() else {
+            panic!("not a history");
+        };
+        let web = &entries[0].command["ImportServices"]["services"]["web"];
+        assert_eq!(web["environment"]["PORT"], "8080");
+        assert_eq!(web["environment"]["GitHub_Token"], "<hidden>");
+        assert_eq!(
+            web["kind"]["Asynchronous"]["stop_environment"]["DB_PASSWORD"],
+            "<hidden>"
+        );
+        // The rest of the definition is kept as it is.
+        assert_eq!(web["working_directory"], "/srv/web");
+    }
+
+    #[test]
+    fn the_history_file_is_only_readable_by_the_user() {
+        let (history, path) = history("the_history_file_is_only_readable_by_the_user");
+        let command = Command::StartService {
+            name: "web".to_string(),
+        };
+        history.record(serde_json::to_value(&command).unwrap(), ResponseStatus::Ok);
+
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+}
@@ -1,50 +1,225 @@
 use std::collections::HashMap;
-use std::io::{self, Read, Write};
+use std::io::{self, BufRead, Write};
 
 use serde::{Deserialize, Serialize};
 
+pub const MAX_COMMAND_SIZE: u64 = 10 * 1024 * 1024;
+
+/// How much of the end of the logs a status includes when the command doesn't say, in bytes.
+pub const DEFAULT_STATUS_LOG_BYTES: usize = 16 * 1024;
+
 #[derive(Serialize, Deserialize, Debug)]
+#[non_exhaustive]
 pub enum Command {
-    AddSynchronousService {
+    AddService {
         name: String,
-        working_directory: String,
-        environment: HashMap<String, String>,
-        group: Option<String>,
-
-        command: Vec<String>,
-    },
-    AddAsynchronousService {
-        name: String,
-        working_directory: String,
-        environment: HashMap<String, String>,
-        group: Option<String>,
-
-        start_command: Vec<String>,
-        stop_command: Vec<String>,
+        service: Box<super::Service>,
+        /// Skips checking that the working directory and the executables exist.
+        #[serde(default)]
+        force: bool,
     },
     RemoveService {
         name: String,
     },
+    /// Adds several services at once. Nothing changes unless every one of them is valid.
+    ImportServices {
+        services: HashMap<String, super::Service>,
+        /// Replaces the services that already exist and removes the ones that aren't imported,
+        /// instead of leaving them as they are.
+        #[serde(default)]
+        replace: bool,
+        #[serde(default)]
+        force: bool,
+    },
 
     StartService {
         name: String,
     },
     StopService {
         name: String,
+        #[serde(default)]
+        with_dependents: bool,
     },
     RestartService {
         name: String,
     },
+    /// Freezes a running service with SIGSTOP.
+    PauseService {
+        name: String,
+    },
+    /// Unfreezes a paused service with SIGCONT.
+    ResumeService {
+        name: String,
+    },
+    /// Has a running service reload its configuration with its reload signal or command.
+    ReloadService {
+        name: String,
+    },
+    WaitForService {
+        name: String,
+        timeout_ms: u64,
+    },
 
     GetServiceStatus {
         name: String,
+        /// How much of the end of the logs to include, in bytes, which defaults to
+        /// `DEFAULT_STATUS_LOG_BYTES`. The whole logs can be streamed with `StreamServiceLogs`.
+        #[serde(default)]
+        log_bytes: Option<usize>,
+    },
+    GetServiceSummaries,
+    GetServiceLogs {
+        name: String,
+        lines: usize,
+    },
+    StreamServiceLogs {
+        name: String,
+    },
+    ListServices {
+        /// Only lists the services of this group.
+        #[serde(default)]
+        group: Option<String>,
+        /// Only lists the services that are running, or only the ones that aren't.
+        #[serde(default)]
+        running: Option<bool>,
+        /// Only lists the services that have all of these labels.
+        #[serde(default)]
+        labels: HashMap<String, String>,
+    },
+
+    AddServiceToTarget {
+        target: String,
+        service: String,
     },
-    ListServices,
+    RemoveServiceFromTarget {
+        target: String,
+        service: String,
+    },
+    StartTarget {
+        name: String,
+        exclusive: bool,
+    },
+    StopTarget {
+        name: String,
+    },
+    ListTargets,
+
+    /// Lists every group that has services or a description.
+    ListGroups,
+    /// Sets the description of a group, or removes it if empty. The group doesn't need to have
+    /// any services.
+    DescribeGroup {
+        name: String,
+        description: String,
+    },
+    /// Moves every service of a group to another one, or out of any group if `to` is `None`.
+    MoveGroup {
+        from: String,
+        to: Option<String>,
+    },
+
+    /// Keeps the connection open and sends an event each time a service goes through one of
+    /// these kinds of events, or any of them if there are none. No other command can be sent
+    /// on the connection afterwards.
+    Subscribe {
+        #[serde(default)]
+        events: Vec<super::EventKind>,
+    },
+
+    /// Reads the configuration file again and applies what changed in it.
+    ReloadConfig,
+    /// Writes the configuration right away, instead of within a second of when it changes.
+    Flush,
+
+    /// Saves the definitions of the services, along with which of them are running, under a
+    /// name. A snapshot with the same name is replaced.
+    SaveSnapshot {
+        name: String,
+    },
+    /// Makes the services match a snapshot: the ones not in it are stopped and removed, the
+    /// others are added or changed to match it, and then only the ones that were running when
+    /// it was taken are left running.
+    RestoreSnapshot {
+        name: String,
+    },
+    ListSnapshots,
+
+    /// Gets the last `limit` commands that changed something, from the oldest to the newest.
+    GetHistory {
+        limit: usize,
+    },
+
+    Ping,
+    GetDaemonEnvironment,
+    RestartDaemon,
+    Shutdown,
 }
 
 impl Command {
-    pub fn read_from_stream<T: Read>(stream: &mut T) -> io::Result<Option<Command>> {
-        super::read_from_stream(stream)
+    /// Whether the command only looks at the state of the daemon, which it can still do while
+    /// the daemon stops.
+    pub fn is_read_only(&self) -> bool {
+        matches!(
+            self,
+            Self::WaitForService { .. }
+                | Self::GetServiceStatus { .. }
+                | Self::GetServiceSummaries
+                | Self::GetServiceLogs { .. }
+                | Self::StreamServiceLogs { .. }
+                | Self::ListServices { .. }
+                | Self::ListTargets
+                | Self::ListGroups
+                | Self::DescribeGroup { .. }
+                | Self::Subscribe { .. }
+                | Self::ListSnapshots
+                | Self::GetHistory { .. }
+                | Self::Ping
+                | Self::GetDaemonEnvironment
+        )
+    }
+
+    /// The name of the variant, such as `StartService`, for the logs of the daemon.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::AddService { .. } => "AddService",
+            Self::RemoveService { .. } => "RemoveService",
+            Self::ImportServices { .. } => "ImportServices",
+            Self::StartService { .. } => "StartService",
+            Self::StopService { .. } => "StopService",
+            Self::RestartService { .. } => "RestartService",
+            Self::PauseService { .. } => "PauseService",
+            Self::ResumeService { .. } => "ResumeService",
+            Self::ReloadService { .. } => "ReloadService",
+            Self::WaitForService { .. } => "WaitForService",
+            Self::GetServiceStatus { .. } => "GetServiceStatus",
+            Self::GetServiceSummaries => "GetServiceSummaries",
+            Self::GetServiceLogs { .. } => "GetServiceLogs",
+            Self::StreamServiceLogs { .. } => "StreamServiceLogs",
+            Self::ListServices { .. } => "ListServices",
+            Self::AddServiceToTarget { .. } => "AddServiceToTarget",
+            Self::RemoveServiceFromTarget { .. } => "RemoveServiceFromTarget",
+            Self::StartTarget { .. } => "StartTarget",
+            Self::StopTarget { .. } => "StopTarget",
+            Self::ListTargets => "ListTargets",
+            Self::ListGroups => "ListGroups",
+            Self::DescribeGroup { .. } => "DescribeGroup",
+            Self::MoveGroup { .. } => "MoveGroup",
+            Self::Subscribe { .. } => "Subscribe",
+            Self::ReloadConfig => "ReloadConfig",
+            Self::Flush => "Flush",
+            Self::SaveSnapshot { .. } => "SaveSnapshot",
+            Self::RestoreSnapshot { .. } => "RestoreSnapshot",
+            Self::ListSnapshots => "ListSnapshots",
+            Self::GetHistory { .. } => "GetHistory",
+            Self::Ping => "Ping",
+            Self::GetDaemonEnvironment => "GetDaemonEnvironment",
+            Self::RestartDaemon => "RestartDaemon",
+            Self::Shutdown => "Shutdown",
+        }
+    }
+
+    pub fn read_from_stream<T: BufRead>(stream: &mut T) -> io::Result<Option<Command>> {
+        super::read_from_stream(stream, MAX_COMMAND_SIZE)
     }
 
     pub fn write_to_stream<T: Write>(&self, stream: &mut T) -> io::Result<()> {
@@ -1,20 +1,27 @@
 use std::collections::HashMap;
-use std::fs;
-use std::io::{self, BufRead, BufReader, Read, Write};
-use std::path::Path;
-
-use nix::unistd;
+use std::io::{self, BufRead, Read, Write};
 
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 
-fn read_from_stream<S: Read, T: DeserializeOwned>(stream: &mut S) -> io::Result<Option<T>> {
+fn read_from_stream<S: BufRead, T: DeserializeOwned>(
+    stream: &mut S,
+    max_size: u64,
+) -> io::Result<Option<T>> {
     let mut bytes = Vec::<u8>::new();
-    BufReader::new(stream).read_until(255, &mut bytes)?;
-    if bytes.len() == 0 {
+    Read::take(stream, max_size.saturating_add(1)).read_until(255, &mut bytes)?;
+    if bytes.is_empty() {
         return Ok(None);
     }
-    bytes.pop();
+    if bytes.pop() != Some(255) {
+        if bytes.len() as u64 >= max_size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "message exceeds the maximum size",
+            ));
+        }
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+    }
 
     let data = serde_json::from_slice::<T>(bytes.as_slice())?;
 
@@ -28,31 +35,11 @@ fn write_to_stream<S: Write, T: Serialize>(data: &T, stream: &mut S) -> io::Resu
     stream.flush()
 }
 
-#[allow(dead_code)]
 pub mod command;
-#[allow(dead_code)]
 pub mod response;
 
-pub fn get_socket_path() -> io::Result<String> {
-    let mut base_path = "";
-    for path in vec!["/run", "/var/run", "/tmp"] {
-        if Path::new(path).exists() {
-            base_path = path;
-            break;
-        }
-    }
-    if base_path == "" {
-        return Err(io::Error::from(io::ErrorKind::NotFound));
-    }
-
-    let user_path = format!("{base_path}/user/{}", unistd::getuid().as_raw());
-    match fs::create_dir_all(&user_path) {
-        Ok(_) => Ok(format!("{user_path}/userserversd.sock")),
-        Err(_) => Ok(format!("{base_path}/userserversd.sock")),
-    }
-}
-
-#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+#[non_exhaustive]
 pub enum ServiceKind {
     Synchronous {
         command: Vec<String>,
@@ -60,13 +47,356 @@ pub enum ServiceKind {
     Asynchronous {
         start_command: Vec<String>,
         stop_command: Vec<String>,
+        stop_environment: Option<HashMap<String, String>>,
+        stop_working_directory: Option<String>,
+        pidfile: Option<String>,
+        /// How long the start and stop commands can run before they get killed, 60 seconds if
+        /// unset.
+        #[serde(default)]
+        command_timeout_secs: Option<u64>,
+    },
+    Oneshot {
+        command: Vec<String>,
+    },
+    Timer {
+        command: Vec<String>,
+        interval_secs: u64,
     },
 }
 
-#[derive(Serialize, Deserialize, Debug, PartialEq)]
+impl ServiceKind {
+    /// What the kind is called, such as `synchronous`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Synchronous { .. } => "synchronous",
+            Self::Asynchronous { .. } => "asynchronous",
+            Self::Oneshot { .. } => "oneshot",
+            Self::Timer { .. } => "timer",
+        }
+    }
+
+    /// The command that starts the service.
+    pub fn command(&self) -> &[String] {
+        match self {
+            Self::Synchronous { command }
+            | Self::Oneshot { command }
+            | Self::Timer { command, .. } => command,
+            Self::Asynchronous { start_command, .. } => start_command,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+#[non_exhaustive]
 pub struct Service {
     pub working_directory: String,
     pub environment: HashMap<String, String>,
     pub group: Option<String>,
     pub kind: ServiceKind,
+    #[serde(default)]
+    pub ports: Vec<u16>,
+    #[serde(default)]
+    pub health_check: Option<Vec<String>>,
+    #[serde(default)]
+    pub health_check_interval_secs: Option<u64>,
+    #[serde(default)]
+    pub ready_command: Option<Vec<String>>,
+    #[serde(default)]
+    pub ready_tcp_port: Option<u16>,
+    #[serde(default)]
+    pub watch_paths: Vec<String>,
+    #[serde(default)]
+    pub watch_disabled: bool,
+    #[serde(default)]
+    pub depends_on: Vec<String>,
+    #[serde(default)]
+    pub log_retention_secs: Option<u64>,
+    #[serde(default)]
+    pub pre_start: Option<Vec<String>>,
+    #[serde(default)]
+    pub post_stop: Option<Vec<String>>,
+    #[serde(default)]
+    pub on_failure: Option<Vec<String>>,
+    #[serde(default)]
+    pub clean_environment: bool,
+    #[serde(default)]
+    pub shell: bool,
+    #[serde(default)]
+    pub description: Option<String>,
+    #[serde(default)]
+    pub labels: HashMap<String, String>,
+    #[serde(default)]
+    pub reload_signal: Option<String>,
+    #[serde(default)]
+    pub reload_command: Option<Vec<String>>,
+    #[serde(default)]
+    pub create_working_directory: bool,
+    #[serde(default)]
+    pub process_title: bool,
+}
+
+impl Service {
+    /// A service with nothing set besides where and how it runs, which the other fields can be
+    /// set on afterwards.
+    ///
+    /// ```no_run
+    /// use userserversd::client::{Client, Error};
+    /// use userserversd::ipc::{Service, ServiceKind};
+    ///
+    /// let mut service = Service::new(
+    ///     "/srv/www",
+    ///     ServiceKind::Synchronous {
+    ///         command: vec!["python3".into(), "-m".into(), "http.server".into()],
+    ///     },
+    /// );
+    /// service.ports = vec![8000];
+    ///
+    /// let mut client = Client::connect()?;
+    /// client.add_service("www", service, false)?;
+    /// let status = client.service_status("www")?;
+    /// println!("www is {:?}", status.state);
+    /// # Ok::<(), Error>(())
+    /// ```
+    pub fn new(working_directory: impl Into<String>, kind: ServiceKind) -> Self {
+        Self {
+            working_directory: working_directory.into(),
+            environment: HashMap::new(),
+            group: None,
+            kind,
+            ports: Vec::new(),
+            health_check: None,
+            health_check_interval_secs: None,
+            ready_command: None,
+            ready_tcp_port: None,
+            watch_paths: Vec::new(),
+            watch_disabled: false,
+            depends_on: Vec::new(),
+            log_retention_secs: None,
+            pre_start: None,
+            post_stop: None,
+            on_failure: None,
+            clean_environment: false,
+            shell: false,
+            description: None,
+            labels: HashMap::new(),
+            reload_signal: None,
+            reload_command: None,
+            create_working_directory: false,
+            process_title: false,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Default)]
+#[non_exhaustive]
+pub struct ExitStatus {
+    pub code: Option<i32>,
+    pub signal: Option<i32>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Default)]
+#[non_exhaustive]
+pub struct ServiceRun {
+    pub started_secs_ago: u64,
+    pub ended_secs_ago: Option<u64>,
+    pub end_state: Option<ServiceState>,
+    pub exit: Option<ExitStatus>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Default)]
+#[non_exhaustive]
+pub struct TimerSchedule {
+    pub last_run_secs_ago: Option<u64>,
+    pub next_run_in_secs: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Default)]
+#[non_exhaustive]
+pub struct WatchTrigger {
+    pub path: String,
+    pub secs_ago: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Default)]
+#[non_exhaustive]
+pub struct Target {
+    pub services: Vec<String>,
+    pub running: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Default)]
+#[non_exhaustive]
+pub struct GroupInfo {
+    pub services: usize,
+    pub running: usize,
+    pub description: Option<String>,
+}
+
+impl ExitStatus {
+    pub fn new(code: Option<i32>, signal: Option<i32>) -> Self {
+        Self { code, signal }
+    }
+}
+
+impl ServiceRun {
+    /// A run that hasn't ended yet.
+    pub fn new(started_secs_ago: u64) -> Self {
+        Self {
+            started_secs_ago,
+            ..Self::default()
+        }
+    }
+}
+
+impl TimerSchedule {
+    pub fn new(last_run_secs_ago: Option<u64>, next_run_in_secs: Option<u64>) -> Self {
+        Self {
+            last_run_secs_ago,
+            next_run_in_secs,
+        }
+    }
+}
+
+impl WatchTrigger {
+    pub fn new(path: impl Into<String>, secs_ago: u64) -> Self {
+        Self {
+            path: path.into(),
+            secs_ago,
+        }
+    }
+}
+
+impl Target {
+    pub fn new(services: Vec<String>, running: usize) -> Self {
+        Self { services, running }
+    }
+}
+
+impl GroupInfo {
+    /// A group no service belongs to yet.
+    pub fn new(description: Option<String>) -> Self {
+        Self {
+            description,
+            ..Self::default()
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy, Default)]
+#[non_exhaustive]
+pub enum ServiceState {
+    #[default]
+    Stopped,
+    Running,
+    Unhealthy,
+    Failed,
+    Completed,
+    /// Running, but frozen with SIGSTOP until it is resumed.
+    Paused,
+}
+
+/// What a service went through, as told to the clients that subscribed to events.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+#[non_exhaustive]
+pub enum EventKind {
+    Started,
+    Stopped,
+    Failed,
+    /// Any other change of state, such as becoming unhealthy or being paused.
+    StateChanged,
+    Added,
+    Removed,
+    Edited,
+}
+
+/// The names event kinds go by on the command line.
+pub const EVENT_KINDS: [(&str, EventKind); 7] = [
+    ("started", EventKind::Started),
+    ("stopped", EventKind::Stopped),
+    ("failed", EventKind::Failed),
+    ("state-changed", EventKind::StateChanged),
+    ("added", EventKind::Added),
+    ("removed", EventKind::Removed),
+    ("edited", EventKind::Edited),
+];
+
+impl ServiceState {
+    /// Whether the service's process is up, healthy, unhealthy or paused.
+    pub fn is_active(self) -> bool {
+        matches!(self, Self::Running | Self::Unhealthy | Self::Paused)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    fn read(bytes: &[u8], max_size: u64) -> io::Result<Option<Vec<String>>> {
+        read_from_stream(&mut Cursor::new(bytes), max_size)
+    }
+
+    #[test]
+    fn messages_are_read_one_at_a_time() {
+        let mut bytes = Vec::new();
+        write_to_stream(&vec!["first"], &mut bytes).unwrap();
+        write_to_stream(&vec!["second", "third"], &mut bytes).unwrap();
+
+        let mut stream = Cursor::new(bytes);
+        let first: Option<Vec<String>> = read_from_stream(&mut stream, 1024).unwrap();
+        let second: Option<Vec<String>> = read_from_stream(&mut stream, 1024).unwrap();
+        let end: Option<Vec<String>> = read_from_stream(&mut stream, 1024).unwrap();
+        assert_eq!(first.unwrap(), ["first"]);
+        assert_eq!(second.unwrap(), ["second", "third"]);
+        assert_eq!(end, None);
+    }
+
+    #[test]
+    fn a_message_of_exactly_the_maximum_size_is_read() {
+        let message = b"[\"abcdef\"]\xff";
+        let max_size = message.len() as u64 - 1;
+        assert_eq!(read(message, max_size).unwrap().unwrap(), ["abcdef"]);
+    }
+
+    #[test]
+    fn a_message_over_the_maximum_size_is_refused() {
+        let message = b"[\"abcdefg\"]\xff";
+        let err = read(message, message.len() as u64 - 2).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(err.to_string(), "message exceeds the maximum size");
+    }
+
+    #[test]
+    fn an_unterminated_message_is_cut_short() {
+        let err = read(b"[\"abc", 1024).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn malformed_json_is_invalid_data() {
+        let err = read(b"[abc\xff", 1024).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn asynchronous_services_are_started_by_their_start_command() {
+        let kind = ServiceKind::Asynchronous {
+            start_command: vec!["pg_ctl".to_string(), "start".to_string()],
+            stop_command: vec!["pg_ctl".to_string(), "stop".to_string()],
+            stop_environment: None,
+            stop_working_directory: None,
+            pidfile: None,
+            command_timeout_secs: None,
+        };
+        assert_eq!(kind.name(), "asynchronous");
+        assert_eq!(kind.command(), ["pg_ctl", "start"]);
+
+        let kind = ServiceKind::Timer {
+            command: vec!["backup".to_string()],
+            interval_secs: 3600,
+        };
+        assert_eq!(kind.name(), "timer");
+        assert_eq!(kind.command(), ["backup"]);
+    }
 }
@@ -1,37 +1,469 @@
 use std::collections::HashMap;
-use std::io::{self, Read, Write};
+use std::fmt;
+use std::io::{self, BufRead, Write};
+use std::sync::Arc;
 
 use serde::{Deserialize, Serialize};
 
-#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+#[non_exhaustive]
 pub enum ResponseStatus {
     Ok,
     ServiceAlreadyExists,
     ServiceDoesNotExist,
+    TargetDoesNotExist,
+    ServiceNotInTarget,
+    GroupIsEmpty,
+    Busy,
+    ShuttingDown,
+    Stopping,
+    StartCommandFailed {
+        code: Option<i32>,
+    },
+    StopCommandFailed {
+        code: Option<i32>,
+    },
+    PidfileTimeout,
+    CommandTimedOut {
+        command: String,
+        secs: u64,
+    },
+    WorkingDirectoryNotFound {
+        path: String,
+    },
+    /// The PATH that was searched is only there for programs named without a slash.
+    ExecutableNotFound {
+        program: String,
+        search_path: Option<String>,
+    },
+    NotExecutable {
+        program: String,
+    },
+    HookFailed {
+        hook: String,
+        code: Option<i32>,
+    },
+    ServiceNotRunning,
+    ServiceAlreadyRunning,
+    /// The system refused something the daemon needed to do, such as spawning a process.
+    IoError {
+        message: String,
+    },
+    WaitTimedOut,
+    PortInUse {
+        port: u16,
+        service: Option<String>,
+    },
+    DependencyCycle {
+        services: Vec<String>,
+    },
+    DependencyFailed {
+        service: String,
+    },
+    ValidationFailed {
+        message: String,
+    },
+    NoReloadMethod,
+    NoKnownProcess,
+    ConfigReloadFailed {
+        message: String,
+    },
+    FlushFailed {
+        message: String,
+    },
+    SnapshotDoesNotExist,
+    SnapshotFailed {
+        message: String,
+    },
+    ReloadCommandFailed {
+        code: Option<i32>,
+    },
+    HistoryFailed {
+        message: String,
+    },
+}
+
+impl fmt::Display for ResponseStatus {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        match self {
+            Self::Ok => write!(fmt, "ok"),
+            Self::ServiceAlreadyExists => write!(fmt, "the service already exists"),
+            Self::ServiceDoesNotExist => write!(fmt, "the service does not exist"),
+            Self::TargetDoesNotExist => write!(fmt, "the target does not exist"),
+            Self::ServiceNotInTarget => write!(fmt, "the service is not part of the target"),
+            Self::GroupIsEmpty => write!(fmt, "no service belongs to the group"),
+            Self::Busy => write!(fmt, "the daemon is busy, try again later"),
+            Self::ShuttingDown => write!(fmt, "the daemon is restarting, try again in a moment"),
+            Self::Stopping => write!(fmt, "the daemon is stopping"),
+            Self::StartCommandFailed { code: Some(code) } => {
+                write!(fmt, "the start command exited with code {code}")
+            }
+            Self::StartCommandFailed { code: None } => {
+                write!(fmt, "the start command was killed by a signal")
+            }
+            Self::StopCommandFailed { code: Some(code) } => {
+                write!(fmt, "the stop command exited with code {code}")
+            }
+            Self::StopCommandFailed { code: None } => {
+                write!(fmt, "the stop command was killed by a signal")
+            }
+            Self::HookFailed {
+                hook,
+                code: Some(code),
+            } => write!(fmt, "the {hook} hook exited with code {code}"),
+            Self::HookFailed { hook, code: None } => {
+                write!(fmt, "the {hook} hook was killed by a signal")
+            }
+            Self::PortInUse {
+                port,
+                service: Some(service),
+            } => write!(
+                fmt,
+                "port {port} is already in use by the service `{service}`"
+            ),
+            Self::PortInUse {
+                port,
+                service: None,
+            } => write!(fmt, "port {port} is already in use"),
+            Self::ServiceNotRunning => write!(fmt, "the service is not running"),
+            Self::ServiceAlreadyRunning => write!(fmt, "the service is already running"),
+            Self::IoError { message } => write!(fmt, "{message}"),
+            Self::WaitTimedOut => write!(fmt, "the service did not become ready in time"),
+            Self::DependencyCycle { services } => write!(
+                fmt,
+                "the dependencies would form a cycle: {}",
+                services.join(" -> ")
+            ),
+            Self::DependencyFailed { service } => {
+                write!(fmt, "the dependency `{service}` failed to start")
+            }
+            Self::PidfileTimeout => {
+                write!(fmt, "the pidfile did not name a running process in time")
+            }
+            Self::CommandTimedOut { command, secs } => write!(
+                fmt,
+                "the {command} command was killed after running for {secs}s"
+            ),
+            Self::WorkingDirectoryNotFound { path } => {
+                write!(fmt, "the working directory `{path}` does not exist")
+            }
+            Self::ExecutableNotFound {
+                program,
+                search_path: Some(search_path),
+            } => write!(
+                fmt,
+                "the executable `{program}` was not found in PATH `{search_path}`"
+            ),
+            Self::ExecutableNotFound {
+                program,
+                search_path: None,
+            } => write!(fmt, "the executable `{program}` was not found"),
+            Self::NotExecutable { program } => {
+                write!(fmt, "`{program}` could not be run: permission denied")
+            }
+            Self::ValidationFailed { message } => write!(fmt, "{message}"),
+            Self::NoReloadMethod => write!(
+                fmt,
+                "the service has neither a reload signal nor a reload command"
+            ),
+            Self::ConfigReloadFailed { message } => {
+                write!(
+                    fmt,
+                    "the configuration file could not be reloaded: {message}"
+                )
+            }
+            Self::FlushFailed { message } => {
+                write!(fmt, "the configuration could not be saved: {message}")
+            }
+            Self::SnapshotDoesNotExist => write!(fmt, "the snapshot does not exist"),
+            Self::SnapshotFailed { message } => write!(fmt, "{message}"),
+            Self::HistoryFailed { message } => {
+                write!(fmt, "the command history could not be read: {message}")
+            }
+            Self::NoKnownProcess => write!(
+                fmt,
+                "the daemon knows of no process of the service to signal"
+            ),
+            Self::ReloadCommandFailed { code: Some(code) } => {
+                write!(fmt, "the reload command exited with code {code}")
+            }
+            Self::ReloadCommandFailed { code: None } => {
+                write!(fmt, "the reload command was killed by a signal")
+            }
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[non_exhaustive]
 pub enum ResponseKind {
     None,
-    ServiceStatus {
-        service: super::Service,
-        running: bool,
+    ServiceStatus(Box<ServiceStatus>),
+    ServiceLogs {
         logs: String,
     },
-    ServiceList {
-        services: HashMap<String, super::Service>,
+    LogChunk {
+        data: String,
+    },
+    LogEnd,
+    ServiceList(ServiceList),
+    ServiceSummaries {
+        summaries: HashMap<String, ServiceSummary>,
+    },
+    TargetList {
+        targets: HashMap<String, super::Target>,
+    },
+    GroupList {
+        groups: HashMap<String, super::GroupInfo>,
     },
+    MovedServices {
+        services: Vec<String>,
+    },
+    ConfigReloaded(ConfigReload),
+    Snapshot(SnapshotInfo),
+    SnapshotList {
+        snapshots: Vec<SnapshotInfo>,
+    },
+    SnapshotRestored(SnapshotRestore),
+    History {
+        entries: Vec<HistoryEntry>,
+    },
+    /// Sent for each event on a connection that subscribed to them.
+    Event(ServiceEvent),
+    DaemonInfo(DaemonInfo),
+    DaemonEnvironment(DaemonEnvironment),
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[non_exhaustive]
+pub struct ServiceStatus {
+    pub service: super::Service,
+    pub state: super::ServiceState,
+    pub running: bool,
+    pub ready: bool,
+    pub last_watch_trigger: Option<super::WatchTrigger>,
+    pub last_exit: Option<super::ExitStatus>,
+    pub timer: Option<super::TimerSchedule>,
+    pub oldest_log_secs_ago: Option<u64>,
+    pub pid: Option<u32>,
+    pub memory_bytes: Option<u64>,
+    pub cpu_percent: Option<f32>,
+    pub start_count: u64,
+    pub runs: Vec<super::ServiceRun>,
+    /// The end of the logs, starting at a line when it can.
+    pub logs: String,
+    /// Whether earlier logs were left out of `logs`.
+    pub logs_truncated: bool,
+}
+
+impl ServiceStatus {
+    /// The status of a service that never ran, which the rest of the fields can be set on.
+    pub fn new(service: super::Service, state: super::ServiceState) -> Self {
+        Self {
+            service,
+            state,
+            running: false,
+            ready: false,
+            last_watch_trigger: None,
+            last_exit: None,
+            timer: None,
+            oldest_log_secs_ago: None,
+            pid: None,
+            memory_bytes: None,
+            cpu_percent: None,
+            start_count: 0,
+            runs: Vec::new(),
+            logs: String::new(),
+            logs_truncated: false,
+        }
+    }
+}
+
+/// The state of a service at a glance, without its definition and logs.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Default)]
+#[non_exhaustive]
+pub struct ServiceSummary {
+    pub group: Option<String>,
+    pub state: super::ServiceState,
+    pub uptime_secs: Option<u64>,
+    pub start_count: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Default)]
+#[non_exhaustive]
+pub struct ServiceList {
+    pub services: HashMap<String, Arc<super::Service>>,
+    pub states: HashMap<String, super::ServiceState>,
+}
+
+impl ServiceList {
+    pub fn new(
+        services: HashMap<String, Arc<super::Service>>,
+        states: HashMap<String, super::ServiceState>,
+    ) -> Self {
+        Self { services, states }
+    }
+}
+
+/// What reloading the configuration file changed, with the names of the services sorted.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Default)]
+#[non_exhaustive]
+pub struct ConfigReload {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    pub changed: Vec<String>,
+}
+
+impl ConfigReload {
+    pub fn new(added: Vec<String>, removed: Vec<String>, changed: Vec<String>) -> Self {
+        Self {
+            added,
+            removed,
+            changed,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Default)]
+#[non_exhaustive]
+pub struct SnapshotInfo {
+    pub name: String,
+    /// When the snapshot was taken, as an RFC 3339 UTC timestamp.
+    pub created: String,
+    pub services: usize,
+    pub running: usize,
+}
+
+impl SnapshotInfo {
+    pub fn new(name: impl Into<String>, created: String, services: usize, running: usize) -> Self {
+        Self {
+            name: name.into(),
+            created,
+            services,
+            running,
+        }
+    }
+}
+
+/// What restoring a snapshot did, with the names of the services sorted.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Default)]
+#[non_exhaustive]
+pub struct SnapshotRestore {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    pub changed: Vec<String>,
+    /// The services that were started, including changed ones that were started again.
+    pub started: Vec<String>,
+    /// The services that were left in place but stopped, as they weren't running in the
+    /// snapshot.
+    pub stopped: Vec<String>,
+}
+
+/// A command the daemon ran, as recorded in its history.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[non_exhaustive]
+pub struct HistoryEntry {
+    /// When the command was run, as an RFC 3339 UTC timestamp.
+    pub timestamp: String,
+    pub command: serde_json::Value,
+    pub status: ResponseStatus,
+}
+
+impl HistoryEntry {
+    pub fn new(timestamp: String, command: serde_json::Value, status: ResponseStatus) -> Self {
+        Self {
+            timestamp,
+            command,
+            status,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[non_exhaustive]
+pub struct ServiceEvent {
+    pub service: String,
+    pub kind: super::EventKind,
+    /// The state of the service after the event.
+    pub state: super::ServiceState,
+    /// What caused the event, such as `exited with code 1`.
+    pub detail: String,
+    /// When the event happened, as an RFC 3339 UTC timestamp.
+    pub timestamp: String,
+}
+
+impl ServiceEvent {
+    pub fn new(
+        service: impl Into<String>,
+        kind: super::EventKind,
+        state: super::ServiceState,
+        detail: String,
+        timestamp: String,
+    ) -> Self {
+        Self {
+            service: service.into(),
+            kind,
+            state,
+            detail,
+            timestamp,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Default)]
+#[non_exhaustive]
+pub struct DaemonInfo {
+    pub version: String,
+    pub uptime_secs: u64,
+    pub config_path: Option<String>,
+    /// Why the configuration couldn't be saved the last time the daemon tried, if it couldn't.
+    #[serde(default)]
+    pub config_save_error: Option<String>,
+    pub socket_path: String,
+    pub watch_mechanism: String,
+    pub watch_interval_ms: u64,
+    pub clients_active: usize,
+    pub clients_max: usize,
+    pub services_total: usize,
+    pub services_running: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Default)]
+#[non_exhaustive]
+pub struct DaemonEnvironment {
+    pub working_directory: Option<String>,
+    pub uid: u32,
+    pub gid: u32,
+    pub variables_total: usize,
+    pub variables: HashMap<String, String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[non_exhaustive]
 pub struct Response {
     pub status: ResponseStatus,
     pub kind: ResponseKind,
+    /// Caveats about a command that still succeeded.
+    #[serde(default)]
+    pub warnings: Vec<String>,
 }
 
 impl Response {
-    pub fn read_from_stream<T: Read>(stream: &mut T) -> io::Result<Option<Response>> {
-        super::read_from_stream(stream)
+    pub fn new(status: ResponseStatus, kind: ResponseKind) -> Self {
+        Self {
+            status,
+            kind,
+            warnings: Vec::new(),
+        }
+    }
+
+    pub fn with_warnings(mut self, warnings: Vec<String>) -> Self {
+        self.warnings = warnings;
+        self
+    }
+
+    pub fn read_from_stream<T: BufRead>(stream: &mut T) -> io::Result<Option<Response>> {
+        super::read_from_stream(stream, u64::MAX)
     }
 
     pub fn write_to_stream<T: Write>(&self, stream: &mut T) -> io::Result<()> {
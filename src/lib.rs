@@ -0,0 +1,38 @@
+//! The protocol spoken over the socket of userserversd, and a blocking client for it.
+//!
+//! The library is versioned along with the daemon, so a given version of it speaks the
+//! protocol of the daemon of the same version.
+//!
+//! The structs and enums of the protocol are `#[non_exhaustive]`, so that newer versions can
+//! add fields and variants to them. Structs are made with their `new` function or `default`,
+//! with the rest of their fields set afterwards, and matches on enums need a wildcard arm.
+//!
+//! ```no_run
+//! use userserversd::client::{Client, Error, ServiceFilter};
+//!
+//! let mut client = Client::connect()?;
+//! let list = client.list_services(ServiceFilter::default())?;
+//! for (name, state) in &list.states {
+//!     if state.is_active() {
+//!         println!("{name} is running");
+//!     }
+//! }
+//! # Ok::<(), Error>(())
+//! ```
+
+/// The version of the crate, along with the commit and the day it was built from, such as
+/// `0.1.0 (6349d48, built 2024-05-17)`.
+pub const VERSION: &str = concat!(
+    env!("CARGO_PKG_VERSION"),
+    " (",
+    env!("USERSERVERSD_GIT_HASH"),
+    ", built ",
+    env!("USERSERVERSD_BUILD_DATE"),
+    ")"
+);
+
+pub mod client;
+pub mod ipc;
+#[cfg(test)]
+mod scratch;
+pub mod user_context;
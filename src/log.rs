@@ -0,0 +1,165 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Write};
+use std::sync::{Mutex, OnceLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+impl Level {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Error => "ERROR",
+            Self::Warn => "WARN",
+            Self::Info => "INFO",
+            Self::Debug => "DEBUG",
+        }
+    }
+}
+
+/// How each line is written.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Text,
+    /// One JSON object per line, with the timestamp, level, message and any fields the line was
+    /// logged with.
+    Json,
+}
+
+struct Logger {
+    level: Level,
+    format: Format,
+    file: Option<Mutex<File>>,
+}
+
+static LOGGER: OnceLock<Logger> = OnceLock::new();
+
+/// Sets up where lines go, how they look and which ones are kept. Lines logged before this are
+/// written to the standard streams as text at the info level.
+pub fn init(level: Level, format: Format, file: Option<File>) {
+    let _ = LOGGER.set(Logger {
+        level,
+        format,
+        file: file.map(Mutex::new),
+    });
+}
+
+/// Formats a time as an RFC 3339 UTC timestamp with milliseconds, such as
+/// `2024-05-17T09:41:07.215Z`.
+pub fn format_timestamp(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (days, secs_of_day) = (secs / 86400, secs % 86400);
+
+    // Converts days since the epoch to a date in the proleptic Gregorian calendar, with eras of
+    // 400 years starting on March 1st so that leap days fall at the end of each year.
+    let days = days as i64 + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{:03}Z",
+        secs_of_day / 3600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+/// Writes a line as `<timestamp> <LEVEL> <message>`, or as a JSON object that also holds the
+/// fields. Without a log file, errors and warnings go to stderr and everything else to stdout.
+pub fn write(level: Level, fields: &[(&str, &dyn fmt::Display)], args: fmt::Arguments) {
+    let logger = LOGGER.get();
+    if level > logger.map_or(Level::Info, |logger| logger.level) {
+        return;
+    }
+
+    let timestamp = format_timestamp(SystemTime::now());
+    let line = match logger.map_or(Format::Text, |logger| logger.format) {
+        Format::Text => format!("{timestamp} {:5} {args}\n", level.name()),
+        Format::Json => {
+            // Written by hand rather than through a map, so that the fields keep their order.
+            let quote = |text: &str| serde_json::Value::from(text).to_string();
+            let mut line = format!(
+                "{{\"timestamp\":{},\"level\":{},\"message\":{}",
+                quote(&timestamp),
+                quote(&level.name().to_ascii_lowercase()),
+                quote(&args.to_string())
+            );
+            for (key, value) in fields {
+                line += &format!(",{}:{}", quote(key), quote(&value.to_string()));
+            }
+            line + "}\n"
+        }
+    };
+    // The test harness only captures what goes through the print macros.
+    if cfg!(test) && logger.is_none() {
+        if level <= Level::Warn {
+            eprint!("{line}");
+        } else {
+            print!("{line}");
+        }
+        return;
+    }
+    let _ = match logger.and_then(|logger| logger.file.as_ref()) {
+        Some(file) => file.lock().unwrap().write_all(line.as_bytes()),
+        None if level <= Level::Warn => io::stderr().write_all(line.as_bytes()),
+        None => io::stdout().write_all(line.as_bytes()),
+    };
+}
+
+/// Logs a line, optionally starting with `key = value` fields separated by commas and followed by
+/// a semicolon, which only show up in JSON logs.
+macro_rules! log_line {
+    ($level:ident, $($key:ident = $value:expr),+; $($arg:tt)*) => {
+        $crate::log::write(
+            $crate::log::Level::$level,
+            &[$((stringify!($key), &$value as &dyn std::fmt::Display)),+],
+            format_args!($($arg)*),
+        )
+    };
+    ($level:ident, $($arg:tt)*) => {
+        $crate::log::write($crate::log::Level::$level, &[], format_args!($($arg)*))
+    };
+}
+
+macro_rules! error {
+    ($($arg:tt)*) => {
+        log_line!(Error, $($arg)*)
+    };
+}
+
+macro_rules! warn {
+    ($($arg:tt)*) => {
+        log_line!(Warn, $($arg)*)
+    };
+}
+
+macro_rules! info {
+    ($($arg:tt)*) => {
+        log_line!(Info, $($arg)*)
+    };
+}
+
+macro_rules! debug {
+    ($($arg:tt)*) => {
+        log_line!(Debug, $($arg)*)
+    };
+}
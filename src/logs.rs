@@ -0,0 +1,259 @@
+use std::collections::VecDeque;
+use std::fmt::{self, Write};
+use std::time::{Duration, SystemTime};
+
+/// How much output of a service is kept however recent it is, so that a chatty service can't
+/// make the daemon grow without bound.
+pub const MAX_LOG_BYTES: usize = 4 * 1024 * 1024;
+
+/// The output of a service, along with when it was written so that old output can be pruned.
+///
+/// Offsets into the logs count every byte ever written, so they stay valid while output is
+/// pruned from the front.
+#[derive(Default)]
+pub struct Logs {
+    text: String,
+    pruned: usize,
+    // Where the first line started during each second that output was written.
+    line_times: VecDeque<(usize, SystemTime)>,
+}
+
+impl Logs {
+    /// Restores logs from the text and line times returned by `line_times`.
+    pub fn restore(text: String, line_times: Vec<(usize, SystemTime)>) -> Self {
+        let mut line_times = VecDeque::from(line_times);
+        if line_times.is_empty() && !text.is_empty() {
+            line_times.push_back((0, SystemTime::now()));
+        }
+
+        Self {
+            text,
+            pruned: 0,
+            line_times,
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.text
+    }
+
+    /// Returns where the line times are, relative to the start of the text.
+    pub fn line_times(&self) -> Vec<(usize, SystemTime)> {
+        self.line_times
+            .iter()
+            .map(|(offset, time)| (offset - self.pruned, *time))
+            .collect()
+    }
+
+    /// The offset of the first byte that is still kept.
+    pub fn start(&self) -> usize {
+        self.pruned
+    }
+
+    /// The offset right after the last byte written.
+    pub fn end(&self) -> usize {
+        self.pruned + self.text.len()
+    }
+
+    /// Returns the part of the logs between the two offsets, leaving out whatever was pruned.
+    pub fn slice(&self, start: usize, end: usize) -> &str {
+        let start = start.max(self.pruned) - self.pruned;
+        let end = end.max(self.pruned) - self.pruned;
+        &self.text[start..end.max(start)]
+    }
+
+    /// Returns when the oldest output still kept was written.
+    pub fn oldest(&self) -> Option<SystemTime> {
+        if self.text.is_empty() {
+            return None;
+        }
+        self.line_times.front().map(|(_, time)| *time)
+    }
+
+    /// Writes a line of the daemon's own, such as to tell apart the output of hooks.
+    pub fn mark(&mut self, message: &str) {
+        let separator = if self.text.is_empty() || self.text.ends_with('\n') {
+            ""
+        } else {
+            "\n"
+        };
+        let _ = writeln!(self, "{separator}[userserversd] {message}");
+    }
+
+    /// Drops every line written more than `retention` before `now`, as well as the oldest lines
+    /// past the last `max_bytes` bytes. Whichever of the two drops more wins.
+    pub fn prune(&mut self, retention: Option<Duration>, max_bytes: usize, now: SystemTime) {
+        let mut keep_from = self.pruned;
+        if let Some(cutoff) = retention.and_then(|retention| now.checked_sub(retention)) {
+            keep_from = match self.line_times.iter().find(|(_, time)| *time >= cutoff) {
+                Some((offset, _)) => *offset,
+                None => self.end(),
+            };
+        }
+
+        if self.text.len() > max_bytes {
+            // Cut at the start of a line, unless the last line alone is too long.
+            let cut = self.text.len() - max_bytes;
+            let line_start = if self.text.as_bytes()[cut - 1] == b'\n' {
+                Some(cut)
+            } else {
+                self.text.as_bytes()[cut..]
+                    .iter()
+                    .position(|byte| *byte == b'\n')
+                    .map(|index| cut + index + 1)
+            };
+            let mut start = line_start.unwrap_or(cut);
+            while !self.text.is_char_boundary(start) {
+                start += 1;
+            }
+            keep_from = keep_from.max(self.pruned + start);
+        }
+
+        self.drain_to(keep_from);
+    }
+
+    fn drain_to(&mut self, keep_from: usize) {
+        // What is left of a line that got cut into was written when its start was.
+        let mut cut_time = None;
+        while let Some((offset, time)) = self.line_times.front()
+            && *offset < keep_from
+        {
+            cut_time = Some(*time);
+            self.line_times.pop_front();
+        }
+        if let Some(time) = cut_time
+            && keep_from < self.end()
+            && self
+                .line_times
+                .front()
+                .is_none_or(|(offset, _)| *offset > keep_from)
+        {
+            self.line_times.push_front((keep_from, time));
+        }
+
+        self.text.drain(..keep_from - self.pruned);
+        self.pruned = keep_from;
+    }
+}
+
+impl fmt::Write for Logs {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let end = self.end();
+        let line_start = if self.text.is_empty() || self.text.ends_with('\n') {
+            Some(end)
+        } else {
+            s.find('\n')
+                .filter(|index| index + 1 < s.len())
+                .map(|index| end + index + 1)
+        };
+
+        // Lines started within a second of the last recorded one share its time, which keeps
+        // chatty services from growing this much.
+        let now = SystemTime::now();
+        if let Some(line_start) = line_start
+            && self.line_times.back().is_none_or(|(_, time)| {
+                now.duration_since(*time).unwrap_or_default() >= Duration::from_secs(1)
+            })
+        {
+            self.line_times.push_back((line_start, now));
+        }
+
+        self.text.push_str(s);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOUR: Duration = Duration::from_secs(60 * 60);
+
+    fn at(hours: u32) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000) + HOUR * hours
+    }
+
+    // Three lines an hour apart, the last one written at hour 2.
+    fn hourly() -> Logs {
+        Logs::restore(
+            "started\nlistening on :8080\nGET / 200\n".to_string(),
+            vec![(0, at(0)), (8, at(1)), (27, at(2))],
+        )
+    }
+
+    #[test]
+    fn old_lines_are_dropped_whole() {
+        let mut logs = hourly();
+        logs.prune(Some(HOUR + HOUR / 2), MAX_LOG_BYTES, at(2));
+
+        assert_eq!(logs.as_str(), "listening on :8080\nGET / 200\n");
+        assert_eq!(logs.oldest(), Some(at(1)));
+        assert_eq!((logs.start(), logs.end()), (8, 37));
+        assert_eq!(logs.line_times(), [(0, at(1)), (19, at(2))]);
+    }
+
+    #[test]
+    fn size_cap_cuts_at_the_start_of_a_line() {
+        let mut logs = hourly();
+        logs.prune(None, 15, at(2));
+
+        assert_eq!(logs.as_str(), "GET / 200\n");
+        assert_eq!(logs.oldest(), Some(at(2)));
+
+        // A line sharing its time with an earlier one keeps that time once cut loose.
+        let mut logs = Logs::restore("a\nb\nc\n".to_string(), vec![(0, at(0))]);
+        logs.prune(None, 3, at(5));
+        assert_eq!(logs.as_str(), "c\n");
+        assert_eq!(logs.line_times(), [(0, at(0))]);
+    }
+
+    #[test]
+    fn stricter_of_age_and_size_wins() {
+        let cases = [
+            // Neither limit is reached.
+            (
+                Some(HOUR * 3),
+                100,
+                "started\nlistening on :8080\nGET / 200\n",
+            ),
+            // The age drops more than the size.
+            (Some(HOUR / 2), 30, "GET / 200\n"),
+            // The size drops more than the age.
+            (Some(HOUR * 3), 30, "listening on :8080\nGET / 200\n"),
+            (Some(HOUR * 3), 12, "GET / 200\n"),
+            // Both drop everything.
+            (Some(Duration::ZERO), 0, ""),
+        ];
+
+        for (retention, max_bytes, kept) in cases {
+            let mut logs = hourly();
+            logs.prune(retention, max_bytes, at(2) + Duration::from_secs(1));
+            assert_eq!(logs.as_str(), kept, "{retention:?}, {max_bytes}");
+            assert_eq!(logs.end(), 37);
+            assert_eq!(logs.oldest().is_none(), kept.is_empty());
+        }
+    }
+
+    #[test]
+    fn line_longer_than_the_cap_keeps_its_end() {
+        let mut logs = Logs::restore("ééééé".to_string(), vec![(0, at(0))]);
+        logs.prune(None, 5, at(0));
+
+        // The cut lands within a character, so it moves past it.
+        assert_eq!(logs.as_str(), "éé");
+        assert_eq!(logs.start(), 6);
+        assert_eq!(logs.oldest(), Some(at(0)));
+    }
+
+    #[test]
+    fn offsets_survive_pruning() {
+        let mut logs = hourly();
+        let end = logs.end();
+        logs.prune(Some(HOUR / 2), MAX_LOG_BYTES, at(2));
+
+        assert_eq!(logs.slice(0, end), "GET / 200\n");
+        assert_eq!(logs.slice(33, end), "200\n");
+        writeln!(logs, "GET /health 200").unwrap();
+        assert_eq!(logs.slice(end, logs.end()), "GET /health 200\n");
+    }
+}
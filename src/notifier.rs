@@ -0,0 +1,396 @@
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::process::{self, Stdio};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+use super::events::{Event, EventCause};
+use super::ipc::{self, EventKind, ServiceState};
+use super::log;
+
+/// How many notifications can wait to be sent before new ones get dropped, so that an endpoint
+/// that stopped answering can't make the daemon pile them up.
+const QUEUE_LENGTH: usize = 64;
+/// How many times a notification is sent before it's given up on.
+const ATTEMPTS: u32 = 3;
+/// How long connecting, sending and waiting for the response can each take.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// What gets posted to the webhook, as JSON.
+#[derive(Serialize)]
+struct Notification {
+    service: String,
+    event: &'static str,
+    state: ServiceState,
+    detail: String,
+    timestamp: String,
+    exit_code: Option<i32>,
+}
+
+/// Posts the events of the kinds it was set up with to an HTTP endpoint, from a thread of its
+/// own.
+pub struct Webhook {
+    address: String,
+    host: String,
+    path: String,
+    kinds: Vec<EventKind>,
+}
+
+impl Webhook {
+    /// Parses an `http://host[:port][/path]` URL. There is no TLS, so endpoints that need HTTPS
+    /// have to be reached through a relay.
+    pub fn new(url: &str, kinds: Vec<EventKind>) -> Result<Self, String> {
+        let Some(rest) = url.strip_prefix("http://") else {
+            return Err(if url.starts_with("https://") {
+                "HTTPS isn't supported, use an http:// URL, such as that of a local relay"
+                    .to_string()
+            } else {
+                "the URL must start with http://".to_string()
+            });
+        };
+
+        let (host, path) = match rest.find('/') {
+            Some(slash) => (&rest[..slash], &rest[slash..]),
+            None => (rest, "/"),
+        };
+        if host.is_empty() || host.contains('@') {
+            return Err("the URL must have a host, without a user".to_string());
+        }
+        // A colon after the closing bracket of an IPv6 address, or anywhere in other hosts,
+        // starts the port.
+        let has_port = match host.rfind(']') {
+            Some(bracket) => host[bracket..].contains(':'),
+            None => host.contains(':'),
+        };
+        let address = if has_port {
+            host.to_string()
+        } else {
+            format!("{host}:80")
+        };
+
+        Ok(Self {
+            address,
+            host: host.to_string(),
+            path: path.to_string(),
+            kinds,
+        })
+    }
+
+    /// Starts the thread that sends the notifications, returning where to queue them.
+    pub fn spawn(self) -> WebhookQueue {
+        let (sender, receiver) = mpsc::sync_channel::<Notification>(QUEUE_LENGTH);
+        let kinds = self.kinds.clone();
+        thread::spawn(move || {
+            for notification in receiver {
+                let body = serde_json::to_string(&notification).unwrap();
+                for attempt in 1..=ATTEMPTS {
+                    match self.post(&body) {
+                        Ok(()) => break,
+                        Err(err) if attempt == ATTEMPTS => warn!(
+                            service = notification.service;
+                            "Gave up on notifying the webhook about service `{}`: {err}",
+                            notification.service
+                        ),
+                        Err(err) => {
+                            debug!(
+                                service = notification.service;
+                                "Failed to notify the webhook about service `{}`, trying again: {err}",
+                                notification.service
+                            );
+                            thread::sleep(Duration::from_secs(1 << attempt));
+                        }
+                    }
+                }
+            }
+        });
+
+        WebhookQueue { sender, kinds }
+    }
+
+    fn post(&self, body: &str) -> io::Result<()> {
+        let mut last_err = io::Error::other("the host has no addresses");
+        let mut stream = None;
+        for address in self.address.to_socket_addrs()? {
+            match TcpStream::connect_timeout(&address, TIMEOUT) {
+                Ok(connected) => {
+                    stream = Some(connected);
+                    break;
+                }
+                Err(err) => last_err = err,
+            }
+        }
+        let mut stream = stream.ok_or(last_err)?;
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        stream.set_write_timeout(Some(TIMEOUT))?;
+
+        write!(
+            stream,
+            "POST {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: userserversd/{}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            self.path,
+            self.host,
+            userserversd::VERSION,
+            body.len()
+        )?;
+        stream.flush()?;
+
+        let mut status_line = String::new();
+        BufReader::new(stream).read_line(&mut status_line)?;
+        let status = status_line
+            .split_whitespace()
+            .nth(1)
+            .and_then(|status| status.parse::<u16>().ok())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "malformed response"))?;
+        if !(200..300).contains(&status) {
+            return Err(io::Error::other(format!(
+                "the endpoint responded with `{}`",
+                status_line.trim()
+            )));
+        }
+        Ok(())
+    }
+}
+
+pub struct WebhookQueue {
+    sender: mpsc::SyncSender<Notification>,
+    kinds: Vec<EventKind>,
+}
+
+impl WebhookQueue {
+    /// Queues a notification about the event if it is of a kind the webhook is for, dropping it
+    /// if too many are waiting already.
+    pub fn notify(&self, event: &Event) {
+        let kind = event.kind();
+        if !self.kinds.contains(&kind) {
+            return;
+        }
+
+        let exit_code = match event.cause {
+            EventCause::Exited { code, .. } => code,
+            _ => None,
+        };
+        let notification = Notification {
+            service: event.service.clone(),
+            event: ipc::EVENT_KINDS
+                .iter()
+                .find(|(_, known)| *known == kind)
+                .map_or("", |(name, _)| name),
+            state: event.new_state,
+            detail: event.cause.to_string(),
+            timestamp: log::format_timestamp(event.timestamp),
+            exit_code,
+        };
+        if let Err(mpsc::TrySendError::Full(notification)) = self.sender.try_send(notification) {
+            warn!(
+                service = notification.service;
+                "Dropped a notification about service `{}`, as the webhook is behind",
+                notification.service
+            );
+        }
+    }
+}
+
+/// How long a service has to wait after a desktop notification about it before it gets another,
+/// so that one that keeps crashing doesn't flood the desktop.
+const DESKTOP_NOTIFICATION_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Shows desktop notifications about services that died or became unhealthy on their own, by
+/// running a command such as `notify-send` with a summary and a body as its last arguments.
+pub struct DesktopNotifier {
+    command: Vec<String>,
+    last_sent: HashMap<String, Instant>,
+}
+
+impl DesktopNotifier {
+    pub fn new(command: Vec<String>) -> Self {
+        Self {
+            command,
+            last_sent: HashMap::new(),
+        }
+    }
+
+    /// Shows a notification if the event is the service exiting with a failure or failing its
+    /// health check. Stops that were asked for never count.
+    pub fn notify(&mut self, event: &Event) {
+        let summary = match event.cause {
+            EventCause::Exited { .. } if event.new_state == ServiceState::Failed => {
+                format!("Service `{}` failed", event.service)
+            }
+            EventCause::HealthCheckFailed { .. } => {
+                format!("Service `{}` is unhealthy", event.service)
+            }
+            _ => return,
+        };
+
+        let now = Instant::now();
+        if self
+            .last_sent
+            .get(&event.service)
+            .is_some_and(|sent_at| now.duration_since(*sent_at) < DESKTOP_NOTIFICATION_INTERVAL)
+        {
+            return;
+        }
+        self.last_sent.insert(event.service.clone(), now);
+
+        let Some((program, args)) = self.command.split_first() else {
+            return;
+        };
+        let child = process::Command::new(program)
+            .args(args)
+            .arg(summary)
+            .arg(event.cause.to_string())
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn();
+        match child {
+            // Waited for elsewhere, so that it doesn't hold up the next event.
+            Ok(mut child) => {
+                thread::spawn(move || child.wait());
+            }
+            Err(err) => warn!(
+                service = event.service;
+                "Failed to run `{program}` to notify about service `{}`: {err}",
+                event.service
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+    use std::net::TcpListener;
+    use std::time::SystemTime;
+
+    use super::*;
+
+    /// Listens for a single request, answering it with `status_line`, and returns the request.
+    fn endpoint(status_line: &'static str) -> (String, thread::JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let handler = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(&stream);
+            let mut request = String::new();
+            let mut content_length = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if let Some(length) = line.strip_prefix("Content-Length: ") {
+                    content_length = length.trim().parse().unwrap();
+                }
+                request.push_str(&line);
+                if line == "\r\n" {
+                    break;
+                }
+            }
+            let mut body = vec![0; content_length];
+            reader.read_exact(&mut body).unwrap();
+            request.push_str(&String::from_utf8(body).unwrap());
+
+            write!(&stream, "{status_line}\r\nContent-Length: 0\r\n\r\n").unwrap();
+            request
+        });
+        (format!("http://{address}/hooks/userserversd"), handler)
+    }
+
+    fn event(service: &str, new_state: ServiceState, cause: EventCause) -> Event {
+        Event {
+            service: service.to_string(),
+            old_state: ServiceState::Running,
+            new_state,
+            cause,
+            timestamp: SystemTime::now(),
+        }
+    }
+
+    #[test]
+    fn urls_are_split_into_where_to_connect_and_what_to_ask_for() {
+        let cases = [
+            ("http://localhost", "localhost:80", "localhost", "/"),
+            (
+                "http://relay:8080/ntfy/alerts",
+                "relay:8080",
+                "relay:8080",
+                "/ntfy/alerts",
+            ),
+            ("http://[::1]/hook", "[::1]:80", "[::1]", "/hook"),
+            ("http://[::1]:9000", "[::1]:9000", "[::1]:9000", "/"),
+        ];
+        for (url, address, host, path) in cases {
+            let webhook = Webhook::new(url, Vec::new()).unwrap();
+            assert_eq!(
+                (
+                    webhook.address.as_str(),
+                    webhook.host.as_str(),
+                    webhook.path.as_str()
+                ),
+                (address, host, path),
+                "{url}"
+            );
+        }
+
+        for url in [
+            "https://hooks.slack.com/x",
+            "ftp://host",
+            "http://",
+            "http://me@host/",
+        ] {
+            assert!(Webhook::new(url, Vec::new()).is_err(), "{url}");
+        }
+    }
+
+    #[test]
+    fn events_of_the_chosen_kinds_are_posted_as_json() {
+        let (url, endpoint) = endpoint("HTTP/1.1 204 No Content");
+        let queue = Webhook::new(&url, vec![EventKind::Failed]).unwrap().spawn();
+        // Queued first, so that it would be the one the endpoint gets if it wasn't left out.
+        queue.notify(&event("web", ServiceState::Running, EventCause::Requested));
+        queue.notify(&event(
+            "web",
+            ServiceState::Failed,
+            EventCause::Exited {
+                code: Some(3),
+                signal: None,
+            },
+        ));
+
+        let request = endpoint.join().unwrap();
+        let (head, body) = request.split_once("\r\n\r\n").unwrap();
+        let mut lines = head.lines();
+        assert_eq!(lines.next(), Some("POST /hooks/userserversd HTTP/1.1"));
+        let host = url.trim_start_matches("http://").split('/').next().unwrap();
+        assert!(lines.any(|line| line == format!("Host: {host}")), "{head}");
+        assert!(head.contains("Content-Type: application/json"), "{head}");
+
+        let body = serde_json::from_str::<serde_json::Value>(body).unwrap();
+        assert_eq!(body["service"], "web");
+        assert_eq!(body["event"], "failed");
+        assert_eq!(
+            body["state"],
+            serde_json::to_value(ServiceState::Failed).unwrap()
+        );
+        assert_eq!(body["detail"], "exited with code 3");
+        assert_eq!(body["exit_code"], 3);
+        assert!(body["timestamp"].is_string());
+    }
+
+    #[test]
+    fn responses_other_than_success_are_errors() {
+        let (url, endpoint) = endpoint("HTTP/1.1 500 Internal Server Error");
+        let webhook = Webhook::new(&url, Vec::new()).unwrap();
+        let err = webhook.post("{}").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "the endpoint responded with `HTTP/1.1 500 Internal Server Error`"
+        );
+        assert!(endpoint.join().unwrap().ends_with("\r\n\r\n{}"));
+
+        // Nothing listens there anymore once the endpoint answered.
+        assert!(webhook.post("{}").is_err());
+    }
+}
@@ -0,0 +1,153 @@
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::clock::SharedClock;
+
+type Task = Box<dyn FnMut() + Send>;
+
+/// Runs every registered task on the same thread once per interval, so that all periodic work
+/// the daemon does shares a single wakeup.
+///
+/// Ticks are measured with the monotonic clock, so changes to the wall clock don't affect them.
+/// If ticks are missed because the tasks took too long or the process was stalled, they are
+/// coalesced into a single run instead of being run back to back.
+pub struct Scheduler {
+    interval: Duration,
+    tasks: Vec<Task>,
+    clock: SharedClock,
+}
+
+impl Scheduler {
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            tasks: Vec::new(),
+            clock: SharedClock::default(),
+        }
+    }
+
+    pub fn add_task<F: FnMut() + Send + 'static>(&mut self, task: F) {
+        self.tasks.push(Box::new(task));
+    }
+
+    pub fn spawn(mut self) {
+        thread::spawn(move || {
+            let mut next_run = self.clock.now() + self.interval;
+            loop {
+                thread::sleep(next_run.saturating_duration_since(self.clock.now()));
+                next_run = self.tick(next_run);
+            }
+        });
+    }
+
+    /// Runs the tasks if the tick due at `next_run` has come, returning when the next one is.
+    fn tick(&mut self, next_run: Instant) -> Instant {
+        if self.clock.now() < next_run {
+            return next_run;
+        }
+        for task in &mut self.tasks {
+            task();
+        }
+
+        let now = self.clock.now();
+        let next_run = next_run + self.interval;
+        if next_run <= now {
+            now + self.interval
+        } else {
+            next_run
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::super::clock::{Clock, TestClock};
+    use super::*;
+
+    const INTERVAL: Duration = Duration::from_secs(5);
+
+    // A scheduler whose only task counts its runs and takes `task_takes` of the clock's time.
+    fn counting_scheduler(
+        clock: &TestClock,
+        task_takes: Duration,
+    ) -> (Scheduler, Arc<AtomicUsize>) {
+        let runs = Arc::new(AtomicUsize::new(0));
+        let mut scheduler = Scheduler {
+            clock: clock.shared(),
+            ..Scheduler::new(INTERVAL)
+        };
+        let task_runs = runs.clone();
+        let task_clock = clock.clone();
+        scheduler.add_task(move || {
+            task_runs.fetch_add(1, Ordering::SeqCst);
+            task_clock.advance(task_takes);
+        });
+        (scheduler, runs)
+    }
+
+    #[test]
+    fn ticks_keep_to_the_schedule_however_long_the_tasks_take() {
+        let clock = TestClock::new();
+        let (mut scheduler, runs) = counting_scheduler(&clock, Duration::from_secs(2));
+        let first = clock.now() + INTERVAL;
+
+        assert_eq!(scheduler.tick(first), first);
+        assert_eq!(runs.load(Ordering::SeqCst), 0);
+
+        clock.advance(INTERVAL);
+        let second = scheduler.tick(first);
+        assert_eq!(second, first + INTERVAL);
+        clock.advance(second - clock.now());
+        assert_eq!(scheduler.tick(second), second + INTERVAL);
+        assert_eq!(runs.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn missed_ticks_are_coalesced_into_one_run() {
+        let clock = TestClock::new();
+        let (mut scheduler, runs) = counting_scheduler(&clock, Duration::ZERO);
+        let first = clock.now() + INTERVAL;
+
+        // Stalled for ten intervals and then some, such as while stopped with SIGSTOP.
+        clock.advance(INTERVAL * 10 + Duration::from_secs(2));
+        let next_run = scheduler.tick(first);
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+        assert_eq!(next_run, clock.now() + INTERVAL);
+
+        assert_eq!(scheduler.tick(next_run), next_run);
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn tasks_outlasting_the_interval_dont_run_back_to_back() {
+        let clock = TestClock::new();
+        let (mut scheduler, runs) = counting_scheduler(&clock, INTERVAL * 3);
+        let first = clock.now() + INTERVAL;
+
+        clock.advance(INTERVAL);
+        let next_run = scheduler.tick(first);
+        assert_eq!(next_run, clock.now() + INTERVAL);
+        assert_eq!(scheduler.tick(next_run), next_run);
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn wall_clock_jumps_dont_make_ticks_due() {
+        let clock = TestClock::new();
+        let (mut scheduler, runs) = counting_scheduler(&clock, Duration::ZERO);
+        let first = clock.now() + INTERVAL;
+
+        clock.shift_wall(24 * 60 * 60);
+        assert_eq!(scheduler.tick(first), first);
+        clock.shift_wall(-48 * 60 * 60);
+        assert_eq!(scheduler.tick(first), first);
+        assert_eq!(runs.load(Ordering::SeqCst), 0);
+
+        clock.advance(INTERVAL);
+        assert_eq!(scheduler.tick(first), first + INTERVAL);
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+    }
+}
@@ -0,0 +1,13 @@
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process;
+
+/// A directory of its own for each test, emptied beforehand. `module` keeps apart tests of the
+/// same name in different modules, since they all run in the same process.
+pub fn scratch_dir(module: &str, test: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("userserversd-{module}-{}-{test}", process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
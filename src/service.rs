@@ -1,117 +1,415 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 use std::fmt;
-use std::io::{self, BufReader, Read};
-use std::sync::{Arc, Mutex};
-use std::time::{Duration, Instant};
+use std::fs;
+use std::io::{self, Read};
+use std::os::fd::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock};
+use std::time::{Duration, Instant, SystemTime};
 use std::{process, thread};
 
+use nix::errno::Errno;
 use nix::sys::signal::{self, Signal};
+use nix::sys::wait;
 use nix::unistd;
 
 use serde::de::{Deserializer, MapAccess, Visitor};
-use serde::ser::SerializeStruct;
+use serde::ser::{SerializeStruct, Serializer};
 use serde::{Deserialize, Serialize};
 
+use super::clock::SharedClock;
+use super::events::{EventCause, EventSender};
+use super::ipc::ServiceState;
+use super::logs::{Logs, MAX_LOG_BYTES};
+
+/// A process the daemon is the parent of, either spawned by this instance or adopted from the
+/// one it replaced.
+enum Child {
+    Spawned(process::Child),
+    Adopted {
+        pid: unistd::Pid,
+        status: Option<process::ExitStatus>,
+    },
+}
+
+impl Child {
+    fn try_wait(&mut self) -> io::Result<Option<process::ExitStatus>> {
+        let (pid, status) = match self {
+            Self::Spawned(child) => return child.try_wait(),
+            Self::Adopted { pid, status } => (*pid, status),
+        };
+        if status.is_some() {
+            return Ok(*status);
+        }
+
+        *status = match wait::waitpid(pid, Some(wait::WaitPidFlag::WNOHANG))? {
+            wait::WaitStatus::Exited(_, code) => Some(process::ExitStatus::from_raw(code << 8)),
+            wait::WaitStatus::Signaled(_, signal, _) => {
+                Some(process::ExitStatus::from_raw(signal as i32))
+            }
+            _ => None,
+        };
+        Ok(*status)
+    }
+
+    fn wait(&mut self) -> io::Result<process::ExitStatus> {
+        if let Self::Spawned(child) = self {
+            return child.wait();
+        }
+
+        loop {
+            if let Some(status) = self.try_wait()? {
+                return Ok(status);
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+    }
+
+    fn kill(&mut self) -> io::Result<()> {
+        match self {
+            Self::Spawned(child) => child.kill(),
+            Self::Adopted { pid, .. } => Ok(signal::kill(*pid, Signal::SIGKILL)?),
+        }
+    }
+}
+
+/// The variables of the daemon that services with a clean environment still get.
+const CLEAN_ENVIRONMENT_BASE: [&str; 4] = ["HOME", "PATH", "USER", "LANG"];
+
 struct Command<W: fmt::Write> {
-    child: Arc<Mutex<process::Child>>,
+    child: Arc<Mutex<Child>>,
+    // Kept outside of the mutex, which is held for as long as a stop takes.
+    pid: u32,
+    // Needed to keep reading the output of the process after a handover.
+    stdout_fd: Option<RawFd>,
+    stderr_fd: Option<RawFd>,
     logs: Arc<Mutex<W>>,
 }
 
+impl<W: fmt::Write> Clone for Command<W> {
+    fn clone(&self) -> Self {
+        Self {
+            child: self.child.clone(),
+            pid: self.pid,
+            stdout_fd: self.stdout_fd,
+            stderr_fd: self.stderr_fd,
+            logs: self.logs.clone(),
+        }
+    }
+}
+
+/// How much output is read from a service at once, which is also how long a line can get before
+/// it's written without its newline.
+const LOG_READ_BUFFER_SIZE: usize = 64 * 1024;
+
+/// How many of the bytes can be written without splitting a UTF-8 sequence that they end partway
+/// through.
+fn utf8_boundary(bytes: &[u8]) -> usize {
+    for back in 1..=bytes.len().min(4) {
+        let byte = bytes[bytes.len() - back];
+        if byte & 0xC0 == 0x80 {
+            continue;
+        }
+        let width = match byte {
+            0xF0.. => 4,
+            0xE0.. => 3,
+            0xC0.. => 2,
+            _ => 1,
+        };
+        return if width > back {
+            bytes.len() - back
+        } else {
+            bytes.len()
+        };
+    }
+    bytes.len()
+}
+
+fn spawn_log_reader<R: Read + Send + 'static, W: fmt::Write + Send + 'static>(
+    mut reader: R,
+    output: Arc<Mutex<W>>,
+) {
+    thread::spawn(move || {
+        let write_lossy = |bytes: &[u8]| {
+            let mut output = output.lock().unwrap();
+            let _ = output.write_str(&String::from_utf8_lossy(bytes));
+        };
+
+        let mut buffer = vec![0u8; LOG_READ_BUFFER_SIZE];
+        // The start of the buffer holds what was read after the last newline.
+        let mut pending = 0;
+        loop {
+            let bytes_read = match reader.read(&mut buffer[pending..]) {
+                Ok(0) => break,
+                Ok(bytes_read) => bytes_read,
+                Err(error) if error.kind() == io::ErrorKind::Interrupted => continue,
+                Err(_) => break,
+            };
+            let filled = pending + bytes_read;
+
+            // Whole lines are written together, and a line only gets written unfinished once it
+            // fills the buffer.
+            let end = match buffer[pending..filled]
+                .iter()
+                .rposition(|&byte| byte == b'\n')
+            {
+                Some(index) => pending + index + 1,
+                None if filled == buffer.len() => utf8_boundary(&buffer[..filled]),
+                None => {
+                    pending = filled;
+                    continue;
+                }
+            };
+            write_lossy(&buffer[..end]);
+            buffer.copy_within(end..filled, 0);
+            pending = filled - end;
+        }
+
+        if pending > 0 {
+            write_lossy(&buffer[..pending]);
+        }
+    });
+}
+
+static BASE_ENVIRONMENT: OnceLock<BTreeMap<String, String>> = OnceLock::new();
+
+/// The environment of the daemon as it was when this was first called, which services inherit
+/// instead of whatever the daemon's own environment has become since. Variables that aren't
+/// valid UTF-8 are left out.
+pub fn base_environment() -> &'static BTreeMap<String, String> {
+    BASE_ENVIRONMENT.get_or_init(|| {
+        std::env::vars_os()
+            .filter_map(|(key, value)| Some((key.into_string().ok()?, value.into_string().ok()?)))
+            .collect()
+    })
+}
+
+/// Replaces every `${VAR}` with the value of the variable in `environment`, or with nothing if
+/// it isn't set. `$${` stands for a literal `${`, and any other `$` is left alone.
+fn expand_variables(value: &str, environment: &BTreeMap<String, String>) -> String {
+    let mut expanded = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(index) = rest.find('$') {
+        expanded.push_str(&rest[..index]);
+        rest = &rest[index..];
+
+        if let Some(after) = rest.strip_prefix("$${") {
+            expanded.push_str("${");
+            rest = after;
+        } else if let Some((name, after)) = rest
+            .strip_prefix("${")
+            .and_then(|after| after.split_once('}'))
+        {
+            if let Some(value) = environment.get(name) {
+                expanded.push_str(value);
+            }
+            rest = after;
+        } else {
+            expanded.push('$');
+            rest = &rest[1..];
+        }
+    }
+    expanded.push_str(rest);
+    expanded
+}
+
+/// Expands a leading `~` or `~user` to the matching home directory, then the variables.
+fn expand_path(path: &str, environment: &BTreeMap<String, String>) -> String {
+    let path = expand_variables(path, environment);
+    let Some(after_tilde) = path.strip_prefix('~') else {
+        return path;
+    };
+
+    let (user, rest) = match after_tilde.find('/') {
+        Some(index) => after_tilde.split_at(index),
+        None => (after_tilde, ""),
+    };
+    let home = if user.is_empty() {
+        environment.get("HOME").cloned()
+    } else {
+        unistd::User::from_name(user)
+            .ok()
+            .flatten()
+            .map(|user| user.dir.to_string_lossy().into_owned())
+    };
+
+    match home {
+        Some(home) => format!("{home}{rest}"),
+        None => path,
+    }
+}
+
+fn is_executable(path: &Path) -> bool {
+    fs::metadata(path)
+        .is_ok_and(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+}
+
+/// Tells whether the program would be found when spawned from the working directory, the same
+/// way `process::Command` looks it up.
+fn program_exists(program: &str, working_directory: &Path, path_variable: Option<&String>) -> bool {
+    if program.contains('/') {
+        return is_executable(&working_directory.join(program));
+    }
+
+    path_variable.is_some_and(|paths| {
+        paths.split(':').any(|directory| {
+            let directory = match directory {
+                "" => working_directory,
+                directory => Path::new(directory),
+            };
+            is_executable(&directory.join(program))
+        })
+    })
+}
+
 impl<W: fmt::Write + Send + 'static> Command<W> {
+    /// Spawns the command with `~` and variables in the working directory and arguments
+    /// expanded against the environment it gets. The definition of the service keeps them as
+    /// written, so that changes to the environment apply on the next start.
+    ///
+    /// Every command runs in a session of its own, so that signals meant for the terminal of
+    /// the daemon don't reach it, and leads its process group, so that stopping it also stops
+    /// whatever it started. A shell command is run with `sh -c` as it is written, and the shell
+    /// expands its variables from the same environment: replacing them beforehand would have
+    /// the shell parse their values, running whatever commands they happen to contain.
     fn start(
         command: &[&str],
         working_directory: &str,
-        environment_overrides: HashMap<String, String>,
+        environment: BTreeMap<String, String>,
+        shell: bool,
+        arg0: Option<&str>,
         output: Arc<Mutex<W>>,
     ) -> io::Result<Self> {
-        let mut environment = HashMap::<String, String>::new();
-        for (key, value) in std::env::vars() {
-            environment.insert(key.to_string(), value.to_string());
-        }
+        let command = if shell {
+            vec!["sh".to_string(), "-c".to_string(), command.join(" ")]
+        } else {
+            command
+                .iter()
+                .map(|argument| expand_variables(argument, &environment))
+                .collect()
+        };
+        let working_directory = expand_path(working_directory, &environment);
 
-        for (key, value) in environment_overrides {
-            environment.insert(key, value);
+        let mut process = process::Command::new(&command[0]);
+        if let Some(arg0) = arg0 {
+            process.arg0(arg0);
         }
-
-        let child = process::Command::new(command[0])
+        // SAFETY: setsid is async-signal-safe, and nothing else runs between fork and exec.
+        unsafe {
+            process.pre_exec(|| {
+                unistd::setsid()?;
+                Ok(())
+            });
+        }
+        let mut child = process
             .args(&command[1..])
             .current_dir(working_directory)
+            .env_clear()
             .envs(environment)
             .stdin(process::Stdio::null())
             .stdout(process::Stdio::piped())
             .stderr(process::Stdio::piped())
             .spawn()?;
+
+        let stdout = child.stdout.take();
+        let stderr = child.stderr.take();
         let command = Self {
-            child: Arc::new(Mutex::new(child)),
+            pid: child.id(),
+            child: Arc::new(Mutex::new(Child::Spawned(child))),
+            stdout_fd: stdout.as_ref().map(|stdout| stdout.as_raw_fd()),
+            stderr_fd: stderr.as_ref().map(|stderr| stderr.as_raw_fd()),
             logs: output,
         };
 
-        let stdout_thread_output = command.logs.clone();
-        let stdout_thread_child = command.child.clone();
-        thread::spawn(move || {
-            let stdout = match stdout_thread_child.lock().unwrap().stdout.take() {
-                Some(stdout) => stdout,
-                None => return,
-            };
-            let mut reader = BufReader::new(stdout);
-
-            let mut chunk = [0u8; 16];
-            while let Ok(bytes_read) = reader.read(&mut chunk) {
-                if bytes_read == 0 {
-                    break;
-                }
-                let chunk = &chunk[..bytes_read];
-
-                let mut output = stdout_thread_output.lock().unwrap();
-                let _ = write!(output, "{}", String::from_utf8_lossy(chunk));
-            }
-        });
-
-        let stderr_thread_output = command.logs.clone();
-        let stderr_thread_child = command.child.clone();
-        thread::spawn(move || {
-            let stderr = match stderr_thread_child.lock().unwrap().stderr.take() {
-                Some(stderr) => stderr,
-                None => return,
-            };
-            let mut reader = BufReader::new(stderr);
+        if let Some(stdout) = stdout {
+            spawn_log_reader(stdout, command.logs.clone());
+        }
+        if let Some(stderr) = stderr {
+            spawn_log_reader(stderr, command.logs.clone());
+        }
 
-            let mut chunk = [0u8; 16];
-            while let Ok(bytes_read) = reader.read(&mut chunk) {
-                if bytes_read == 0 {
-                    break;
-                }
-                let chunk = &chunk[..bytes_read];
+        Ok(command)
+    }
 
-                let mut output = stderr_thread_output.lock().unwrap();
-                let _ = write!(output, "{}", String::from_utf8_lossy(chunk));
-            }
-        });
+    /// Takes over a process that the previous daemon instance spawned, along with the pipes its
+    /// output goes to.
+    fn adopt(handover: &ChildHandover, output: Arc<Mutex<W>>) -> Self {
+        for fd in [handover.stdout_fd, handover.stderr_fd]
+            .into_iter()
+            .flatten()
+        {
+            // SAFETY: the previous instance handed these pipes over to us, and nothing else in
+            // this process knows about them.
+            let pipe = unsafe { fs::File::from_raw_fd(fd) };
+            spawn_log_reader(pipe, output.clone());
+        }
 
-        Ok(command)
+        Self {
+            child: Arc::new(Mutex::new(Child::Adopted {
+                pid: unistd::Pid::from_raw(handover.pid),
+                status: None,
+            })),
+            pid: handover.pid as u32,
+            stdout_fd: handover.stdout_fd,
+            stderr_fd: handover.stderr_fd,
+            logs: output,
+        }
     }
 
+    /// Sends SIGTERM until the process exits, then SIGKILL. The child is only locked for each
+    /// check, so that status queries aren't held up for as long as the stop takes.
     fn stop(&self) -> io::Result<()> {
-        let mut child = self.child.lock().unwrap();
-        let child_pid = unistd::Pid::from_raw(child.id() as i32);
+        let child_pid = unistd::Pid::from_raw(self.pid as i32);
+        // Processes adopted from a daemon that predates sessions only lead a process group if
+        // they were started through the shell, and get signaled alone otherwise.
+        let group = unistd::getpgid(Some(child_pid)) == Ok(child_pid);
+        let kill = |signal| {
+            if group {
+                signal::killpg(child_pid, signal)
+            } else {
+                signal::kill(child_pid, signal)
+            }
+        };
 
-        'kill_attempt: for _ in 0..5 {
-            signal::kill(child_pid, Signal::SIGTERM)?;
+        for _ in 0..5 {
+            {
+                // Checking under the same lock as the signal keeps us from signaling a reaped
+                // child whose PID got reused.
+                let mut child = self.child.lock().unwrap();
+                if child.try_wait()?.is_some() {
+                    return Ok(());
+                }
+
+                match kill(Signal::SIGTERM) {
+                    Ok(()) => {}
+                    // The process exited between the check and the signal.
+                    Err(Errno::ESRCH) => break,
+                    Err(err) => return Err(err.into()),
+                }
+            }
 
             let timeout = Duration::from_secs(30);
             let deadline = Instant::now() + timeout;
-            while match child.try_wait() {
-                Ok(None) => true,
-                _ => break 'kill_attempt,
-            } {
+            while self.try_wait()?.is_none() {
                 if Instant::now() > deadline {
                     break;
                 }
-                thread::sleep(timeout / 15);
+                thread::sleep(Duration::from_millis(50));
+            }
+        }
+
+        let mut child = self.child.lock().unwrap();
+        if child.try_wait()?.is_none() {
+            if group {
+                let _ = kill(Signal::SIGKILL);
             }
+            child.kill()?;
         }
+        child.wait()?;
 
-        child.kill()
+        Ok(())
     }
 
     fn wait(&self) -> io::Result<process::ExitStatus> {
@@ -119,12 +417,36 @@ impl<W: fmt::Write + Send + 'static> Command<W> {
         child.wait()
     }
 
-    fn is_running(&self) -> bool {
+    /// Waits for the process to exit, killing it along with its process group if it has one
+    /// once the timeout runs out, in which case `None` is returned.
+    fn wait_timeout(&self, timeout: Duration) -> io::Result<Option<process::ExitStatus>> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Some(status) = self.try_wait()? {
+                return Ok(Some(status));
+            }
+            if Instant::now() > deadline {
+                break;
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+
         let mut child = self.child.lock().unwrap();
-        if let Ok(Some(_)) = child.try_wait() {
-            return false;
+        if let Some(status) = child.try_wait()? {
+            return Ok(Some(status));
         }
-        true
+        let child_pid = unistd::Pid::from_raw(self.pid as i32);
+        if unistd::getpgid(Some(child_pid)) == Ok(child_pid) {
+            let _ = signal::killpg(child_pid, Signal::SIGKILL);
+        }
+        child.kill()?;
+        child.wait()?;
+        Ok(None)
+    }
+
+    fn try_wait(&self) -> io::Result<Option<process::ExitStatus>> {
+        let mut child = self.child.lock().unwrap();
+        child.try_wait()
     }
 }
 
@@ -132,6 +454,46 @@ pub enum ServiceError {
     IOError(io::Error),
     ServiceNotRunning,
     ServiceAlreadyRunning,
+    StartCommandFailed {
+        code: Option<i32>,
+    },
+    StopCommandFailed {
+        code: Option<i32>,
+    },
+    PidfileTimeout,
+    PortInUse {
+        port: u16,
+    },
+    HookFailed {
+        hook: &'static str,
+        code: Option<i32>,
+    },
+    NoReloadMethod,
+    NoKnownProcess,
+    ReloadCommandFailed {
+        code: Option<i32>,
+    },
+    CommandTimedOut {
+        command: &'static str,
+        secs: u64,
+    },
+    WorkingDirectoryNotFound {
+        path: PathBuf,
+    },
+    ExecutableNotFound {
+        program: String,
+        search_path: Option<String>,
+    },
+    NotExecutable {
+        program: String,
+    },
+}
+
+/// What a successful stop left behind.
+pub struct StopOutcome {
+    /// The PIDs of the processes started by the service that outlived it.
+    pub survivors: Vec<u32>,
+    pub post_stop_error: Option<ServiceError>,
 }
 
 impl fmt::Display for ServiceError {
@@ -140,6 +502,62 @@ impl fmt::Display for ServiceError {
             Self::IOError(err) => err.fmt(fmt),
             Self::ServiceNotRunning => write!(fmt, "service not running"),
             Self::ServiceAlreadyRunning => write!(fmt, "service already running"),
+            Self::StartCommandFailed { code: Some(code) } => {
+                write!(fmt, "start command exited with code {code}")
+            }
+            Self::StartCommandFailed { code: None } => {
+                write!(fmt, "start command was killed by a signal")
+            }
+            Self::StopCommandFailed { code: Some(code) } => {
+                write!(fmt, "stop command exited with code {code}")
+            }
+            Self::StopCommandFailed { code: None } => {
+                write!(fmt, "stop command was killed by a signal")
+            }
+            Self::PortInUse { port } => write!(fmt, "port {port} is already in use"),
+            Self::HookFailed {
+                hook,
+                code: Some(code),
+            } => write!(fmt, "{hook} hook exited with code {code}"),
+            Self::HookFailed { hook, code: None } => {
+                write!(fmt, "{hook} hook was killed by a signal")
+            }
+            Self::PidfileTimeout => write!(
+                fmt,
+                "pidfile did not name a running process after {}s",
+                PIDFILE_TIMEOUT.as_secs()
+            ),
+            Self::NoReloadMethod => write!(fmt, "service has no way to reload"),
+            Self::NoKnownProcess => write!(fmt, "service has no known process to signal"),
+            Self::ReloadCommandFailed { code: Some(code) } => {
+                write!(fmt, "reload command exited with code {code}")
+            }
+            Self::ReloadCommandFailed { code: None } => {
+                write!(fmt, "reload command was killed by a signal")
+            }
+            Self::CommandTimedOut { command, secs } => {
+                write!(
+                    fmt,
+                    "{command} command was killed after running for {secs}s"
+                )
+            }
+            Self::WorkingDirectoryNotFound { path } => {
+                write!(fmt, "working directory `{}` does not exist", path.display())
+            }
+            Self::ExecutableNotFound {
+                program,
+                search_path: Some(search_path),
+            } => write!(
+                fmt,
+                "executable `{program}` was not found in PATH `{search_path}`"
+            ),
+            Self::ExecutableNotFound {
+                program,
+                search_path: None,
+            } => write!(fmt, "executable `{program}` was not found"),
+            Self::NotExecutable { program } => {
+                write!(fmt, "`{program}` could not be run: permission denied")
+            }
         }
     }
 }
@@ -152,31 +570,501 @@ pub enum ServiceKind {
     Asynchronous {
         start_command: Vec<String>,
         stop_command: Vec<String>,
+        #[serde(default, serialize_with = "serialize_sorted_environment")]
+        stop_environment: Option<HashMap<String, String>>,
+        #[serde(default)]
+        stop_working_directory: Option<String>,
+        #[serde(default)]
+        pidfile: Option<String>,
+        /// How long the start and stop commands can run before they get killed, defaulting to
+        /// `DEFAULT_COMMAND_TIMEOUT`.
+        #[serde(default)]
+        command_timeout_secs: Option<u64>,
+    },
+    Oneshot {
+        command: Vec<String>,
+    },
+    Timer {
+        command: Vec<String>,
+        interval_secs: u64,
     },
 }
 
+fn command_timeout(command_timeout_secs: Option<u64>) -> Duration {
+    command_timeout_secs.map_or(DEFAULT_COMMAND_TIMEOUT, Duration::from_secs)
+}
+
+fn serialize_sorted_environment<S: Serializer>(
+    environment: &Option<HashMap<String, String>>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    environment
+        .as_ref()
+        .map(|environment| environment.iter().collect::<BTreeMap<_, _>>())
+        .serialize(serializer)
+}
+
+/// What a new daemon instance needs to take over a service from the one it replaces.
+#[derive(Serialize, Deserialize)]
+pub struct ServiceHandover {
+    state: ServiceState,
+    ready: bool,
+    child: Option<ChildHandover>,
+    pidfile_process: Option<(i32, Option<u64>)>,
+    last_exit: Option<(Option<i32>, Option<i32>)>,
+    #[serde(default)]
+    timer_last_run: Option<SystemTime>,
+    logs: String,
+    #[serde(default)]
+    log_line_times: Vec<(usize, SystemTime)>,
+    #[serde(default)]
+    start_count: u64,
+    #[serde(default)]
+    runs: Vec<Run>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ChildHandover {
+    pid: i32,
+    stdout_fd: Option<RawFd>,
+    stderr_fd: Option<RawFd>,
+}
+
+impl ServiceHandover {
+    /// The file descriptors that must survive the exec of the new instance.
+    pub fn inherited_fds(&self) -> Vec<RawFd> {
+        match &self.child {
+            Some(child) => [child.stdout_fd, child.stderr_fd]
+                .into_iter()
+                .flatten()
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+}
+
+/// Keeps a service from changing until the daemon is replaced.
+pub struct HandoverGuard<'a> {
+    _operation: MutexGuard<'a, ()>,
+    _runtime: MutexGuard<'a, Runtime>,
+}
+
 pub struct Service {
     pub working_directory: String,
     pub environment: HashMap<String, String>,
-    pub group: Option<String>,
+    // Groups can be renamed while services run, so this is the only part of the definition that
+    // can change in place.
+    group: Mutex<Option<String>>,
     pub kind: ServiceKind,
+    pub ports: Vec<u16>,
+    pub health_check: Option<Vec<String>>,
+    pub health_check_interval_secs: Option<u64>,
+    pub ready_command: Option<Vec<String>>,
+    pub ready_tcp_port: Option<u16>,
+    pub watch_paths: Vec<String>,
+    pub watch_disabled: bool,
+    pub depends_on: Vec<String>,
+    pub log_retention_secs: Option<u64>,
+    pub pre_start: Option<Vec<String>>,
+    pub post_stop: Option<Vec<String>>,
+    pub on_failure: Option<Vec<String>>,
+    pub clean_environment: bool,
+    pub shell: bool,
+    pub description: Option<String>,
+    pub labels: HashMap<String, String>,
+    pub reload_signal: Option<String>,
+    pub reload_command: Option<Vec<String>>,
+    /// Whether the working directory gets created, along with its parents, before the service
+    /// starts.
+    pub create_working_directory: bool,
+    /// Whether the processes of the service get `userserversd: <service name>` as their argv[0],
+    /// so that they are easy to tell apart in `ps`.
+    pub process_title: bool,
+
+    // Held for the whole duration of a start, stop or restart.
+    operation: Mutex<()>,
+    // Only ever held briefly, so that queries don't wait on operations.
+    runtime: Mutex<Runtime>,
+    logs: Arc<Mutex<Logs>>,
+}
+
+#[derive(Default)]
+struct Runtime {
+    state: ServiceState,
+    child: Option<Command<Logs>>,
+    pidfile_process: Option<Process>,
+    stopping: bool,
+    health: Health,
+    ready: bool,
+    probing_readiness: bool,
+    running_failure_hook: bool,
+    watch: Watch,
+    // Oneshot services complete instead of stopping when their command succeeds.
+    oneshot: bool,
+    // Only set for timer services, whose runs don't change their state.
+    timer: Option<Timer>,
+    last_exit: Option<(Option<i32>, Option<i32>)>,
+    start_count: u64,
+    runs: VecDeque<Run>,
+    events: Option<EventSender>,
+    clock: SharedClock,
+}
+
+const RUN_HISTORY_LENGTH: usize = 10;
+
+/// A start of the service, along with how it ended once it did.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Run {
+    pub started_at: SystemTime,
+    pub ended: Option<RunEnd>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct RunEnd {
+    pub at: SystemTime,
+    pub state: ServiceState,
+    pub exit: Option<(Option<i32>, Option<i32>)>,
+}
+
+#[derive(Default)]
+struct Health {
+    consecutive_failures: u32,
+    last_check: Option<Instant>,
+    checking: bool,
+}
+
+#[derive(Default)]
+struct Watch {
+    modified: HashMap<String, Option<SystemTime>>,
+    pending: Option<(String, Instant)>,
+    last_trigger: Option<(String, SystemTime)>,
+}
+
+struct Timer {
+    next_run: Instant,
+    last_run: Option<SystemTime>,
+}
+
+impl Runtime {
+    fn transition(&mut self, new_state: ServiceState, cause: EventCause) {
+        let old_state = self.state;
+        self.state = new_state;
+
+        if new_state.is_active() && !old_state.is_active() {
+            self.health.consecutive_failures = 0;
+            self.health.last_check = Some(self.clock.now());
+            self.ready = false;
+            self.last_exit = None;
+
+            self.start_count += 1;
+            if self.runs.len() == RUN_HISTORY_LENGTH {
+                self.runs.pop_front();
+            }
+            self.runs.push_back(Run {
+                started_at: self.clock.wall(),
+                ended: None,
+            });
+        } else if !new_state.is_active()
+            && old_state.is_active()
+            && let Some(run) = self.runs.back_mut()
+        {
+            run.ended = Some(RunEnd {
+                at: self.clock.wall(),
+                state: new_state,
+                exit: self.last_exit,
+            });
+        }
+
+        if let Some(events) = &self.events {
+            events.emit(old_state, new_state, cause);
+        }
+    }
+
+    // Notices a service's process exiting on its own.
+    fn refresh(&mut self) {
+        if self.stopping || !self.state.is_active() {
+            return;
+        }
+
+        if let Some(process) = &self.pidfile_process {
+            if !process.is_alive() {
+                self.pidfile_process = None;
+                self.transition(
+                    ServiceState::Failed,
+                    EventCause::Exited {
+                        code: None,
+                        signal: None,
+                    },
+                );
+            }
+            return;
+        }
+
+        let (status, logs) = match &self.child {
+            Some(child) => match child.try_wait() {
+                Ok(Some(status)) => (status, child.logs.clone()),
+                _ => return,
+            },
+            None => return,
+        };
 
-    async_running: bool,
-    child: Option<Command<String>>,
-    logs: Arc<Mutex<String>>,
+        let cause = EventCause::Exited {
+            code: status.code(),
+            signal: status.signal(),
+        };
+        logs.lock().unwrap().mark(&cause.to_string());
+        self.last_exit = Some((status.code(), status.signal()));
+        if self.timer.is_some() {
+            self.child = None;
+            return;
+        }
+
+        let new_state = match (status.success(), self.oneshot) {
+            (true, true) => ServiceState::Completed,
+            (true, false) => ServiceState::Stopped,
+            (false, _) => ServiceState::Failed,
+        };
+        self.transition(new_state, cause);
+    }
+}
+
+/// Returns the TCP ports that something on this machine is listening on.
+fn listening_ports() -> HashSet<u16> {
+    const TCP_LISTEN: &str = "0A";
+
+    let mut ports = HashSet::new();
+    for table in ["/proc/net/tcp", "/proc/net/tcp6"] {
+        let Ok(table) = fs::read_to_string(table) else {
+            continue;
+        };
+
+        for line in table.lines().skip(1) {
+            let mut fields = line.split_whitespace();
+            let (Some(local_address), Some(state)) = (fields.nth(1), fields.nth(1)) else {
+                continue;
+            };
+            if state != TCP_LISTEN {
+                continue;
+            }
+
+            if let Some((_, port)) = local_address.rsplit_once(':')
+                && let Ok(port) = u16::from_str_radix(port, 16)
+            {
+                ports.insert(port);
+            }
+        }
+    }
+
+    ports
+}
+
+const DEFAULT_HEALTH_CHECK_INTERVAL_SECS: u64 = 30;
+const HEALTH_CHECK_FAILURE_THRESHOLD: u32 = 3;
+
+const PIDFILE_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long the start and stop commands of asynchronous services can run by default.
+pub const DEFAULT_COMMAND_TIMEOUT: Duration = Duration::from_secs(60);
+
+const WATCH_DEBOUNCE: Duration = Duration::from_secs(1);
+
+/// A process that is being kept track of, like the one named by the pidfile of an asynchronous
+/// service.
+struct Process {
+    pid: unistd::Pid,
+    // Used to tell whether the PID got reused by another process. Unlike the command name, the
+    // start time doesn't change when the process execs.
+    start_time: Option<u64>,
+}
+
+// The unit of the times in `/proc/<pid>/stat`, which Linux fixes regardless of its timer rate.
+const CLOCK_TICKS_PER_SEC: f64 = 100.0;
+
+impl Process {
+    // Returns the fields of `/proc/<pid>/stat` that come after the command name, starting with
+    // the state.
+    fn read_stat(pid: unistd::Pid) -> Option<Vec<String>> {
+        let stat = fs::read_to_string(format!("/proc/{pid}/stat")).ok()?;
+        // The command name can contain spaces and parentheses, so skip past its closing one.
+        let (_, fields) = stat.rsplit_once(')')?;
+        Some(fields.split_whitespace().map(str::to_string).collect())
+    }
+
+    fn read_start_time(pid: unistd::Pid) -> Option<u64> {
+        Self::read_stat(pid)?.get(19)?.parse().ok()
+    }
+
+    /// Returns the resident memory of a process in bytes, along with the share of a CPU it used
+    /// on average since it started, as a percentage.
+    fn usage(pid: unistd::Pid) -> Option<(u64, f32)> {
+        let stat = Self::read_stat(pid)?;
+        let field = |index: usize| stat.get(index)?.parse::<u64>().ok();
+        let cpu_ticks = field(11)? + field(12)?;
+        let start_ticks = field(19)?;
+
+        let status = fs::read_to_string(format!("/proc/{pid}/status")).ok()?;
+        let rss_kib = status
+            .lines()
+            .find_map(|line| line.strip_prefix("VmRSS:"))?
+            .trim()
+            .strip_suffix("kB")?
+            .trim()
+            .parse::<u64>()
+            .ok()?;
+
+        let uptime_secs = fs::read_to_string("/proc/uptime")
+            .ok()?
+            .split_whitespace()
+            .next()?
+            .parse::<f64>()
+            .ok()?;
+        let running_secs = uptime_secs - start_ticks as f64 / CLOCK_TICKS_PER_SEC;
+        let cpu_percent = if running_secs > 0.0 {
+            cpu_ticks as f64 / CLOCK_TICKS_PER_SEC / running_secs * 100.0
+        } else {
+            0.0
+        };
+
+        Some((rss_kib * 1024, cpu_percent as f32))
+    }
+
+    /// Returns every process descending from the given one.
+    fn descendants(pid: unistd::Pid) -> Vec<Self> {
+        let Ok(entries) = fs::read_dir("/proc") else {
+            return Vec::new();
+        };
+
+        let mut children = HashMap::<i32, Vec<unistd::Pid>>::new();
+        for entry in entries.flatten() {
+            let Some(child) = entry
+                .file_name()
+                .to_str()
+                .and_then(|name| name.parse().ok())
+            else {
+                continue;
+            };
+            let child = unistd::Pid::from_raw(child);
+            if let Some(parent) =
+                Self::read_stat(child).and_then(|fields| fields.get(1)?.parse::<i32>().ok())
+            {
+                children.entry(parent).or_default().push(child);
+            }
+        }
+
+        let mut descendants = Vec::new();
+        let mut pending = vec![pid];
+        while let Some(parent) = pending.pop() {
+            for child in children.remove(&parent.as_raw()).unwrap_or_default() {
+                descendants.push(Self {
+                    pid: child,
+                    start_time: Self::read_start_time(child),
+                });
+                pending.push(child);
+            }
+        }
+        descendants
+    }
+
+    /// Reads the pidfile, ignoring it if it was last written before `not_before`.
+    fn read(path: &Path, not_before: SystemTime) -> Option<Self> {
+        if fs::metadata(path).ok()?.modified().ok()? < not_before {
+            return None;
+        }
+
+        let pid = fs::read_to_string(path).ok()?.trim().parse::<i32>().ok()?;
+        if pid <= 0 {
+            return None;
+        }
+        let pid = unistd::Pid::from_raw(pid);
+
+        let process = Self {
+            pid,
+            start_time: Self::read_start_time(pid),
+        };
+        process.is_alive().then_some(process)
+    }
+
+    fn is_alive(&self) -> bool {
+        if signal::kill(self.pid, None).is_err() {
+            return false;
+        }
+
+        // Zombies have already exited, they're just waiting to be reaped by their parent.
+        let stat = Self::read_stat(self.pid).unwrap_or_default();
+        if stat.first().map(String::as_str) == Some("Z") {
+            return false;
+        }
+
+        match self.start_time {
+            Some(start_time) => stat.get(19).and_then(|s| s.parse().ok()) == Some(start_time),
+            None => true,
+        }
+    }
 }
 
 impl Serialize for Service {
     fn serialize<S: serde::ser::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
-        let mut s = serializer.serialize_struct("Service", 3)?;
+        let mut s = serializer.serialize_struct("Service", FIELDS.len())?;
         s.serialize_field("working_directory", &self.working_directory)?;
-        s.serialize_field("environment", &self.environment)?;
-        s.serialize_field("group", &self.group)?;
+        s.serialize_field(
+            "environment",
+            &self.environment.iter().collect::<BTreeMap<_, _>>(),
+        )?;
+        s.serialize_field("group", &self.group())?;
         s.serialize_field("kind", &self.kind)?;
+        s.serialize_field("ports", &self.ports)?;
+        s.serialize_field("health_check", &self.health_check)?;
+        s.serialize_field(
+            "health_check_interval_secs",
+            &self.health_check_interval_secs,
+        )?;
+        s.serialize_field("ready_command", &self.ready_command)?;
+        s.serialize_field("ready_tcp_port", &self.ready_tcp_port)?;
+        s.serialize_field("watch_paths", &self.watch_paths)?;
+        s.serialize_field("watch_disabled", &self.watch_disabled)?;
+        s.serialize_field("depends_on", &self.depends_on)?;
+        s.serialize_field("log_retention_secs", &self.log_retention_secs)?;
+        s.serialize_field("pre_start", &self.pre_start)?;
+        s.serialize_field("post_stop", &self.post_stop)?;
+        s.serialize_field("on_failure", &self.on_failure)?;
+        s.serialize_field("clean_environment", &self.clean_environment)?;
+        s.serialize_field("shell", &self.shell)?;
+        s.serialize_field("description", &self.description)?;
+        s.serialize_field("labels", &self.labels.iter().collect::<BTreeMap<_, _>>())?;
+        s.serialize_field("reload_signal", &self.reload_signal)?;
+        s.serialize_field("reload_command", &self.reload_command)?;
+        s.serialize_field("create_working_directory", &self.create_working_directory)?;
+        s.serialize_field("process_title", &self.process_title)?;
         s.end()
     }
 }
 
+const FIELDS: &[&str] = &[
+    "working_directory",
+    "environment",
+    "group",
+    "kind",
+    "ports",
+    "health_check",
+    "health_check_interval_secs",
+    "ready_command",
+    "ready_tcp_port",
+    "watch_paths",
+    "watch_disabled",
+    "depends_on",
+    "log_retention_secs",
+    "pre_start",
+    "post_stop",
+    "on_failure",
+    "clean_environment",
+    "shell",
+    "description",
+    "labels",
+    "reload_signal",
+    "reload_command",
+    "create_working_directory",
+    "process_title",
+];
+
 impl<'de> Deserialize<'de> for Service {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
@@ -199,9 +1087,29 @@ impl<'de> Deserialize<'de> for Service {
                 let mut environment = None;
                 let mut kind = None;
                 let mut group = None;
+                let mut ports = None;
+                let mut health_check = None;
+                let mut health_check_interval_secs = None;
+                let mut ready_command = None;
+                let mut ready_tcp_port = None;
+                let mut watch_paths = None;
+                let mut watch_disabled = None;
+                let mut depends_on = None;
+                let mut log_retention_secs = None;
+                let mut pre_start = None;
+                let mut post_stop = None;
+                let mut on_failure = None;
+                let mut clean_environment = None;
+                let mut shell = None;
+                let mut description = None;
+                let mut labels = None;
+                let mut reload_signal = None;
+                let mut reload_command = None;
+                let mut create_working_directory = None;
+                let mut process_title = None;
 
-                while let Some(key) = map.next_key()? {
-                    match key {
+                while let Some(key) = map.next_key::<String>()? {
+                    match key.as_str() {
                         "working_directory" => {
                             if working_directory.is_some() {
                                 return Err(serde::de::Error::duplicate_field("working_directory"));
@@ -226,11 +1134,134 @@ impl<'de> Deserialize<'de> for Service {
                             }
                             kind = Some(map.next_value()?);
                         }
-                        field => {
-                            return Err(serde::de::Error::unknown_field(
-                                field,
-                                &["working_directory", "environment", "group", "kind"],
-                            ));
+                        "ports" => {
+                            if ports.is_some() {
+                                return Err(serde::de::Error::duplicate_field("ports"));
+                            }
+                            ports = Some(map.next_value()?);
+                        }
+                        "health_check" => {
+                            if health_check.is_some() {
+                                return Err(serde::de::Error::duplicate_field("health_check"));
+                            }
+                            health_check = Some(map.next_value()?);
+                        }
+                        "health_check_interval_secs" => {
+                            if health_check_interval_secs.is_some() {
+                                return Err(serde::de::Error::duplicate_field(
+                                    "health_check_interval_secs",
+                                ));
+                            }
+                            health_check_interval_secs = Some(map.next_value()?);
+                        }
+                        "ready_command" => {
+                            if ready_command.is_some() {
+                                return Err(serde::de::Error::duplicate_field("ready_command"));
+                            }
+                            ready_command = Some(map.next_value()?);
+                        }
+                        "ready_tcp_port" => {
+                            if ready_tcp_port.is_some() {
+                                return Err(serde::de::Error::duplicate_field("ready_tcp_port"));
+                            }
+                            ready_tcp_port = Some(map.next_value()?);
+                        }
+                        "watch_paths" => {
+                            if watch_paths.is_some() {
+                                return Err(serde::de::Error::duplicate_field("watch_paths"));
+                            }
+                            watch_paths = Some(map.next_value()?);
+                        }
+                        "watch_disabled" => {
+                            if watch_disabled.is_some() {
+                                return Err(serde::de::Error::duplicate_field("watch_disabled"));
+                            }
+                            watch_disabled = Some(map.next_value()?);
+                        }
+                        "depends_on" => {
+                            if depends_on.is_some() {
+                                return Err(serde::de::Error::duplicate_field("depends_on"));
+                            }
+                            depends_on = Some(map.next_value()?);
+                        }
+                        "log_retention_secs" => {
+                            if log_retention_secs.is_some() {
+                                return Err(serde::de::Error::duplicate_field(
+                                    "log_retention_secs",
+                                ));
+                            }
+                            log_retention_secs = Some(map.next_value()?);
+                        }
+                        "pre_start" => {
+                            if pre_start.is_some() {
+                                return Err(serde::de::Error::duplicate_field("pre_start"));
+                            }
+                            pre_start = Some(map.next_value()?);
+                        }
+                        "post_stop" => {
+                            if post_stop.is_some() {
+                                return Err(serde::de::Error::duplicate_field("post_stop"));
+                            }
+                            post_stop = Some(map.next_value()?);
+                        }
+                        "on_failure" => {
+                            if on_failure.is_some() {
+                                return Err(serde::de::Error::duplicate_field("on_failure"));
+                            }
+                            on_failure = Some(map.next_value()?);
+                        }
+                        "clean_environment" => {
+                            if clean_environment.is_some() {
+                                return Err(serde::de::Error::duplicate_field("clean_environment"));
+                            }
+                            clean_environment = Some(map.next_value()?);
+                        }
+                        "shell" => {
+                            if shell.is_some() {
+                                return Err(serde::de::Error::duplicate_field("shell"));
+                            }
+                            shell = Some(map.next_value()?);
+                        }
+                        "description" => {
+                            if description.is_some() {
+                                return Err(serde::de::Error::duplicate_field("description"));
+                            }
+                            description = Some(map.next_value()?);
+                        }
+                        "labels" => {
+                            if labels.is_some() {
+                                return Err(serde::de::Error::duplicate_field("labels"));
+                            }
+                            labels = Some(map.next_value()?);
+                        }
+                        "reload_signal" => {
+                            if reload_signal.is_some() {
+                                return Err(serde::de::Error::duplicate_field("reload_signal"));
+                            }
+                            reload_signal = Some(map.next_value()?);
+                        }
+                        "reload_command" => {
+                            if reload_command.is_some() {
+                                return Err(serde::de::Error::duplicate_field("reload_command"));
+                            }
+                            reload_command = Some(map.next_value()?);
+                        }
+                        "create_working_directory" => {
+                            if create_working_directory.is_some() {
+                                return Err(serde::de::Error::duplicate_field(
+                                    "create_working_directory",
+                                ));
+                            }
+                            create_working_directory = Some(map.next_value()?);
+                        }
+                        "process_title" => {
+                            if process_title.is_some() {
+                                return Err(serde::de::Error::duplicate_field("process_title"));
+                            }
+                            process_title = Some(map.next_value()?);
+                        }
+                        field => {
+                            return Err(serde::de::Error::unknown_field(field, FIELDS));
                         }
                     }
                 }
@@ -239,163 +1270,1859 @@ impl<'de> Deserialize<'de> for Service {
                     .ok_or_else(|| serde::de::Error::missing_field("working_directory"))?;
                 let environment =
                     environment.ok_or_else(|| serde::de::Error::missing_field("environment"))?;
-                let group = group.ok_or_else(|| serde::de::Error::missing_field("group"))?;
+                // TOML has no null, so a service without a group has no `group` key there.
+                let group = group.unwrap_or_default();
                 let kind = kind.ok_or_else(|| serde::de::Error::missing_field("kind"))?;
 
-                Ok(Service::new(working_directory, environment, group, kind))
+                let mut service = Service::new(working_directory, environment, group, kind);
+                service.ports = ports.unwrap_or_default();
+                service.health_check = health_check.unwrap_or_default();
+                service.health_check_interval_secs = health_check_interval_secs.unwrap_or_default();
+                service.ready_command = ready_command.unwrap_or_default();
+                service.ready_tcp_port = ready_tcp_port.unwrap_or_default();
+                service.watch_paths = watch_paths.unwrap_or_default();
+                service.watch_disabled = watch_disabled.unwrap_or_default();
+                service.depends_on = depends_on.unwrap_or_default();
+                service.log_retention_secs = log_retention_secs.unwrap_or_default();
+                service.pre_start = pre_start.unwrap_or_default();
+                service.post_stop = post_stop.unwrap_or_default();
+                service.on_failure = on_failure.unwrap_or_default();
+                service.clean_environment = clean_environment.unwrap_or_default();
+                service.shell = shell.unwrap_or_default();
+                service.description = description.unwrap_or_default();
+                service.labels = labels.unwrap_or_default();
+                service.reload_signal = reload_signal.unwrap_or_default();
+                service.reload_command = reload_command.unwrap_or_default();
+                service.create_working_directory = create_working_directory.unwrap_or_default();
+                service.process_title = process_title.unwrap_or_default();
+                Ok(service)
             }
         }
 
-        deserializer.deserialize_struct(
-            "Service",
-            &["working_directory", "environment", "kind", "group"],
-            ServiceVisitor,
-        )
+        deserializer.deserialize_struct("Service", FIELDS, ServiceVisitor)
     }
 }
 
 impl Service {
+    pub fn group(&self) -> Option<String> {
+        self.group.lock().unwrap().clone()
+    }
+
+    pub fn set_group(&self, group: Option<String>) {
+        *self.group.lock().unwrap() = group;
+    }
+
     pub fn new(
         working_directory: String,
         environment: HashMap<String, String>,
         group: Option<String>,
         kind: ServiceKind,
     ) -> Self {
+        let oneshot = matches!(kind, ServiceKind::Oneshot { .. });
+        let timer = matches!(kind, ServiceKind::Timer { .. }).then(|| Timer {
+            next_run: Instant::now(),
+            last_run: None,
+        });
+
         Self {
             working_directory,
             environment,
-            group,
+            group: Mutex::new(group),
             kind,
+            ports: Vec::new(),
+            health_check: None,
+            health_check_interval_secs: None,
+            ready_command: None,
+            ready_tcp_port: None,
+            watch_paths: Vec::new(),
+            watch_disabled: false,
+            depends_on: Vec::new(),
+            log_retention_secs: None,
+            pre_start: None,
+            post_stop: None,
+            on_failure: None,
+            clean_environment: false,
+            shell: false,
+            description: None,
+            labels: HashMap::new(),
+            reload_signal: None,
+            reload_command: None,
+            create_working_directory: false,
+            process_title: false,
 
-            async_running: false,
-            child: None,
-            logs: Arc::new(Mutex::new(String::new())),
+            operation: Mutex::new(()),
+            runtime: Mutex::new(Runtime {
+                oneshot,
+                timer,
+                ..Runtime::default()
+            }),
+            logs: Arc::new(Mutex::new(Logs::default())),
         }
     }
 
-    fn start_synchronous(&mut self, command: Vec<String>) -> Result<(), ServiceError> {
-        self.child = Some(
-            match Command::start(
-                command
-                    .iter()
-                    .map(|s| s.as_str())
-                    .collect::<Vec<&str>>()
-                    .as_slice(),
-                &self.working_directory,
-                self.environment.clone(),
-                self.logs.clone(),
-            ) {
-                Ok(command) => command,
-                Err(err) => return Err(ServiceError::IOError(err)),
-            },
-        );
-        Ok(())
-    }
-
-    fn start_asynchronous(&mut self, start_command: Vec<String>) -> Result<(), ServiceError> {
-        let command = match Command::start(
-            start_command
+    /// Spawns one of the commands of the service. Hooks are always argument lists, so only the
+    /// main commands of shell services get run through the shell.
+    fn spawn(
+        &self,
+        command: &[String],
+        working_directory: &str,
+        environment: &HashMap<String, String>,
+        shell: bool,
+    ) -> Result<Command<Logs>, ServiceError> {
+        let environment = self.child_environment(environment);
+        // Spawning fails the same way whether the working directory or the executable is missing.
+        let working_directory_path = PathBuf::from(expand_path(working_directory, &environment));
+        let program = if shell { "sh" } else { &command[0] }.to_string();
+        // The service only knows its name through the sender of its events.
+        let title = self
+            .process_title
+            .then(|| {
+                let runtime = self.runtime.lock().unwrap();
+                let events = runtime.events.as_ref()?;
+                Some(format!("userserversd: {}", events.service()))
+            })
+            .flatten();
+        // Only a bare program name is looked up in the PATH the child gets.
+        let search_path =
+            (!program.contains('/')).then(|| environment.get("PATH").cloned().unwrap_or_default());
+        match Command::start(
+            command
                 .iter()
                 .map(|s| s.as_str())
                 .collect::<Vec<&str>>()
                 .as_slice(),
-            &self.working_directory,
-            self.environment.clone(),
+            working_directory,
+            environment,
+            shell,
+            title.as_deref(),
             self.logs.clone(),
         ) {
-            Ok(command) => command,
-            Err(err) => return Err(ServiceError::IOError(err)),
+            Ok(command) => Ok(command),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                if !working_directory_path.is_dir() {
+                    Err(ServiceError::WorkingDirectoryNotFound {
+                        path: working_directory_path,
+                    })
+                } else {
+                    Err(ServiceError::ExecutableNotFound {
+                        program,
+                        search_path,
+                    })
+                }
+            }
+            Err(err)
+                if err.kind() == io::ErrorKind::PermissionDenied
+                    && working_directory_path.is_dir() =>
+            {
+                Err(ServiceError::NotExecutable { program })
+            }
+            Err(err) => Err(ServiceError::IOError(err)),
+        }
+    }
+
+    /// Creates the working directory along with its parents, if the service asks for it.
+    fn create_working_directory(&self) -> Result<(), ServiceError> {
+        if !self.create_working_directory {
+            return Ok(());
+        }
+
+        let path = self.working_directory_path();
+        fs::create_dir_all(&path).map_err(|err| {
+            ServiceError::IOError(io::Error::new(
+                err.kind(),
+                format!(
+                    "could not create the working directory `{}`: {err}",
+                    path.display()
+                ),
+            ))
+        })
+    }
+
+    /// The environment the processes of the service get: the base environment of the daemon, or
+    /// only its `CLEAN_ENVIRONMENT_BASE` variables for a clean environment, with `overrides` on
+    /// top.
+    fn child_environment(&self, overrides: &HashMap<String, String>) -> BTreeMap<String, String> {
+        let mut environment = base_environment()
+            .iter()
+            .filter(|(key, _)| {
+                !self.clean_environment || CLEAN_ENVIRONMENT_BASE.contains(&key.as_str())
+            })
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect::<BTreeMap<_, _>>();
+        environment.extend(
+            overrides
+                .iter()
+                .map(|(key, value)| (key.clone(), value.clone())),
+        );
+        environment
+    }
+
+    /// The working directory with `~` and variables expanded, as the service's processes get it.
+    pub fn working_directory_path(&self) -> PathBuf {
+        let environment = self.child_environment(&self.environment);
+        PathBuf::from(expand_path(&self.working_directory, &environment))
+    }
+
+    /// Checks that the working directory exists and that the commands name executables, so that
+    /// mistakes show up when the service is added rather than once it fails to start.
+    pub fn validate(&self) -> Result<(), String> {
+        let environment = self.child_environment(&self.environment);
+        let working_directory = PathBuf::from(expand_path(&self.working_directory, &environment));
+        // A working directory that gets created on start only has to not be something else.
+        let missing = self.create_working_directory && !working_directory.exists();
+        if !missing && !working_directory.is_dir() {
+            return Err(format!(
+                "the working directory `{}` is not a directory",
+                working_directory.display()
+            ));
+        }
+
+        let commands = match &self.kind {
+            ServiceKind::Synchronous { command }
+            | ServiceKind::Oneshot { command }
+            | ServiceKind::Timer { command, .. } => vec![command],
+            ServiceKind::Asynchronous {
+                start_command,
+                stop_command,
+                ..
+            } => vec![start_command, stop_command],
         };
-        if let Err(err) = command.wait() {
-            return Err(ServiceError::IOError(err));
+        // The shell is the program of shell commands, and is assumed to be there.
+        let commands = if self.shell { Vec::new() } else { commands };
+        for program in commands.into_iter().filter_map(|command| command.first()) {
+            let program = expand_variables(program, &environment);
+            if !program_exists(&program, &working_directory, environment.get("PATH")) {
+                return Err(format!(
+                    "`{program}` is neither an executable file nor a command found in PATH"
+                ));
+            }
         }
+        Ok(())
+    }
 
-        self.async_running = true;
+    /// Checks that the definition makes sense at all: every command has a program to run and
+    /// every environment variable has a usable name.
+    pub fn check_definition(&self) -> Result<(), String> {
+        let mut commands = match &self.kind {
+            ServiceKind::Synchronous { command }
+            | ServiceKind::Oneshot { command }
+            | ServiceKind::Timer { command, .. } => vec![("command", command)],
+            ServiceKind::Asynchronous {
+                start_command,
+                stop_command,
+                ..
+            } => vec![
+                ("start command", start_command),
+                ("stop command", stop_command),
+            ],
+        };
+        for (name, command) in [
+            ("health check", &self.health_check),
+            ("readiness command", &self.ready_command),
+            ("pre-start hook", &self.pre_start),
+            ("post-stop hook", &self.post_stop),
+            ("on-failure hook", &self.on_failure),
+            ("reload command", &self.reload_command),
+        ] {
+            if let Some(command) = command {
+                commands.push((name, command));
+            }
+        }
+        if let Some((name, _)) = commands.iter().find(|(_, command)| command.is_empty()) {
+            return Err(format!("the {name} must not be empty"));
+        }
+
+        let stop_environment = match &self.kind {
+            ServiceKind::Asynchronous {
+                stop_environment: Some(stop_environment),
+                ..
+            } => Some(stop_environment),
+            _ => None,
+        };
+        for key in self.environment.keys().chain(
+            stop_environment
+                .into_iter()
+                .flat_map(|environment| environment.keys()),
+        ) {
+            if key.is_empty() || key.contains(['=', '\0']) {
+                return Err(format!(
+                    "`{}` is not a valid environment variable name",
+                    key.escape_debug()
+                ));
+            }
+        }
+        if self.labels.keys().any(|key| key.is_empty()) {
+            return Err("label names must not be empty".to_string());
+        }
+        if let Some(reload_signal) = &self.reload_signal
+            && reload_signal.parse::<Signal>().is_err()
+        {
+            return Err(format!("`{reload_signal}` is not a signal"));
+        }
+        if self.reload_signal.is_some() && self.reload_command.is_some() {
+            return Err(
+                "a service can either reload with a signal or with a command, not both".to_string(),
+            );
+        }
+        if let ServiceKind::Asynchronous {
+            command_timeout_secs: Some(0),
+            ..
+        } = &self.kind
+        {
+            return Err("the command timeout must be at least 1 second".to_string());
+        }
         Ok(())
     }
 
-    pub fn start(&mut self) -> Result<(), ServiceError> {
-        if self.is_running() {
-            return Err(ServiceError::ServiceAlreadyRunning);
+    pub fn attach_events(&self, events: EventSender) {
+        self.runtime.lock().unwrap().events = Some(events);
+    }
+
+    pub fn attach_clock(&self, clock: SharedClock) {
+        self.runtime.lock().unwrap().clock = clock;
+    }
+
+    /// Captures what a new daemon instance needs to take over the service. The service can't
+    /// change while the returned guard is held, so nothing gets reaped or started after the
+    /// state was captured.
+    pub fn hand_over(&self) -> (HandoverGuard<'_>, ServiceHandover) {
+        let operation = self.operation.lock().unwrap();
+        let mut runtime = self.runtime.lock().unwrap();
+        runtime.refresh();
+
+        let active = runtime.state.is_active();
+        let logs = self.logs.lock().unwrap();
+        let handover = ServiceHandover {
+            state: runtime.state,
+            ready: runtime.ready,
+            child: runtime
+                .child
+                .as_ref()
+                .filter(|_| active)
+                .map(|child| ChildHandover {
+                    pid: child.pid as i32,
+                    stdout_fd: child.stdout_fd,
+                    stderr_fd: child.stderr_fd,
+                }),
+            pidfile_process: runtime
+                .pidfile_process
+                .as_ref()
+                .map(|process| (process.pid.as_raw(), process.start_time)),
+            last_exit: runtime.last_exit,
+            start_count: runtime.start_count,
+            runs: runtime.runs.iter().cloned().collect(),
+            timer_last_run: runtime.timer.as_ref().and_then(|timer| timer.last_run),
+            logs: logs.as_str().to_string(),
+            log_line_times: logs.line_times(),
+        };
+        drop(logs);
+
+        let guard = HandoverGuard {
+            _operation: operation,
+            _runtime: runtime,
+        };
+        (guard, handover)
+    }
+
+    /// Takes the service over from the daemon instance this one replaced, without touching its
+    /// processes.
+    pub fn adopt(&self, handover: ServiceHandover) {
+        *self.logs.lock().unwrap() = Logs::restore(handover.logs, handover.log_line_times);
+
+        let mut runtime = self.runtime.lock().unwrap();
+        runtime.state = handover.state;
+        runtime.ready = handover.ready;
+        runtime.last_exit = handover.last_exit;
+        runtime.start_count = handover.start_count;
+        runtime.runs = handover.runs.into();
+        runtime.child = handover
+            .child
+            .map(|child| Command::adopt(&child, self.logs.clone()));
+        runtime.pidfile_process = handover.pidfile_process.map(|(pid, start_time)| Process {
+            pid: unistd::Pid::from_raw(pid),
+            start_time,
+        });
+        let clock = runtime.clock.clone();
+        if runtime.state.is_active() {
+            runtime.health.last_check = Some(clock.now());
         }
 
-        match &self.kind {
-            ServiceKind::Synchronous { command } => self.start_synchronous(command.clone())?,
-            ServiceKind::Asynchronous { start_command, .. } => {
-                self.start_asynchronous(start_command.clone())?;
+        // Carry on with the schedule instead of running the timer again right away. The last
+        // run is only known by the wall clock, so a run that seems to be in the future waits a
+        // whole interval and one from before a long suspend runs right away.
+        if let (Some(timer), ServiceKind::Timer { interval_secs, .. }) =
+            (&mut runtime.timer, &self.kind)
+        {
+            timer.last_run = handover.timer_last_run;
+            timer.next_run = clock.now();
+            if let Some(last_run) = timer.last_run {
+                timer.next_run +=
+                    Duration::from_secs(*interval_secs).saturating_sub(clock.since(last_run));
             }
         }
+    }
+
+    fn start_synchronous(&self, command: &[String], cause: EventCause) -> Result<(), ServiceError> {
+        let child = self.spawn(
+            command,
+            &self.working_directory,
+            &self.environment,
+            self.shell,
+        )?;
 
+        let mut runtime = self.runtime.lock().unwrap();
+        runtime.child = Some(child);
+        runtime.transition(ServiceState::Running, cause);
         Ok(())
     }
 
-    fn stop_synchronous(&mut self) -> Result<(), ServiceError> {
-        if !self.is_running() {
-            return Err(ServiceError::ServiceNotRunning);
-        }
-        let child = self.child.as_ref().unwrap();
-        if let Err(err) = child.stop() {
-            return Err(ServiceError::IOError(err));
+    fn start_timer(&self, cause: EventCause) -> Result<(), ServiceError> {
+        let mut runtime = self.runtime.lock().unwrap();
+        runtime.child = None;
+        let now = runtime.clock.now();
+        if let Some(timer) = &mut runtime.timer {
+            timer.next_run = now;
         }
+        runtime.transition(ServiceState::Running, cause);
         Ok(())
     }
 
-    fn stop_asynchronous(&mut self, stop_command: Vec<String>) -> Result<(), ServiceError> {
-        let command = match Command::start(
-            stop_command
-                .iter()
-                .map(|s| s.as_str())
-                .collect::<Vec<&str>>()
-                .as_slice(),
+    fn start_asynchronous(
+        &self,
+        start_command: &[String],
+        pidfile: &Option<String>,
+        timeout: Duration,
+        cause: EventCause,
+    ) -> Result<(), ServiceError> {
+        // File timestamps can be slightly behind the clock, so leave some slack.
+        let started_at = SystemTime::now() - Duration::from_secs(1);
+        let command = self.spawn(
+            start_command,
             &self.working_directory,
-            self.environment.clone(),
-            self.logs.clone(),
-        ) {
-            Ok(command) => command,
-            Err(err) => return Err(ServiceError::IOError(err)),
+            &self.environment,
+            self.shell,
+        )?;
+        let status = command
+            .wait_timeout(timeout)
+            .map_err(ServiceError::IOError)?
+            .ok_or(ServiceError::CommandTimedOut {
+                command: "start",
+                secs: timeout.as_secs(),
+            })?;
+        if !status.success() {
+            return Err(ServiceError::StartCommandFailed {
+                code: status.code(),
+            });
+        }
+
+        let pidfile_process = match pidfile {
+            Some(pidfile) => Some(self.wait_for_pidfile(pidfile, started_at)?),
+            None => None,
+        };
+
+        let mut runtime = self.runtime.lock().unwrap();
+        runtime.pidfile_process = pidfile_process;
+        runtime.transition(ServiceState::Running, cause);
+        Ok(())
+    }
+
+    fn wait_for_pidfile(
+        &self,
+        pidfile: &str,
+        started_at: SystemTime,
+    ) -> Result<Process, ServiceError> {
+        let path = self.working_directory_path().join(pidfile);
+
+        let deadline = Instant::now() + PIDFILE_TIMEOUT;
+        loop {
+            if let Some(process) = Process::read(&path, started_at) {
+                return Ok(process);
+            }
+            if Instant::now() > deadline {
+                return Err(ServiceError::PidfileTimeout);
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+    }
+
+    fn check_ports(&self) -> Result<(), ServiceError> {
+        if self.ports.is_empty() {
+            return Ok(());
+        }
+
+        let listening_ports = listening_ports();
+        match self
+            .ports
+            .iter()
+            .find(|port| listening_ports.contains(port))
+        {
+            Some(port) => Err(ServiceError::PortInUse { port: *port }),
+            None => Ok(()),
+        }
+    }
+
+    /// Runs a hook of the service to completion, with a line in the logs telling its output
+    /// apart from the service's own.
+    fn run_hook(
+        &self,
+        hook: &'static str,
+        command: &Option<Vec<String>>,
+        environment: &HashMap<String, String>,
+    ) -> Result<(), ServiceError> {
+        let Some(command) = command else {
+            return Ok(());
+        };
+
+        self.logs
+            .lock()
+            .unwrap()
+            .mark(&format!("running the {hook} hook: {command:?}"));
+        let status = self
+            .spawn(command, &self.working_directory, environment, false)?
+            .wait()
+            .map_err(ServiceError::IOError)?;
+        if !status.success() {
+            return Err(ServiceError::HookFailed {
+                hook,
+                code: status.code(),
+            });
+        }
+        Ok(())
+    }
+
+    fn start_locked(&self, cause: EventCause) -> Result<(), ServiceError> {
+        if self.is_running() {
+            return Err(ServiceError::ServiceAlreadyRunning);
+        }
+
+        let result = self
+            .check_ports()
+            .and_then(|_| self.create_working_directory())
+            .and_then(|_| self.run_hook("pre-start", &self.pre_start, &self.environment))
+            .and_then(|_| match &self.kind {
+                ServiceKind::Synchronous { command } | ServiceKind::Oneshot { command } => {
+                    self.start_synchronous(command, cause)
+                }
+                ServiceKind::Asynchronous {
+                    start_command,
+                    pidfile,
+                    command_timeout_secs,
+                    ..
+                } => self.start_asynchronous(
+                    start_command,
+                    pidfile,
+                    command_timeout(*command_timeout_secs),
+                    cause,
+                ),
+                ServiceKind::Timer { .. } => self.start_timer(cause),
+            });
+
+        if let Err(err) = &result {
+            let mut runtime = self.runtime.lock().unwrap();
+            runtime.transition(
+                ServiceState::Failed,
+                EventCause::StartFailed {
+                    error: err.to_string(),
+                },
+            );
+        }
+
+        result
+    }
+
+    pub fn start(&self) -> Result<(), ServiceError> {
+        let _operation = self.operation.lock().unwrap();
+        self.start_locked(EventCause::Requested)
+    }
+
+    fn stop_synchronous(&self) -> Result<(), ServiceError> {
+        let child = match &self.runtime.lock().unwrap().child {
+            Some(child) => child.clone(),
+            None => return Err(ServiceError::ServiceNotRunning),
         };
-        if let Err(err) = command.wait() {
+        if let Err(err) = child.stop() {
             return Err(ServiceError::IOError(err));
         }
+        Ok(())
+    }
+
+    // Stopping a timer disables its schedule, and stops the run in progress if there is one.
+    fn stop_timer(&self) -> Result<(), ServiceError> {
+        let child = self.runtime.lock().unwrap().child.clone();
+        match child {
+            Some(child) => child.stop().map_err(ServiceError::IOError),
+            None => Ok(()),
+        }
+    }
 
-        self.async_running = false;
+    fn stop_asynchronous(
+        &self,
+        stop_command: &[String],
+        stop_working_directory: &Option<String>,
+        stop_environment: &Option<HashMap<String, String>>,
+        timeout: Duration,
+    ) -> Result<(), ServiceError> {
+        let command = self.spawn(
+            stop_command,
+            stop_working_directory
+                .as_ref()
+                .unwrap_or(&self.working_directory),
+            stop_environment.as_ref().unwrap_or(&self.environment),
+            self.shell,
+        )?;
+        let status = command
+            .wait_timeout(timeout)
+            .map_err(ServiceError::IOError)?
+            .ok_or(ServiceError::CommandTimedOut {
+                command: "stop",
+                secs: timeout.as_secs(),
+            })?;
+        if !status.success() {
+            return Err(ServiceError::StopCommandFailed {
+                code: status.code(),
+            });
+        }
         Ok(())
     }
 
-    pub fn stop(&mut self) -> Result<(), ServiceError> {
+    fn stop_locked(&self, cause: EventCause) -> Result<StopOutcome, ServiceError> {
         if !self.is_running() {
             return Err(ServiceError::ServiceNotRunning);
         }
 
-        match &self.kind {
-            ServiceKind::Synchronous { .. } => self.stop_synchronous()?,
-            ServiceKind::Asynchronous { stop_command, .. } => {
-                self.stop_asynchronous(stop_command.clone())?;
+        let processes = match self.pid() {
+            Some(pid) => {
+                let pid = unistd::Pid::from_raw(pid as i32);
+                let mut processes = Process::descendants(pid);
+                processes.push(Process {
+                    pid,
+                    start_time: Process::read_start_time(pid),
+                });
+                processes
+            }
+            None => Vec::new(),
+        };
+
+        // A frozen process can't act on being told to stop.
+        if self.state() == ServiceState::Paused {
+            for process in &processes {
+                let _ = signal::kill(process.pid, Signal::SIGCONT);
             }
         }
 
+        self.runtime.lock().unwrap().stopping = true;
+        let result = match &self.kind {
+            ServiceKind::Synchronous { .. } | ServiceKind::Oneshot { .. } => {
+                self.stop_synchronous()
+            }
+            ServiceKind::Asynchronous {
+                stop_command,
+                stop_working_directory,
+                stop_environment,
+                command_timeout_secs,
+                ..
+            } => self.stop_asynchronous(
+                stop_command,
+                stop_working_directory,
+                stop_environment,
+                command_timeout(*command_timeout_secs),
+            ),
+            ServiceKind::Timer { .. } => self.stop_timer(),
+        };
+
+        let survivors = processes
+            .iter()
+            .filter(|process| process.is_alive())
+            .map(|process| process.pid.as_raw() as u32)
+            .collect();
+
+        let mut runtime = self.runtime.lock().unwrap();
+        runtime.stopping = false;
+        match &result {
+            Ok(()) => {
+                runtime.pidfile_process = None;
+                runtime.transition(ServiceState::Stopped, cause);
+            }
+            Err(err) => {
+                let state = runtime.state;
+                runtime.transition(
+                    state,
+                    EventCause::StopFailed {
+                        error: err.to_string(),
+                    },
+                );
+            }
+        }
+        drop(runtime);
+
+        result?;
+        Ok(StopOutcome {
+            survivors,
+            post_stop_error: self
+                .run_hook("post-stop", &self.post_stop, &self.environment)
+                .err(),
+        })
+    }
+
+    pub fn stop(&self) -> Result<StopOutcome, ServiceError> {
+        let _operation = self.operation.lock().unwrap();
+        self.stop_locked(EventCause::Requested)
+    }
+
+    /// Restarts the service, returning what stopping its previous run left behind.
+    pub fn restart(&self) -> Result<StopOutcome, ServiceError> {
+        self.restart_because(EventCause::Requested)
+    }
+
+    pub fn restart_because(&self, cause: EventCause) -> Result<StopOutcome, ServiceError> {
+        let _operation = self.operation.lock().unwrap();
+        let outcome = self.stop_locked(cause.clone())?;
+        self.start_locked(cause).map(|_| outcome)
+    }
+
+    /// Has the running service reload its configuration, either by sending its process the
+    /// reload signal or by running the reload command to completion.
+    pub fn reload(&self) -> Result<(), ServiceError> {
+        let _operation = self.operation.lock().unwrap();
+        if !self.is_running() {
+            return Err(ServiceError::ServiceNotRunning);
+        }
+
+        if let Some(reload_signal) = &self.reload_signal {
+            let signal = reload_signal
+                .parse::<Signal>()
+                .map_err(|err| ServiceError::IOError(err.into()))?;
+            let Some(pid) = self.pid() else {
+                return Err(ServiceError::NoKnownProcess);
+            };
+            self.logs
+                .lock()
+                .unwrap()
+                .mark(&format!("reloading with {reload_signal}"));
+            signal::kill(unistd::Pid::from_raw(pid as i32), signal)
+                .map_err(|err| ServiceError::IOError(err.into()))?;
+            return Ok(());
+        }
+
+        let Some(reload_command) = &self.reload_command else {
+            return Err(ServiceError::NoReloadMethod);
+        };
+        self.logs
+            .lock()
+            .unwrap()
+            .mark(&format!("running the reload command: {reload_command:?}"));
+        // Like the stop command, it runs with the stop environment and working directory when
+        // those are set.
+        let (working_directory, environment) = match &self.kind {
+            ServiceKind::Asynchronous {
+                stop_working_directory,
+                stop_environment,
+                ..
+            } => (
+                stop_working_directory
+                    .as_ref()
+                    .unwrap_or(&self.working_directory),
+                stop_environment.as_ref().unwrap_or(&self.environment),
+            ),
+            _ => (&self.working_directory, &self.environment),
+        };
+        let status = self
+            .spawn(reload_command, working_directory, environment, false)?
+            .wait()
+            .map_err(ServiceError::IOError)?;
+        if !status.success() {
+            return Err(ServiceError::ReloadCommandFailed {
+                code: status.code(),
+            });
+        }
         Ok(())
     }
 
-    pub fn restart(&mut self) -> Result<(), ServiceError> {
-        self.stop()?;
-        self.start()
+    /// Freezes the process of the running service and everything it started with SIGSTOP.
+    /// Timers and asynchronous services without a pidfile have no process known to be theirs,
+    /// so they can't be paused.
+    pub fn pause(&self) -> Result<(), ServiceError> {
+        let _operation = self.operation.lock().unwrap();
+        match self.state() {
+            ServiceState::Paused => return Ok(()),
+            state if !state.is_active() => return Err(ServiceError::ServiceNotRunning),
+            _ => {}
+        }
+
+        self.signal_processes(Signal::SIGSTOP)?;
+        self.runtime
+            .lock()
+            .unwrap()
+            .transition(ServiceState::Paused, EventCause::Requested);
+        Ok(())
+    }
+
+    /// Lets a paused service carry on where it was frozen.
+    pub fn resume(&self) -> Result<(), ServiceError> {
+        let _operation = self.operation.lock().unwrap();
+        match self.state() {
+            ServiceState::Paused => {}
+            state if !state.is_active() => return Err(ServiceError::ServiceNotRunning),
+            _ => return Ok(()),
+        }
+
+        self.signal_processes(Signal::SIGCONT)?;
+        self.runtime
+            .lock()
+            .unwrap()
+            .transition(ServiceState::Running, EventCause::Requested);
+        Ok(())
+    }
+
+    // Signals the process of the service first, so that it can't start anything new in between.
+    fn signal_processes(&self, signal: Signal) -> Result<(), ServiceError> {
+        let pid = match (&self.kind, self.pid()) {
+            (ServiceKind::Timer { .. }, _) | (_, None) => {
+                return Err(ServiceError::NoKnownProcess);
+            }
+            (_, Some(pid)) => unistd::Pid::from_raw(pid as i32),
+        };
+
+        signal::kill(pid, signal).map_err(|err| ServiceError::IOError(err.into()))?;
+        for process in Process::descendants(pid) {
+            let _ = signal::kill(process.pid, signal);
+        }
+        Ok(())
+    }
+
+    pub fn state(&self) -> ServiceState {
+        let mut runtime = self.runtime.lock().unwrap();
+        runtime.refresh();
+        runtime.state
     }
 
     pub fn is_running(&self) -> bool {
-        match self.kind {
-            ServiceKind::Synchronous { .. } => {
-                if let Some(child) = &self.child {
-                    child.is_running()
-                } else {
-                    false
+        self.state().is_active()
+    }
+
+    /// Sends SIGKILL to the process of the service, and to its process group if it leads one,
+    /// without waiting for an operation in progress such as a stop that takes too long.
+    pub fn kill(&self) {
+        let Some(pid) = self.pid() else {
+            return;
+        };
+        let pid = unistd::Pid::from_raw(pid as i32);
+        if unistd::getpgid(Some(pid)) == Ok(pid) {
+            let _ = signal::killpg(pid, Signal::SIGKILL);
+        }
+        let _ = signal::kill(pid, Signal::SIGKILL);
+    }
+
+    pub fn has_readiness_probe(&self) -> bool {
+        self.ready_command.is_some() || self.ready_tcp_port.is_some()
+    }
+
+    /// Whether the service is up and its readiness probe, if it has one, has passed since it
+    /// was started.
+    pub fn is_ready(&self) -> bool {
+        let mut runtime = self.runtime.lock().unwrap();
+        runtime.refresh();
+        if runtime.oneshot {
+            return runtime.state == ServiceState::Completed;
+        }
+        runtime.state.is_active() && (runtime.ready || !self.has_readiness_probe())
+    }
+
+    /// Returns how many times the service was started, along with its latest runs, oldest first.
+    pub fn run_history(&self) -> (u64, Vec<Run>) {
+        let mut runtime = self.runtime.lock().unwrap();
+        runtime.refresh();
+        (runtime.start_count, runtime.runs.iter().cloned().collect())
+    }
+
+    /// Returns the exit code and signal of the last run that ended on its own.
+    pub fn last_exit(&self) -> Option<(Option<i32>, Option<i32>)> {
+        let mut runtime = self.runtime.lock().unwrap();
+        runtime.refresh();
+        runtime.last_exit
+    }
+
+    /// Marks the readiness probe as started if the service has one that still needs to pass.
+    pub fn begin_readiness_probe(&self) -> bool {
+        if !self.has_readiness_probe() {
+            return false;
+        }
+
+        let mut runtime = self.runtime.lock().unwrap();
+        runtime.refresh();
+        if !runtime.state.is_active()
+            || runtime.state == ServiceState::Paused
+            || runtime.ready
+            || runtime.probing_readiness
+        {
+            return false;
+        }
+
+        runtime.probing_readiness = true;
+        true
+    }
+
+    /// Runs the readiness probe, which must have been started with `begin_readiness_probe`.
+    pub fn run_readiness_probe(&self) {
+        let port_ready = self
+            .ready_tcp_port
+            .is_none_or(|port| listening_ports().contains(&port));
+        let command_ready = port_ready
+            && match &self.ready_command {
+                Some(ready_command) => self
+                    .spawn(
+                        ready_command,
+                        &self.working_directory,
+                        &self.environment,
+                        false,
+                    )
+                    .and_then(|command| command.wait().map_err(ServiceError::IOError))
+                    .is_ok_and(|status| status.success()),
+                None => true,
+            };
+
+        let mut runtime = self.runtime.lock().unwrap();
+        runtime.probing_readiness = false;
+        if command_ready && runtime.state.is_active() {
+            runtime.ready = true;
+        }
+    }
+
+    /// Marks the health check as started if the service has one and it is due.
+    pub fn begin_health_check(&self) -> bool {
+        if self.health_check.is_none() {
+            return false;
+        }
+        let interval = Duration::from_secs(
+            self.health_check_interval_secs
+                .unwrap_or(DEFAULT_HEALTH_CHECK_INTERVAL_SECS),
+        );
+
+        let mut runtime = self.runtime.lock().unwrap();
+        runtime.refresh();
+        if !runtime.state.is_active()
+            || runtime.state == ServiceState::Paused
+            || runtime.stopping
+            || runtime.health.checking
+        {
+            return false;
+        }
+        let now = runtime.clock.now();
+        if runtime
+            .health
+            .last_check
+            .is_some_and(|last_check| now.saturating_duration_since(last_check) < interval)
+        {
+            return false;
+        }
+
+        runtime.health.checking = true;
+        runtime.health.last_check = Some(now);
+        true
+    }
+
+    /// Runs the health check, which must have been started with `begin_health_check`. Its
+    /// output goes to the logs of the service.
+    pub fn run_health_check(&self) {
+        let health_check = self.health_check.as_deref().unwrap_or_default();
+        let result = self
+            .spawn(
+                health_check,
+                &self.working_directory,
+                &self.environment,
+                false,
+            )
+            .and_then(|command| command.wait().map_err(ServiceError::IOError));
+
+        let mut runtime = self.runtime.lock().unwrap();
+        runtime.health.checking = false;
+        if !runtime.state.is_active() || runtime.stopping {
+            return;
+        }
+
+        let code = match result {
+            Ok(status) if status.success() => {
+                runtime.health.consecutive_failures = 0;
+                if runtime.state == ServiceState::Unhealthy {
+                    runtime.transition(ServiceState::Running, EventCause::HealthCheckPassed);
                 }
+                return;
+            }
+            Ok(status) => status.code(),
+            Err(_) => None,
+        };
+
+        runtime.health.consecutive_failures += 1;
+        if runtime.state == ServiceState::Running
+            && runtime.health.consecutive_failures >= HEALTH_CHECK_FAILURE_THRESHOLD
+        {
+            runtime.transition(
+                ServiceState::Unhealthy,
+                EventCause::HealthCheckFailed { code },
+            );
+        }
+    }
+
+    /// Runs the command of a timer service if it is due and its previous run has finished. The
+    /// output of the run goes to the logs of the service.
+    pub fn run_timer(&self) -> Result<(), ServiceError> {
+        let ServiceKind::Timer {
+            command,
+            interval_secs,
+        } = &self.kind
+        else {
+            return Ok(());
+        };
+        // A start or stop in progress is about to change the schedule anyway.
+        let Ok(_operation) = self.operation.try_lock() else {
+            return Ok(());
+        };
+
+        {
+            let mut runtime = self.runtime.lock().unwrap();
+            runtime.refresh();
+            if !runtime.state.is_active() || runtime.child.is_some() {
+                return Ok(());
             }
-            ServiceKind::Asynchronous { .. } => self.async_running,
+            let now = runtime.clock.now();
+            if runtime
+                .timer
+                .as_ref()
+                .is_none_or(|timer| timer.next_run > now)
+            {
+                return Ok(());
+            }
+        }
+
+        let result = self.spawn(
+            command,
+            &self.working_directory,
+            &self.environment,
+            self.shell,
+        );
+
+        let mut runtime = self.runtime.lock().unwrap();
+        let (now, wall) = (runtime.clock.now(), runtime.clock.wall());
+        if let Some(timer) = &mut runtime.timer {
+            // Keep to the schedule rather than drifting by however late this run was, but skip
+            // the runs that were missed entirely instead of making up for them back to back.
+            let interval = Duration::from_secs(*interval_secs);
+            timer.next_run += interval;
+            if timer.next_run <= now {
+                timer.next_run = now + interval;
+            }
+            timer.last_run = Some(wall);
+        }
+        runtime.child = Some(result?);
+        Ok(())
+    }
+
+    /// Returns when a timer service last ran its command, and how long until it runs it again.
+    pub fn timer_schedule(&self) -> Option<(Option<SystemTime>, Option<Duration>)> {
+        let mut runtime = self.runtime.lock().unwrap();
+        runtime.refresh();
+        let active = runtime.state.is_active();
+        let timer = runtime.timer.as_ref()?;
+
+        let next_run = active.then(|| {
+            timer
+                .next_run
+                .saturating_duration_since(runtime.clock.now())
+        });
+        Some((timer.last_run, next_run))
+    }
+
+    /// Runs the on-failure hook after the service exited with a failure, with the name of the
+    /// service and how it exited in its environment. Failures that happen while the hook from an
+    /// earlier one still runs are skipped, so a crash loop can't pile hooks up.
+    pub fn run_failure_hook(
+        &self,
+        name: &str,
+        code: Option<i32>,
+        signal: Option<i32>,
+    ) -> Result<(), ServiceError> {
+        if self.on_failure.is_none() {
+            return Ok(());
+        }
+        {
+            let mut runtime = self.runtime.lock().unwrap();
+            if runtime.running_failure_hook {
+                return Ok(());
+            }
+            runtime.running_failure_hook = true;
+        }
+
+        let mut environment = self.environment.clone();
+        environment.insert("USERSERVERSD_SERVICE".to_string(), name.to_string());
+        if let Some(code) = code {
+            environment.insert("USERSERVERSD_EXIT_CODE".to_string(), code.to_string());
+        }
+        if let Some(signal) = signal {
+            environment.insert("USERSERVERSD_EXIT_SIGNAL".to_string(), signal.to_string());
         }
+        let result = self.run_hook("on-failure", &self.on_failure, &environment);
+
+        self.runtime.lock().unwrap().running_failure_hook = false;
+        result
     }
 
-    pub fn get_logs(&self) -> String {
-        self.logs.clone().lock().unwrap().clone()
+    /// Compares the modification times of the watched paths with the ones seen last time. A
+    /// change is only reported once no further changes were seen for `WATCH_DEBOUNCE`, so that
+    /// a burst of writes leads to a single restart.
+    pub fn check_watched_paths(&self) -> Option<String> {
+        if self.watch_paths.is_empty() {
+            return None;
+        }
+
+        let working_directory = self.working_directory_path();
+        let modified = self
+            .watch_paths
+            .iter()
+            .map(|path| {
+                let modified = fs::metadata(working_directory.join(path))
+                    .and_then(|metadata| metadata.modified())
+                    .ok();
+                (path.clone(), modified)
+            })
+            .collect::<Vec<_>>();
+
+        let mut runtime = self.runtime.lock().unwrap();
+        runtime.refresh();
+
+        let mut changed = None;
+        for (path, modified) in modified {
+            if let Some(previous) = runtime.watch.modified.insert(path.clone(), modified)
+                && previous != modified
+            {
+                changed = Some(path);
+            }
+        }
+
+        if self.watch_disabled || !runtime.state.is_active() || runtime.stopping {
+            runtime.watch.pending = None;
+            return None;
+        }
+        let now = runtime.clock.now();
+        if let Some(path) = changed {
+            runtime.watch.pending = Some((path, now));
+            return None;
+        }
+
+        if runtime
+            .watch
+            .pending
+            .as_ref()
+            .is_none_or(|(_, changed_at)| {
+                now.saturating_duration_since(*changed_at) < WATCH_DEBOUNCE
+            })
+        {
+            return None;
+        }
+        let (path, _) = runtime.watch.pending.take().unwrap();
+        runtime.watch.last_trigger = Some((path.clone(), runtime.clock.wall()));
+        Some(path)
+    }
+
+    /// Returns the watched path whose change last restarted the service, and when that was.
+    pub fn last_watch_trigger(&self) -> Option<(String, SystemTime)> {
+        self.runtime.lock().unwrap().watch.last_trigger.clone()
+    }
+
+    /// Returns the PID of the service's main process, if it is known.
+    pub fn pid(&self) -> Option<u32> {
+        let runtime = self.runtime.lock().unwrap();
+        if !runtime.state.is_active() {
+            return None;
+        }
+
+        match &runtime.pidfile_process {
+            Some(process) => Some(process.pid.as_raw() as u32),
+            None => runtime.child.as_ref().map(|child| child.pid),
+        }
+    }
+
+    /// Returns the memory in bytes and the CPU percentage used by the process of the service and
+    /// everything it started, or `None` if the process isn't known.
+    pub fn resource_usage(&self) -> Option<(u64, f32)> {
+        let pid = unistd::Pid::from_raw(self.pid()? as i32);
+        let (mut memory_bytes, mut cpu_percent) = Process::usage(pid)?;
+        for process in Process::descendants(pid) {
+            if let Some((memory, cpu)) = Process::usage(process.pid) {
+                memory_bytes += memory;
+                cpu_percent += cpu;
+            }
+        }
+        Some((memory_bytes, cpu_percent))
+    }
+
+    /// Returns at most the last `max_bytes` bytes of the logs, starting at a line unless that
+    /// would leave nothing, and whether anything before them was left out.
+    pub fn get_logs_tail_bytes(&self, max_bytes: usize) -> (String, bool) {
+        let logs = self.logs.lock().unwrap();
+        let logs = logs.as_str();
+        if logs.len() <= max_bytes {
+            return (logs.to_string(), false);
+        }
+
+        let mut start = logs.len() - max_bytes;
+        while !logs.is_char_boundary(start) {
+            start += 1;
+        }
+        if logs.as_bytes()[start - 1] != b'\n'
+            && let Some(index) = logs[start..].find('\n')
+            && start + index + 1 < logs.len()
+        {
+            start += index + 1;
+        }
+        (logs[start..].to_string(), true)
+    }
+
+    /// Returns the last `lines` lines of the logs, copying only those.
+    pub fn get_logs_tail(&self, lines: usize) -> String {
+        if lines == 0 {
+            return String::new();
+        }
+
+        let logs = self.logs.lock().unwrap();
+        let logs = logs.as_str();
+        let content = logs.strip_suffix('\n').unwrap_or(logs);
+
+        let start = match content.rmatch_indices('\n').nth(lines - 1) {
+            Some((index, _)) => index + 1,
+            None => 0,
+        };
+        logs[start..].to_string()
+    }
+
+    /// Returns the offsets of the first byte of the logs still kept and of the end of the logs.
+    pub fn logs_bounds(&self) -> (usize, usize) {
+        let logs = self.logs.lock().unwrap();
+        (logs.start(), logs.end())
+    }
+
+    /// Copies at most `max_len` bytes of the logs between `offset` and `end`, stopping early
+    /// rather than splitting a character. Returns the copied part along with the offset it ends
+    /// at, which skips past anything pruned in the meantime.
+    pub fn read_logs(&self, offset: usize, end: usize, max_len: usize) -> (String, usize) {
+        let logs = self.logs.lock().unwrap();
+        let start = offset.max(logs.start());
+        let text = logs.slice(start, end);
+
+        let mut len = text.len().min(max_len);
+        while !text.is_char_boundary(len) {
+            len -= 1;
+        }
+        (text[..len].to_string(), start + len)
+    }
+
+    /// Drops the output older than the log retention of the service, if it has one, and the
+    /// output past the most recent `MAX_LOG_BYTES`.
+    pub fn prune_logs(&self) {
+        let retention = self.log_retention_secs.map(Duration::from_secs);
+        let now = self.runtime.lock().unwrap().clock.wall();
+        self.logs
+            .lock()
+            .unwrap()
+            .prune(retention, MAX_LOG_BYTES, now);
+    }
+
+    /// Returns when the oldest output still kept was written.
+    pub fn oldest_log(&self) -> Option<SystemTime> {
+        self.logs.lock().unwrap().oldest()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use super::super::clock::{Clock, TestClock};
+    use super::super::events::EventBus;
+    use super::super::scratch::scratch_dir;
+    use super::*;
+
+    const INTERVAL_SECS: u64 = 60;
+
+    fn timer(clock: &TestClock) -> Service {
+        let service = Service::new(
+            env::temp_dir().to_string_lossy().to_string(),
+            HashMap::new(),
+            None,
+            ServiceKind::Timer {
+                command: vec!["true".to_string()],
+                interval_secs: INTERVAL_SECS,
+            },
+        );
+        service.attach_clock(clock.shared());
+        service
+    }
+
+    fn last_run(service: &Service) -> Option<SystemTime> {
+        service.timer_schedule().unwrap().0
+    }
+
+    fn next_run_in(service: &Service) -> Duration {
+        service.timer_schedule().unwrap().1.unwrap()
+    }
+
+    // Keeps asking the timer to run, as the scheduler would, until it did. Only the previous
+    // run still going holds it back, and that one exits right away.
+    fn run_when_idle(service: &Service) {
+        let before = last_run(service);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while last_run(service) == before {
+            assert!(Instant::now() < deadline, "the timer didn't run");
+            assert!(service.run_timer().is_ok());
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn timer_runs_missed_while_stalled_are_coalesced() {
+        let clock = TestClock::new();
+        let service = timer(&clock);
+        assert!(service.start().is_ok());
+
+        run_when_idle(&service);
+        assert_eq!(last_run(&service), Some(clock.wall()));
+        assert_eq!(next_run_in(&service), Duration::from_secs(INTERVAL_SECS));
+
+        clock.advance(Duration::from_secs(INTERVAL_SECS * 10 + 30));
+        assert_eq!(next_run_in(&service), Duration::ZERO);
+        run_when_idle(&service);
+        assert_eq!(last_run(&service), Some(clock.wall()));
+        assert_eq!(next_run_in(&service), Duration::from_secs(INTERVAL_SECS));
+
+        // Nothing is made up for, however many times the timer gets asked.
+        thread::sleep(Duration::from_millis(100));
+        for _ in 0..3 {
+            assert!(service.run_timer().is_ok());
+        }
+        assert_eq!(last_run(&service), Some(clock.wall()));
+    }
+
+    #[test]
+    fn timer_schedule_ignores_wall_clock_jumps() {
+        let clock = TestClock::new();
+        let service = timer(&clock);
+        assert!(service.start().is_ok());
+        run_when_idle(&service);
+        let ran_at = last_run(&service);
+
+        // Like a resume after a day of suspend, or an NTP correction back by an hour.
+        for secs in [24 * 60 * 60, -25 * 60 * 60] {
+            clock.shift_wall(secs);
+            thread::sleep(Duration::from_millis(50));
+            assert!(service.run_timer().is_ok());
+            assert_eq!(last_run(&service), ran_at);
+            assert_eq!(next_run_in(&service), Duration::from_secs(INTERVAL_SECS));
+        }
+
+        clock.advance(Duration::from_secs(INTERVAL_SECS));
+        run_when_idle(&service);
+    }
+
+    fn handover(timer_last_run: SystemTime) -> ServiceHandover {
+        ServiceHandover {
+            state: ServiceState::Running,
+            ready: false,
+            child: None,
+            pidfile_process: None,
+            last_exit: None,
+            timer_last_run: Some(timer_last_run),
+            logs: String::new(),
+            log_line_times: Vec::new(),
+            start_count: 1,
+            runs: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn adopted_timer_carries_on_with_its_schedule() {
+        let clock = TestClock::new();
+        let cases = [
+            // Ran a little before the daemon was replaced.
+            (clock.wall() - Duration::from_secs(20), INTERVAL_SECS - 20),
+            // The wall clock went back since, so the run looks like it is still to come.
+            (clock.wall() + Duration::from_secs(3600), INTERVAL_SECS),
+            // The machine was suspended for a day in between.
+            (clock.wall() - Duration::from_secs(24 * 60 * 60), 0),
+        ];
+
+        for (timer_last_run, next_run_in_secs) in cases {
+            let service = timer(&clock);
+            service.adopt(handover(timer_last_run));
+            assert_eq!(last_run(&service), Some(timer_last_run));
+            assert_eq!(next_run_in(&service), Duration::from_secs(next_run_in_secs));
+        }
+    }
+
+    #[test]
+    fn health_checks_are_due_by_the_monotonic_clock_alone() {
+        let clock = TestClock::new();
+        let mut service = Service::new(
+            env::temp_dir().to_string_lossy().to_string(),
+            HashMap::new(),
+            None,
+            ServiceKind::Synchronous {
+                command: vec!["sleep".to_string(), "1000".to_string()],
+            },
+        );
+        service.health_check = Some(vec!["true".to_string()]);
+        service.health_check_interval_secs = Some(10);
+        service.attach_clock(clock.shared());
+        assert!(service.start().is_ok());
+
+        // Starting counts as the first check.
+        assert!(!service.begin_health_check());
+        clock.shift_wall(24 * 60 * 60);
+        assert!(!service.begin_health_check());
+
+        clock.advance(Duration::from_secs(10));
+        assert!(service.begin_health_check());
+        service.run_health_check();
+        assert!(!service.begin_health_check());
+
+        assert!(service.stop().is_ok());
+    }
+
+    /// Output that arrives in the chunks it was given, each split further if it doesn't fit in
+    /// what the reader asks for.
+    struct Chunks(VecDeque<Vec<u8>>);
+
+    impl Read for Chunks {
+        fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+            let Some(mut chunk) = self.0.pop_front() else {
+                return Ok(0);
+            };
+            let length = chunk.len().min(buffer.len());
+            buffer[..length].copy_from_slice(&chunk[..length]);
+            if length < chunk.len() {
+                self.0.push_front(chunk.split_off(length));
+            }
+            Ok(length)
+        }
+    }
+
+    /// Keeps every write apart, to tell how the output was batched.
+    #[derive(Default)]
+    struct Writes(Vec<String>);
+
+    impl fmt::Write for Writes {
+        fn write_str(&mut self, s: &str) -> fmt::Result {
+            self.0.push(s.to_string());
+            Ok(())
+        }
+    }
+
+    /// Reads the chunks through a log reader, returning what it wrote once the pipe closed.
+    fn read_logs(chunks: &[&[u8]]) -> Vec<String> {
+        let output = Arc::new(Mutex::new(Writes::default()));
+        let chunks = Chunks(chunks.iter().map(|chunk| chunk.to_vec()).collect());
+        spawn_log_reader(chunks, output.clone());
+
+        // The reader lets go of the output when it's done.
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while Arc::strong_count(&output) > 1 {
+            assert!(Instant::now() < deadline, "the log reader didn't finish");
+            thread::sleep(Duration::from_millis(10));
+        }
+        Arc::into_inner(output).unwrap().into_inner().unwrap().0
+    }
+
+    #[test]
+    fn output_is_written_a_line_at_a_time_or_more() {
+        assert_eq!(
+            read_logs(&[b"one\ntw", b"o\nthr", b"ee\nfour\nfi", b"ve"]),
+            ["one\n", "two\n", "three\nfour\n", "five"]
+        );
+    }
+
+    #[test]
+    fn characters_split_between_reads_are_kept_whole() {
+        assert_eq!(
+            read_logs(&[b"caf\xC3", b"\xA9 \xE2\x82", b"\xAC\n"]),
+            ["caf\u{e9} \u{20ac}\n"]
+        );
+        assert_eq!(read_logs(&[b"bad \xFF byte\n"]), ["bad \u{fffd} byte\n"]);
+    }
+
+    #[test]
+    fn lines_filling_the_buffer_are_cut_between_characters() {
+        let mut line = vec![b'a'; LOG_READ_BUFFER_SIZE - 1];
+        line.extend_from_slice("\u{e9}\n".as_bytes());
+        assert_eq!(
+            read_logs(&[&line]),
+            ["a".repeat(LOG_READ_BUFFER_SIZE - 1), "\u{e9}\n".to_string()]
+        );
+    }
+
+    /// An asynchronous service running from a directory of its own, whose commands time out
+    /// after a second.
+    fn asynchronous(test: &str, start_command: &str, stop_command: &str) -> Service {
+        let mut service = Service::new(
+            scratch_dir("service", test).to_string_lossy().to_string(),
+            HashMap::new(),
+            None,
+            ServiceKind::Asynchronous {
+                start_command: vec![start_command.to_string()],
+                stop_command: vec![stop_command.to_string()],
+                stop_environment: None,
+                stop_working_directory: None,
+                pidfile: None,
+                command_timeout_secs: Some(1),
+            },
+        );
+        service.shell = true;
+        service
+    }
+
+    #[test]
+    fn start_commands_that_hang_are_killed_along_with_their_children() {
+        let service = asynchronous(
+            "start_commands_that_hang_are_killed_along_with_their_children",
+            "sleep 1000 & echo $! > sleeper.pid; wait",
+            "true",
+        );
+        let started_at = Instant::now();
+        assert!(matches!(
+            service.start(),
+            Err(ServiceError::CommandTimedOut {
+                command: "start",
+                secs: 1
+            })
+        ));
+        assert!(started_at.elapsed() < Duration::from_secs(5));
+        assert_eq!(service.state(), ServiceState::Failed);
+
+        let pid = fs::read_to_string(service.working_directory_path().join("sleeper.pid")).unwrap();
+        let sleeper = Process {
+            pid: unistd::Pid::from_raw(pid.trim().parse().unwrap()),
+            start_time: None,
+        };
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while sleeper.is_alive() {
+            assert!(
+                Instant::now() < deadline,
+                "`sleep 1000` outlived the start command"
+            );
+            thread::sleep(Duration::from_millis(50));
+        }
+    }
+
+    #[test]
+    fn stop_commands_that_hang_leave_the_service_running() {
+        let service = asynchronous(
+            "stop_commands_that_hang_leave_the_service_running",
+            "true",
+            "sleep 1000",
+        );
+        assert!(service.start().is_ok());
+        assert_eq!(service.state(), ServiceState::Running);
+
+        assert!(matches!(
+            service.stop(),
+            Err(ServiceError::CommandTimedOut {
+                command: "stop",
+                secs: 1
+            })
+        ));
+        assert_eq!(service.state(), ServiceState::Running);
+    }
+
+    #[test]
+    fn reload_commands_run_like_stop_commands() {
+        let mut service = asynchronous("reload_commands_run_like_stop_commands", "true", "true");
+        let dir = service.working_directory_path();
+        fs::create_dir(dir.join("stop")).unwrap();
+        service.environment = HashMap::from([("STAGE".to_string(), "start".to_string())]);
+        service.reload_command = Some(vec![
+            "sh".to_string(),
+            "-c".to_string(),
+            "printf '%s %s' \"$PWD\" \"$STAGE\" > reload.out".to_string(),
+        ]);
+        assert!(service.start().is_ok());
+
+        assert!(service.reload().is_ok());
+        assert_eq!(
+            fs::read_to_string(dir.join("reload.out")).unwrap(),
+            format!("{} start", dir.display())
+        );
+
+        let ServiceKind::Asynchronous {
+            stop_environment,
+            stop_working_directory,
+            ..
+        } = &mut service.kind
+        else {
+            unreachable!();
+        };
+        *stop_environment = Some(HashMap::from([("STAGE".to_string(), "stop".to_string())]));
+        *stop_working_directory = Some(dir.join("stop").to_string_lossy().to_string());
+        assert!(service.reload().is_ok());
+        assert_eq!(
+            fs::read_to_string(dir.join("stop/reload.out")).unwrap(),
+            format!("{} stop", dir.join("stop").display())
+        );
+    }
+
+    #[test]
+    fn status_queries_arent_held_up_by_a_stop_in_progress() {
+        // Takes two seconds to exit once told to stop.
+        let mut service = Service::new(
+            env::temp_dir().to_string_lossy().to_string(),
+            HashMap::new(),
+            None,
+            ServiceKind::Synchronous {
+                command: vec![
+                    "trap 'sleep 2; exit 0' TERM; while :; do sleep 0.1; done".to_string(),
+                ],
+            },
+        );
+        service.shell = true;
+        assert!(service.start().is_ok());
+
+        thread::scope(|scope| {
+            let stop = scope.spawn(|| service.stop().is_ok());
+            let deadline = Instant::now() + Duration::from_secs(5);
+            while !service.runtime.lock().unwrap().stopping {
+                assert!(Instant::now() < deadline, "the stop didn't begin");
+                thread::sleep(Duration::from_millis(10));
+            }
+            thread::sleep(Duration::from_millis(200));
+
+            for _ in 0..5 {
+                let asked_at = Instant::now();
+                assert!(service.is_running());
+                assert!(service.pid().is_some());
+                assert!(asked_at.elapsed() < Duration::from_millis(500));
+                thread::sleep(Duration::from_millis(100));
+            }
+            assert!(!stop.is_finished());
+            assert!(stop.join().unwrap());
+        });
+        assert_eq!(service.state(), ServiceState::Stopped);
+    }
+
+    fn environment(variables: &[(&str, &str)]) -> BTreeMap<String, String> {
+        variables
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn service_variables_take_precedence_over_the_daemons() {
+        let base = base_environment();
+        let mut service = Service::new(
+            env::temp_dir().to_string_lossy().to_string(),
+            HashMap::from([
+                ("PATH".to_string(), "/opt/web/bin".to_string()),
+                ("PORT".to_string(), "8080".to_string()),
+            ]),
+            None,
+            ServiceKind::Oneshot {
+                command: vec!["true".to_string()],
+            },
+        );
+
+        let environment = service.child_environment(&service.environment);
+        assert_eq!(environment["PATH"], "/opt/web/bin");
+        assert_eq!(environment["PORT"], "8080");
+        assert_eq!(
+            environment.len(),
+            base.len() + usize::from(!base.contains_key("PORT"))
+        );
+
+        // A clean environment only keeps a few of the daemon's variables under the service's.
+        service.clean_environment = true;
+        let environment = service.child_environment(&service.environment);
+        assert!(
+            environment
+                .keys()
+                .all(|key| CLEAN_ENVIRONMENT_BASE.contains(&key.as_str())
+                    || service.environment.contains_key(key))
+        );
+        assert_eq!(environment["PATH"], "/opt/web/bin");
+        assert_eq!(environment.get("HOME"), base.get("HOME"));
+    }
+
+    #[test]
+    fn processes_only_get_the_environment_they_are_given() {
+        let dir = scratch_dir(
+            "service",
+            "processes_only_get_the_environment_they_are_given",
+        );
+        // Any variable of the test process stands for one the daemon got after it started,
+        // which the environment services are given leaves out.
+        let (variable, _) = env::vars_os()
+            .filter_map(|(key, value)| Some((key.into_string().ok()?, value)))
+            .find(|(key, _)| key != "PATH")
+            .unwrap();
+        let environment = BTreeMap::from([("GREETING".to_string(), "hello".to_string())]);
+
+        let command = Command::start(
+            &[&format!(
+                "printf '[%s] [%s]' \"${variable}\" \"$GREETING\" > env.out"
+            )],
+            &dir.to_string_lossy(),
+            environment,
+            true,
+            None,
+            Arc::new(Mutex::new(Writes::default())),
+        )
+        .unwrap();
+        assert!(command.wait().unwrap().success());
+        assert_eq!(
+            fs::read_to_string(dir.join("env.out")).unwrap(),
+            "[] [hello]"
+        );
+    }
+
+    fn sleeper(working_directory: &Path, program: &str) -> Service {
+        Service::new(
+            working_directory.to_string_lossy().to_string(),
+            HashMap::new(),
+            None,
+            ServiceKind::Synchronous {
+                command: vec![program.to_string(), "1000".to_string()],
+            },
+        )
+    }
+
+    #[test]
+    fn a_missing_working_directory_is_told_apart_from_a_missing_executable() {
+        let dir = scratch_dir(
+            "service",
+            "a_missing_working_directory_is_told_apart_from_a_missing_executable",
+        );
+        let missing_dir = dir.join("nowhere");
+        let service = sleeper(&missing_dir, "sleep");
+        match service.start() {
+            Err(ServiceError::WorkingDirectoryNotFound { path }) => assert_eq!(path, missing_dir),
+            _ => panic!("the missing working directory wasn't reported"),
+        }
+        assert_eq!(service.state(), ServiceState::Failed);
+
+        let service = sleeper(&dir, "userserversd-no-such-program");
+        match service.start() {
+            Err(ServiceError::ExecutableNotFound {
+                program,
+                search_path,
+            }) => {
+                assert_eq!(program, "userserversd-no-such-program");
+                assert_eq!(search_path.as_ref(), base_environment().get("PATH"));
+            }
+            _ => panic!("the missing executable wasn't reported"),
+        }
+
+        // Paths aren't looked up, so there's no search path to report.
+        let program = dir.join("bin/server").to_string_lossy().to_string();
+        match sleeper(&dir, &program).start() {
+            Err(ServiceError::ExecutableNotFound {
+                program: missing,
+                search_path: None,
+            }) => assert_eq!(missing, program),
+            _ => panic!("the missing executable wasn't reported"),
+        }
+    }
+
+    #[test]
+    fn working_directories_are_created_on_start_when_asked_to() {
+        let dir = scratch_dir(
+            "service",
+            "working_directories_are_created_on_start_when_asked_to",
+        );
+        let mut service = sleeper(&dir.join("cache/${APP}"), "sleep");
+        service.environment = HashMap::from([("APP".to_string(), "web".to_string())]);
+        assert!(service.validate().is_err());
+
+        service.create_working_directory = true;
+        assert!(service.validate().is_ok());
+        assert!(service.start().is_ok());
+        assert!(dir.join("cache/web").is_dir());
+        assert!(service.stop().is_ok());
+
+        // It still has to be a directory if something is already there.
+        fs::write(dir.join("file"), "").unwrap();
+        let mut service = sleeper(&dir.join("file"), "sleep");
+        service.create_working_directory = true;
+        assert!(service.validate().is_err());
+        assert!(matches!(service.start(), Err(ServiceError::IOError(_))));
+    }
+
+    #[test]
+    fn services_run_in_sessions_of_their_own() {
+        let dir = scratch_dir("service", "services_run_in_sessions_of_their_own");
+        let mut service = sleeper(&dir, "sleep");
+        service.process_title = true;
+        service.attach_events(EventSender::new(
+            "web".to_string(),
+            Arc::new(EventBus::default()),
+        ));
+        assert!(service.start().is_ok());
+
+        // Signals to the daemon's process group, such as a Ctrl-C in its terminal, can't reach
+        // the service, which leads a session and a process group of its own.
+        let pid = unistd::Pid::from_raw(service.pid().unwrap() as i32);
+        assert_eq!(unistd::getsid(Some(pid)), Ok(pid));
+        assert_eq!(unistd::getpgid(Some(pid)), Ok(pid));
+        assert_ne!(unistd::getsid(None), Ok(pid));
+        assert_ne!(unistd::getpgrp(), pid);
+
+        let cmdline = fs::read(format!("/proc/{pid}/cmdline")).unwrap();
+        assert!(cmdline.starts_with(b"userserversd: web\0"));
+        assert!(service.stop().is_ok());
+    }
+
+    #[test]
+    fn variables_are_expanded_and_dollars_can_be_escaped() {
+        let environment = environment(&[("PORT", "8080"), ("NAME", "web"), ("EMPTY", "")]);
+        let cases = [
+            ("--port=${PORT}", "--port=8080"),
+            ("${NAME}-${PORT}.log", "web-8080.log"),
+            ("[${EMPTY}]", "[]"),
+            ("[${UNSET}]", "[]"),
+            ("$${PORT}", "${PORT}"),
+            ("$$${PORT}", "$${PORT}"),
+            ("$PORT and $1", "$PORT and $1"),
+            ("costs $5", "costs $5"),
+            ("${PORT", "${PORT"),
+            ("trailing $", "trailing $"),
+            ("${NAME}${NAME}", "webweb"),
+        ];
+        for (value, expanded) in cases {
+            assert_eq!(expand_variables(value, &environment), expanded, "{value}");
+        }
+    }
+
+    #[test]
+    fn only_a_leading_tilde_is_expanded_in_paths() {
+        let environment = environment(&[("HOME", "/home/me"), ("APP", "site")]);
+        let current = unistd::User::from_uid(unistd::getuid()).unwrap().unwrap();
+        let current_home = current.dir.to_string_lossy();
+        let cases = [
+            ("~".to_string(), "/home/me".to_string()),
+            (
+                "~/projects/${APP}".to_string(),
+                "/home/me/projects/site".to_string(),
+            ),
+            (
+                format!("~{}/srv", current.name),
+                format!("{current_home}/srv"),
+            ),
+            (
+                "~no-such-user-here/srv".to_string(),
+                "~no-such-user-here/srv".to_string(),
+            ),
+            ("/srv/~/app".to_string(), "/srv/~/app".to_string()),
+            ("./~".to_string(), "./~".to_string()),
+        ];
+        for (path, expanded) in cases {
+            assert_eq!(expand_path(&path, &environment), expanded, "{path}");
+        }
+
+        // Without a HOME to go by, the tilde is kept as it is.
+        assert_eq!(expand_path("~/app", &BTreeMap::new()), "~/app");
     }
 }
@@ -1,331 +1,3869 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::env;
-use std::fs::{self, File};
+use std::fmt;
+use std::fs;
 use std::io;
-use std::io::Write;
+use std::os::fd::RawFd;
+use std::os::unix::net::UnixStream;
 use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock, RwLock, RwLockReadGuard, mpsc};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
 
 use nix::unistd;
 
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+
 use super::ipc;
-use super::ipc::response::{ResponseKind, ResponseStatus};
+use super::ipc::response::{
+    ConfigReload, DaemonEnvironment, DaemonInfo, ResponseKind, ResponseStatus, ServiceList,
+    ServiceStatus, ServiceSummary, SnapshotInfo, SnapshotRestore,
+};
 
-use super::service::{Service, ServiceKind};
+use super::clock::SharedClock;
+use super::events::{Event, EventBus, EventCause, EventSender};
+use super::history::History;
+use super::log;
+use super::service::{Service, ServiceError, ServiceHandover, ServiceKind, StopOutcome};
+use super::user_context::UserContext;
 
 fn service_to_ipc_service(service: &Service) -> ipc::Service {
-    ipc::Service {
-        working_directory: service.working_directory.clone(),
-        environment: service.environment.clone(),
-        group: service.group.clone(),
-        kind: match &service.kind {
-            ServiceKind::Synchronous { command } => ipc::ServiceKind::Synchronous {
-                command: command.clone(),
-            },
+    let kind = match &service.kind {
+        ServiceKind::Synchronous { command } => ipc::ServiceKind::Synchronous {
+            command: command.clone(),
+        },
+
+        ServiceKind::Asynchronous {
+            start_command,
+            stop_command,
+            stop_environment,
+            stop_working_directory,
+            pidfile,
+            command_timeout_secs,
+        } => ipc::ServiceKind::Asynchronous {
+            start_command: start_command.clone(),
+            stop_command: stop_command.clone(),
+            stop_environment: stop_environment.clone(),
+            stop_working_directory: stop_working_directory.clone(),
+            pidfile: pidfile.clone(),
+            command_timeout_secs: *command_timeout_secs,
+        },
+
+        ServiceKind::Oneshot { command } => ipc::ServiceKind::Oneshot {
+            command: command.clone(),
+        },
+
+        ServiceKind::Timer {
+            command,
+            interval_secs,
+        } => ipc::ServiceKind::Timer {
+            command: command.clone(),
+            interval_secs: *interval_secs,
+        },
+    };
+
+    let mut converted = ipc::Service::new(service.working_directory.clone(), kind);
+    converted.environment = service.environment.clone();
+    converted.group = service.group();
+    converted.ports = service.ports.clone();
+    converted.health_check = service.health_check.clone();
+    converted.health_check_interval_secs = service.health_check_interval_secs;
+    converted.ready_command = service.ready_command.clone();
+    converted.ready_tcp_port = service.ready_tcp_port;
+    converted.watch_paths = service.watch_paths.clone();
+    converted.watch_disabled = service.watch_disabled;
+    converted.depends_on = service.depends_on.clone();
+    converted.log_retention_secs = service.log_retention_secs;
+    converted.pre_start = service.pre_start.clone();
+    converted.post_stop = service.post_stop.clone();
+    converted.on_failure = service.on_failure.clone();
+    converted.clean_environment = service.clean_environment;
+    converted.shell = service.shell;
+    converted.description = service.description.clone();
+    converted.labels = service.labels.clone();
+    converted.reload_signal = service.reload_signal.clone();
+    converted.reload_command = service.reload_command.clone();
+    converted.create_working_directory = service.create_working_directory;
+    converted.process_title = service.process_title;
+    converted
+}
 
-            ServiceKind::Asynchronous {
+fn ipc_service_to_service(service: ipc::Service) -> Service {
+    let mut converted = Service::new(
+        service.working_directory,
+        service.environment,
+        service.group,
+        match service.kind {
+            ipc::ServiceKind::Synchronous { command } => ServiceKind::Synchronous { command },
+
+            ipc::ServiceKind::Asynchronous {
+                start_command,
+                stop_command,
+                stop_environment,
+                stop_working_directory,
+                pidfile,
+                command_timeout_secs,
+            } => ServiceKind::Asynchronous {
                 start_command,
                 stop_command,
-            } => ipc::ServiceKind::Asynchronous {
-                start_command: start_command.clone(),
-                stop_command: stop_command.clone(),
+                stop_environment,
+                stop_working_directory,
+                pidfile,
+                command_timeout_secs,
+            },
+
+            ipc::ServiceKind::Oneshot { command } => ServiceKind::Oneshot { command },
+
+            ipc::ServiceKind::Timer {
+                command,
+                interval_secs,
+            } => ServiceKind::Timer {
+                command,
+                interval_secs,
             },
+
+            // The daemon is built along with the library, so it knows every kind there is.
+            kind => unreachable!("unknown service kind {kind:?}"),
         },
-    }
+    );
+    converted.ports = service.ports;
+    converted.health_check = service.health_check;
+    converted.health_check_interval_secs = service.health_check_interval_secs;
+    converted.ready_command = service.ready_command;
+    converted.ready_tcp_port = service.ready_tcp_port;
+    converted.watch_paths = service.watch_paths;
+    converted.watch_disabled = service.watch_disabled;
+    converted.depends_on = service.depends_on;
+    converted.log_retention_secs = service.log_retention_secs;
+    converted.pre_start = service.pre_start;
+    converted.post_stop = service.post_stop;
+    converted.on_failure = service.on_failure;
+    converted.clean_environment = service.clean_environment;
+    converted.shell = service.shell;
+    converted.description = service.description;
+    converted.labels = service.labels;
+    converted.reload_signal = service.reload_signal;
+    converted.reload_command = service.reload_command;
+    converted.create_working_directory = service.create_working_directory;
+    converted.process_title = service.process_title;
+    converted
+}
+
+/// What is known about a group besides its services, which are what make it exist.
+#[derive(Serialize, Deserialize, Clone)]
+struct GroupMetadata {
+    description: String,
+}
+
+/// The version of the configuration file this daemon writes. Version 0 is the bare service map
+/// of the first releases, and version 1 keeps the services next to the targets and groups.
+const CONFIG_VERSION: u64 = 1;
+
+/// Upgrades a configuration file to the next version, indexed by the version it upgrades from.
+const MIGRATIONS: [fn(Value) -> Value; CONFIG_VERSION as usize] = [migrate_from_version_0];
+
+fn migrate_from_version_0(services: Value) -> Value {
+    json!({ "version": 1, "services": services })
+}
+
+#[derive(Default)]
+struct Config {
+    services: HashMap<String, Service>,
+    // The services that couldn't be read, kept as they are so that saving doesn't lose them.
+    unloaded_services: HashMap<String, Value>,
+    targets: HashMap<String, Vec<String>>,
+    groups: HashMap<String, GroupMetadata>,
+}
+
+/// A configuration file of the current version, whose services are read one by one so that a
+/// broken one doesn't keep the others from loading.
+#[derive(Deserialize)]
+struct RawConfig {
+    services: serde_json::Map<String, Value>,
+    #[serde(default)]
+    targets: HashMap<String, Vec<String>>,
+    #[serde(default)]
+    groups: HashMap<String, GroupMetadata>,
+}
+
+#[derive(Serialize)]
+struct ConfigRef<'a> {
+    version: u64,
+    services: BTreeMap<&'a String, ServiceEntry<'a>>,
+    targets: BTreeMap<&'a String, &'a Vec<String>>,
+    groups: BTreeMap<&'a String, &'a GroupMetadata>,
+}
+
+/// The format the configuration file and the drop-in files are written in.
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+pub enum ConfigFormat {
+    #[default]
+    Json,
+    Toml,
 }
 
-fn get_config_file_path() -> Option<String> {
-    if let Ok(config_dir) = env::var("XDG_CONFIG_HOME") {
-        return Some(format!("{config_dir}/userserversd_services.json"));
+impl ConfigFormat {
+    fn extension(self) -> &'static str {
+        match self {
+            Self::Json => ".json",
+            Self::Toml => ".toml",
+        }
     }
 
-    let home = match env::var("HOME") {
-        Ok(path) => path,
-        Err(_) => {
-            let uid = unistd::getuid();
-            match unistd::User::from_uid(uid) {
-                Ok(Some(user)) => {
-                    let home = format!("/home/{}", user.name);
-                    if !Path::new(&home).exists() {
-                        return None;
-                    }
-                    home
-                }
-                _ => return None,
-            }
+    /// Pretty-prints a value, without a trailing newline.
+    fn serialize<T: Serialize>(self, value: &T) -> Result<String, String> {
+        match self {
+            Self::Json => serde_json::to_string_pretty(value).map_err(|err| err.to_string()),
+            Self::Toml => toml::to_string_pretty(value)
+                .map(|string| string.trim_end().to_string())
+                .map_err(|err| err.to_string()),
         }
-    };
+    }
 
-    let config_file = if Path::new(&format!("{home}/.userserversd_services.json")).exists()
-        || !Path::new(&format!("{home}/.config")).exists()
-    {
-        format!("{home}/.userserversd_services.json")
-    } else {
-        format!("{home}/.config/userserversd_services.json")
-    };
+    fn deserialize<T: DeserializeOwned>(self, contents: &str) -> Result<T, String> {
+        match self {
+            Self::Json => serde_json::from_str(contents).map_err(|err| err.to_string()),
+            Self::Toml => toml::from_str(contents).map_err(|err| err.message().to_string()),
+        }
+    }
+}
 
-    Some(config_file)
+/// The configuration file in the provided format. The TOML one sits next to where the JSON one
+/// would be.
+pub fn config_file_path(context: &UserContext, format: ConfigFormat) -> Option<String> {
+    let path = context.config_file_path()?;
+    Some(match format {
+        ConfigFormat::Json => path,
+        ConfigFormat::Toml => format!("{}.toml", path.strip_suffix(".json").unwrap_or(&path)),
+    })
 }
 
-pub struct ServiceManager {
-    services: HashMap<String, Service>,
+#[derive(Serialize)]
+#[serde(untagged)]
+enum ServiceEntry<'a> {
+    Loaded(&'a Service),
+    Unloaded(&'a Value),
 }
 
-impl ServiceManager {
-    pub fn new() -> Self {
-        let mut selff = Self {
-            services: HashMap::<String, Service>::new(),
-        };
+const MAX_SERVICE_NAME_LENGTH: usize = 128;
 
-        let config_file_path = match get_config_file_path() {
-            Some(path) => path,
-            None => {
-                println!(
-                    "Failed to get path for configuration file. Service list will NOT be loaded!"
-                );
-                return selff;
-            }
-        };
+fn check_service_name(name: &str) -> Result<(), String> {
+    if name.is_empty() {
+        return Err("the service name must not be empty".to_string());
+    }
+    if name.len() > MAX_SERVICE_NAME_LENGTH {
+        return Err(format!(
+            "the service name must not be longer than {MAX_SERVICE_NAME_LENGTH} bytes"
+        ));
+    }
+    if name.contains(['\n', '\0']) {
+        return Err("the service name must not contain newlines or NUL characters".to_string());
+    }
+    Ok(())
+}
 
-        let config_file_contents = match fs::read_to_string(&config_file_path) {
-            Ok(contents) => contents,
+/// Why a configuration file can't be loaded.
+enum ConfigError {
+    /// The file was written by a newer daemon, so it must be left as it is.
+    TooNew(String),
+    Invalid(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::TooNew(message) | Self::Invalid(message) => write!(fmt, "{message}"),
+        }
+    }
+}
+
+/// Tells which version a configuration file is of. Files of version 1 were written without a
+/// `version` key at first, so a file without one is of version 1 if it is shaped like it. Names
+/// of services can be anything, so a bare service map of version 0 is told apart by its values,
+/// which are service definitions, rather than by its keys.
+fn config_version(config: &Value) -> Result<u64, String> {
+    let Some(config) = config.as_object() else {
+        return Err("the configuration must be a map".to_string());
+    };
+    let is_service = |value: &Value| value.get("working_directory").is_some_and(Value::is_string);
+    if config.values().any(is_service) {
+        return Ok(0);
+    }
+    if let Some(version) = config.get("version") {
+        return version
+            .as_u64()
+            .ok_or_else(|| format!("the version must be a non-negative integer, not {version}"));
+    }
+
+    let unmarked_version_1 = config.get("services").is_some_and(Value::is_object)
+        && config
+            .keys()
+            .all(|key| ["services", "targets", "groups"].contains(&key.as_str()));
+    Ok(if unmarked_version_1 { 1 } else { 0 })
+}
+
+/// Reads a configuration file of any version up to the current one, upgrading it along the way.
+/// The services that can't be read are left out, along with why.
+fn parse_config(
+    contents: &str,
+    format: ConfigFormat,
+    skipped: &mut Vec<String>,
+) -> Result<Config, ConfigError> {
+    let mut config = format
+        .deserialize::<Value>(contents)
+        .map_err(ConfigError::Invalid)?;
+    let version = config_version(&config).map_err(ConfigError::Invalid)?;
+    if version > CONFIG_VERSION {
+        return Err(ConfigError::TooNew(format!(
+            "it uses version {version} of the configuration format, but this userserversd only knows up to version {CONFIG_VERSION}; use a newer userserversd"
+        )));
+    }
+    for migrate in &MIGRATIONS[version as usize..] {
+        config = migrate(config);
+    }
+
+    let config = serde_json::from_value::<RawConfig>(config)
+        .map_err(|err| ConfigError::Invalid(err.to_string()))?;
+    let mut services = HashMap::new();
+    let mut unloaded_services = HashMap::new();
+    let mut newly_skipped = Vec::new();
+    for (name, entry) in config.services {
+        let service = serde_json::from_value::<Service>(entry.clone())
+            .map_err(|err| err.to_string())
+            .and_then(|service| {
+                check_service_name(&name)?;
+                service.check_definition()?;
+                Ok(service)
+            });
+        match service {
+            Ok(service) => {
+                services.insert(name, service);
+            }
             Err(err) => {
-                if err.kind() != io::ErrorKind::NotFound {
-                    println!(
-                        "Failed to read configuration file for the following reason: {err}. Service list will NOT be loaded!"
-                    );
-                }
-                return selff;
+                warn!(
+                    service = name;
+                    "Skipped service `{}` from the configuration file: {err}",
+                    name.escape_debug()
+                );
+                newly_skipped.push(format!(
+                    "skipped the service `{}` from the configuration file: {err}",
+                    name.escape_debug()
+                ));
+                unloaded_services.insert(name, entry);
             }
-        };
+        }
+    }
+    newly_skipped.sort();
+    skipped.extend(newly_skipped);
 
-        match serde_json::from_str(&config_file_contents) {
-            Ok(services) => selff.services = services,
-            Err(err) => println!(
-                "Failed to deserialize configuration file for the following reason: {err}. Service list will NOT be loaded!"
-            ),
+    Ok(Config {
+        services,
+        unloaded_services,
+        targets: config.targets,
+        groups: config.groups,
+    })
+}
+
+/// A snapshot, which is a configuration file along with when it was taken and which services
+/// were running, so that older ones get upgraded the same way.
+#[derive(Serialize)]
+struct SnapshotRef<'a> {
+    #[serde(flatten)]
+    config: ConfigRef<'a>,
+    created: String,
+    running: Vec<&'a String>,
+}
+
+/// What listing snapshots reads of them, without reading the services themselves.
+#[derive(Deserialize)]
+struct SnapshotMetadata {
+    #[serde(default)]
+    created: String,
+    #[serde(default)]
+    running: Vec<String>,
+    #[serde(default)]
+    services: serde_json::Map<String, Value>,
+}
+
+/// The file of a snapshot. Names can't start with `.` or contain `/`, so that each makes a single
+/// file in the snapshot directory.
+fn snapshot_path(context: &UserContext, name: &str) -> Result<String, ResponseStatus> {
+    if name.is_empty() || name.starts_with('.') || name.contains(['/', '\n', '\0']) {
+        return Err(ResponseStatus::ValidationFailed {
+            message: "snapshot names must not be empty, start with `.` or contain `/`".to_string(),
+        });
+    }
+    let snapshots_dir =
+        context
+            .snapshots_dir_path()
+            .ok_or_else(|| ResponseStatus::SnapshotFailed {
+                message: "there is no state directory to keep snapshots in".to_string(),
+            })?;
+    Ok(format!("{snapshots_dir}/{name}.json"))
+}
+
+/// Names the drop-in file of a service. `%`, `/` and a leading `.` are percent-encoded, so that
+/// every service name makes a single file that isn't hidden.
+fn drop_in_file_name(name: &str, format: ConfigFormat) -> String {
+    let mut file_name = String::new();
+    for (i, c) in name.chars().enumerate() {
+        match c {
+            '%' => file_name.push_str("%25"),
+            '/' => file_name.push_str("%2F"),
+            '.' if i == 0 => file_name.push_str("%2E"),
+            c => file_name.push(c),
         }
+    }
+    file_name + format.extension()
+}
+
+/// The service a drop-in file defines, or `None` if the file isn't a drop-in, such as a hidden
+/// file or an editor backup.
+fn service_name_of_drop_in(file_name: &str, format: ConfigFormat) -> Option<String> {
+    let mut rest = file_name.strip_suffix(format.extension())?;
+    if rest.is_empty() || rest.starts_with('.') {
+        return None;
+    }
 
-        println!("Starting services...");
+    let mut name = String::new();
+    while let Some(i) = rest.find('%') {
+        name.push_str(&rest[..i]);
+        name.push(match rest.get(i + 1..i + 3)? {
+            "25" => '%',
+            "2F" | "2f" => '/',
+            "2E" | "2e" => '.',
+            _ => return None,
+        });
+        rest = &rest[i + 3..];
+    }
+    name.push_str(rest);
+    Some(name)
+}
 
-        for (service_name, service) in &mut selff.services {
-            println!("Starting service `{service_name}`");
-            if let Err(err) = service.start() {
-                println!("Failed to start service `{service_name}`: {err}");
+/// What was read from the configuration file and, with the drop-in layout, from the drop-in
+/// directory.
+struct LoadedConfig {
+    config: Config,
+    // How many of the services are defined in the configuration file itself.
+    in_file: usize,
+    drop_in_files: HashSet<String>,
+}
+
+/// Reads the configuration file and, with the drop-in layout, merges the services of the drop-in
+/// directory in, which win over the services of the same name in the file. Services that can't
+/// be loaded are left out, along with a warning.
+fn read_config(
+    context: &UserContext,
+    format: ConfigFormat,
+    drop_ins: bool,
+    warnings: &mut Vec<String>,
+) -> Result<LoadedConfig, ConfigError> {
+    let config_file_path = config_file_path(context, format).ok_or_else(|| {
+        ConfigError::Invalid("there is no path for the configuration file".to_string())
+    })?;
+    let config = match fs::read_to_string(&config_file_path) {
+        Ok(contents) => parse_config(&contents, format, warnings).map_err(|err| match err {
+            ConfigError::TooNew(err) => ConfigError::TooNew(format!("`{config_file_path}`: {err}")),
+            ConfigError::Invalid(err) => {
+                ConfigError::Invalid(format!("failed to parse `{config_file_path}`: {err}"))
             }
+        })?,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Config::default(),
+        Err(err) => {
+            return Err(ConfigError::Invalid(format!(
+                "failed to read `{config_file_path}`: {err}"
+            )));
         }
+    };
 
-        return selff;
+    let mut loaded = LoadedConfig {
+        in_file: config.services.len(),
+        config,
+        drop_in_files: HashSet::new(),
+    };
+    if !drop_ins {
+        return Ok(loaded);
     }
+    let Some(services_dir) = context.services_dir_path() else {
+        return Err(ConfigError::Invalid(
+            "there is no path for the drop-in directory".to_string(),
+        ));
+    };
+    let entries = match fs::read_dir(&services_dir) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(loaded),
+        Err(err) => {
+            return Err(ConfigError::Invalid(format!(
+                "failed to read `{services_dir}`: {err}"
+            )));
+        }
+    };
 
-    fn flush(&self) {
-        let config_file_path = match get_config_file_path() {
-            Some(path) => path,
-            None => {
-                println!(
-                    "Failed to get path for configuration file. Service list will NOT be saved!"
-                );
-                return;
-            }
+    let mut skipped = Vec::new();
+    let mut collisions = Vec::new();
+    for entry in entries {
+        let entry = entry.map_err(|err| {
+            ConfigError::Invalid(format!("failed to read `{services_dir}`: {err}"))
+        })?;
+        let file_name = entry.file_name().to_string_lossy().into_owned();
+        let Some(name) = service_name_of_drop_in(&file_name, format) else {
+            continue;
         };
 
-        let mut config_file = match File::create(config_file_path) {
-            Ok(file) => file,
+        let service = fs::read_to_string(entry.path())
+            .map_err(|err| err.to_string())
+            .and_then(|contents| format.deserialize::<Service>(&contents))
+            .and_then(|service| {
+                check_service_name(&name)?;
+                service.check_definition()?;
+                Ok(service)
+            });
+        let service = match service {
+            Ok(service) => service,
             Err(err) => {
-                println!("Failed to create configuration file: {err}");
-                return;
+                warn!("Skipped the drop-in file `{services_dir}/{file_name}`: {err}");
+                skipped.push(format!(
+                    "skipped the drop-in file `{services_dir}/{file_name}`: {err}"
+                ));
+                continue;
             }
         };
 
-        match serde_json::to_string(&self.services) {
-            Ok(string) => {
-                if let Err(err) = write!(config_file, "{string}") {
-                    println!(
-                        "Failed to write configuration file for the following reason: {err}. Service list will NOT be saved!"
-                    );
-                    return;
-                }
-            }
-            Err(err) => {
-                println!(
-                    "Failed to serialize configuration file for the following reason: {err}. Service list will NOT be saved!"
-                );
-                return;
-            }
+        loaded.drop_in_files.insert(file_name);
+        if loaded
+            .config
+            .services
+            .insert(name.clone(), service)
+            .is_some()
+        {
+            warn!(
+                service = name;
+                "The service `{}` is defined both in the configuration file and in `{services_dir}`, the drop-in file is used",
+                name.escape_debug()
+            );
+            collisions.push(format!(
+                "the service `{}` is defined both in the configuration file and in `{services_dir}`, the drop-in file is used",
+                name.escape_debug()
+            ));
         }
     }
+    skipped.sort();
+    collisions.sort();
+    warnings.extend(skipped);
+    warnings.extend(collisions);
+    Ok(loaded)
+}
 
-    fn get_service_mut(&mut self, name: &String) -> Result<&mut Service, ResponseStatus> {
-        match self.services.get_mut(name) {
-            Some(service) => Ok(service),
-            None => Err(ResponseStatus::ServiceDoesNotExist),
-        }
+// Definitions are compared through their serialized form, which is what the configuration file
+// holds.
+fn same_definition(a: &Service, b: &Service) -> bool {
+    match (serde_json::to_value(a), serde_json::to_value(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
+
+pub struct DaemonState {
+    pub started_at: Instant,
+    pub socket_path: String,
+    pub watch_interval: Duration,
+    pub watch_mechanism: &'static str,
+    pub max_clients: usize,
+    pub active_clients: AtomicUsize,
+    pub listener_fd: OnceLock<RawFd>,
+    /// Whether the socket was passed by systemd, which then binds and removes it instead of the
+    /// daemon.
+    pub socket_activated: bool,
+    /// Held for reading while a command runs, so that a restart can wait for them to finish.
+    pub commands: RwLock<()>,
+    pub clients: Mutex<HashMap<RawFd, UnixStream>>,
+    pub restarting: AtomicBool,
+    pub history: History,
+}
+
+/// Variables that tell whether the daemon was started from inside a user session.
+fn is_diagnostic_variable(name: &str) -> bool {
+    matches!(
+        name,
+        "PATH"
+            | "HOME"
+            | "USER"
+            | "SHELL"
+            | "DISPLAY"
+            | "WAYLAND_DISPLAY"
+            | "DBUS_SESSION_BUS_ADDRESS"
+    ) || name.starts_with("XDG_")
+        || name.starts_with("USERSERVERSD_")
+}
+
+pub(crate) fn is_secret_variable(name: &str) -> bool {
+    let name = name.to_uppercase();
+    [
+        "TOKEN",
+        "SECRET",
+        "PASSWORD",
+        "PASSWD",
+        "KEY",
+        "CREDENTIAL",
+        "AUTH",
+        "COOKIE",
+    ]
+    .iter()
+    .any(|word| name.contains(word))
+}
+
+fn stop_warnings(name: &str, outcome: StopOutcome, warnings: &mut Vec<String>) {
+    if !outcome.survivors.is_empty() {
+        warnings.push(format!(
+            "{} process(es) started by the service `{name}` are still running after it stopped: {}",
+            outcome.survivors.len(),
+            outcome
+                .survivors
+                .iter()
+                .map(|pid| pid.to_string())
+                .collect::<Vec<String>>()
+                .join(", ")
+        ));
+    }
+    if let Some(err) = outcome.post_stop_error {
+        warnings.push(format!("the service `{name}` stopped, but its {err}"));
     }
+}
+
+type ServiceDefinitions = HashMap<String, Arc<ipc::Service>>;
+
+/// The definitions last sent to clients, along with the services they were converted from.
+type ServiceListCache = HashMap<String, (Arc<Service>, Arc<ipc::Service>)>;
+
+#[derive(Default)]
+struct Registry {
+    services: HashMap<String, Arc<Service>>,
+    targets: HashMap<String, Vec<String>>,
+    groups: HashMap<String, GroupMetadata>,
+    format: ConfigFormat,
+    // What the configuration file holds for the services that couldn't be read.
+    unloaded_services: HashMap<String, Value>,
+    // Whether the configuration changed since it was last written.
+    dirty: bool,
+    // The drop-in files the daemon read or wrote, if it uses the drop-in layout. Only those are
+    // removed along with their service, so that files that failed to load aren't lost.
+    drop_in_files: Option<HashSet<String>>,
+    // The services that should be running, as last started or stopped through a command. The
+    // next daemon starts them again.
+    running: HashSet<String>,
+    // Whether `running` changed since it was last written.
+    running_dirty: bool,
+}
 
-    fn get_service(&self, name: &String) -> Result<&Service, ResponseStatus> {
+impl Registry {
+    fn get_service(&self, name: &String) -> Result<Arc<Service>, ResponseStatus> {
         match self.services.get(name) {
-            Some(service) => Ok(service),
+            Some(service) => Ok(service.clone()),
             None => Err(ResponseStatus::ServiceDoesNotExist),
         }
     }
 
-    pub fn add_synchronous(
-        &mut self,
-
-        name: String,
-        working_directory: String,
-        environment: HashMap<String, String>,
-        group: Option<String>,
+    fn get_target(&self, name: &String) -> Result<Vec<String>, ResponseStatus> {
+        match self.targets.get(name) {
+            Some(target) => Ok(target.clone()),
+            None => Err(ResponseStatus::TargetDoesNotExist),
+        }
+    }
 
-        command: Vec<String>,
-    ) -> Result<ResponseKind, ResponseStatus> {
-        println!("Adding service `{name}`");
+    /// Returns the given services preceded by all of their dependencies, transitively, so that
+    /// every service comes after the ones it depends on. Dependencies that don't exist are left
+    /// out.
+    fn in_dependency_order(&self, names: &[String]) -> Vec<(String, Arc<Service>)> {
+        fn visit(
+            registry: &Registry,
+            name: &String,
+            visited: &mut HashSet<String>,
+            order: &mut Vec<(String, Arc<Service>)>,
+        ) {
+            if !visited.insert(name.clone()) {
+                return;
+            }
+            let Some(service) = registry.services.get(name) else {
+                return;
+            };
 
-        if self.services.contains_key(&name) {
-            return Err(ResponseStatus::ServiceAlreadyExists);
+            for dependency in &service.depends_on {
+                visit(registry, dependency, visited, order);
+            }
+            order.push((name.clone(), service.clone()));
         }
 
-        self.services.insert(
-            name.clone(),
-            Service::new(
-                working_directory,
-                environment,
-                group,
-                ServiceKind::Synchronous { command },
-            ),
-        );
+        let mut visited = HashSet::new();
+        let mut order = Vec::new();
+        for name in names {
+            visit(self, name, &mut visited, &mut order);
+        }
+        order
+    }
 
-        println!("Starting service `{name}`");
-        if let Err(err) = self.services.get_mut(&name).unwrap().start() {
-            println!("Failed to start service `{name}`: {err}");
+    /// Returns the services that depend on the given one, transitively, each coming before the
+    /// ones it depends on.
+    fn dependents_of(&self, name: &str) -> Vec<(String, Arc<Service>)> {
+        let mut dependents = HashSet::new();
+        let mut pending = vec![name.to_string()];
+        while let Some(dependency) = pending.pop() {
+            for (other_name, other) in &self.services {
+                if other.depends_on.contains(&dependency) && dependents.insert(other_name.clone()) {
+                    pending.push(other_name.clone());
+                }
+            }
         }
 
-        self.flush();
+        let dependents = dependents.into_iter().collect::<Vec<String>>();
+        let mut order = self.in_dependency_order(&dependents);
+        order.retain(|(other_name, _)| dependents.contains(other_name));
+        order.reverse();
+        order
+    }
+
+    /// Looks for a cycle that a service with the given name and dependencies would be part of,
+    /// returning the services that form it.
+    fn find_dependency_cycle(&self, name: &String, depends_on: &[String]) -> Option<Vec<String>> {
+        fn find_path(
+            registry: &Registry,
+            target: &String,
+            dependencies: &[String],
+            visited: &mut HashSet<String>,
+            path: &mut Vec<String>,
+        ) -> bool {
+            for dependency in dependencies {
+                path.push(dependency.clone());
+                if dependency == target {
+                    return true;
+                }
+                if visited.insert(dependency.clone())
+                    && let Some(service) = registry.services.get(dependency)
+                    && find_path(registry, target, &service.depends_on, visited, path)
+                {
+                    return true;
+                }
+                path.pop();
+            }
+            false
+        }
 
-        Ok(ResponseKind::None)
+        let mut path = vec![name.clone()];
+        find_path(self, name, depends_on, &mut HashSet::new(), &mut path).then_some(path)
     }
 
-    pub fn add_asynchronous(
-        &mut self,
+    /// Marks the configuration as changed, so that it gets written shortly in the background.
+    fn mark_dirty(&mut self) {
+        self.dirty = true;
+    }
 
-        name: String,
-        working_directory: String,
-        environment: HashMap<String, String>,
-        group: Option<String>,
+    /// Records whether a service should be running, which gets written shortly in the
+    /// background.
+    fn set_running(&mut self, name: &str, running: bool) {
+        let changed = if running {
+            self.running.insert(name.to_string())
+        } else {
+            self.running.remove(name)
+        };
+        self.running_dirty |= changed;
+    }
 
-        start_command: Vec<String>,
-        stop_command: Vec<String>,
-    ) -> Result<ResponseKind, ResponseStatus> {
-        println!("Adding service `{name}`");
+    /// Takes what saving the running services writes, if there is a state directory to write it
+    /// to.
+    fn running_state(&mut self, context: &UserContext) -> Option<RunningStateSnapshot> {
+        self.running_dirty = false;
+        let path = context.running_state_file_path()?;
+        let state = RunningState {
+            running: self.running.iter().cloned().collect(),
+        };
+        Some(RunningStateSnapshot {
+            path,
+            contents: serde_json::to_string_pretty(&state).unwrap(),
+        })
+    }
 
-        if self.services.contains_key(&name) {
-            return Err(ResponseStatus::ServiceAlreadyExists);
+    /// The configuration as it gets written, with or without the services.
+    fn config_ref(&self, with_services: bool) -> ConfigRef<'_> {
+        ConfigRef {
+            version: CONFIG_VERSION,
+            services: self
+                .services
+                .iter()
+                .filter(|_| with_services)
+                .map(|(name, service)| (name, ServiceEntry::Loaded(service)))
+                .chain(
+                    self.unloaded_services
+                        .iter()
+                        .filter(|(name, _)| !self.services.contains_key(*name))
+                        .map(|(name, entry)| (name, ServiceEntry::Unloaded(entry))),
+                )
+                .collect(),
+            targets: self.targets.iter().collect(),
+            groups: self.groups.iter().collect(),
         }
+    }
 
-        self.services.insert(
-            name.clone(),
-            Service::new(
-                working_directory,
-                environment,
-                group,
-                ServiceKind::Asynchronous {
-                    start_command,
-                    stop_command,
-                },
-            ),
-        );
+    /// Replaces the services with the provided ones, except for those whose definition didn't
+    /// change, which are kept as they are.
+    fn replace_services(
+        &mut self,
+        services: HashMap<String, Service>,
+        events: &Arc<EventBus>,
+        clock: &SharedClock,
+    ) -> Replacement {
+        let mut removed = self
+            .services
+            .keys()
+            .filter(|name| !services.contains_key(*name))
+            .cloned()
+            .collect::<Vec<String>>();
+        removed.sort();
 
-        println!("Starting service `{name}`");
-        if let Err(err) = self.services.get_mut(&name).unwrap().start() {
-            println!("Failed to start service `{name}`: {err}");
+        let mut added = Vec::new();
+        let mut changed = Vec::new();
+        let mut previous = Vec::new();
+        for name in &removed {
+            let service = self.services.remove(name).unwrap();
+            previous.push((name.clone(), service));
         }
+        for (name, service) in services {
+            match self.services.get(&name) {
+                Some(current) if same_definition(current, &service) => continue,
+                Some(_) => changed.push(name.clone()),
+                None => added.push(name.clone()),
+            }
 
-        self.flush();
+            service.attach_events(EventSender::new(name.clone(), events.clone()));
+            service.attach_clock(clock.clone());
+            if let Some(replaced) = self.services.insert(name.clone(), Arc::new(service)) {
+                previous.push((name, replaced));
+            }
+        }
+        added.sort();
+        changed.sort();
 
-        Ok(ResponseKind::None)
+        Replacement {
+            added,
+            removed,
+            changed,
+            previous,
+        }
     }
 
-    pub fn remove(&mut self, name: String) -> Result<ResponseKind, ResponseStatus> {
-        println!("Removing service `{name}`");
+    /// Takes what saving the configuration writes, so that writing it doesn't hold the registry
+    /// lock.
+    fn snapshot(&mut self, context: &UserContext) -> Result<ConfigSnapshot, String> {
+        self.dirty = false;
+        let config_file_path = config_file_path(context, self.format)
+            .ok_or_else(|| "there is no path for the configuration file".to_string())?;
 
-        let service = self.get_service_mut(&name)?;
+        // Sorted and pretty-printed, so that the file only changes where the state did.
+        let contents = self.format.serialize(&self.config_ref(true))?;
+        if self.drop_in_files.is_none() {
+            return Ok(ConfigSnapshot {
+                config_file_path,
+                contents,
+                drop_ins: None,
+            });
+        }
 
-        println!("Stopping service `{name}`");
-        if service.is_running()
-            && let Err(err) = service.stop()
-        {
-            println!("Failed to stop service `{name}`: {err}");
+        let contents_without_services = self.format.serialize(&self.config_ref(false))?;
+        let services_dir = context
+            .services_dir_path()
+            .ok_or_else(|| "there is no path for the drop-in directory".to_string())?;
+        let mut files = Vec::new();
+        let mut current_files = HashSet::new();
+        for (name, service) in &self.services {
+            let file_name = drop_in_file_name(name, self.format);
+            let contents = self
+                .format
+                .serialize(service.as_ref())
+                .map_err(|err| format!("failed to serialize the service `{name}`: {err}"))?;
+            files.push((format!("{services_dir}/{file_name}"), contents + "\n"));
+            current_files.insert(file_name);
         }
 
-        self.services.remove(&name);
-        println!("Service removed");
+        let drop_in_files = self.drop_in_files.as_mut().unwrap();
+        let removed = drop_in_files
+            .difference(&current_files)
+            .map(|file_name| format!("{services_dir}/{file_name}"))
+            .collect();
+        *drop_in_files = current_files;
+        Ok(ConfigSnapshot {
+            config_file_path,
+            contents,
+            drop_ins: Some(DropInSnapshot {
+                services_dir,
+                files,
+                removed,
+                contents: contents_without_services,
+            }),
+        })
+    }
+}
 
-        self.flush();
+/// The services that should be running, as written to the state directory.
+#[derive(Serialize, Deserialize)]
+struct RunningState {
+    running: BTreeSet<String>,
+}
 
-        Ok(ResponseKind::None)
+/// Reads which services were running when the previous daemon stopped, if it recorded it.
+fn read_running_state(context: &UserContext) -> Option<BTreeSet<String>> {
+    let path = context.running_state_file_path()?;
+    let contents = match fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return None,
+        Err(err) => {
+            warn!("Failed to read `{path}`: {err}");
+            return None;
+        }
+    };
+    match serde_json::from_str::<RunningState>(&contents) {
+        Ok(state) => Some(state.running),
+        Err(err) => {
+            warn!("Ignoring `{path}`, which is invalid: {err}");
+            None
+        }
     }
+}
 
-    pub fn start(&mut self, name: String) -> Result<ResponseKind, ResponseStatus> {
-        let service = self.get_service_mut(&name)?;
+/// What saving the running services writes.
+struct RunningStateSnapshot {
+    path: String,
+    contents: String,
+}
 
-        println!("Starting service `{name}`");
-        if let Err(err) = service.start() {
-            println!("Failed to start service `{name}`: {err}");
+impl RunningStateSnapshot {
+    fn write(self) -> Result<(), String> {
+        if let Some(state_dir) = Path::new(&self.path).parent() {
+            fs::create_dir_all(state_dir)
+                .map_err(|err| format!("failed to create `{}`: {err}", state_dir.display()))?;
         }
+        fs::write(&self.path, self.contents + "\n")
+            .map_err(|err| format!("failed to write `{}`: {err}", self.path))
+    }
+}
 
-        Ok(ResponseKind::None)
+/// What replacing the services changed, with the names sorted.
+struct Replacement {
+    added: Vec<String>,
+    removed: Vec<String>,
+    changed: Vec<String>,
+    // The previous definitions of the removed and changed services, which have to be stopped.
+    previous: Vec<(String, Arc<Service>)>,
+}
+
+/// What saving the configuration writes.
+struct ConfigSnapshot {
+    config_file_path: String,
+    // The configuration with every service in it.
+    contents: String,
+    drop_ins: Option<DropInSnapshot>,
+}
+
+/// What saving the configuration writes with the drop-in layout.
+struct DropInSnapshot {
+    services_dir: String,
+    // The path and contents of the file of each service.
+    files: Vec<(String, String)>,
+    // The files of the services that are gone.
+    removed: Vec<String>,
+    // The configuration without the services, which are in their own files.
+    contents: String,
+}
+
+impl ConfigSnapshot {
+    /// Writes the configuration file, after the file of each service with the drop-in layout.
+    /// The configuration file keeps every service unless they could all be written to their own
+    /// files.
+    fn write(self) -> Result<(), String> {
+        let (contents, drop_ins_result) = match self.drop_ins {
+            Some(drop_ins) => match drop_ins.write() {
+                Ok(contents) => (contents, Ok(())),
+                Err(err) => (self.contents, Err(err)),
+            },
+            None => (self.contents, Ok(())),
+        };
+        fs::write(&self.config_file_path, contents + "\n")
+            .map_err(|err| format!("failed to write `{}`: {err}", self.config_file_path))?;
+        drop_ins_result
     }
+}
 
-    pub fn stop(&mut self, name: String) -> Result<ResponseKind, ResponseStatus> {
-        let service = self.get_service_mut(&name)?;
+impl DropInSnapshot {
+    /// Writes the files of the services that changed and removes the ones of the services that
+    /// are gone. Returns the configuration to write along with them.
+    fn write(self) -> Result<String, String> {
+        fs::create_dir_all(&self.services_dir)
+            .map_err(|err| format!("failed to create `{}`: {err}", self.services_dir))?;
 
-        println!("Stopping service `{name}`");
-        if let Err(err) = service.stop() {
-            println!("Failed to stop service `{name}`: {err}");
+        let mut failures = Vec::new();
+        for (path, contents) in self.files {
+            if !fs::read_to_string(&path).is_ok_and(|current| current == contents)
+                && let Err(err) = fs::write(&path, contents)
+            {
+                failures.push(format!("failed to write `{path}`: {err}"));
+            }
+        }
+        for path in self.removed {
+            match fs::remove_file(&path) {
+                Ok(()) => {}
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+                Err(err) => error!("Failed to remove `{path}`: {err}"),
+            }
         }
 
-        Ok(ResponseKind::None)
+        if !failures.is_empty() {
+            return Err(failures.join(", "));
+        }
+        Ok(self.contents)
     }
+}
+
+/// Keeps track of all services.
+///
+/// The registry lock is only ever held to look services up or to change the service list, so
+/// slow operations on one service don't block queries or operations on any other.
+pub struct ServiceManager {
+    // Where the configuration and the state of the daemon are kept.
+    context: &'static UserContext,
+    default_target: Option<String>,
+    // Whether services get started when the daemon starts or they are added to the
+    // configuration file.
+    start_services: bool,
+    format: ConfigFormat,
+    // Whether each service is kept in its own file in the drop-in directory.
+    drop_ins: bool,
+    registry: Mutex<Registry>,
+    service_list_cache: Mutex<ServiceListCache>,
+    events: Arc<EventBus>,
+    clock: SharedClock,
+    // Held shared by the commands that change something and exclusively by reloads, so that no
+    // change lands between a reload reading the configuration and replacing the services.
+    changes: RwLock<()>,
+    // Held while the configuration is written, so that an older state never replaces a newer one.
+    flush_lock: Mutex<()>,
+    // Why the configuration couldn't be written the last time, if it couldn't.
+    flush_error: Mutex<Option<String>>,
+    // Set once the daemon starts exiting, after which nothing gets started anymore.
+    stopping: AtomicBool,
+}
+
+impl ServiceManager {
+    /// Loads the services from the configuration file, and from the drop-in directory if
+    /// `drop_ins` is true, then either starts them unless `start_services` is false or, if the
+    /// daemon replaced a previous instance of itself, takes them over from it.
+    ///
+    /// The services started are the ones that were running when the previous daemon stopped, as
+    /// recorded in the state directory. Without that record, or with `fresh`, they are the
+    /// services of the default target, or all of them if there is none.
+    ///
+    /// With the drop-in layout, the services still defined in the configuration file are moved
+    /// to their own files right away. Fails if the configuration file was written by a newer
+    /// daemon, which would lose what it doesn't know about by saving it.
+    pub fn new(
+        context: &'static UserContext,
+        default_target: Option<String>,
+        start_services: bool,
+        format: ConfigFormat,
+        drop_ins: bool,
+        fresh: bool,
+        handover: Option<HashMap<String, ServiceHandover>>,
+    ) -> Result<Self, String> {
+        let selff = Self {
+            context,
+            default_target: default_target.clone(),
+            start_services,
+            format,
+            drop_ins,
+            registry: Mutex::new(Registry {
+                format,
+                ..Registry::default()
+            }),
+            service_list_cache: Mutex::new(HashMap::new()),
+            events: Arc::new(EventBus::default()),
+            clock: SharedClock::default(),
+            changes: RwLock::new(()),
+            flush_lock: Mutex::new(()),
+            flush_error: Mutex::new(None),
+            stopping: AtomicBool::new(false),
+        };
+
+        let loaded = match read_config(context, format, drop_ins, &mut Vec::new()) {
+            Ok(loaded) => loaded,
+            Err(ConfigError::TooNew(err)) => return Err(err),
+            Err(err) => {
+                error!("Failed to load the configuration: {err}. Service list will NOT be loaded!");
+                return Ok(selff);
+            }
+        };
+
+        let mut registry = selff.registry.lock().unwrap();
+        registry.services = loaded
+            .config
+            .services
+            .into_iter()
+            .map(|(name, service)| {
+                service.attach_events(EventSender::new(name.clone(), selff.events.clone()));
+                service.attach_clock(selff.clock.clone());
+                (name, Arc::new(service))
+            })
+            .collect();
+        registry.unloaded_services = loaded.config.unloaded_services;
+        registry.targets = loaded.config.targets;
+        registry.groups = loaded.config.groups;
+        if drop_ins {
+            registry.drop_in_files = Some(loaded.drop_in_files);
+            if loaded.in_file > 0 {
+                info!(
+                    "Moving the {} service(s) of the configuration file to the drop-in directory",
+                    loaded.in_file
+                );
+                registry.mark_dirty();
+            }
+        }
 
-    pub fn restart(&mut self, name: String) -> Result<ResponseKind, ResponseStatus> {
-        let service = self.get_service_mut(&name)?;
+        if let Some(mut handover) = handover {
+            info!("Taking services over from the previous instance...");
+            for (service_name, service) in &registry.services {
+                if let Some(service_handover) = handover.remove(service_name) {
+                    service.adopt(service_handover);
+                }
+            }
+            match read_running_state(context) {
+                Some(running) => registry.running = running.into_iter().collect(),
+                None => {
+                    registry.running = registry
+                        .services
+                        .iter()
+                        .filter(|(_, service)| service.is_running())
+                        .map(|(name, _)| name.clone())
+                        .collect();
+                    registry.running_dirty = true;
+                }
+            }
+            drop(registry);
+            return Ok(selff);
+        }
 
-        println!("Restarting service `{name}`");
-        if let Err(err) = service.restart() {
-            println!("Failed to restart service `{name}`: {err}");
+        if !start_services {
+            info!(
+                "Not starting any of the {} service(s), as asked by --no-start-services",
+                registry.services.len()
+            );
+            drop(registry);
+            return Ok(selff);
         }
 
-        Ok(ResponseKind::None)
-    }
+        let previously_running = if fresh {
+            None
+        } else {
+            read_running_state(context)
+        };
+        let mut autostart = match (previously_running, &default_target) {
+            (Some(running), _) => {
+                info!("Starting the services that were running before the daemon stopped...");
+                running
+                    .into_iter()
+                    .filter(|name| {
+                        let exists = registry.services.contains_key(name);
+                        if !exists {
+                            warn!(
+                                service = name;
+                                "Service `{name}` was running before the daemon stopped, but does not exist anymore"
+                            );
+                        }
+                        exists
+                    })
+                    .collect()
+            }
+            (None, Some(target)) => {
+                info!(target = target; "Starting services of target `{target}`...");
+                match registry.targets.get(target) {
+                    Some(services) => services.clone(),
+                    None => {
+                        warn!(
+                            target = target;
+                            "Default target `{target}` does not exist. No services will be started!"
+                        );
+                        Vec::new()
+                    }
+                }
+            }
+            (None, None) => {
+                info!("Starting services...");
+                registry.services.keys().cloned().collect()
+            }
+        };
+        autostart.sort();
+        registry.running = autostart.iter().cloned().collect();
+        registry.running_dirty = true;
+        let autostart = registry.in_dependency_order(&autostart);
+        drop(registry);
 
-    pub fn stop_all(&mut self) {
-        println!("Stopping services...");
+        for (service_name, service) in autostart {
+            if service.is_running() {
+                continue;
+            }
 
-        for (service_name, service) in &mut self.services {
-            println!("Stopping service `{service_name}`");
-            if service.is_running()
-                && let Err(err) = service.stop()
-            {
-                println!("Failed to stop service `{service_name}`: {err}");
+            info!(service = service_name; "Starting service `{service_name}`");
+            if let Err(err) = service.start() {
+                warn!(service = service_name; "Failed to start service `{service_name}`: {err}");
             }
         }
+
+        Ok(selff)
     }
 
-    pub fn get_status(&self, name: String) -> Result<ResponseKind, ResponseStatus> {
-        let service = self.get_service(&name)?;
+    /// Makes the daemon and the services it has read the time from `clock`.
+    #[cfg(test)]
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        for service in self.registry.get_mut().unwrap().services.values() {
+            service.attach_clock(clock.clone());
+        }
+        self.clock = clock;
+        self
+    }
 
-        Ok(ResponseKind::ServiceStatus {
-            service: service_to_ipc_service(&service),
-            running: service.is_running(),
-            logs: service.get_logs(),
-        })
+    /// Lets a change through unless a reload is under way or waiting for the changes let through
+    /// before it, in which case the change has to be tried again once the reload is done.
+    pub fn begin_change(&self) -> Option<RwLockReadGuard<'_, ()>> {
+        self.changes.try_read().ok()
     }
 
-    pub fn list_services(&self) -> Result<ResponseKind, ResponseStatus> {
-        let mut services = HashMap::<String, ipc::Service>::new();
-        for (k, v) in &self.services {
-            services.insert(k.clone(), service_to_ipc_service(v));
-        }
+    /// Maps a service error to the response status that tells clients about it.
+    fn service_error_status(&self, name: &str, err: &ServiceError) -> ResponseStatus {
+        match err {
+            ServiceError::IOError(err) => ResponseStatus::IoError {
+                message: err.to_string(),
+            },
+            ServiceError::ServiceNotRunning => ResponseStatus::ServiceNotRunning,
+            ServiceError::ServiceAlreadyRunning => ResponseStatus::ServiceAlreadyRunning,
+            ServiceError::StartCommandFailed { code } => {
+                ResponseStatus::StartCommandFailed { code: *code }
+            }
+            ServiceError::StopCommandFailed { code } => {
+                ResponseStatus::StopCommandFailed { code: *code }
+            }
+            ServiceError::PidfileTimeout => ResponseStatus::PidfileTimeout,
+            ServiceError::CommandTimedOut { command, secs } => ResponseStatus::CommandTimedOut {
+                command: command.to_string(),
+                secs: *secs,
+            },
+            ServiceError::WorkingDirectoryNotFound { path } => {
+                ResponseStatus::WorkingDirectoryNotFound {
+                    path: path.display().to_string(),
+                }
+            }
+            ServiceError::ExecutableNotFound {
+                program,
+                search_path,
+            } => ResponseStatus::ExecutableNotFound {
+                program: program.clone(),
+                search_path: search_path.clone(),
+            },
+            ServiceError::NotExecutable { program } => ResponseStatus::NotExecutable {
+                program: program.clone(),
+            },
+            ServiceError::NoReloadMethod => ResponseStatus::NoReloadMethod,
+            ServiceError::NoKnownProcess => ResponseStatus::NoKnownProcess,
+            ServiceError::ReloadCommandFailed { code } => {
+                ResponseStatus::ReloadCommandFailed { code: *code }
+            }
+            ServiceError::HookFailed { hook, code } => ResponseStatus::HookFailed {
+                hook: hook.to_string(),
+                code: *code,
+            },
+            ServiceError::PortInUse { port } => {
+                let service = self
+                    .services()
+                    .into_iter()
+                    .find(|(other_name, other)| other_name != name && other.ports.contains(port))
+                    .map(|(other_name, _)| other_name);
+                ResponseStatus::PortInUse {
+                    port: *port,
+                    service,
+                }
+            }
+        }
+    }
+
+    fn get_service(&self, name: &String) -> Result<Arc<Service>, ResponseStatus> {
+        self.registry.lock().unwrap().get_service(name)
+    }
+
+    pub fn add(
+        &self,
+        name: String,
+        service: ipc::Service,
+        force: bool,
+        warnings: &mut Vec<String>,
+    ) -> Result<ResponseKind, ResponseStatus> {
+        info!(service = name; "Adding service `{name}`");
+
+        let service = ipc_service_to_service(service);
+        check_service_name(&name)
+            .and_then(|_| service.check_definition())
+            .and_then(|_| if force { Ok(()) } else { service.validate() })
+            .map_err(|message| ResponseStatus::ValidationFailed { message })?;
+
+        service.attach_events(EventSender::new(name.clone(), self.events.clone()));
+        service.attach_clock(self.clock.clone());
+        let service = Arc::new(service);
+
+        let mut registry = self.registry.lock().unwrap();
+        if registry.services.contains_key(&name) {
+            return Err(ResponseStatus::ServiceAlreadyExists);
+        }
+        if let Some(services) = registry.find_dependency_cycle(&name, &service.depends_on) {
+            return Err(ResponseStatus::DependencyCycle { services });
+        }
+        registry.services.insert(name.clone(), service.clone());
+        registry.mark_dirty();
+        registry.set_running(&name, true);
+        drop(registry);
+        self.events
+            .emit_definition(name.clone(), service.state(), EventCause::Added);
+
+        info!(service = name; "Starting service `{name}`");
+        if let Err(err) = service.start() {
+            warn!(service = name; "Failed to start service `{name}`: {err}");
+            warnings.push(format!("the service was added but failed to start: {err}"));
+        }
+
+        Ok(ResponseKind::None)
+    }
+
+    pub fn import(
+        &self,
+        services: HashMap<String, ipc::Service>,
+        replace: bool,
+        force: bool,
+        warnings: &mut Vec<String>,
+    ) -> Result<ResponseKind, ResponseStatus> {
+        info!("Importing {} service(s)", services.len());
+
+        for (name, service) in &services {
+            let converted = ipc_service_to_service(service.clone());
+            check_service_name(name)
+                .and_then(|_| converted.check_definition())
+                .and_then(|_| if force { Ok(()) } else { converted.validate() })
+                .map_err(|message| ResponseStatus::ValidationFailed {
+                    message: format!("service `{name}`: {message}"),
+                })?;
+        }
+
+        let mut existing = self
+            .services()
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect::<Vec<String>>();
+        existing.sort();
+        for name in existing {
+            if replace {
+                // Someone else removing it in the meantime is just as good.
+                let _ = self.remove(name);
+            } else if services.contains_key(&name) {
+                warnings.push(format!(
+                    "the service `{name}` already exists, so it was left as it is"
+                ));
+            }
+        }
+
+        let mut names = services.keys().cloned().collect::<Vec<String>>();
+        names.sort();
+        let mut services = services;
+        for name in names {
+            let service = services.remove(&name).unwrap();
+            let mut add_warnings = Vec::new();
+            match self.add(name.clone(), service, true, &mut add_warnings) {
+                Ok(_) | Err(ResponseStatus::ServiceAlreadyExists) => {}
+                Err(status) => {
+                    warnings.push(format!("the service `{name}` was not added: {status}"))
+                }
+            }
+            warnings.extend(
+                add_warnings
+                    .into_iter()
+                    .map(|warning| format!("service `{name}`: {warning}")),
+            );
+        }
+
+        Ok(ResponseKind::None)
+    }
+
+    pub fn remove(&self, name: String) -> Result<ResponseKind, ResponseStatus> {
+        info!(service = name; "Removing service `{name}`");
+
+        let service = self.get_service(&name)?;
+
+        info!(service = name; "Stopping service `{name}`");
+        if service.is_running()
+            && let Err(err) = service.stop()
+        {
+            warn!(service = name; "Failed to stop service `{name}`: {err}");
+        }
+
+        let mut registry = self.registry.lock().unwrap();
+        // Someone else might have removed the service while it was being stopped.
+        if registry
+            .services
+            .get(&name)
+            .is_some_and(|current| Arc::ptr_eq(current, &service))
+        {
+            registry.services.remove(&name);
+            registry.set_running(&name, false);
+            for services in registry.targets.values_mut() {
+                services.retain(|service_name| *service_name != name);
+            }
+            registry.targets.retain(|_, services| !services.is_empty());
+            registry.mark_dirty();
+            drop(registry);
+            self.events
+                .emit_definition(name.clone(), service.state(), EventCause::Removed);
+        }
+        info!(service = name; "Service removed");
+
+        Ok(ResponseKind::None)
+    }
+
+    pub fn start(
+        &self,
+        name: String,
+        warnings: &mut Vec<String>,
+    ) -> Result<ResponseKind, ResponseStatus> {
+        let mut registry = self.registry.lock().unwrap();
+        let service = registry.get_service(&name)?;
+        registry.set_running(&name, true);
+        let mut dependencies = registry.in_dependency_order(std::slice::from_ref(&name));
+        for (dependent_name, dependent) in &dependencies {
+            for dependency in &dependent.depends_on {
+                if !registry.services.contains_key(dependency) {
+                    warnings.push(format!(
+                        "the service `{dependent_name}` depends on `{dependency}`, which does not exist"
+                    ));
+                }
+            }
+        }
+        drop(registry);
+        dependencies.retain(|(dependency_name, _)| *dependency_name != name);
+
+        for (dependency_name, dependency) in dependencies {
+            if dependency.is_running() {
+                continue;
+            }
+
+            info!(
+                service = dependency_name;
+                "Starting service `{dependency_name}`, which `{name}` depends on"
+            );
+            if let Err(err) = dependency.start() {
+                warn!(
+                    service = dependency_name;
+                    "Failed to start service `{dependency_name}`: {err}"
+                );
+                return Err(ResponseStatus::DependencyFailed {
+                    service: dependency_name,
+                });
+            }
+        }
+
+        info!(service = name; "Starting service `{name}`");
+        if let Err(err) = service.start() {
+            warn!(service = name; "Failed to start service `{name}`: {err}");
+            return Err(self.service_error_status(&name, &err));
+        }
+
+        for path in &service.watch_paths {
+            if !service.working_directory_path().join(path).exists() {
+                warnings.push(format!(
+                    "the watched path `{path}` does not exist, so changes to it can only be noticed once it is created"
+                ));
+            }
+        }
+
+        Ok(ResponseKind::None)
+    }
+
+    pub fn stop(
+        &self,
+        name: String,
+        with_dependents: bool,
+        warnings: &mut Vec<String>,
+    ) -> Result<ResponseKind, ResponseStatus> {
+        let mut registry = self.registry.lock().unwrap();
+        let service = registry.get_service(&name)?;
+        let mut dependents = registry.dependents_of(&name);
+        registry.set_running(&name, false);
+        if with_dependents {
+            for (dependent_name, _) in &dependents {
+                registry.set_running(dependent_name, false);
+            }
+        }
+        drop(registry);
+
+        if !with_dependents {
+            let mut running = dependents
+                .iter()
+                .filter(|(_, dependent)| dependent.is_running())
+                .map(|(dependent_name, _)| dependent_name.as_str())
+                .collect::<Vec<&str>>();
+            if !running.is_empty() {
+                running.sort();
+                warnings.push(format!(
+                    "the following services depend on `{name}` and are still running: {}. Pass --with-dependents to stop them as well.",
+                    running.join(", ")
+                ));
+            }
+            dependents.clear();
+        }
+
+        for (dependent_name, dependent) in dependents {
+            if !dependent.is_running() {
+                continue;
+            }
+
+            info!(
+                service = dependent_name;
+                "Stopping service `{dependent_name}`, which depends on `{name}`"
+            );
+            match dependent.stop() {
+                Ok(outcome) => stop_warnings(&dependent_name, outcome, warnings),
+                Err(err) => {
+                    warn!(
+                        service = dependent_name;
+                        "Failed to stop service `{dependent_name}`: {err}"
+                    )
+                }
+            }
+        }
+
+        info!(service = name; "Stopping service `{name}`");
+        match service.stop() {
+            Ok(outcome) => stop_warnings(&name, outcome, warnings),
+            Err(err) => {
+                warn!(service = name; "Failed to stop service `{name}`: {err}");
+                return Err(self.service_error_status(&name, &err));
+            }
+        }
+
+        Ok(ResponseKind::None)
+    }
+
+    pub fn restart(
+        &self,
+        name: String,
+        warnings: &mut Vec<String>,
+    ) -> Result<ResponseKind, ResponseStatus> {
+        let mut registry = self.registry.lock().unwrap();
+        let service = registry.get_service(&name)?;
+        registry.set_running(&name, true);
+        drop(registry);
+
+        info!(service = name; "Restarting service `{name}`");
+        match service.restart() {
+            Ok(outcome) => stop_warnings(&name, outcome, warnings),
+            Err(err) => {
+                warn!(service = name; "Failed to restart service `{name}`: {err}");
+                return Err(self.service_error_status(&name, &err));
+            }
+        }
+
+        Ok(ResponseKind::None)
+    }
+
+    pub fn pause(&self, name: String) -> Result<ResponseKind, ResponseStatus> {
+        let service = self.get_service(&name)?;
+
+        info!(service = name; "Pausing service `{name}`");
+        if let Err(err) = service.pause() {
+            warn!(service = name; "Failed to pause service `{name}`: {err}");
+            return Err(self.service_error_status(&name, &err));
+        }
+
+        Ok(ResponseKind::None)
+    }
+
+    pub fn resume(&self, name: String) -> Result<ResponseKind, ResponseStatus> {
+        let service = self.get_service(&name)?;
+
+        info!(service = name; "Resuming service `{name}`");
+        if let Err(err) = service.resume() {
+            warn!(service = name; "Failed to resume service `{name}`: {err}");
+            return Err(self.service_error_status(&name, &err));
+        }
+
+        Ok(ResponseKind::None)
+    }
+
+    pub fn reload(&self, name: String) -> Result<ResponseKind, ResponseStatus> {
+        let service = self.get_service(&name)?;
+
+        info!(service = name; "Reloading service `{name}`");
+        if let Err(err) = service.reload() {
+            warn!(service = name; "Failed to reload service `{name}`: {err}");
+            return Err(self.service_error_status(&name, &err));
+        }
+
+        Ok(ResponseKind::None)
+    }
+
+    fn services(&self) -> Vec<(String, Arc<Service>)> {
+        self.registry
+            .lock()
+            .unwrap()
+            .services
+            .iter()
+            .map(|(name, service)| (name.clone(), service.clone()))
+            .collect()
+    }
+
+    /// Captures the state of every service and passes it to `exec`, which is expected to replace
+    /// the daemon and only returns on failure. Services are kept from changing in between.
+    pub fn hand_over<F: FnOnce(HashMap<String, ServiceHandover>) -> io::Error>(
+        &self,
+        exec: F,
+    ) -> io::Error {
+        let services = self.services();
+
+        let mut guards = Vec::new();
+        let mut handover = HashMap::new();
+        for (name, service) in &services {
+            let (guard, service_handover) = service.hand_over();
+            guards.push(guard);
+            handover.insert(name.clone(), service_handover);
+        }
+
+        exec(handover)
+    }
+
+    /// Keeps file changes and timers from starting services from now on, for the daemon to exit.
+    /// Commands that change anything should be turned away too.
+    pub fn begin_stopping(&self) {
+        self.stopping.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_stopping(&self) -> bool {
+        self.stopping.load(Ordering::SeqCst)
+    }
+
+    /// Stops every service, each one once the services depending on it are stopped, several
+    /// at a time. Once `timeout` runs out, whatever is still running gets killed.
+    pub fn stop_all(&self, timeout: Duration) {
+        const MAX_PARALLEL_STOPS: usize = 8;
+
+        info!("Stopping services...");
+        let started_at = Instant::now();
+        let deadline = started_at + timeout;
+
+        let services = self.services().into_iter().collect::<HashMap<_, _>>();
+        // How many services that depend on each one are left to stop.
+        let mut dependents = services
+            .keys()
+            .map(|name| (name.clone(), 0))
+            .collect::<HashMap<String, usize>>();
+        for service in services.values() {
+            for dependency in &service.depends_on {
+                if let Some(count) = dependents.get_mut(dependency) {
+                    *count += 1;
+                }
+            }
+        }
+
+        let (results_tx, results_rx) = mpsc::channel();
+        let mut ready = Vec::new();
+        let mut in_flight = 0;
+        let (mut stopped, mut failed) = (0, 0);
+        loop {
+            if ready.is_empty() && in_flight == 0 {
+                // Only a dependency cycle can hold services back at this point.
+                ready = dependents
+                    .iter()
+                    .filter(|(_, count)| **count == 0)
+                    .map(|(name, _)| name.clone())
+                    .collect();
+                if ready.is_empty() {
+                    ready = dependents.keys().cloned().collect();
+                }
+                if ready.is_empty() {
+                    break;
+                }
+                for name in &ready {
+                    dependents.remove(name);
+                }
+            }
+
+            while in_flight < MAX_PARALLEL_STOPS
+                && let Some(name) = ready.pop()
+            {
+                let service = services[&name].clone();
+                let results_tx = results_tx.clone();
+                thread::spawn(move || {
+                    let started_at = Instant::now();
+                    let result = if service.is_running() {
+                        info!(service = name; "Stopping service `{name}`");
+                        service.stop().map(|_| true).map_err(|err| err.to_string())
+                    } else {
+                        Ok(false)
+                    };
+                    let _ = results_tx.send((name, result, started_at.elapsed()));
+                });
+                in_flight += 1;
+            }
+            if in_flight == 0 {
+                continue;
+            }
+
+            let wait = deadline.saturating_duration_since(Instant::now());
+            let Ok((name, result, elapsed)) = results_rx.recv_timeout(wait) else {
+                warn!(
+                    "Services are still stopping after {:.1}s",
+                    timeout.as_secs_f64()
+                );
+                break;
+            };
+            in_flight -= 1;
+            match result {
+                Ok(true) => {
+                    stopped += 1;
+                    info!(
+                        service = name;
+                        "Stopped service `{name}` in {:.1}s",
+                        elapsed.as_secs_f64()
+                    );
+                }
+                Ok(false) => {}
+                Err(err) => {
+                    failed += 1;
+                    warn!(service = name; "Failed to stop service `{name}`: {err}");
+                }
+            }
+
+            // Failing to stop doesn't hold back the services it depends on.
+            for dependency in &services[&name].depends_on {
+                if let Some(count) = dependents.get_mut(dependency) {
+                    *count -= 1;
+                    if *count == 0 {
+                        dependents.remove(dependency);
+                        ready.push(dependency.clone());
+                    }
+                }
+            }
+        }
+
+        // The stops still in progress are left to the threads running them, which exiting ends.
+        let mut killed = 0;
+        for (name, service) in &services {
+            if service.is_running() {
+                warn!(service = name; "Killing service `{name}`, which is still running");
+                service.kill();
+                killed += 1;
+            }
+        }
+
+        info!(
+            "Stopped {stopped} service(s) in {:.1}s, with {failed} failing to stop and {killed} killed",
+            started_at.elapsed().as_secs_f64()
+        );
+    }
+
+    pub fn get_status(
+        &self,
+        name: String,
+        log_bytes: usize,
+    ) -> Result<ResponseKind, ResponseStatus> {
+        let service = self.get_service(&name)?;
+
+        let usage = service.resource_usage();
+        let (logs, logs_truncated) = service.get_logs_tail_bytes(log_bytes);
+        let (start_count, runs) = service.run_history();
+        let secs_ago = |time: SystemTime| self.clock.since(time).as_secs();
+        let mut status = ServiceStatus::new(service_to_ipc_service(&service), service.state());
+        status.running = service.is_running();
+        status.ready = service.is_ready();
+        status.last_exit = service
+            .last_exit()
+            .map(|(code, signal)| ipc::ExitStatus::new(code, signal));
+        status.timer = service.timer_schedule().map(|(last_run, next_run)| {
+            ipc::TimerSchedule::new(
+                last_run.map(secs_ago),
+                next_run.map(|next_run| next_run.as_secs()),
+            )
+        });
+        status.last_watch_trigger = service
+            .last_watch_trigger()
+            .map(|(path, triggered_at)| ipc::WatchTrigger::new(path, secs_ago(triggered_at)));
+        status.oldest_log_secs_ago = service.oldest_log().map(secs_ago);
+        status.pid = service.pid();
+        status.memory_bytes = usage.map(|(memory_bytes, _)| memory_bytes);
+        status.cpu_percent = usage.map(|(_, cpu_percent)| cpu_percent);
+        status.start_count = start_count;
+        status.runs = runs
+            .into_iter()
+            .map(|run| {
+                let mut converted = ipc::ServiceRun::new(secs_ago(run.started_at));
+                if let Some(end) = run.ended {
+                    converted.ended_secs_ago = Some(secs_ago(end.at));
+                    converted.end_state = Some(end.state);
+                    converted.exit = end
+                        .exit
+                        .map(|(code, signal)| ipc::ExitStatus::new(code, signal));
+                }
+                converted
+            })
+            .collect();
+        status.logs = logs;
+        status.logs_truncated = logs_truncated;
+        Ok(ResponseKind::ServiceStatus(Box::new(status)))
+    }
+
+    pub fn get_summaries(&self) -> Result<ResponseKind, ResponseStatus> {
+        let summaries = self
+            .services()
+            .into_iter()
+            .map(|(name, service)| {
+                let state = service.state();
+                let (start_count, runs) = service.run_history();
+                let uptime_secs = runs
+                    .last()
+                    .filter(|_| state.is_active())
+                    .map(|run| self.clock.since(run.started_at).as_secs());
+                let mut summary = ServiceSummary::default();
+                summary.group = service.group();
+                summary.state = state;
+                summary.uptime_secs = uptime_secs;
+                summary.start_count = start_count;
+                (name, summary)
+            })
+            .collect();
+        Ok(ResponseKind::ServiceSummaries { summaries })
+    }
+
+    pub fn get_logs(&self, name: String, lines: usize) -> Result<ResponseKind, ResponseStatus> {
+        let service = self.get_service(&name)?;
+
+        Ok(ResponseKind::ServiceLogs {
+            logs: service.get_logs_tail(lines),
+        })
+    }
+
+    /// Passes the logs of a service to `send` piece by piece, so that neither the whole log
+    /// nor the lock on it are held for the whole transfer. Stops early if `send` fails.
+    pub fn stream_logs<F: FnMut(String) -> io::Result<()>>(
+        &self,
+        name: String,
+        mut send: F,
+    ) -> Result<ResponseKind, ResponseStatus> {
+        const CHUNK_SIZE: usize = 64 * 1024;
+
+        let service = self.get_service(&name)?;
+
+        // Anything logged after the transfer started is left out.
+        let (mut offset, end) = service.logs_bounds();
+        while offset < end {
+            let chunk;
+            (chunk, offset) = service.read_logs(offset, end, CHUNK_SIZE);
+            if chunk.is_empty() {
+                break;
+            }
+
+            if send(chunk).is_err() {
+                break;
+            }
+        }
+
+        Ok(ResponseKind::LogEnd)
+    }
+
+    pub fn list_services(
+        &self,
+        group: Option<String>,
+        running: Option<bool>,
+        labels: HashMap<String, String>,
+    ) -> Result<ResponseKind, ResponseStatus> {
+        let registry = self.registry.lock().unwrap();
+        let mut cache = self.service_list_cache.lock().unwrap();
+
+        // Service definitions never change in place, apart from groups being moved, so only the
+        // services added or replaced since the last list are converted.
+        cache.retain(|name, (service, _)| {
+            registry
+                .services
+                .get(name)
+                .is_some_and(|current| Arc::ptr_eq(current, service))
+        });
+        let mut services = registry
+            .services
+            .iter()
+            .map(|(name, service)| {
+                let (_, converted) = cache.entry(name.clone()).or_insert_with(|| {
+                    (service.clone(), Arc::new(service_to_ipc_service(service)))
+                });
+                (name.clone(), converted.clone())
+            })
+            .collect::<ServiceDefinitions>();
+        let definitions = registry.services.clone();
+        drop(cache);
+        drop(registry);
+
+        let mut states = definitions
+            .into_iter()
+            .map(|(name, service)| (name, service.state()))
+            .collect::<HashMap<_, _>>();
+
+        services.retain(|name, service| {
+            group
+                .as_ref()
+                .is_none_or(|group| service.group.as_ref() == Some(group))
+                && labels
+                    .iter()
+                    .all(|(key, value)| service.labels.get(key) == Some(value))
+                && running.is_none_or(|running| {
+                    states.get(name).is_some_and(|state| state.is_active()) == running
+                })
+        });
+        states.retain(|name, _| services.contains_key(name));
+        Ok(ResponseKind::ServiceList(ServiceList::new(
+            services, states,
+        )))
+    }
+
+    pub fn add_to_target(
+        &self,
+        target: String,
+        service: String,
+    ) -> Result<ResponseKind, ResponseStatus> {
+        let mut registry = self.registry.lock().unwrap();
+        registry.get_service(&service)?;
+
+        info!(
+            service = service, target = target;
+            "Adding service `{service}` to target `{target}`"
+        );
+
+        let services = registry.targets.entry(target).or_default();
+        if !services.contains(&service) {
+            services.push(service);
+        }
+
+        registry.mark_dirty();
+
+        Ok(ResponseKind::None)
+    }
+
+    pub fn remove_from_target(
+        &self,
+        target: String,
+        service: String,
+    ) -> Result<ResponseKind, ResponseStatus> {
+        let mut registry = self.registry.lock().unwrap();
+        let services = match registry.targets.get_mut(&target) {
+            Some(services) => services,
+            None => return Err(ResponseStatus::TargetDoesNotExist),
+        };
+        if !services.contains(&service) {
+            return Err(ResponseStatus::ServiceNotInTarget);
+        }
+
+        info!(
+            service = service, target = target;
+            "Removing service `{service}` from target `{target}`"
+        );
+
+        services.retain(|service_name| *service_name != service);
+        if services.is_empty() {
+            registry.targets.remove(&target);
+        }
+
+        registry.mark_dirty();
+
+        Ok(ResponseKind::None)
+    }
+
+    pub fn start_target(
+        &self,
+        name: String,
+        exclusive: bool,
+    ) -> Result<ResponseKind, ResponseStatus> {
+        let mut registry = self.registry.lock().unwrap();
+        let target = registry.get_target(&name)?;
+
+        let others = registry
+            .targets
+            .iter()
+            .filter(|(target_name, _)| **target_name != name)
+            .flat_map(|(_, services)| services.iter())
+            .filter(|service_name| !target.contains(service_name))
+            .filter_map(|service_name| {
+                Some((
+                    service_name.clone(),
+                    registry.get_service(service_name).ok()?,
+                ))
+            })
+            .collect::<Vec<(String, Arc<Service>)>>();
+        let target = target
+            .iter()
+            .filter_map(|service_name| {
+                Some((
+                    service_name.clone(),
+                    registry.get_service(service_name).ok()?,
+                ))
+            })
+            .collect::<Vec<(String, Arc<Service>)>>();
+        for (service_name, _) in &target {
+            registry.set_running(service_name, true);
+        }
+        if exclusive {
+            for (service_name, _) in &others {
+                registry.set_running(service_name, false);
+            }
+        }
+        drop(registry);
+
+        info!(target = name; "Starting target `{name}`");
+
+        if exclusive {
+            for (service_name, service) in others {
+                if service.is_running() {
+                    info!(service = service_name; "Stopping service `{service_name}`");
+                    if let Err(err) = service.stop() {
+                        warn!(
+                            service = service_name;
+                            "Failed to stop service `{service_name}`: {err}"
+                        );
+                    }
+                }
+            }
+        }
+
+        for (service_name, service) in target {
+            if !service.is_running() {
+                info!(service = service_name; "Starting service `{service_name}`");
+                if let Err(err) = service.start() {
+                    warn!(
+                        service = service_name;
+                        "Failed to start service `{service_name}`: {err}"
+                    );
+                }
+            }
+        }
+
+        Ok(ResponseKind::None)
+    }
+
+    pub fn stop_target(&self, name: String) -> Result<ResponseKind, ResponseStatus> {
+        let mut registry = self.registry.lock().unwrap();
+        let target = registry
+            .get_target(&name)?
+            .iter()
+            .filter_map(|service_name| {
+                Some((
+                    service_name.clone(),
+                    registry.get_service(service_name).ok()?,
+                ))
+            })
+            .collect::<Vec<(String, Arc<Service>)>>();
+        for (service_name, _) in &target {
+            registry.set_running(service_name, false);
+        }
+        drop(registry);
+
+        info!(target = name; "Stopping target `{name}`");
+
+        for (service_name, service) in target {
+            if service.is_running() {
+                info!(service = service_name; "Stopping service `{service_name}`");
+                if let Err(err) = service.stop() {
+                    warn!(service = service_name; "Failed to stop service `{service_name}`: {err}");
+                }
+            }
+        }
+
+        Ok(ResponseKind::None)
+    }
+
+    pub fn list_targets(&self) -> Result<ResponseKind, ResponseStatus> {
+        let registry = self.registry.lock().unwrap();
+        let targets = registry.targets.clone();
+        let services = registry.services.clone();
+        drop(registry);
+
+        let mut target_list = HashMap::<String, ipc::Target>::new();
+        for (name, target) in targets {
+            let running = target
+                .iter()
+                .filter(|service_name| {
+                    services
+                        .get(*service_name)
+                        .is_some_and(|service| service.is_running())
+                })
+                .count();
+
+            target_list.insert(name, ipc::Target::new(target, running));
+        }
+
+        Ok(ResponseKind::TargetList {
+            targets: target_list,
+        })
+    }
+
+    pub fn list_groups(&self) -> Result<ResponseKind, ResponseStatus> {
+        let registry = self.registry.lock().unwrap();
+        let services = registry.services.clone();
+        let mut groups = registry
+            .groups
+            .iter()
+            .map(|(name, metadata)| {
+                (
+                    name.clone(),
+                    ipc::GroupInfo::new(Some(metadata.description.clone())),
+                )
+            })
+            .collect::<HashMap<String, ipc::GroupInfo>>();
+        drop(registry);
+
+        for service in services.values() {
+            let Some(group) = service.group() else {
+                continue;
+            };
+
+            let info = groups
+                .entry(group)
+                .or_insert_with(|| ipc::GroupInfo::new(None));
+            info.services += 1;
+            if service.is_running() {
+                info.running += 1;
+            }
+        }
+
+        Ok(ResponseKind::GroupList { groups })
+    }
+
+    pub fn describe_group(
+        &self,
+        name: String,
+        description: String,
+    ) -> Result<ResponseKind, ResponseStatus> {
+        let mut registry = self.registry.lock().unwrap();
+        if description.is_empty() {
+            info!(group = name; "Removing the description of group `{name}`");
+            registry.groups.remove(&name);
+        } else {
+            info!(group = name; "Describing group `{name}`");
+            registry.groups.insert(name, GroupMetadata { description });
+        }
+        registry.mark_dirty();
+
+        Ok(ResponseKind::None)
+    }
+
+    pub fn move_group(
+        &self,
+        from: String,
+        to: Option<String>,
+    ) -> Result<ResponseKind, ResponseStatus> {
+        let mut registry = self.registry.lock().unwrap();
+
+        let mut moved = registry
+            .services
+            .iter()
+            .filter(|(_, service)| service.group().as_ref() == Some(&from))
+            .map(|(name, service)| (name.clone(), service.clone()))
+            .collect::<Vec<(String, Arc<Service>)>>();
+        if moved.is_empty() {
+            return Err(ResponseStatus::GroupIsEmpty);
+        }
+
+        match &to {
+            Some(to) => {
+                info!(group = from; "Moving the services of group `{from}` to group `{to}`")
+            }
+            None => info!(group = from; "Removing the services of group `{from}` from it"),
+        }
+        let mut cache = self.service_list_cache.lock().unwrap();
+        for (name, service) in &moved {
+            service.set_group(to.clone());
+            cache.remove(name);
+        }
+        drop(cache);
+
+        // The description follows the services, unless the other group already has its own.
+        if let Some(metadata) = registry.groups.remove(&from)
+            && let Some(to) = &to
+        {
+            registry.groups.entry(to.clone()).or_insert(metadata);
+        }
+
+        registry.mark_dirty();
+
+        moved.sort_by(|a, b| a.0.cmp(&b.0));
+        Ok(ResponseKind::MovedServices {
+            services: moved.into_iter().map(|(name, _)| name).collect(),
+        })
+    }
+
+    /// Reads the configuration file again and applies what changed in it. Services that were
+    /// added get started if the daemon would have started them, the ones that were removed get
+    /// stopped, and the ones whose definition changed get restarted if they were running. The
+    /// services that didn't change are left alone.
+    pub fn reload_config(
+        &self,
+        warnings: &mut Vec<String>,
+    ) -> Result<ResponseKind, ResponseStatus> {
+        info!("Reloading the configuration file");
+
+        let failed = |message: String| {
+            warn!("Failed to reload the configuration file: {message}");
+            ResponseStatus::ConfigReloadFailed { message }
+        };
+        // Only the services get replaced exclusively, which is quick. The changes that were let
+        // through before are written first, as reading the file would undo them otherwise.
+        let changes = self.changes.write().unwrap();
+        if let Err(err) = self.flush(false)
+            && self.registry.lock().unwrap().dirty
+        {
+            return Err(failed(format!(
+                "the changes made since it was last saved couldn't be written, and reading it would undo them: {err}"
+            )));
+        }
+        let loaded = read_config(self.context, self.format, self.drop_ins, warnings)
+            .map_err(|err| failed(err.to_string()))?;
+        let config = loaded.config;
+
+        let mut registry = self.registry.lock().unwrap();
+        let Replacement {
+            added,
+            removed,
+            changed,
+            previous,
+        } = registry.replace_services(config.services, &self.events, &self.clock);
+        // The previous definitions that have to be stopped, along with whether to start the new
+        // one in their place.
+        let replaced = previous
+            .into_iter()
+            .map(|(name, previous)| {
+                let was_running = !removed.contains(&name) && previous.is_running();
+                (name, previous, was_running)
+            })
+            .collect::<Vec<_>>();
+
+        registry.unloaded_services = config.unloaded_services;
+        registry.targets = config.targets;
+        registry.groups = config.groups;
+        if self.drop_ins {
+            registry.drop_in_files = Some(loaded.drop_in_files);
+            // Services added to the configuration file by hand move to their own files too.
+            if loaded.in_file > 0 {
+                registry.mark_dirty();
+            }
+        }
+
+        let mut to_start = match &self.default_target {
+            Some(target) => registry.targets.get(target).cloned().unwrap_or_default(),
+            None => registry.services.keys().cloned().collect(),
+        };
+        to_start.retain(|name| self.start_services && added.contains(name));
+        to_start.extend(
+            replaced
+                .iter()
+                .filter(|(_, _, was_running)| *was_running)
+                .map(|(name, _, _)| name.clone()),
+        );
+        to_start.sort();
+        for name in &removed {
+            registry.set_running(name, false);
+        }
+        for name in &to_start {
+            registry.set_running(name, true);
+        }
+        let to_start = registry.in_dependency_order(&to_start);
+        drop(registry);
+        drop(changes);
+
+        for (name, previous, _) in replaced {
+            if previous.is_running() {
+                info!(service = name; "Stopping service `{name}`");
+                match previous.stop() {
+                    Ok(outcome) => stop_warnings(&name, outcome, warnings),
+                    Err(err) => warn!(service = name; "Failed to stop service `{name}`: {err}"),
+                }
+            }
+        }
+        for (names, cause) in [
+            (&removed, EventCause::Removed),
+            (&changed, EventCause::Edited),
+            (&added, EventCause::Added),
+        ] {
+            for name in names {
+                self.events.emit_definition(
+                    name.clone(),
+                    ipc::ServiceState::Stopped,
+                    cause.clone(),
+                );
+            }
+        }
+
+        for (name, service) in to_start {
+            if service.is_running() {
+                continue;
+            }
+
+            info!(service = name; "Starting service `{name}`");
+            if let Err(err) = service.start() {
+                warn!(service = name; "Failed to start service `{name}`: {err}");
+                warnings.push(format!("the service `{name}` failed to start: {err}"));
+            }
+        }
+
+        info!(
+            "Reloaded the configuration file: {} added, {} removed, {} changed",
+            added.len(),
+            removed.len(),
+            changed.len()
+        );
+        Ok(ResponseKind::ConfigReloaded(ConfigReload::new(
+            added, removed, changed,
+        )))
+    }
+
+    /// Writes the configuration if it changed since it was last written, or regardless with
+    /// `force`. When that fails, the error is kept for the daemon info and writing is tried again
+    /// the next time.
+    pub fn flush(&self, force: bool) -> Result<(), String> {
+        let _flush_lock = self.flush_lock.lock().unwrap();
+        let (snapshot, running_state) = {
+            let mut registry = self.registry.lock().unwrap();
+            if !registry.dirty && !registry.running_dirty && !force {
+                return Ok(());
+            }
+            let snapshot = (registry.dirty || force).then(|| registry.snapshot(self.context));
+            let running_state = if registry.running_dirty || force {
+                registry.running_state(self.context)
+            } else {
+                None
+            };
+            (snapshot, running_state)
+        };
+
+        let config_result =
+            snapshot.map_or(Ok(()), |snapshot| snapshot.and_then(ConfigSnapshot::write));
+        let running_state_result = running_state.map_or(Ok(()), RunningStateSnapshot::write);
+        if config_result.is_err() || running_state_result.is_err() {
+            let mut registry = self.registry.lock().unwrap();
+            registry.dirty |= config_result.is_err();
+            registry.running_dirty |= running_state_result.is_err();
+        }
+        let result = config_result.and(running_state_result);
+        let mut flush_error = self.flush_error.lock().unwrap();
+        match &result {
+            Ok(()) => {
+                if flush_error.take().is_some() {
+                    info!("Saved the configuration again");
+                }
+            }
+            Err(err) => {
+                // Retried every second, so the same failure is only logged once.
+                if flush_error.as_ref() != Some(err) {
+                    error!("Failed to save the configuration: {err}");
+                }
+                *flush_error = Some(err.clone());
+            }
+        }
+        result
+    }
+
+    pub fn flush_now(&self) -> Result<ResponseKind, ResponseStatus> {
+        self.flush(true)
+            .map(|()| ResponseKind::None)
+            .map_err(|message| ResponseStatus::FlushFailed { message })
+    }
+
+    pub fn save_snapshot(&self, name: String) -> Result<ResponseKind, ResponseStatus> {
+        info!(snapshot = name; "Saving snapshot `{name}`");
+
+        let path = snapshot_path(self.context, &name)?;
+        let created = log::format_timestamp(SystemTime::now());
+        let registry = self.registry.lock().unwrap();
+        let mut running = registry
+            .services
+            .iter()
+            .filter(|(_, service)| service.is_running())
+            .map(|(name, _)| name)
+            .collect::<Vec<&String>>();
+        running.sort();
+        let snapshot = SnapshotRef {
+            config: registry.config_ref(true),
+            created: created.clone(),
+            running,
+        };
+        let info = SnapshotInfo::new(
+            name,
+            created,
+            snapshot.config.services.len(),
+            snapshot.running.len(),
+        );
+        let contents = serde_json::to_string_pretty(&snapshot).unwrap();
+        drop(registry);
+
+        let failed = |message: String| {
+            warn!(snapshot = info.name; "Failed to save snapshot `{}`: {message}", info.name);
+            ResponseStatus::SnapshotFailed { message }
+        };
+        let snapshots_dir = Path::new(&path).parent().unwrap();
+        fs::create_dir_all(snapshots_dir).map_err(|err| {
+            failed(format!(
+                "failed to create `{}`: {err}",
+                snapshots_dir.display()
+            ))
+        })?;
+        fs::write(&path, contents + "\n")
+            .map_err(|err| failed(format!("failed to write `{path}`: {err}")))?;
+
+        Ok(ResponseKind::Snapshot(info))
+    }
+
+    /// Makes the services match a snapshot. Services that are in it with the same definition are
+    /// left as they are, besides being started or stopped to match it.
+    pub fn restore_snapshot(
+        &self,
+        name: String,
+        warnings: &mut Vec<String>,
+    ) -> Result<ResponseKind, ResponseStatus> {
+        info!(snapshot = name; "Restoring snapshot `{name}`");
+
+        let path = snapshot_path(self.context, &name)?;
+        let failed = |message: String| {
+            warn!(snapshot = name; "Failed to restore snapshot `{name}`: {message}");
+            ResponseStatus::SnapshotFailed { message }
+        };
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                return Err(ResponseStatus::SnapshotDoesNotExist);
+            }
+            Err(err) => return Err(failed(format!("failed to read `{path}`: {err}"))),
+        };
+        let config = parse_config(&contents, ConfigFormat::Json, warnings)
+            .map_err(|err| failed(format!("`{path}` can't be restored: {err}")))?;
+        let running = serde_json::from_str::<SnapshotMetadata>(&contents)
+            .map_err(|err| failed(format!("`{path}` can't be restored: {err}")))?
+            .running;
+
+        let mut registry = self.registry.lock().unwrap();
+        let Replacement {
+            added,
+            removed,
+            changed,
+            previous,
+        } = registry.replace_services(config.services, &self.events, &self.clock);
+        registry.unloaded_services = config.unloaded_services;
+        registry.targets = config.targets;
+        registry.groups = config.groups;
+        registry.mark_dirty();
+
+        let mut to_stop = registry
+            .services
+            .iter()
+            .filter(|(name, service)| !running.contains(name) && service.is_running())
+            .map(|(name, _)| name.clone())
+            .collect::<Vec<String>>();
+        to_stop.sort();
+        // Dependents are stopped before what they depend on.
+        let mut to_stop = registry.in_dependency_order(&to_stop);
+        to_stop.retain(|(name, service)| !running.contains(name) && service.is_running());
+        to_stop.reverse();
+
+        for name in &running {
+            if !registry.services.contains_key(name) {
+                warnings.push(format!(
+                    "the service `{name}` was running when the snapshot was taken, but couldn't be restored"
+                ));
+            }
+        }
+        registry.running = running
+            .iter()
+            .filter(|name| registry.services.contains_key(*name))
+            .cloned()
+            .collect();
+        registry.running_dirty = true;
+        let to_start = registry.in_dependency_order(&running);
+        drop(registry);
+
+        for (name, previous) in previous {
+            if previous.is_running() {
+                info!(service = name; "Stopping service `{name}`");
+                match previous.stop() {
+                    Ok(outcome) => stop_warnings(&name, outcome, warnings),
+                    Err(err) => warn!(service = name; "Failed to stop service `{name}`: {err}"),
+                }
+            }
+        }
+        let mut stopped = Vec::new();
+        for (name, service) in to_stop {
+            info!(service = name; "Stopping service `{name}`");
+            match service.stop() {
+                Ok(outcome) => stop_warnings(&name, outcome, warnings),
+                Err(err) => {
+                    warn!(service = name; "Failed to stop service `{name}`: {err}");
+                    warnings.push(format!("the service `{name}` failed to stop: {err}"));
+                }
+            }
+            stopped.push(name);
+        }
+        for (names, cause) in [
+            (&removed, EventCause::Removed),
+            (&changed, EventCause::Edited),
+            (&added, EventCause::Added),
+        ] {
+            for name in names {
+                self.events.emit_definition(
+                    name.clone(),
+                    ipc::ServiceState::Stopped,
+                    cause.clone(),
+                );
+            }
+        }
+
+        let mut started = Vec::new();
+        for (name, service) in to_start {
+            if service.is_running() {
+                continue;
+            }
+
+            info!(service = name; "Starting service `{name}`");
+            match service.start() {
+                Ok(()) => started.push(name),
+                Err(err) => {
+                    warn!(service = name; "Failed to start service `{name}`: {err}");
+                    warnings.push(format!("the service `{name}` failed to start: {err}"));
+                }
+            }
+        }
+        started.sort();
+        stopped.sort();
+
+        info!(
+            snapshot = name;
+            "Restored snapshot `{name}`: {} added, {} removed, {} changed, {} started, {} stopped",
+            added.len(),
+            removed.len(),
+            changed.len(),
+            started.len(),
+            stopped.len()
+        );
+        let mut restore = SnapshotRestore::default();
+        restore.added = added;
+        restore.removed = removed;
+        restore.changed = changed;
+        restore.started = started;
+        restore.stopped = stopped;
+        Ok(ResponseKind::SnapshotRestored(restore))
+    }
+
+    /// Lists the snapshots from the oldest to the newest, leaving out the files that can't be
+    /// read along with a warning.
+    pub fn list_snapshots(
+        &self,
+        warnings: &mut Vec<String>,
+    ) -> Result<ResponseKind, ResponseStatus> {
+        let Some(snapshots_dir) = self.context.snapshots_dir_path() else {
+            return Ok(ResponseKind::SnapshotList {
+                snapshots: Vec::new(),
+            });
+        };
+        let entries = match fs::read_dir(&snapshots_dir) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                return Ok(ResponseKind::SnapshotList {
+                    snapshots: Vec::new(),
+                });
+            }
+            Err(err) => {
+                return Err(ResponseStatus::SnapshotFailed {
+                    message: format!("failed to read `{snapshots_dir}`: {err}"),
+                });
+            }
+        };
+
+        let mut snapshots = Vec::new();
+        for entry in entries.flatten() {
+            let file_name = entry.file_name().to_string_lossy().to_string();
+            let Some(name) = file_name.strip_suffix(".json") else {
+                continue;
+            };
+            if name.starts_with('.') {
+                continue;
+            }
+
+            let path = entry.path();
+            let metadata = fs::read_to_string(&path)
+                .map_err(|err| err.to_string())
+                .and_then(|contents| {
+                    serde_json::from_str::<SnapshotMetadata>(&contents)
+                        .map_err(|err| err.to_string())
+                });
+            match metadata {
+                Ok(metadata) => snapshots.push(SnapshotInfo::new(
+                    name,
+                    metadata.created,
+                    metadata.services.len(),
+                    metadata.running.len(),
+                )),
+                Err(err) => warnings.push(format!(
+                    "skipped `{}`, which can't be read: {err}",
+                    path.display()
+                )),
+            }
+        }
+        // Timestamps are all in UTC, so they sort in the order they were taken.
+        snapshots.sort_by(|a, b| (&a.created, &a.name).cmp(&(&b.created, &b.name)));
+
+        Ok(ResponseKind::SnapshotList { snapshots })
+    }
+
+    /// Notices the services whose processes exited. Runs whenever SIGCHLD arrives, and once a
+    /// tick for the processes that aren't children of the daemon, such as those of a pidfile.
+    pub fn poll_services(&self) {
+        for (_, service) in self.services() {
+            service.state();
+        }
+    }
+
+    pub fn wait_for(
+        &self,
+        name: String,
+        timeout_ms: u64,
+        daemon_state: &DaemonState,
+        warnings: &mut Vec<String>,
+    ) -> Result<ResponseKind, ResponseStatus> {
+        let service = self.get_service(&name)?;
+
+        // Oneshot services are ready once they complete.
+        if !service.has_readiness_probe() && !matches!(service.kind, ServiceKind::Oneshot { .. }) {
+            warnings.push(
+                "the service has no readiness probe, so it counts as ready as soon as it is running"
+                    .to_string(),
+            );
+        }
+
+        let deadline = Instant::now() + Duration::from_millis(timeout_ms);
+        loop {
+            if service.is_ready() {
+                return Ok(ResponseKind::None);
+            }
+            if !service.is_running() {
+                return Err(ResponseStatus::ServiceNotRunning);
+            }
+            if Instant::now() > deadline {
+                return Err(ResponseStatus::WaitTimedOut);
+            }
+            // The daemon waits for the commands being run before stopping the services or
+            // restarting, so don't hold either up.
+            if self.is_stopping() {
+                return Err(ResponseStatus::Stopping);
+            }
+            if daemon_state.restarting.load(Ordering::SeqCst) {
+                return Err(ResponseStatus::ShuttingDown);
+            }
+
+            if service.begin_readiness_probe() {
+                service.run_readiness_probe();
+            }
+            thread::sleep(Duration::from_millis(250));
+        }
+    }
+
+    /// Probes the readiness of services that were started but aren't known to be ready yet.
+    pub fn probe_readiness(&self) {
+        for (_, service) in self.services() {
+            if service.begin_readiness_probe() {
+                thread::spawn(move || service.run_readiness_probe());
+            }
+        }
+    }
+
+    /// Restarts the services whose watched paths changed.
+    pub fn check_watched_paths(&self) {
+        if self.is_stopping() {
+            return;
+        }
+        for (name, service) in self.services() {
+            let Some(path) = service.check_watched_paths() else {
+                continue;
+            };
+
+            thread::spawn(move || {
+                info!(service = name; "Restarting service `{name}` because `{path}` changed");
+                if let Err(err) = service.restart_because(EventCause::FileChanged { path }) {
+                    warn!(service = name; "Failed to restart service `{name}`: {err}");
+                }
+            });
+        }
+    }
+
+    /// Drops the logs that are older than the retention of their service or past the size cap.
+    pub fn prune_logs(&self) {
+        for (_, service) in self.services() {
+            service.prune_logs();
+        }
+    }
+
+    /// Whether notifications get sent about the service, which it opts out of with the
+    /// `notify=false` label. Services that don't exist anymore get them.
+    pub fn notifies_about(&self, name: &String) -> bool {
+        self.get_service(name).map_or(true, |service| {
+            service.labels.get("notify").map(String::as_str) != Some("false")
+        })
+    }
+
+    /// Runs the on-failure hook of the service if the event is it exiting with a failure on its
+    /// own. Stops that were asked for never count.
+    pub fn run_failure_hook(&self, event: &Event) {
+        let EventCause::Exited { code, signal } = event.cause else {
+            return;
+        };
+        if event.new_state != ipc::ServiceState::Failed {
+            return;
+        }
+        let Ok(service) = self.get_service(&event.service) else {
+            return;
+        };
+
+        let name = event.service.clone();
+        thread::spawn(move || {
+            if let Err(err) = service.run_failure_hook(&name, code, signal) {
+                warn!(service = name; "The on-failure hook of service `{name}` failed: {err}");
+            }
+        });
+    }
+
+    /// Runs the commands of the timer services that are due.
+    pub fn run_timers(&self) {
+        if self.is_stopping() {
+            return;
+        }
+        for (name, service) in self.services() {
+            if let Err(err) = service.run_timer() {
+                warn!(service = name; "Failed to run timer `{name}`: {err}");
+            }
+        }
+    }
+
+    /// Starts the health checks that are due, each on its own thread so that a slow check
+    /// doesn't hold up the others.
+    pub fn run_health_checks(&self) {
+        for (_, service) in self.services() {
+            if service.begin_health_check() {
+                thread::spawn(move || service.run_health_check());
+            }
+        }
+    }
+
+    pub fn events(&self) -> &Arc<EventBus> {
+        &self.events
+    }
+
+    pub fn get_daemon_info(
+        &self,
+        daemon_state: &DaemonState,
+    ) -> Result<ResponseKind, ResponseStatus> {
+        let services = self.services();
+
+        let mut info = DaemonInfo::default();
+        info.version = userserversd::VERSION.to_string();
+        info.uptime_secs = daemon_state.started_at.elapsed().as_secs();
+        info.config_path = config_file_path(self.context, self.format);
+        info.config_save_error = self.flush_error.lock().unwrap().clone();
+        info.socket_path = daemon_state.socket_path.clone();
+        info.watch_mechanism = daemon_state.watch_mechanism.to_string();
+        info.watch_interval_ms = daemon_state.watch_interval.as_millis() as u64;
+        info.clients_active = daemon_state.active_clients.load(Ordering::SeqCst);
+        info.clients_max = daemon_state.max_clients;
+        info.services_total = services.len();
+        info.services_running = services
+            .iter()
+            .filter(|(_, service)| service.is_running())
+            .count();
+        Ok(ResponseKind::DaemonInfo(info))
+    }
+
+    pub fn get_daemon_environment(&self) -> Result<ResponseKind, ResponseStatus> {
+        let mut variables_total = 0;
+        let mut variables = HashMap::new();
+        for (name, value) in env::vars_os() {
+            variables_total += 1;
+
+            let name = name.to_string_lossy().to_string();
+            if !is_diagnostic_variable(&name) {
+                continue;
+            }
+
+            let value = if is_secret_variable(&name) {
+                "<hidden>".to_string()
+            } else {
+                value.to_string_lossy().to_string()
+            };
+            variables.insert(name, value);
+        }
+
+        let mut environment = DaemonEnvironment::default();
+        environment.working_directory = env::current_dir()
+            .ok()
+            .map(|path| path.to_string_lossy().to_string());
+        environment.uid = unistd::getuid().as_raw();
+        environment.gid = unistd::getgid().as_raw();
+        environment.variables_total = variables_total;
+        environment.variables = variables;
+        Ok(ResponseKind::DaemonEnvironment(environment))
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::path::PathBuf;
+
+    use nix::sys::signal::{self, Signal};
+
+    use super::*;
+    use crate::clock::TestClock;
+    use crate::scratch::scratch_dir;
+    use crate::user_context::Account;
+
+    /// A daemon of its own for each test, whose configuration and state live in an emptied
+    /// directory.
+    pub(crate) struct TestDaemon {
+        pub(crate) dir: PathBuf,
+        pub(crate) context: &'static UserContext,
+    }
+
+    impl TestDaemon {
+        pub(crate) fn new(test: &str) -> Self {
+            let dir = scratch_dir("manager", test);
+            fs::create_dir_all(dir.join("config")).unwrap();
+            let root = dir.to_string_lossy().to_string();
+            let context = UserContext::resolve_for(Account::current(), &[], |key| match key {
+                "USERSERVERSD_HOME" => Some(root.clone()),
+                "USERSERVERSD_CONFIG_DIR" => Some(format!("{root}/config")),
+                "USERSERVERSD_STATE_DIR" => Some(format!("{root}/state")),
+                "USERSERVERSD_RUNTIME_DIR" => Some(format!("{root}/runtime")),
+                _ => None,
+            });
+            Self {
+                dir,
+                context: Box::leak(Box::new(context)),
+            }
+        }
+
+        pub(crate) fn manager(&self) -> ServiceManager {
+            ServiceManager::new(
+                self.context,
+                None,
+                true,
+                ConfigFormat::Json,
+                false,
+                false,
+                None,
+            )
+            .unwrap()
+        }
+
+        /// A service running `script` with `sh -c` from the directory of the daemon.
+        pub(crate) fn script(&self, script: &str) -> ipc::Service {
+            ipc::Service::new(
+                self.dir.to_string_lossy(),
+                ipc::ServiceKind::Synchronous {
+                    command: vec!["sh".to_string(), "-c".to_string(), script.to_string()],
+                },
+            )
+        }
+    }
+
+    impl Drop for TestDaemon {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    fn add(manager: &ServiceManager, name: &str, service: ipc::Service) {
+        let mut warnings = Vec::new();
+        manager
+            .add(name.to_string(), service, false, &mut warnings)
+            .unwrap();
+        assert_eq!(warnings, Vec::<String>::new());
+    }
+
+    fn status(manager: &ServiceManager, name: &str) -> ServiceStatus {
+        match manager.get_status(name.to_string(), 0) {
+            Ok(ResponseKind::ServiceStatus(status)) => *status,
+            Ok(_) => panic!("not a status"),
+            Err(status) => panic!("no status for `{name}`: {status}"),
+        }
+    }
+
+    #[test]
+    fn queries_are_answered_while_a_stop_hangs() {
+        let daemon = TestDaemon::new("stop-hangs");
+        let manager = daemon.manager();
+        // Ignores SIGTERM, so the stop keeps waiting for it to exit.
+        add(
+            &manager,
+            "stubborn",
+            daemon.script("trap '' TERM; while :; do sleep 0.1; done"),
+        );
+        add(&manager, "bystander", daemon.script("exec sleep 1000"));
+        let pid = status(&manager, "stubborn").pid.unwrap();
+
+        thread::scope(|scope| {
+            let stop = scope.spawn(|| manager.stop("stubborn".to_string(), false, &mut Vec::new()));
+            thread::sleep(Duration::from_millis(300));
+            assert!(!stop.is_finished());
+
+            let asked_at = Instant::now();
+            assert!(status(&manager, "stubborn").running);
+            assert!(status(&manager, "bystander").running);
+            manager.list_services(None, None, HashMap::new()).unwrap();
+            manager
+                .stop("bystander".to_string(), false, &mut Vec::new())
+                .unwrap();
+            assert!(asked_at.elapsed() < Duration::from_secs(2));
+
+            signal::killpg(unistd::Pid::from_raw(pid as i32), Signal::SIGKILL).unwrap();
+            stop.join().unwrap().unwrap();
+        });
+        assert!(!status(&manager, "stubborn").running);
+        assert!(!status(&manager, "bystander").running);
+    }
+
+    #[test]
+    fn times_ago_follow_the_wall_clock_without_going_negative() {
+        let daemon = TestDaemon::new("times-ago");
+        let clock = TestClock::new();
+        let manager = daemon.manager().with_clock(clock.shared());
+        add(&manager, "sleeper", daemon.script("exec sleep 1000"));
+        let times_ago = || {
+            let status = status(&manager, "sleeper");
+            let Ok(ResponseKind::ServiceSummaries { summaries }) = manager.get_summaries() else {
+                panic!("no summaries");
+            };
+            (
+                status.runs[0].started_secs_ago,
+                summaries["sleeper"].uptime_secs,
+            )
+        };
+
+        clock.advance(Duration::from_secs(90));
+        assert_eq!(times_ago(), (90, Some(90)));
+        // Set back by an NTP correction.
+        clock.shift_wall(-3600);
+        assert_eq!(times_ago(), (0, Some(0)));
+        // A day of suspend counts, as it's what the wall clock says.
+        clock.shift_wall(3600 + 24 * 60 * 60);
+        assert_eq!(times_ago(), (86490, Some(86490)));
+
+        manager
+            .stop("sleeper".to_string(), false, &mut Vec::new())
+            .unwrap();
+        let run = &status(&manager, "sleeper").runs[0];
+        assert_eq!(run.ended_secs_ago, Some(0));
+    }
+
+    #[test]
+    fn starting_warns_about_missing_dependencies_and_watched_paths() {
+        let daemon = TestDaemon::new("start-warnings");
+        let manager = daemon.manager();
+        let mut service = daemon.script("exec sleep 1000");
+        service.depends_on = vec!["ghost".to_string()];
+        service.watch_paths = vec!["app.conf".to_string()];
+        define(&manager, "web", service);
+
+        let mut warnings = Vec::new();
+        manager.start("web".to_string(), &mut warnings).unwrap();
+        assert_eq!(
+            warnings,
+            [
+                "the service `web` depends on `ghost`, which does not exist",
+                "the watched path `app.conf` does not exist, so changes to it can only be noticed once it is created",
+            ]
+        );
+
+        // Nothing to warn about once the path is there.
+        fs::write(daemon.dir.join("app.conf"), "").unwrap();
+        manager
+            .stop("web".to_string(), false, &mut warnings)
+            .unwrap();
+        warnings.clear();
+        define(&manager, "web", daemon.script("exec sleep 1000"));
+        manager.start("web".to_string(), &mut warnings).unwrap();
+        assert_eq!(warnings, Vec::<String>::new());
+        manager
+            .stop("web".to_string(), false, &mut warnings)
+            .unwrap();
+    }
+
+    #[test]
+    fn adding_warns_when_the_service_fails_to_start() {
+        let daemon = TestDaemon::new("add-warnings");
+        let manager = daemon.manager();
+        let service = ipc::Service::new(
+            daemon.dir.to_string_lossy(),
+            ipc::ServiceKind::Synchronous {
+                command: vec!["./missing-server".to_string()],
+            },
+        );
+
+        // Forced past the validation that would have caught it.
+        let mut warnings = Vec::new();
+        manager
+            .add("web".to_string(), service, true, &mut warnings)
+            .unwrap();
+        assert_eq!(warnings.len(), 1);
+        assert!(
+            warnings[0].starts_with("the service was added but failed to start: "),
+            "{warnings:?}"
+        );
+        assert!(list(&manager).services.contains_key("web"));
+    }
+
+    #[test]
+    fn stopping_warns_about_survivors_and_failed_post_stop_hooks() {
+        let daemon = TestDaemon::new("stop-warnings");
+        let manager = daemon.manager();
+        // The background sleep moves to a session of its own, out of reach of the stop.
+        let mut service = daemon.script("setsid sleep 1000 & exec sleep 1000");
+        service.post_stop = Some(vec!["false".to_string()]);
+        add(&manager, "leaky", service);
+        thread::sleep(Duration::from_millis(300));
+
+        let mut warnings = Vec::new();
+        manager
+            .stop("leaky".to_string(), false, &mut warnings)
+            .unwrap();
+        assert_eq!(warnings.len(), 2, "{warnings:?}");
+        let (_, pids) = warnings[0]
+            .split_once(
+                "1 process(es) started by the service `leaky` are still running after it stopped: ",
+            )
+            .unwrap();
+        let survivor = unistd::Pid::from_raw(pids.parse().unwrap());
+        signal::kill(survivor, Signal::SIGKILL).unwrap();
+        assert_eq!(
+            warnings[1],
+            "the service `leaky` stopped, but its post-stop hook exited with code 1"
+        );
+    }
+
+    /// Defines a service without starting it, as if it came from the configuration file.
+    fn define(manager: &ServiceManager, name: &str, service: ipc::Service) {
+        let mut registry = manager.registry.lock().unwrap();
+        registry
+            .services
+            .insert(name.to_string(), Arc::new(ipc_service_to_service(service)));
+    }
+
+    fn list(manager: &ServiceManager) -> ServiceList {
+        match manager.list_services(None, None, HashMap::new()) {
+            Ok(ResponseKind::ServiceList(list)) => list,
+            _ => panic!("not a service list"),
+        }
+    }
+
+    // A service with enough variables and labels that unordered maps would show.
+    fn configured(daemon: &TestDaemon, port: &str) -> ipc::Service {
+        let mut service = daemon.script("exec ./server");
+        for (index, name) in ["PORT", "HOST", "MODE", "LANG", "TZ", "HOME", "PATH", "USER"]
+            .into_iter()
+            .enumerate()
+        {
+            service
+                .environment
+                .insert(name.to_string(), index.to_string());
+            service
+                .labels
+                .insert(name.to_lowercase(), index.to_string());
+        }
+        service
+            .environment
+            .insert("PORT".to_string(), port.to_string());
+        service
+    }
+
+    fn flushed(daemon: &TestDaemon, manager: &ServiceManager, format: ConfigFormat) -> String {
+        manager.flush(true).unwrap();
+        fs::read_to_string(config_file_path(daemon.context, format).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn same_services_are_written_the_same_way_every_time() {
+        for format in [ConfigFormat::Json, ConfigFormat::Toml] {
+            let daemon = TestDaemon::new("stable-config");
+            let load = || {
+                ServiceManager::new(daemon.context, None, false, format, false, false, None)
+                    .unwrap()
+            };
+
+            let manager = load();
+            for name in ["web", "db", "worker", "cache", "api"] {
+                define(&manager, name, configured(&daemon, "8080"));
+            }
+            let first = flushed(&daemon, &manager, format);
+            assert_eq!(flushed(&daemon, &manager, format), first);
+
+            // Read back by another daemon, whose maps are ordered differently.
+            let second = flushed(&daemon, &load(), format);
+            assert_eq!(second, first);
+
+            let names = ["api", "cache", "db", "web", "worker"];
+            let positions = names.map(|name| {
+                let key = match format {
+                    ConfigFormat::Json => format!("\"{name}\""),
+                    ConfigFormat::Toml => format!("[services.{name}]"),
+                };
+                first.find(&key).unwrap()
+            });
+            assert!(positions.is_sorted(), "{first}");
+            let positions = ["HOME", "HOST", "LANG", "MODE", "PATH", "PORT", "TZ", "USER"]
+                .map(|name| first[positions[0]..].find(name).unwrap());
+            assert!(positions.is_sorted(), "{first}");
+        }
+    }
+
+    #[test]
+    fn changing_a_service_only_changes_its_lines() {
+        let daemon = TestDaemon::new("minimal-diff");
+        let manager = daemon.manager();
+        for name in ["web", "db", "worker"] {
+            define(&manager, name, configured(&daemon, "8080"));
+        }
+        let before = flushed(&daemon, &manager, ConfigFormat::Json);
+
+        define(&manager, "db", configured(&daemon, "5432"));
+        let after = flushed(&daemon, &manager, ConfigFormat::Json);
+
+        assert_eq!(before.lines().count(), after.lines().count());
+        let changed = before
+            .lines()
+            .zip(after.lines())
+            .filter(|(before, after)| before != after)
+            .collect::<Vec<_>>();
+        assert_eq!(changed.len(), 1, "{changed:?}");
+        assert_eq!(changed[0].1.trim(), "\"PORT\": \"5432\",");
+    }
+
+    #[test]
+    fn service_list_is_converted_again_only_once_definitions_change() {
+        let daemon = TestDaemon::new("list-cache");
+        let manager = daemon.manager();
+        define(&manager, "a", daemon.script("true"));
+        define(&manager, "b", daemon.script("true"));
+
+        let first = list(&manager);
+        let second = list(&manager);
+        assert!(Arc::ptr_eq(&first.services["a"], &second.services["a"]));
+        assert!(Arc::ptr_eq(&first.services["b"], &second.services["b"]));
+
+        // Only the services that changed are converted again.
+        define(&manager, "b", daemon.script("false"));
+        let third = list(&manager);
+        assert!(Arc::ptr_eq(&first.services["a"], &third.services["a"]));
+        assert!(!Arc::ptr_eq(&first.services["b"], &third.services["b"]));
+
+        manager.remove("b".to_string()).unwrap();
+        let fourth = list(&manager);
+        assert!(Arc::ptr_eq(&first.services["a"], &fourth.services["a"]));
+        assert!(!fourth.services.contains_key("b"));
+        assert_eq!(fourth.states.len(), 1);
+        assert_eq!(manager.service_list_cache.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn service_list_follows_groups_being_moved() {
+        let daemon = TestDaemon::new("list-cache-groups");
+        let manager = daemon.manager();
+        let mut service = daemon.script("true");
+        service.group = Some("old".to_string());
+        define(&manager, "a", service);
+
+        assert_eq!(list(&manager).services["a"].group.as_deref(), Some("old"));
+        manager
+            .move_group("old".to_string(), Some("new".to_string()))
+            .unwrap();
+        assert_eq!(list(&manager).services["a"].group.as_deref(), Some("new"));
+        let listed = manager
+            .list_services(Some("new".to_string()), None, HashMap::new())
+            .unwrap();
+        assert!(matches!(listed, ResponseKind::ServiceList(list) if list.services.len() == 1));
+    }
+
+    // Waits for the file a service writes to appear, returning what is in it.
+    fn written(path: &Path) -> String {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            if let Ok(contents) = fs::read_to_string(path)
+                && contents.ends_with('\n')
+            {
+                return contents;
+            }
+            assert!(
+                Instant::now() < deadline,
+                "`{}` wasn't written",
+                path.display()
+            );
+            thread::sleep(Duration::from_millis(20));
+        }
+    }
+
+    #[test]
+    fn expansions_happen_at_each_start_and_the_config_keeps_them_as_written() {
+        let daemon = TestDaemon::new("expansions");
+        let manager = daemon.manager();
+        let output = daemon.dir.join("greeting");
+        let greeter = |greeting: &str| {
+            let mut service = daemon.script("echo \"$1\" > \"$2\"");
+            service.working_directory = "${WORKDIR}".to_string();
+            if let ipc::ServiceKind::Synchronous { command } = &mut service.kind {
+                command.extend(["sh", "${GREETING}, ${WHO}", "${OUT}"].map(String::from));
+            }
+            service.environment = [
+                ("WORKDIR", daemon.dir.to_string_lossy().to_string()),
+                ("GREETING", greeting.to_string()),
+                ("WHO", "world".to_string()),
+                ("OUT", "greeting".to_string()),
+            ]
+            .into_iter()
+            .map(|(key, value)| (key.to_string(), value))
+            .collect();
+            service
+        };
+
+        add(&manager, "greeter", greeter("hello"));
+        assert_eq!(written(&output), "hello, world\n");
+        let config = flushed(&daemon, &manager, ConfigFormat::Json);
+        assert!(config.contains("\"${WORKDIR}\""), "{config}");
+        assert!(config.contains("\"${GREETING}, ${WHO}\""), "{config}");
+        assert!(!config.contains("hello, world"), "{config}");
+
+        // Changing the environment changes what the next start expands to.
+        manager.remove("greeter".to_string()).unwrap();
+        fs::remove_file(&output).unwrap();
+        add(&manager, "greeter", greeter("goodbye"));
+        assert_eq!(written(&output), "goodbye, world\n");
+        let status = status(&manager, "greeter");
+        assert_eq!(status.service.working_directory, "${WORKDIR}");
+    }
+
+    #[test]
+    fn malformed_definitions_are_refused_even_when_forced() {
+        let daemon = TestDaemon::new("malformed");
+        let manager = daemon.manager();
+        let with = |change: fn(&mut ipc::Service)| {
+            let mut service = daemon.script("true");
+            change(&mut service);
+            service
+        };
+        let asynchronous = |start: &[&str], stop: &[&str]| {
+            let mut service = daemon.script("true");
+            service.kind = ipc::ServiceKind::Asynchronous {
+                start_command: start.iter().map(|arg| arg.to_string()).collect(),
+                stop_command: stop.iter().map(|arg| arg.to_string()).collect(),
+                stop_environment: None,
+                stop_working_directory: None,
+                pidfile: None,
+                command_timeout_secs: None,
+            };
+            service
+        };
+        let cases = [
+            ("", daemon.script("true"), "must not be empty"),
+            ("a\nb", daemon.script("true"), "newlines or NUL"),
+            ("a\0b", daemon.script("true"), "newlines or NUL"),
+            (&*"x".repeat(129), daemon.script("true"), "longer than 128"),
+            (
+                "empty",
+                with(|service| {
+                    service.kind = ipc::ServiceKind::Synchronous {
+                        command: Vec::new(),
+                    }
+                }),
+                "the command must not be empty",
+            ),
+            (
+                "no-stop",
+                asynchronous(&["true"], &[]),
+                "the stop command must not be empty",
+            ),
+            (
+                "no-check",
+                with(|service| service.health_check = Some(Vec::new())),
+                "the health check must not be empty",
+            ),
+            (
+                "no-hook",
+                with(|service| service.post_stop = Some(Vec::new())),
+                "the post-stop hook must not be empty",
+            ),
+            (
+                "assignment",
+                with(|service| {
+                    service.environment.insert("A=B".to_string(), String::new());
+                }),
+                "`A=B` is not a valid",
+            ),
+            (
+                "unnamed",
+                with(|service| {
+                    service.environment.insert(String::new(), "1".to_string());
+                }),
+                "`` is not a valid",
+            ),
+            (
+                "nul",
+                with(|service| {
+                    service
+                        .environment
+                        .insert("A\0".to_string(), "1".to_string());
+                }),
+                "`A\\0` is not a valid",
+            ),
+        ];
+
+        for (name, service, reason) in cases {
+            let result = manager.add(name.to_string(), service, true, &mut Vec::new());
+            match result {
+                Err(ResponseStatus::ValidationFailed { message }) => {
+                    assert!(message.contains(reason), "{name:?}: {message}");
+                }
+                _ => panic!("{name:?} wasn't refused"),
+            }
+        }
+        assert!(list(&manager).services.is_empty());
+        // A name right at the limit is fine.
+        add(&manager, &"x".repeat(128), daemon.script("true"));
+    }
+
+    #[test]
+    fn invalid_config_entries_are_skipped_and_kept_in_the_file() {
+        let daemon = TestDaemon::new("invalid-entries");
+        let path = config_file_path(daemon.context, ConfigFormat::Json).unwrap();
+        let manager = daemon.manager();
+        define(&manager, "good", daemon.script("true"));
+        define(&manager, "empty", daemon.script("true"));
+        define(&manager, "bad-env", daemon.script("true"));
+        let mut config =
+            serde_json::from_str::<Value>(&flushed(&daemon, &manager, ConfigFormat::Json)).unwrap();
+        drop(manager);
+
+        let services = &mut config["services"];
+        services["empty"]["kind"]["Synchronous"]["command"] = Value::Array(Vec::new());
+        services["bad-env"]["environment"] = json!({ "A=B": "1" });
+        services[""] = services["good"].clone();
+        services["not-a-service"] = json!({ "kind": 7 });
+        let contents = serde_json::to_string_pretty(&config).unwrap();
+        fs::write(&path, &contents).unwrap();
+
+        let mut skipped = Vec::new();
+        assert!(parse_config(&contents, ConfigFormat::Json, &mut skipped).is_ok());
+        assert_eq!(skipped.len(), 4, "{skipped:?}");
+        assert!(skipped[0].contains("service ``"), "{skipped:?}");
+        assert!(skipped[1].contains("`bad-env`"), "{skipped:?}");
+        assert!(
+            skipped[2]
+                .ends_with("`empty` from the configuration file: the command must not be empty"),
+            "{skipped:?}"
+        );
+        assert!(skipped[3].contains("`not-a-service`"), "{skipped:?}");
+
+        // Startup goes on with the services that could be read.
+        let manager = daemon.manager();
+        let names = list(&manager).services.into_keys().collect::<Vec<_>>();
+        assert_eq!(names, ["good"]);
+
+        // Writing the configuration again doesn't lose the entries that were skipped.
+        define(&manager, "added", daemon.script("true"));
+        let written =
+            serde_json::from_str::<Value>(&flushed(&daemon, &manager, ConfigFormat::Json)).unwrap();
+        for name in ["", "bad-env", "empty", "not-a-service"] {
+            assert_eq!(
+                written["services"][name], config["services"][name],
+                "{name}"
+            );
+        }
+        assert!(written["services"]["added"].is_object());
+    }
+
+    #[test]
+    fn shell_services_get_variables_from_the_shell_as_plain_values() {
+        let daemon = TestDaemon::new("shell-variables");
+        let manager = daemon.manager();
+        let mut service = daemon.script("printf '%s\\n' \"${VALUE}\" > out");
+        service.kind = ipc::ServiceKind::Synchronous {
+            command: vec!["printf '%s\\n' \"${VALUE}\" > out".to_string()],
+        };
+        service.shell = true;
+        let value = "$(touch injected); touch injected; `touch injected`";
+        service
+            .environment
+            .insert("VALUE".to_string(), value.to_string());
+
+        add(&manager, "shell", service);
+        assert_eq!(written(&daemon.dir.join("out")), format!("{value}\n"));
+        assert!(!daemon.dir.join("injected").exists());
+    }
+
+    #[test]
+    fn stopping_a_shell_service_stops_its_whole_pipeline() {
+        let daemon = TestDaemon::new("shell-pipeline");
+        let manager = daemon.manager();
+        let mut service = daemon.script("");
+        service.kind = ipc::ServiceKind::Synchronous {
+            command: vec!["sleep 1000 | cat | cat".to_string()],
+        };
+        service.shell = true;
+        add(&manager, "pipeline", service);
+        let group = unistd::Pid::from_raw(status(&manager, "pipeline").pid.unwrap() as i32);
+        assert!(signal::killpg(group, None).is_ok());
+
+        manager
+            .stop("pipeline".to_string(), false, &mut Vec::new())
+            .unwrap();
+        // The members of the pipeline are left for init to reap once they are killed.
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while signal::killpg(group, None).is_ok() {
+            assert!(Instant::now() < deadline, "the pipeline outlived the stop");
+            thread::sleep(Duration::from_millis(20));
+        }
+    }
+
+    #[test]
+    fn operations_report_the_state_they_found_the_service_in() {
+        let daemon = TestDaemon::new("found-state");
+        let manager = daemon.manager();
+        add(&manager, "web", daemon.script("exec sleep 1000"));
+        let name = || "web".to_string();
+
+        assert!(matches!(
+            manager.start(name(), &mut Vec::new()),
+            Err(ResponseStatus::ServiceAlreadyRunning)
+        ));
+        assert!(manager.stop(name(), false, &mut Vec::new()).is_ok());
+
+        assert!(matches!(
+            manager.stop(name(), false, &mut Vec::new()),
+            Err(ResponseStatus::ServiceNotRunning)
+        ));
+        assert!(matches!(
+            manager.restart(name(), &mut Vec::new()),
+            Err(ResponseStatus::ServiceNotRunning)
+        ));
+        for result in [
+            manager.pause(name()),
+            manager.resume(name()),
+            manager.reload(name()),
+        ] {
+            assert!(matches!(result, Err(ResponseStatus::ServiceNotRunning)));
+        }
+        assert!(matches!(
+            manager.stop("nothing".to_string(), false, &mut Vec::new()),
+            Err(ResponseStatus::ServiceDoesNotExist)
+        ));
+    }
+
+    #[test]
+    fn spawns_the_system_refuses_are_reported() {
+        let daemon = TestDaemon::new("refused-spawn");
+        let manager = daemon.manager();
+        let file = daemon.dir.join("not-a-directory");
+        fs::write(&file, "").unwrap();
+        let mut service = daemon.script("true");
+        service.working_directory = file.to_string_lossy().to_string();
+        define(&manager, "misplaced", service);
+
+        match manager.start("misplaced".to_string(), &mut Vec::new()) {
+            Err(ResponseStatus::IoError { message }) => {
+                assert!(message.contains("Not a directory"), "{message}");
+            }
+            Err(status) => panic!("failed with `{status}` instead"),
+            Ok(_) => panic!("started"),
+        }
+    }
+
+    fn reloaded(manager: &ServiceManager) -> ConfigReload {
+        match manager.reload_config(&mut Vec::new()) {
+            Ok(ResponseKind::ConfigReloaded(reload)) => reload,
+            Ok(_) => panic!("not a reload"),
+            Err(status) => panic!("failed to reload: {status}"),
+        }
+    }
+
+    #[test]
+    fn reloads_wait_for_changes_in_flight_and_turn_new_ones_away() {
+        let daemon = TestDaemon::new("reload-exclusive");
+        let manager = daemon.manager();
+        add(&manager, "api", daemon.script("exec sleep 1000"));
+        let pid = status(&manager, "api").pid;
+
+        let change = manager.begin_change().unwrap();
+        thread::scope(|scope| {
+            let reload = scope.spawn(|| reloaded(&manager));
+            // Once the reload waits for its turn, no other change gets in before it.
+            let deadline = Instant::now() + Duration::from_secs(5);
+            while let Some(other) = manager.begin_change() {
+                drop(other);
+                assert!(Instant::now() < deadline, "the reload never waited");
+                thread::sleep(Duration::from_millis(10));
+            }
+            assert!(!reload.is_finished());
+
+            // The change finishing in the meantime is what the reload reads back.
+            define(&manager, "worker", daemon.script("exec sleep 1000"));
+            manager.registry.lock().unwrap().mark_dirty();
+            drop(change);
+
+            let reload = reload.join().unwrap();
+            assert_eq!(reload.added, Vec::<String>::new());
+            assert_eq!(reload.removed, Vec::<String>::new());
+        });
+        assert!(manager.begin_change().is_some());
+        assert!(list(&manager).services.contains_key("worker"));
+        assert_eq!(status(&manager, "api").pid, pid);
+    }
+
+    #[test]
+    fn reloads_apply_what_was_edited_by_hand() {
+        let daemon = TestDaemon::new("reload-edited");
+        let manager = daemon.manager();
+        for name in ["kept", "dropped", "edited"] {
+            add(&manager, name, daemon.script("exec sleep 1000"));
+        }
+        let kept = status(&manager, "kept").pid;
+        let edited = status(&manager, "edited").pid;
+
+        let path = config_file_path(daemon.context, ConfigFormat::Json).unwrap();
+        let mut config: serde_json::Value =
+            serde_json::from_str(&flushed(&daemon, &manager, ConfigFormat::Json)).unwrap();
+        let services = config["services"].as_object_mut().unwrap();
+        services.remove("dropped");
+        services["edited"]["environment"] = serde_json::json!({ "MODE": "debug" });
+        let fresh = services["kept"].clone();
+        services.insert("fresh".to_string(), fresh);
+        fs::write(&path, serde_json::to_string_pretty(&config).unwrap()).unwrap();
+
+        assert_eq!(
+            reloaded(&manager),
+            ConfigReload::new(
+                vec!["fresh".to_string()],
+                vec!["dropped".to_string()],
+                vec!["edited".to_string()],
+            )
+        );
+        assert!(!list(&manager).services.contains_key("dropped"));
+        assert!(status(&manager, "fresh").running);
+        assert_eq!(status(&manager, "kept").pid, kept);
+        // Restarted with its new definition, as it was running.
+        let restarted = status(&manager, "edited");
+        assert!(restarted.running);
+        assert_ne!(restarted.pid, edited);
+    }
+
+    // A service of each kind, with every optional field set on one of them at least.
+    fn every_kind(daemon: &TestDaemon) -> HashMap<String, ipc::Service> {
+        let dir = daemon.dir.to_string_lossy().to_string();
+        let command = |words: &[&str]| words.iter().map(|word| word.to_string()).collect();
+
+        let mut web = ipc::Service::new(
+            &dir,
+            ipc::ServiceKind::Synchronous {
+                command: command(&["./server", "--port", "${PORT}"]),
+            },
+        );
+        web.environment = HashMap::from([
+            ("PORT".to_string(), "8080".to_string()),
+            ("GREETING".to_string(), "say \"hi\" = 'hello'".to_string()),
+        ]);
+        web.group = Some("frontend".to_string());
+        web.ports = vec![8080, 8443];
+        web.health_check = Some(command(&["curl", "-f", "localhost:8080"]));
+        web.health_check_interval_secs = Some(15);
+        web.ready_tcp_port = Some(8080);
+        web.watch_paths = vec!["src".to_string(), "/etc/web.conf".to_string()];
+        web.watch_disabled = true;
+        web.depends_on = vec!["db".to_string()];
+        web.log_retention_secs = Some(86400);
+        web.pre_start = Some(command(&["make"]));
+        web.post_stop = Some(command(&["rm", "-f", "web.sock"]));
+        web.on_failure = Some(command(&["notify-send", "web failed"]));
+        web.clean_environment = true;
+        web.description = Some("The site,\nwith a second line and ünïcödé".to_string());
+        web.labels = HashMap::from([("tier".to_string(), "front".to_string())]);
+        web.reload_signal = Some("SIGHUP".to_string());
+        web.create_working_directory = true;
+        web.process_title = true;
+
+        let mut db = ipc::Service::new(
+            &dir,
+            ipc::ServiceKind::Asynchronous {
+                start_command: command(&["pg_ctl", "start"]),
+                stop_command: command(&["pg_ctl", "stop", "-m", "fast"]),
+                stop_environment: Some(HashMap::from([(
+                    "PGDATA".to_string(),
+                    "/var/db".to_string(),
+                )])),
+                stop_working_directory: Some("/var/db".to_string()),
+                pidfile: Some("/var/db/postmaster.pid".to_string()),
+                command_timeout_secs: Some(90),
+            },
+        );
+        db.ready_command = Some(command(&["pg_isready"]));
+        db.reload_command = Some(command(&["pg_ctl", "reload"]));
+
+        let mut migrate = ipc::Service::new(
+            &dir,
+            ipc::ServiceKind::Oneshot {
+                command: command(&["./migrate up && echo done"]),
+            },
+        );
+        migrate.shell = true;
+
+        let backup = ipc::Service::new(
+            &dir,
+            ipc::ServiceKind::Timer {
+                command: command(&["./backup"]),
+                interval_secs: 3600,
+            },
+        );
+
+        HashMap::from([
+            ("web".to_string(), web),
+            ("db".to_string(), db),
+            ("migrate".to_string(), migrate),
+            ("backup".to_string(), backup),
+        ])
+    }
+
+    #[test]
+    fn every_kind_and_optional_field_survives_both_formats() {
+        for format in [ConfigFormat::Json, ConfigFormat::Toml] {
+            let daemon = TestDaemon::new("round-trip");
+            let load = || {
+                ServiceManager::new(daemon.context, None, false, format, false, false, None)
+                    .unwrap()
+            };
+            let services = every_kind(&daemon);
+
+            let manager = load();
+            for (name, service) in &services {
+                define(&manager, name, service.clone());
+            }
+            flushed(&daemon, &manager, format);
+
+            let read_back = list(&load())
+                .services
+                .into_iter()
+                .map(|(name, service)| (name, Arc::unwrap_or_clone(service)))
+                .collect::<HashMap<_, _>>();
+            assert_eq!(read_back, services);
+        }
+    }
+
+    #[test]
+    fn unset_fields_are_left_out_of_toml_and_read_back_as_unset() {
+        let daemon = TestDaemon::new("toml-unset");
+        let manager = ServiceManager::new(
+            daemon.context,
+            None,
+            false,
+            ConfigFormat::Toml,
+            false,
+            false,
+            None,
+        )
+        .unwrap();
+        let service = ipc::Service::new(
+            daemon.dir.to_string_lossy(),
+            ipc::ServiceKind::Oneshot {
+                command: vec!["true".to_string()],
+            },
+        );
+        define(&manager, "bare", service.clone());
+
+        let contents = flushed(&daemon, &manager, ConfigFormat::Toml);
+        assert!(!contents.contains("description"), "{contents}");
+        assert!(!contents.contains("health_check"), "{contents}");
+        let manager = ServiceManager::new(
+            daemon.context,
+            None,
+            false,
+            ConfigFormat::Toml,
+            false,
+            false,
+            None,
+        )
+        .unwrap();
+        assert_eq!(*list(&manager).services["bare"], service);
+        // Nothing was written in the other format.
+        assert!(
+            !Path::new(&config_file_path(daemon.context, ConfigFormat::Json).unwrap()).exists()
+        );
+    }
+
+    fn definition(daemon: &TestDaemon, command: &str) -> Value {
+        serde_json::to_value(ipc_service_to_service(daemon.script(command))).unwrap()
+    }
+
+    #[test]
+    fn versions_of_unmarked_files_are_told_by_the_shape_of_their_values() {
+        let daemon = TestDaemon::new("config-shape");
+        let service = definition(&daemon, "true");
+        let version = |config: Value| config_version(&config);
+
+        assert_eq!(version(json!({})), Ok(0));
+        assert_eq!(version(json!({ "web": service })), Ok(0));
+        // Version 0 maps named like the keys of version 1 are still bare service maps.
+        assert_eq!(version(json!({ "services": service })), Ok(0));
+        assert_eq!(
+            version(json!({ "groups": service, "targets": service })),
+            Ok(0)
+        );
+        assert_eq!(version(json!({ "version": service })), Ok(0));
+
+        assert_eq!(version(json!({ "services": {} })), Ok(1));
+        assert_eq!(
+            version(json!({ "services": { "services": service }, "targets": {} })),
+            Ok(1)
+        );
+        assert_eq!(version(json!({ "version": 3, "services": {} })), Ok(3));
+        assert!(version(json!({ "version": "1" })).is_err());
+        assert!(version(json!([service])).is_err());
+    }
+
+    #[test]
+    fn legacy_service_maps_are_migrated_whatever_their_services_are_called() {
+        let daemon = TestDaemon::new("legacy-names");
+        let path = config_file_path(daemon.context, ConfigFormat::Json).unwrap();
+        let legacy = json!({
+            "services": definition(&daemon, "exec ./api"),
+            "groups": definition(&daemon, "exec ./worker"),
+            "version": definition(&daemon, "exec ./version-check"),
+        });
+        fs::write(&path, serde_json::to_string_pretty(&legacy).unwrap()).unwrap();
+
+        let manager = daemon.manager();
+        let mut names = list(&manager).services.into_keys().collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(names, ["groups", "services", "version"]);
+
+        // Saved in the current version, and read back the same way.
+        let written =
+            serde_json::from_str::<Value>(&flushed(&daemon, &manager, ConfigFormat::Json)).unwrap();
+        assert_eq!(written["version"], CONFIG_VERSION);
+        assert_eq!(written["services"]["services"], legacy["services"]);
+        let reloaded = daemon.manager();
+        assert_eq!(list(&reloaded).services, list(&manager).services);
+    }
+
+    #[test]
+    fn files_of_a_newer_version_are_refused_and_left_alone() {
+        let daemon = TestDaemon::new("config-too-new");
+        let path = config_file_path(daemon.context, ConfigFormat::Json).unwrap();
+        let contents = json!({ "version": CONFIG_VERSION + 1, "services": {}, "pools": {} });
+        let contents = serde_json::to_string_pretty(&contents).unwrap();
+        fs::write(&path, &contents).unwrap();
+
+        let err = ServiceManager::new(
+            daemon.context,
+            None,
+            false,
+            ConfigFormat::Json,
+            false,
+            false,
+            None,
+        )
+        .err()
+        .unwrap();
+        assert!(err.contains("use a newer userserversd"), "{err}");
+        assert_eq!(fs::read_to_string(&path).unwrap(), contents);
+    }
+
+    #[test]
+    fn services_added_right_before_a_reload_survive_it() {
+        let daemon = TestDaemon::new("reload-unsaved");
+        let manager = daemon.manager();
+        add(&manager, "a", daemon.script("exec sleep 1000"));
+        manager.flush(false).unwrap();
+        // Not saved yet, as the background flush only comes once a second.
+        add(&manager, "b", daemon.script("exec sleep 1000"));
+        let pid = status(&manager, "b").pid;
+
+        let reload = reloaded(&manager);
+        assert_eq!(reload, ConfigReload::default());
+        assert_eq!(status(&manager, "b").pid, pid);
+        let path = config_file_path(daemon.context, ConfigFormat::Json).unwrap();
+        assert!(fs::read_to_string(path).unwrap().contains("\"b\""));
+    }
+
+    #[test]
+    fn reloads_are_refused_while_changes_cant_be_saved() {
+        let daemon = TestDaemon::new("reload-unsavable");
+        let manager = daemon.manager();
+        add(&manager, "kept", daemon.script("exec sleep 1000"));
+        manager.flush(false).unwrap();
+        define(&manager, "unsaved", daemon.script("exec sleep 1000"));
+        manager.registry.lock().unwrap().mark_dirty();
+        // Writing the file fails once a directory is in its place.
+        let path = config_file_path(daemon.context, ConfigFormat::Json).unwrap();
+        fs::remove_file(&path).unwrap();
+        fs::create_dir(&path).unwrap();
+
+        match manager.reload_config(&mut Vec::new()) {
+            Err(ResponseStatus::ConfigReloadFailed { message }) => {
+                assert!(message.contains("couldn't be written"), "{message}");
+            }
+            Err(status) => panic!("failed with `{status}` instead"),
+            Ok(_) => panic!("reloaded"),
+        }
+        let services = list(&manager).services;
+        assert!(services.contains_key("kept") && services.contains_key("unsaved"));
+        assert!(status(&manager, "kept").running);
+
+        // Reloading works again once the changes can be saved.
+        fs::remove_dir(&path).unwrap();
+        assert_eq!(reloaded(&manager), ConfigReload::default());
+        assert!(list(&manager).services.contains_key("unsaved"));
+    }
+
+    #[test]
+    fn stopping_a_service_warns_about_the_dependents_left_running() {
+        let daemon = TestDaemon::new("running-dependents");
+        let manager = daemon.manager();
+        let dependent = |dependency: &str| {
+            let mut service = daemon.script("exec sleep 1000");
+            service.depends_on = vec![dependency.to_string()];
+            service
+        };
+        add(&manager, "db", daemon.script("exec sleep 1000"));
+        add(&manager, "web", dependent("db"));
+        add(&manager, "report", dependent("web"));
+        define(&manager, "worker", dependent("db"));
+
+        let mut warnings = Vec::new();
+        manager
+            .stop("db".to_string(), false, &mut warnings)
+            .unwrap();
+        assert_eq!(
+            warnings,
+            [
+                "the following services depend on `db` and are still running: report, web. Pass --with-dependents to stop them as well."
+            ]
+        );
+        assert!(status(&manager, "web").running);
 
-        Ok(ResponseKind::ServiceList { services })
+        let mut warnings = Vec::new();
+        manager
+            .stop("web".to_string(), true, &mut warnings)
+            .unwrap();
+        assert_eq!(warnings, Vec::<String>::new());
+        assert!(!status(&manager, "report").running);
     }
 }
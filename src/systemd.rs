@@ -0,0 +1,59 @@
+use std::env;
+use std::os::fd::{BorrowedFd, RawFd};
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+
+use nix::fcntl::{self, FcntlArg, FdFlag};
+use nix::unistd;
+
+/// The first descriptor passed by socket activation, as defined by `sd_listen_fds(3)`.
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Takes the socket systemd passed to the daemon through socket activation, if it did. The
+/// variables describing it are removed, so that the services don't think it's meant for them.
+pub fn take_listener_fd() -> Option<RawFd> {
+    let pid = env::var("LISTEN_PID").ok();
+    let fds = env::var("LISTEN_FDS").ok();
+    // SAFETY: this runs before the daemon spawns any other thread.
+    unsafe {
+        env::remove_var("LISTEN_PID");
+        env::remove_var("LISTEN_FDS");
+        env::remove_var("LISTEN_FDNAMES");
+    }
+
+    if pid?.parse::<i32>().ok()? != unistd::getpid().as_raw() {
+        return None;
+    }
+    match fds?.parse::<RawFd>().ok()? {
+        0 => return None,
+        1 => {}
+        fds => warn!("systemd passed {fds} sockets, only the first one will be listened on"),
+    }
+
+    // SAFETY: systemd passed the descriptor to this process, which owns it from now on.
+    let fd = unsafe { BorrowedFd::borrow_raw(LISTEN_FDS_START) };
+    if let Err(err) = fcntl::fcntl(fd, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC)) {
+        warn!("Failed to keep the socket passed by systemd from the services: {err}");
+    }
+    Some(LISTEN_FDS_START)
+}
+
+/// Tells systemd about the state of the daemon, such as `READY=1`, if it runs it as a notify
+/// service. Does nothing otherwise.
+pub fn notify(state: &str) {
+    let Ok(path) = env::var("NOTIFY_SOCKET") else {
+        return;
+    };
+
+    let address = match path.strip_prefix('@') {
+        Some(name) => SocketAddr::from_abstract_name(name),
+        None => SocketAddr::from_pathname(&path),
+    };
+    let result = address.and_then(|address| {
+        UnixDatagram::unbound()?.send_to_addr(state.as_bytes(), &address)?;
+        Ok(())
+    });
+    if let Err(err) = result {
+        warn!("Failed to notify systemd of `{state}`: {err}");
+    }
+}
@@ -0,0 +1,1217 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use userserversd::{ipc, user_context};
+
+/// A systemd user unit, translated into a userserversd service.
+pub struct ImportedUnit {
+    pub name: String,
+    pub service: ipc::Service,
+    /// The parts of the unit that have no equivalent, and were left out.
+    pub warnings: Vec<String>,
+}
+
+struct Directive {
+    section: String,
+    key: String,
+    value: String,
+    line: usize,
+}
+
+/// The directives of the `[Service]` section that are translated, along with `Description=`.
+/// Every other directive is reported as unsupported, besides those of `[Install]`, which only
+/// matter to `systemctl enable`.
+const SUPPORTED_DIRECTIVES: &[&str] = &[
+    "Type",
+    "ExecStart",
+    "ExecStartPre",
+    "ExecStop",
+    "ExecStopPost",
+    "ExecReload",
+    "WorkingDirectory",
+    "Environment",
+    "EnvironmentFile",
+    "PIDFile",
+    "TimeoutSec",
+    "TimeoutStartSec",
+    "TimeoutStopSec",
+];
+
+/// Parses a systemd time span such as `90`, `5min 30s` or `1h`, in whole seconds rounded up.
+/// `infinity` has no equivalent, so it is `None` like an invalid span.
+fn parse_time_span(value: &str) -> Option<u64> {
+    let mut total_ms = 0u64;
+    let mut rest = value.trim();
+    if rest.is_empty() {
+        return None;
+    }
+    while !rest.is_empty() {
+        let number_length = rest
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .unwrap_or(rest.len());
+        let number = rest[..number_length].parse::<f64>().ok()?;
+        rest = rest[number_length..].trim_start();
+        let unit_length = rest
+            .find(|c: char| !c.is_ascii_alphabetic())
+            .unwrap_or(rest.len());
+        let unit_ms = match &rest[..unit_length] {
+            "ms" | "msec" => 1.0,
+            "" | "s" | "sec" | "second" | "seconds" => 1000.0,
+            "m" | "min" | "minute" | "minutes" => 60_000.0,
+            "h" | "hr" | "hour" | "hours" => 3_600_000.0,
+            "d" | "day" | "days" => 86_400_000.0,
+            _ => return None,
+        };
+        rest = rest[unit_length..].trim_start();
+        total_ms += (number * unit_ms) as u64;
+    }
+    Some(total_ms.div_ceil(1000))
+}
+
+/// Splits a unit file into its directives, joining continued lines and leaving out comments.
+fn parse_directives(contents: &str) -> Result<Vec<Directive>, String> {
+    let mut directives = Vec::new();
+    let mut section = String::new();
+    let mut lines = contents.lines().enumerate();
+    while let Some((index, line)) = lines.next() {
+        let mut line = line.trim().to_string();
+        while let Some(start) = line.strip_suffix('\\') {
+            line = format!("{start} ");
+            // Comments within a continued line are left out without ending it.
+            match lines.find(|(_, next)| !next.trim_start().starts_with(['#', ';'])) {
+                Some((_, next)) => line.push_str(next.trim()),
+                None => break,
+            }
+        }
+        let line = line.trim();
+
+        if line.is_empty() || line.starts_with(['#', ';']) {
+            continue;
+        }
+        if let Some(name) = line
+            .strip_prefix('[')
+            .and_then(|line| line.strip_suffix(']'))
+        {
+            section = name.to_string();
+            continue;
+        }
+
+        let Some((key, value)) = line.split_once('=') else {
+            return Err(format!("line {}: expected `Key=Value`", index + 1));
+        };
+        if section.is_empty() {
+            return Err(format!(
+                "line {}: directive outside of a section",
+                index + 1
+            ));
+        }
+        directives.push(Directive {
+            section: section.clone(),
+            key: key.trim().to_string(),
+            value: value.trim().to_string(),
+            line: index + 1,
+        });
+    }
+    Ok(directives)
+}
+
+/// Replaces the `%` specifiers of a value, such as `%h` for the home directory. Specifiers that
+/// can't be resolved are kept as they are, along with a warning.
+fn expand_specifiers(value: &str, unit_name: &str, warnings: &mut Vec<String>) -> String {
+    let context = user_context::get();
+    let home = context.home.clone().unwrap_or_else(|| "~".to_string());
+    let env = |key: &str| std::env::var(key).ok().filter(|value| !value.is_empty());
+    let (prefix, instance) = match unit_name.split_once('@') {
+        Some((prefix, instance)) => (prefix, instance),
+        None => (unit_name, ""),
+    };
+
+    let mut expanded = String::new();
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            expanded.push(c);
+            continue;
+        }
+
+        let Some(specifier) = chars.next() else {
+            expanded.push('%');
+            break;
+        };
+        let replacement = match specifier {
+            '%' => Some("%".to_string()),
+            'h' => Some(home.clone()),
+            'u' => context.username.clone(),
+            'U' => Some(context.uid.to_string()),
+            'n' => Some(format!("{unit_name}.service")),
+            'N' => Some(unit_name.to_string()),
+            'p' => Some(prefix.to_string()),
+            'i' | 'I' => Some(instance.to_string()),
+            't' => env("XDG_RUNTIME_DIR"),
+            'E' => Some(env("XDG_CONFIG_HOME").unwrap_or_else(|| format!("{home}/.config"))),
+            'S' => Some(env("XDG_STATE_HOME").unwrap_or_else(|| format!("{home}/.local/state"))),
+            'C' => Some(env("XDG_CACHE_HOME").unwrap_or_else(|| format!("{home}/.cache"))),
+            'T' => Some("/tmp".to_string()),
+            'V' => Some("/var/tmp".to_string()),
+            _ => None,
+        };
+        match replacement {
+            Some(replacement) => expanded.push_str(&replacement),
+            None => {
+                warnings.push(format!(
+                    "the specifier `%{specifier}` isn't supported, so it was kept as it is"
+                ));
+                expanded.push('%');
+                expanded.push(specifier);
+            }
+        }
+    }
+    expanded
+}
+
+/// Splits a value into words the way systemd does, with single and double quotes and C-style
+/// escapes.
+fn split_words(value: &str) -> Result<Vec<String>, String> {
+    let mut words = Vec::new();
+    let mut word: Option<String> = None;
+    let mut quote = None;
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        match (c, quote) {
+            ('\\', _) => {
+                let escaped = match chars.next() {
+                    Some('n') => '\n',
+                    Some('t') => '\t',
+                    Some('r') => '\r',
+                    Some(c) => c,
+                    None => return Err("the value ends with a lone `\\`".to_string()),
+                };
+                word.get_or_insert_default().push(escaped);
+            }
+            ('"' | '\'', None) => {
+                quote = Some(c);
+                word.get_or_insert_default();
+            }
+            (c, Some(open)) if c == open => quote = None,
+            (c, None) if c.is_whitespace() => words.extend(word.take()),
+            (c, _) => word.get_or_insert_default().push(c),
+        }
+    }
+    if quote.is_some() {
+        return Err("the value has an unterminated quote".to_string());
+    }
+    words.extend(word);
+    Ok(words)
+}
+
+/// Turns the variable references of a command into the ones userserversd replaces when the
+/// service starts. `$NAME` becomes `${NAME}`, which keeps it as a single argument instead of
+/// splitting it like systemd does, and `$$` becomes a literal `$`.
+fn translate_variables(word: &str) -> String {
+    let mut translated = String::new();
+    let mut rest = word;
+    while let Some(index) = rest.find('$') {
+        translated.push_str(&rest[..index]);
+        rest = &rest[index + 1..];
+
+        if let Some(after) = rest.strip_prefix('$') {
+            // A literal `${` is written `$${` for userserversd.
+            translated.push_str(if after.starts_with('{') { "$$" } else { "$" });
+            rest = after;
+            continue;
+        }
+        let name_length = rest
+            .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+            .unwrap_or(rest.len());
+        if rest.starts_with('{') || name_length == 0 {
+            translated.push('$');
+        } else {
+            translated.push_str(&format!("${{{}}}", &rest[..name_length]));
+            rest = &rest[name_length..];
+        }
+    }
+    translated.push_str(rest);
+    translated
+}
+
+/// Parses the command line of an `Exec*=` directive, leaving out the prefixes that change how
+/// systemd runs it, which have no equivalent.
+fn parse_command(
+    key: &str,
+    value: &str,
+    warnings: &mut Vec<String>,
+) -> Result<Vec<String>, String> {
+    let mut words = split_words(value).map_err(|err| format!("{key}=: {err}"))?;
+    if let Some(first) = words.first_mut() {
+        let executable = first.trim_start_matches(['@', '-', ':', '+', '!']);
+        if executable.len() != first.len() {
+            warnings.push(format!(
+                "the `{}` prefix of {key}= isn't supported, so it was left out",
+                &first[..first.len() - executable.len()]
+            ));
+            *first = executable.to_string();
+        }
+    }
+    if words.iter().any(|word| word == ";") {
+        warnings.push(format!(
+            "{key}= runs several commands separated by `;`, which isn't supported, so `;` is passed as an argument"
+        ));
+    }
+    if words.first().is_none_or(String::is_empty) {
+        return Err(format!("{key}= has no command"));
+    }
+
+    let command = words
+        .iter()
+        .map(|word| translate_variables(word))
+        .collect::<Vec<String>>();
+    if command.iter().any(|word| word.contains("${MAINPID}")) {
+        warnings.push(format!(
+            "{key}= uses $MAINPID, which userserversd doesn't set"
+        ));
+    }
+    Ok(command)
+}
+
+/// Parses the `KEY=VALUE` assignments of an `Environment=` directive.
+fn parse_environment(value: &str) -> Result<Vec<(String, String)>, String> {
+    split_words(value)
+        .map_err(|err| format!("Environment=: {err}"))?
+        .into_iter()
+        .map(|assignment| match assignment.split_once('=') {
+            Some((key, value)) => Ok((key.to_string(), value.to_string())),
+            None => Err(format!(
+                "Environment=: `{assignment}` isn't a KEY=VALUE assignment"
+            )),
+        })
+        .collect()
+}
+
+/// Reads the variables of an `EnvironmentFile=`, which is missing without an error if its path
+/// starts with `-`.
+fn read_environment_file(value: &str) -> Result<Vec<(String, String)>, String> {
+    let (path, optional) = match value.strip_prefix('-') {
+        Some(path) => (path, true),
+        None => (value, false),
+    };
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(err) if optional && err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => {
+            return Err(format!(
+                "failed to read the environment file `{path}`: {err}"
+            ));
+        }
+    };
+
+    let mut variables = Vec::new();
+    for line in contents.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with(['#', ';']) {
+            continue;
+        }
+        let Some((key, value)) = line.split_once('=') else {
+            return Err(format!(
+                "the environment file `{path}` has a line that isn't a KEY=VALUE assignment: {line}"
+            ));
+        };
+        let value = value.trim();
+        let value = match value.chars().next() {
+            Some(quote @ ('"' | '\'')) if value.len() > 1 && value.ends_with(quote) => {
+                &value[1..value.len() - 1]
+            }
+            _ => value,
+        };
+        variables.push((key.trim().to_string(), value.to_string()));
+    }
+    Ok(variables)
+}
+
+/// Translates the systemd user unit at the provided path into a service named after it.
+pub fn import(path: &Path) -> Result<ImportedUnit, String> {
+    let file_name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or("");
+    let Some(name) = file_name.strip_suffix(".service") else {
+        return Err("only .service units can be imported".to_string());
+    };
+    if name.ends_with('@') {
+        return Err("template units can't be imported, only their instances".to_string());
+    }
+    let contents = fs::read_to_string(path).map_err(|err| format!("failed to read it: {err}"))?;
+    let directives = parse_directives(&contents)?;
+
+    let mut warnings = Vec::new();
+    let mut values: HashMap<&str, Vec<String>> = HashMap::new();
+    let mut description = None;
+    for directive in &directives {
+        let Directive {
+            section,
+            key,
+            value,
+            line,
+        } = directive;
+        match (section.as_str(), key.as_str()) {
+            ("Unit", "Description") => description = Some(value.clone()),
+            ("Install", _) => {}
+            ("Service", key) if SUPPORTED_DIRECTIVES.contains(&key) => {
+                let value = expand_specifiers(value, name, &mut warnings);
+                // An empty value resets what was set before, like with systemd.
+                if value.is_empty() {
+                    values.remove(key);
+                } else {
+                    values.entry(key).or_default().push(value);
+                }
+            }
+            (section, key) => warnings.push(format!(
+                "line {line}: {key}= of [{section}] isn't supported, so it was left out"
+            )),
+        }
+    }
+
+    let mut take_one = |key: &str| -> Option<String> {
+        let mut values = values.remove(key)?;
+        if values.len() > 1 {
+            warnings.push(format!(
+                "{key}= is given {} times, only the last one is used",
+                values.len()
+            ));
+        }
+        values.pop()
+    };
+    let kind_name = take_one("Type").unwrap_or_else(|| "simple".to_string());
+    let exec_start = take_one("ExecStart");
+    let exec_start_pre = take_one("ExecStartPre");
+    let exec_stop = take_one("ExecStop");
+    let exec_stop_post = take_one("ExecStopPost");
+    let exec_reload = take_one("ExecReload");
+    let working_directory = take_one("WorkingDirectory");
+    let pidfile = take_one("PIDFile");
+    let timeout = take_one("TimeoutSec");
+    let start_timeout = take_one("TimeoutStartSec").or(timeout.clone());
+    let stop_timeout = take_one("TimeoutStopSec").or(timeout);
+
+    let Some(exec_start) = exec_start else {
+        return Err("the unit has no ExecStart=".to_string());
+    };
+    let command = parse_command("ExecStart", &exec_start, &mut warnings)?;
+    let mut parse_optional_command = |key: &str, value: Option<String>| {
+        value
+            .map(|value| parse_command(key, &value, &mut warnings))
+            .transpose()
+    };
+    let pre_start = parse_optional_command("ExecStartPre", exec_start_pre)?;
+    let stop_command = parse_optional_command("ExecStop", exec_stop)?;
+    let post_stop = parse_optional_command("ExecStopPost", exec_stop_post)?;
+    let reload_command = parse_optional_command("ExecReload", exec_reload)?;
+
+    let kind = match kind_name.as_str() {
+        "simple" | "exec" | "idle" | "notify" | "notify-reload" | "dbus" => {
+            if kind_name.starts_with("notify") || kind_name == "dbus" {
+                warnings.push(format!(
+                    "Type={kind_name} readiness isn't supported, so the service counts as ready as soon as it runs"
+                ));
+            }
+            if stop_command.is_some() {
+                warnings.push(
+                    "ExecStop= isn't supported for services that don't fork, which are stopped with SIGTERM instead"
+                        .to_string(),
+                );
+            }
+            ipc::ServiceKind::Synchronous { command }
+        }
+        "oneshot" => ipc::ServiceKind::Oneshot { command },
+        "forking" => {
+            let Some(stop_command) = stop_command else {
+                return Err(
+                    "Type=forking units need an ExecStop= to be imported, as asynchronous services are stopped with their stop command"
+                        .to_string(),
+                );
+            };
+            // There is a single timeout for both commands, so the longest one is kept.
+            let mut timeouts = Vec::new();
+            for (key, value) in [
+                ("TimeoutStartSec", &start_timeout),
+                ("TimeoutStopSec", &stop_timeout),
+            ] {
+                let Some(value) = value else {
+                    continue;
+                };
+                match parse_time_span(value) {
+                    Some(secs) => timeouts.push(secs.max(1)),
+                    None => warnings.push(format!(
+                        "{key}={value} isn't supported, so the default timeout of 60s is used"
+                    )),
+                }
+            }
+            let command_timeout_secs = timeouts.iter().max().copied();
+            if let [start, stop] = timeouts[..]
+                && start != stop
+            {
+                warnings.push(format!(
+                    "the start and stop commands share a single timeout, so it is {}s for both",
+                    start.max(stop)
+                ));
+            }
+            ipc::ServiceKind::Asynchronous {
+                start_command: command,
+                stop_command,
+                stop_environment: None,
+                stop_working_directory: None,
+                pidfile: pidfile.clone(),
+                command_timeout_secs,
+            }
+        }
+        kind_name => return Err(format!("Type={kind_name} isn't supported")),
+    };
+    if pidfile.is_some() && kind_name != "forking" {
+        warnings.push("PIDFile= is only used for Type=forking units".to_string());
+    }
+    if (start_timeout.is_some() || stop_timeout.is_some()) && kind_name != "forking" {
+        warnings.push(
+            "timeouts are only supported for Type=forking units, so they were left out".to_string(),
+        );
+    }
+
+    // Files take precedence over Environment=, like with systemd.
+    let mut environment = HashMap::new();
+    for value in values.remove("Environment").unwrap_or_default() {
+        environment.extend(parse_environment(&value)?);
+    }
+    for value in values.remove("EnvironmentFile").unwrap_or_default() {
+        environment.extend(read_environment_file(&value)?);
+    }
+
+    // A missing working directory isn't an error with `-`, but userserversd checks it anyway
+    // unless the service is imported with --force.
+    let working_directory = working_directory
+        .map(|directory| directory.trim_start_matches('-').to_string())
+        .or_else(|| user_context::get().home.clone())
+        .ok_or_else(|| "failed to get home directory path".to_string())?;
+
+    // The same specifier or prefix may come up several times.
+    let mut seen = HashSet::new();
+    warnings.retain(|warning| seen.insert(warning.clone()));
+
+    let mut service = ipc::Service::new(working_directory, kind);
+    service.environment = environment;
+    service.pre_start = pre_start;
+    service.post_stop = post_stop;
+    service.reload_command = reload_command;
+    service.description = description;
+    Ok(ImportedUnit {
+        name: name.to_string(),
+        service,
+        warnings,
+    })
+}
+
+/// A service, written as a systemd user unit.
+pub struct ExportedUnit {
+    pub unit_name: String,
+    pub contents: String,
+    /// The parts of the service that have no equivalent, and were left out.
+    pub warnings: Vec<String>,
+}
+
+/// Whether a name can be used for a unit, being at most 255 bytes long along with `.service` and
+/// only made of ASCII letters, digits and `:_.\-`.
+pub fn is_valid_unit_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() + ".service".len() <= 255
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || ":_.\\-".contains(c))
+}
+
+/// Keeps the `${NAME}` references userserversd replaces, which systemd replaces the same way, and
+/// doubles every other `$` so that systemd leaves it as it is.
+fn escape_dollars(word: &str) -> String {
+    let mut escaped = String::new();
+    let mut rest = word;
+    while let Some(index) = rest.find('$') {
+        escaped.push_str(&rest[..index]);
+        rest = &rest[index..];
+
+        let reference = rest
+            .strip_prefix("${")
+            .and_then(|after| after.find('}'))
+            .map(|end| &rest[..end + 3])
+            .filter(|reference| {
+                reference[2..reference.len() - 1]
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_')
+            });
+        match reference {
+            // Both read `$${` as a literal `${`.
+            _ if rest.starts_with("$${") => {
+                escaped.push_str("$${");
+                rest = &rest[3..];
+            }
+            Some(reference) if reference.len() > 3 => {
+                escaped.push_str(reference);
+                rest = &rest[reference.len()..];
+            }
+            _ => {
+                escaped.push_str("$$");
+                rest = &rest[1..];
+            }
+        }
+    }
+    escaped.push_str(rest);
+    escaped
+}
+
+/// Quotes a value so that systemd reads it back as it is, doubling `%` so that it isn't taken as
+/// a specifier. It is left unquoted if it can be.
+fn quote(value: &str) -> String {
+    // A lone `;` separates commands even when quoted.
+    if value == ";" {
+        return "\\;".to_string();
+    }
+    let value = value.replace('%', "%%");
+    let needs_quotes = value.is_empty()
+        || value.contains(|c: char| c.is_whitespace() || matches!(c, '"' | '\'' | '\\'));
+    if !needs_quotes {
+        return value;
+    }
+
+    let mut quoted = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\t' => quoted.push_str("\\t"),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Writes a command for an `Exec*=` directive. Shell commands are run with `/bin/sh -c`, like
+/// userserversd does for the main commands of shell services, with every `$` doubled so that
+/// the shell gets to expand the variables instead of systemd.
+fn command_line(shell: bool, command: &[String]) -> String {
+    if shell {
+        let script = command.join(" ").replace('$', "$$");
+        return format!("/bin/sh -c {}", quote(&script));
+    }
+    command
+        .iter()
+        .map(|word| quote(&escape_dollars(word)))
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+/// Writes a service as a systemd user unit, named after the service unless `renames` maps it to
+/// another name. Dependencies are renamed the same way.
+pub fn export(
+    name: &str,
+    service: &ipc::Service,
+    renames: &HashMap<String, String>,
+) -> Result<ExportedUnit, String> {
+    let unit_name_of = |name: &str| renames.get(name).cloned().unwrap_or(name.to_string());
+    let unit_name = unit_name_of(name);
+    if !is_valid_unit_name(&unit_name) {
+        return Err(format!(
+            "`{unit_name}` isn't a valid unit name, give it one with --rename {name}=NEW_NAME"
+        ));
+    }
+
+    let mut warnings = Vec::new();
+    let mut unit = vec!["[Unit]".to_string()];
+    let description = match &service.description {
+        Some(description) => description.replace('%', "%%"),
+        None => format!("{} (exported from userserversd)", name.replace('%', "%%")),
+    };
+    unit.push(format!("Description={description}"));
+    for dependency in &service.depends_on {
+        let dependency_unit = unit_name_of(dependency);
+        if !is_valid_unit_name(&dependency_unit) {
+            warnings.push(format!(
+                "the dependency `{dependency}` isn't a valid unit name, so it was left out; rename it with --rename"
+            ));
+            continue;
+        }
+        unit.push(format!("Requires={dependency_unit}.service"));
+        unit.push(format!("After={dependency_unit}.service"));
+    }
+
+    unit.push(String::new());
+    unit.push("[Service]".to_string());
+    let shell = service.shell;
+    match &service.kind {
+        ipc::ServiceKind::Synchronous { command } => {
+            unit.push("Type=simple".to_string());
+            unit.push(format!("ExecStart={}", command_line(shell, command)));
+        }
+        ipc::ServiceKind::Asynchronous {
+            start_command,
+            stop_command,
+            stop_environment,
+            stop_working_directory,
+            pidfile,
+            command_timeout_secs,
+        } => {
+            unit.push("Type=forking".to_string());
+            unit.push(format!("ExecStart={}", command_line(shell, start_command)));
+            unit.push(format!("ExecStop={}", command_line(shell, stop_command)));
+            if let Some(pidfile) = pidfile {
+                unit.push(format!("PIDFile={}", pidfile.replace('%', "%%")));
+            }
+            if let Some(command_timeout_secs) = command_timeout_secs {
+                unit.push(format!("TimeoutSec={command_timeout_secs}"));
+            }
+            if stop_environment.is_some() {
+                warnings.push("the environment of the stop command has no equivalent".to_string());
+            }
+            if stop_working_directory.is_some() {
+                warnings.push(
+                    "the working directory of the stop command has no equivalent".to_string(),
+                );
+            }
+        }
+        ipc::ServiceKind::Oneshot { command } => {
+            unit.push("Type=oneshot".to_string());
+            unit.push(format!("ExecStart={}", command_line(shell, command)));
+        }
+        ipc::ServiceKind::Timer {
+            command,
+            interval_secs,
+        } => {
+            unit.push("Type=oneshot".to_string());
+            unit.push(format!("ExecStart={}", command_line(shell, command)));
+            warnings.push(format!(
+                "timer services need a .timer unit with OnUnitActiveSec={interval_secs}s, which isn't written"
+            ));
+        }
+        kind => {
+            unit.push("Type=simple".to_string());
+            unit.push(format!("ExecStart={}", command_line(shell, kind.command())));
+            warnings.push(format!(
+                "{} services have no equivalent, so it was exported as a simple one",
+                kind.name()
+            ));
+        }
+    }
+
+    let working_directory = &service.working_directory;
+    if working_directory.contains("${") {
+        warnings.push(
+            "systemd doesn't replace variables in the working directory, so they were kept as they are"
+                .to_string(),
+        );
+    }
+    let working_directory = match working_directory.strip_prefix('~') {
+        Some(rest) if rest.is_empty() || rest.starts_with('/') => {
+            format!("%h{}", rest.replace('%', "%%"))
+        }
+        Some(_) => {
+            warnings.push(
+                "systemd doesn't expand `~user` in the working directory, so it was kept as it is"
+                    .to_string(),
+            );
+            working_directory.replace('%', "%%")
+        }
+        None => working_directory.replace('%', "%%"),
+    };
+    unit.push(format!("WorkingDirectory={working_directory}"));
+
+    let mut environment = service.environment.iter().collect::<Vec<_>>();
+    environment.sort();
+    for (key, value) in environment {
+        unit.push(format!("Environment={}", quote(&format!("{key}={value}"))));
+    }
+
+    if let Some(pre_start) = &service.pre_start {
+        unit.push(format!("ExecStartPre={}", command_line(false, pre_start)));
+    }
+    if let Some(post_stop) = &service.post_stop {
+        unit.push(format!("ExecStopPost={}", command_line(false, post_stop)));
+    }
+    if let Some(reload_command) = &service.reload_command {
+        unit.push(format!(
+            "ExecReload={}",
+            command_line(false, reload_command)
+        ));
+    }
+    if let Some(reload_signal) = &service.reload_signal {
+        let signal = reload_signal.trim_start_matches("SIG");
+        unit.push(format!("ExecReload=/bin/kill -s {signal} $MAINPID"));
+    }
+
+    if service.clean_environment {
+        warnings.push(
+            "systemd passes its own environment to the service, which can't be left out"
+                .to_string(),
+        );
+    }
+    for (unsupported, what) in [
+        (service.health_check.is_some(), "the health check"),
+        (
+            service.ready_command.is_some() || service.ready_tcp_port.is_some(),
+            "the readiness probe",
+        ),
+        (!service.ports.is_empty(), "the declared ports"),
+        (!service.watch_paths.is_empty(), "the watched paths"),
+        (service.on_failure.is_some(), "the on-failure hook"),
+        (service.log_retention_secs.is_some(), "the log retention"),
+        (
+            service.create_working_directory,
+            "creating the working directory",
+        ),
+    ] {
+        if unsupported {
+            warnings.push(format!("left out {what}, which can't be translated"));
+        }
+    }
+
+    unit.push(String::new());
+    unit.push("[Install]".to_string());
+    unit.push("WantedBy=default.target".to_string());
+    Ok(ExportedUnit {
+        unit_name,
+        contents: unit.join("\n") + "\n",
+        warnings,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::path::PathBuf;
+
+    use super::super::scratch::scratch_dir;
+    use super::*;
+
+    fn testdata(file: &str) -> PathBuf {
+        PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("src/unit_file/testdata")
+            .join(file)
+    }
+
+    fn home() -> String {
+        user_context::get()
+            .home
+            .clone()
+            .unwrap_or_else(|| "~".to_string())
+    }
+
+    /// Writes a unit to a directory of its own for the test, for units that refer to other files
+    /// by their absolute path or that aren't worth keeping as a fixture.
+    fn scratch_unit(test: &str, file: &str, contents: &str) -> PathBuf {
+        let path = scratch_dir("unit-file", test).join(file);
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    /// Compares the unit exported for `service` with the one in `testdata/exported`, or writes it
+    /// there instead when `UPDATE_GOLDEN_FILES` is set.
+    fn assert_golden(name: &str, service: &ipc::Service, renames: &[(&str, &str)]) -> Vec<String> {
+        let renames = renames
+            .iter()
+            .map(|(from, to)| (from.to_string(), to.to_string()))
+            .collect::<HashMap<String, String>>();
+        let unit = export(name, service, &renames).unwrap();
+        let path = testdata("exported").join(format!("{}.service", unit.unit_name));
+        if env::var_os("UPDATE_GOLDEN_FILES").is_some() {
+            fs::write(&path, &unit.contents).unwrap();
+        }
+        let golden = fs::read_to_string(&path).unwrap();
+        assert!(
+            unit.contents == golden,
+            "the unit of {name} changed; run the tests with UPDATE_GOLDEN_FILES=1 if it should have"
+        );
+        unit.warnings
+    }
+
+    fn import_error(test: &str, contents: &str) -> String {
+        match import(&scratch_unit(test, "broken.service", contents)) {
+            Ok(_) => panic!("the unit was imported"),
+            Err(err) => err,
+        }
+    }
+
+    #[test]
+    fn simple_units_become_synchronous_services() {
+        let home = home();
+        let unit = import(&testdata("web.service")).unwrap();
+        assert_eq!(unit.name, "web");
+
+        let service = &unit.service;
+        assert_eq!(
+            service.kind,
+            ipc::ServiceKind::Synchronous {
+                command: [
+                    "/usr/bin/python3",
+                    "-m",
+                    "http.server",
+                    "8080",
+                    "--bind",
+                    "127.0.0.1",
+                    "--directory",
+                    "public html",
+                ]
+                .map(String::from)
+                .to_vec(),
+            }
+        );
+        assert_eq!(service.working_directory, format!("{home}/sites/web"));
+        assert_eq!(
+            service.pre_start,
+            Some(vec![
+                "/usr/bin/mkdir".to_string(),
+                "-p".to_string(),
+                format!("{home}/sites/web/public html"),
+            ])
+        );
+        assert_eq!(service.description.as_deref(), Some("Web server"));
+        assert_eq!(
+            service.environment,
+            HashMap::from([
+                ("GREETING".to_string(), "hello world".to_string()),
+                ("PORT".to_string(), "8080".to_string()),
+                ("MODE".to_string(), "production".to_string()),
+            ])
+        );
+    }
+
+    #[test]
+    fn unsupported_directives_and_prefixes_are_reported() {
+        let unit = import(&testdata("web.service")).unwrap();
+        assert_eq!(
+            unit.warnings,
+            [
+                "line 4: After= of [Unit] isn't supported, so it was left out",
+                "line 14: Restart= of [Service] isn't supported, so it was left out",
+                "the `-` prefix of ExecStartPre= isn't supported, so it was left out",
+            ]
+        );
+    }
+
+    #[test]
+    fn forking_units_become_asynchronous_services() {
+        let home = home();
+        let unit = import(&testdata("db.service")).unwrap();
+        assert_eq!(unit.name, "db");
+        assert_eq!(
+            unit.service.kind,
+            ipc::ServiceKind::Asynchronous {
+                start_command: vec![
+                    "/usr/bin/pg_ctl".to_string(),
+                    "start".to_string(),
+                    "-D".to_string(),
+                    format!("{home}/db"),
+                    "-l".to_string(),
+                    format!("{home}/db/server log"),
+                ],
+                stop_command: vec![
+                    "/usr/bin/pg_ctl".to_string(),
+                    "stop".to_string(),
+                    "-D".to_string(),
+                    format!("{home}/db"),
+                ],
+                stop_environment: None,
+                stop_working_directory: None,
+                pidfile: Some(format!("{home}/db/postmaster.pid")),
+                command_timeout_secs: Some(120),
+            }
+        );
+        // Without a WorkingDirectory=, services run from the home directory like with systemd.
+        assert_eq!(unit.service.working_directory, home);
+        assert_eq!(
+            unit.warnings,
+            ["the start and stop commands share a single timeout, so it is 120s for both"]
+        );
+    }
+
+    #[test]
+    fn forking_units_need_a_stop_command() {
+        let err = import_error(
+            "forking_units_need_a_stop_command",
+            "[Service]\nType=forking\nExecStart=/usr/bin/daemonize\n",
+        );
+        assert!(err.contains("need an ExecStop="), "{err}");
+    }
+
+    #[test]
+    fn other_types_are_refused_or_run_as_simple() {
+        let path = scratch_unit(
+            "other_types_are_refused_or_run_as_simple",
+            "notifier.service",
+            "[Service]\nType=notify\nExecStart=/usr/bin/notifier\nExecStop=/usr/bin/notifier --quit\n",
+        );
+        let unit = import(&path).unwrap();
+        assert!(matches!(
+            unit.service.kind,
+            ipc::ServiceKind::Synchronous { .. }
+        ));
+        assert_eq!(unit.warnings.len(), 2, "{:?}", unit.warnings);
+        assert!(unit.warnings[0].starts_with("Type=notify readiness isn't supported"));
+        assert!(unit.warnings[1].starts_with("ExecStop= isn't supported"));
+
+        let err = import_error(
+            "other_types_are_refused_or_run_as_simple",
+            "[Service]\nType=bogus\nExecStart=/bin/true\n",
+        );
+        assert_eq!(err, "Type=bogus isn't supported");
+    }
+
+    #[test]
+    fn environment_files_override_environment_directives() {
+        let test = "environment_files_override_environment_directives";
+        let env_file = scratch_unit(
+            test,
+            "worker.env",
+            "# Set up by the installer.\nQUEUE=\"jobs high\"\n\nWORKERS='4'\nTOKEN=abc=def\n",
+        );
+        let unit = env_file.with_file_name("worker.service");
+        fs::write(
+            &unit,
+            format!(
+                "[Service]\nExecStart=/usr/bin/worker\nEnvironment=QUEUE=jobs LOG=info\nEnvironmentFile={}\nEnvironmentFile=-{}/missing.env\n",
+                env_file.display(),
+                env_file.parent().unwrap().display()
+            ),
+        )
+        .unwrap();
+
+        let unit = import(&unit).unwrap();
+        assert_eq!(
+            unit.service.environment,
+            HashMap::from([
+                ("QUEUE".to_string(), "jobs high".to_string()),
+                ("LOG".to_string(), "info".to_string()),
+                ("WORKERS".to_string(), "4".to_string()),
+                ("TOKEN".to_string(), "abc=def".to_string()),
+            ])
+        );
+        assert!(unit.warnings.is_empty(), "{:?}", unit.warnings);
+
+        let err = import_error(
+            test,
+            "[Service]\nExecStart=/usr/bin/worker\nEnvironmentFile=/nonexistent/worker.env\n",
+        );
+        assert!(
+            err.starts_with("failed to read the environment file"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn specifiers_are_expanded_or_kept_with_a_warning() {
+        let mut warnings = Vec::new();
+        assert_eq!(
+            expand_specifiers("%h/%p/%i/100%%", "backup@daily", &mut warnings),
+            format!("{}/backup/daily/100%", home())
+        );
+        assert!(warnings.is_empty());
+
+        assert_eq!(expand_specifiers("%H %", "web", &mut warnings), "%H %");
+        assert_eq!(
+            warnings,
+            ["the specifier `%H` isn't supported, so it was kept as it is"]
+        );
+    }
+
+    #[test]
+    fn words_are_split_with_quotes_and_escapes() {
+        assert_eq!(
+            split_words(r#"a "b c" 'd "e"' f\ g "h\"i" "" x\ty"#).unwrap(),
+            ["a", "b c", "d \"e\"", "f g", "h\"i", "", "x\ty"]
+        );
+        assert!(split_words("\"unterminated").is_err());
+        assert!(split_words("trailing \\").is_err());
+    }
+
+    #[test]
+    fn variables_are_kept_as_single_arguments() {
+        assert_eq!(
+            parse_command(
+                "ExecStart",
+                "/bin/echo $GREETING ${NAME} $$HOME $${X}",
+                &mut Vec::new()
+            )
+            .unwrap(),
+            ["/bin/echo", "${GREETING}", "${NAME}", "$HOME", "$${X}"]
+        );
+    }
+
+    #[test]
+    fn time_spans_are_rounded_up_to_seconds() {
+        assert_eq!(parse_time_span("90"), Some(90));
+        assert_eq!(parse_time_span("5min 30s"), Some(330));
+        assert_eq!(parse_time_span("1h"), Some(3600));
+        assert_eq!(parse_time_span("1500ms"), Some(2));
+        assert_eq!(parse_time_span("infinity"), None);
+        assert_eq!(parse_time_span(""), None);
+    }
+
+    #[test]
+    fn templates_and_other_unit_types_are_refused() {
+        assert_eq!(
+            import(Path::new("/nonexistent/backup@.service"))
+                .err()
+                .unwrap(),
+            "template units can't be imported, only their instances"
+        );
+        assert_eq!(
+            import(Path::new("/nonexistent/backup.timer"))
+                .err()
+                .unwrap(),
+            "only .service units can be imported"
+        );
+    }
+
+    #[test]
+    fn shell_scripts_are_exported_for_the_shell_to_expand() {
+        let script = ["printf '%s\\n' \"${GREETING}\" $HOME | tee out.log".to_string()];
+        assert_eq!(
+            command_line(true, &script),
+            r#"/bin/sh -c "printf '%%s\\n' \"$${GREETING}\" $$HOME | tee out.log""#
+        );
+
+        let command = ["echo", "${GREETING}", "$HOME", "$${HOME}"].map(String::from);
+        assert_eq!(
+            command_line(false, &command),
+            "echo ${GREETING} $$HOME $${HOME}"
+        );
+    }
+
+    #[test]
+    fn synchronous_services_export_as_simple_units() {
+        let command = [
+            "/usr/bin/python3",
+            "-c",
+            "print(\"it's 100% up\")",
+            "--title",
+            "my site",
+            "C:\\sites",
+            ";",
+            "${PORT}",
+            "$5",
+        ];
+        let mut service = ipc::Service::new(
+            "~/sites/web",
+            ipc::ServiceKind::Synchronous {
+                command: command.map(String::from).to_vec(),
+            },
+        );
+        service.description = Some("Web server".to_string());
+        service.depends_on = vec!["db".to_string(), "cache".to_string()];
+        service.environment = HashMap::from([
+            ("PORT".to_string(), "8080".to_string()),
+            ("GREETING".to_string(), "hello \"world\"".to_string()),
+        ]);
+        service.reload_signal = Some("SIGHUP".to_string());
+
+        let warnings = assert_golden("web", &service, &[("cache", "redis")]);
+        assert!(warnings.is_empty(), "{warnings:?}");
+    }
+
+    #[test]
+    fn asynchronous_services_export_as_forking_units_with_their_stop_command() {
+        let mut service = ipc::Service::new(
+            "/srv/db",
+            ipc::ServiceKind::Asynchronous {
+                start_command: ["pg_ctl", "start", "-l", "server log"]
+                    .map(String::from)
+                    .to_vec(),
+                stop_command: ["pg_ctl", "stop", "-m", "fast"].map(String::from).to_vec(),
+                stop_environment: Some(HashMap::new()),
+                stop_working_directory: None,
+                pidfile: Some("/srv/db/postmaster.pid".to_string()),
+                command_timeout_secs: Some(90),
+            },
+        );
+        service.pre_start = Some(["mkdir", "-p", "/srv/db/logs"].map(String::from).to_vec());
+        service.post_stop = Some(
+            ["rm", "-f", "/srv/db/.s.PGSQL.5432"]
+                .map(String::from)
+                .to_vec(),
+        );
+        service.health_check = Some(["pg_isready"].map(String::from).to_vec());
+
+        let warnings = assert_golden("postgres.db", &service, &[("postgres.db", "postgres")]);
+        assert_eq!(
+            warnings,
+            [
+                "the environment of the stop command has no equivalent",
+                "left out the health check, which can't be translated",
+            ]
+        );
+    }
+
+    #[test]
+    fn shell_services_export_their_script_through_sh() {
+        let mut service = ipc::Service::new(
+            "/tmp",
+            ipc::ServiceKind::Timer {
+                command: vec!["date >> \"$HOME/ticks\"".to_string()],
+                interval_secs: 300,
+            },
+        );
+        service.shell = true;
+
+        let warnings = assert_golden("ticker", &service, &[]);
+        assert_eq!(
+            warnings,
+            ["timer services need a .timer unit with OnUnitActiveSec=300s, which isn't written"]
+        );
+    }
+
+    #[test]
+    fn quoted_values_are_read_back_as_they_were() {
+        for value in [
+            "plain",
+            "two words",
+            "",
+            "it's",
+            "say \"hi\"",
+            "back\\slash",
+            "tab\tand\nnewline",
+        ] {
+            assert_eq!(split_words(&quote(value)).unwrap(), [value], "{value:?}");
+        }
+        assert_eq!(quote("50%"), "50%%");
+        assert_eq!(quote(";"), "\\;");
+    }
+
+    #[test]
+    fn unit_names_are_checked() {
+        for name in ["web", "web-2", "my_app.dev", "host:8080", "a\\x2db"] {
+            assert!(is_valid_unit_name(name), "{name}");
+        }
+        let too_long = "a".repeat(255 - ".service".len() + 1);
+        for name in ["", "my app", "web/api", "café", too_long.as_str()] {
+            assert!(!is_valid_unit_name(name), "{name}");
+        }
+        assert!(is_valid_unit_name(&too_long[1..]));
+    }
+
+    #[test]
+    fn invalid_names_need_a_rename() {
+        let service = ipc::Service::new(
+            "/tmp",
+            ipc::ServiceKind::Oneshot {
+                command: vec!["true".to_string()],
+            },
+        );
+        let err = export("my app", &service, &HashMap::new()).err().unwrap();
+        assert_eq!(
+            err,
+            "`my app` isn't a valid unit name, give it one with --rename my app=NEW_NAME"
+        );
+
+        let renames = HashMap::from([("my app".to_string(), "my-app".to_string())]);
+        assert_eq!(
+            export("my app", &service, &renames).unwrap().unit_name,
+            "my-app"
+        );
+    }
+}
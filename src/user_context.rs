@@ -0,0 +1,499 @@
+use std::env;
+use std::fs;
+use std::io;
+use std::os::unix::fs::{DirBuilderExt, MetadataExt};
+use std::path::Path;
+use std::sync::OnceLock;
+
+use nix::unistd;
+
+pub struct UserContext {
+    pub uid: u32,
+    pub username: Option<String>,
+    pub home: Option<String>,
+    pub config_dir: Option<String>,
+    pub runtime_dir: Option<String>,
+    /// Where data that outlives the daemon but isn't configuration goes. It is only created
+    /// once something gets written to it.
+    pub state_dir: Option<String>,
+    // Why no runtime directory could be found, if none was.
+    runtime_dir_error: Option<String>,
+}
+
+static CURRENT: OnceLock<UserContext> = OnceLock::new();
+
+/// The directories a per-user runtime directory is looked for in, in order.
+const RUNTIME_BASE_PATHS: &[&str] = &["/run", "/var/run", "/tmp"];
+
+/// Returns the context of the user running the current process, resolving it on the first call.
+pub fn get() -> &'static UserContext {
+    CURRENT.get_or_init(|| UserContext::resolve(|key| env::var(key).ok()))
+}
+
+/// What the password database says about a user.
+pub struct Account {
+    pub uid: u32,
+    pub name: Option<String>,
+    pub home: Option<String>,
+}
+
+impl Account {
+    /// Looks up the user running the current process.
+    pub fn current() -> Self {
+        let uid = unistd::getuid();
+        let user = unistd::User::from_uid(uid).ok().flatten();
+        Self {
+            uid: uid.as_raw(),
+            name: user.as_ref().map(|user| user.name.clone()),
+            home: user.map(|user| user.dir.to_string_lossy().to_string()),
+        }
+    }
+}
+
+impl UserContext {
+    /// Resolves the context of the user running the current process, looking up environment
+    /// variables through `env`.
+    ///
+    /// `USERSERVERSD_HOME`, `USERSERVERSD_CONFIG_DIR`, `USERSERVERSD_RUNTIME_DIR` and
+    /// `USERSERVERSD_STATE_DIR` take precedence over everything else.
+    pub fn resolve<F: Fn(&str) -> Option<String>>(env: F) -> Self {
+        Self::resolve_for(Account::current(), RUNTIME_BASE_PATHS, env)
+    }
+
+    /// Resolves the context of `account`, looking for a runtime directory in `runtime_base_paths`
+    /// when the environment doesn't name one.
+    pub fn resolve_for<F: Fn(&str) -> Option<String>>(
+        account: Account,
+        runtime_base_paths: &[&str],
+        env: F,
+    ) -> Self {
+        let uid = account.uid;
+        let username = account.name.or_else(|| env("USER"));
+
+        let home = env("USERSERVERSD_HOME")
+            .or_else(|| env("HOME"))
+            .or_else(|| account.home.filter(|home| Path::new(home).exists()));
+
+        let config_dir = env("USERSERVERSD_CONFIG_DIR").or_else(|| env("XDG_CONFIG_HOME"));
+
+        let mut runtime_dir_error = None;
+        let runtime_dir = env("USERSERVERSD_RUNTIME_DIR")
+            .or_else(|| Self::xdg_runtime_dir(env("XDG_RUNTIME_DIR")?))
+            .or_else(|| match Self::probe_runtime_dir(runtime_base_paths, uid) {
+                Ok(runtime_dir) => Some(runtime_dir),
+                Err(err) => {
+                    runtime_dir_error = Some(err);
+                    None
+                }
+            });
+
+        let state_dir = env("USERSERVERSD_STATE_DIR").or_else(|| {
+            let state_home = env("XDG_STATE_HOME")
+                .filter(|state_home| !state_home.is_empty())
+                .or_else(|| Some(format!("{}/.local/state", home.as_ref()?)))?;
+            Some(format!("{state_home}/userserversd"))
+        });
+
+        Self {
+            uid,
+            username,
+            home,
+            config_dir,
+            runtime_dir,
+            state_dir,
+            runtime_dir_error,
+        }
+    }
+
+    /// Uses a directory of its own in the runtime directory of the session, which only the user
+    /// can access.
+    fn xdg_runtime_dir(xdg_runtime_dir: String) -> Option<String> {
+        if xdg_runtime_dir.is_empty() {
+            return None;
+        }
+
+        let path = format!("{xdg_runtime_dir}/userserversd");
+        match fs::DirBuilder::new().mode(0o700).create(&path) {
+            Ok(()) => Some(path),
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => Some(path),
+            Err(_) => None,
+        }
+    }
+
+    /// Uses the first `<base path>/user/<uid>`, such as `/run/user/<uid>`, that can be created,
+    /// or already exists, and belongs to the user. Lists why each of them was passed over
+    /// otherwise.
+    fn probe_runtime_dir(base_paths: &[&str], uid: u32) -> Result<String, String> {
+        let mut failures = Vec::new();
+        for base_path in base_paths {
+            if !Path::new(base_path).exists() {
+                continue;
+            }
+
+            let user_path = format!("{base_path}/user/{uid}");
+            match Self::create_user_dir(&user_path, uid) {
+                Ok(()) => return Ok(user_path),
+                Err(err) => failures.push(format!("`{user_path}` ({err})")),
+            }
+        }
+
+        if failures.is_empty() {
+            return Err(format!("none of {} exist", base_paths.join(", ")));
+        }
+        Err(format!("tried {}", failures.join(", ")))
+    }
+
+    fn create_user_dir(path: &str, uid: u32) -> io::Result<()> {
+        if let Some(parent) = Path::new(path).parent() {
+            fs::create_dir_all(parent)?;
+        }
+        match fs::DirBuilder::new().mode(0o700).create(path) {
+            Ok(()) => return Ok(()),
+            Err(err) if err.kind() != io::ErrorKind::AlreadyExists => return Err(err),
+            Err(_) => {}
+        }
+
+        // Anyone can create it in /tmp, so it is only trusted if it is the user's own.
+        if fs::metadata(path)?.uid() != uid {
+            return Err(io::Error::other("owned by another user"));
+        }
+        unistd::access(path, unistd::AccessFlags::W_OK).map_err(io::Error::from)
+    }
+
+    fn runtime_file_path(&self, name: &str) -> io::Result<String> {
+        match &self.runtime_dir {
+            Some(runtime_dir) => Ok(format!("{runtime_dir}/{name}")),
+            None => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!(
+                    "no usable runtime directory: {}",
+                    self.runtime_dir_error
+                        .as_deref()
+                        .unwrap_or("none was found")
+                ),
+            )),
+        }
+    }
+
+    pub fn config_file_path(&self) -> Option<String> {
+        if let Some(config_dir) = &self.config_dir {
+            return Some(format!("{config_dir}/userserversd_services.json"));
+        }
+
+        let home = self.home.as_ref()?;
+        let config_file = if Path::new(&format!("{home}/.userserversd_services.json")).exists()
+            || !Path::new(&format!("{home}/.config")).exists()
+        {
+            format!("{home}/.userserversd_services.json")
+        } else {
+            format!("{home}/.config/userserversd_services.json")
+        };
+
+        Some(config_file)
+    }
+
+    /// The directory that holds one `<name>.json` file per service with the drop-in layout.
+    pub fn services_dir_path(&self) -> Option<String> {
+        let config_dir = match &self.config_dir {
+            Some(config_dir) => config_dir.clone(),
+            None => format!("{}/.config", self.home.as_ref()?),
+        };
+        Some(format!("{config_dir}/userserversd/services.d"))
+    }
+
+    /// The directory that holds one `<name>.json` file per snapshot of the services.
+    pub fn snapshots_dir_path(&self) -> Option<String> {
+        Some(format!("{}/snapshots", self.state_dir.as_ref()?))
+    }
+
+    /// The file that records which services should be running, for the next daemon to start
+    /// them again.
+    pub fn running_state_file_path(&self) -> Option<String> {
+        Some(format!("{}/running.json", self.state_dir.as_ref()?))
+    }
+
+    /// The file that records the commands the daemon ran, which is moved to `history.jsonl.1`
+    /// once it grows too big.
+    pub fn history_file_path(&self) -> Option<String> {
+        Some(format!("{}/history.jsonl", self.state_dir.as_ref()?))
+    }
+
+    pub fn socket_path(&self) -> io::Result<String> {
+        self.runtime_file_path("userserversd.sock")
+    }
+
+    pub fn lock_file_path(&self) -> io::Result<String> {
+        self.runtime_file_path("userserversd.lock")
+    }
+
+    pub fn handover_file_path(&self) -> io::Result<String> {
+        self.runtime_file_path("userserversd_handover.json")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::os::unix::fs::PermissionsExt;
+
+    use super::super::scratch::scratch_dir;
+    use super::*;
+
+    fn account(home: Option<&Path>) -> Account {
+        Account {
+            uid: unistd::getuid().as_raw(),
+            name: Some("alice".to_string()),
+            home: home.map(|home| home.to_string_lossy().to_string()),
+        }
+    }
+
+    fn resolve(
+        account: Account,
+        runtime_base_paths: &[&str],
+        vars: &[(&str, &str)],
+    ) -> UserContext {
+        let vars: HashMap<_, _> = vars
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+        UserContext::resolve_for(account, runtime_base_paths, |key| vars.get(key).cloned())
+    }
+
+    #[test]
+    fn home_comes_from_the_environment_before_the_password_database() {
+        let dir = scratch_dir("user-context", "home-env");
+        let context = resolve(account(Some(&dir)), &[], &[("HOME", "/home/from-env")]);
+        assert_eq!(context.home.as_deref(), Some("/home/from-env"));
+
+        let context = resolve(
+            account(Some(&dir)),
+            &[],
+            &[
+                ("HOME", "/home/from-env"),
+                ("USERSERVERSD_HOME", "/home/override"),
+            ],
+        );
+        assert_eq!(context.home.as_deref(), Some("/home/override"));
+    }
+
+    #[test]
+    fn home_falls_back_to_the_password_database_only_if_it_exists() {
+        let dir = scratch_dir("user-context", "home-passwd");
+        let context = resolve(account(Some(&dir)), &[], &[]);
+        assert_eq!(context.home.as_deref(), dir.to_str());
+
+        let context = resolve(account(Some(&dir.join("missing"))), &[], &[]);
+        assert_eq!(context.home, None);
+        assert_eq!(context.config_file_path(), None);
+        assert_eq!(context.services_dir_path(), None);
+        assert_eq!(context.state_dir, None);
+    }
+
+    #[test]
+    fn username_falls_back_to_user_variable() {
+        let nameless = Account {
+            name: None,
+            ..account(None)
+        };
+        let context = resolve(nameless, &[], &[("USER", "bob")]);
+        assert_eq!(context.username.as_deref(), Some("bob"));
+
+        let context = resolve(account(None), &[], &[("USER", "bob")]);
+        assert_eq!(context.username.as_deref(), Some("alice"));
+    }
+
+    #[test]
+    fn config_paths_follow_xdg_config_home() {
+        let context = resolve(
+            account(None),
+            &[],
+            &[("HOME", "/home/alice"), ("XDG_CONFIG_HOME", "/xdg/config")],
+        );
+        assert_eq!(
+            context.config_file_path().as_deref(),
+            Some("/xdg/config/userserversd_services.json")
+        );
+        assert_eq!(
+            context.services_dir_path().as_deref(),
+            Some("/xdg/config/userserversd/services.d")
+        );
+    }
+
+    #[test]
+    fn config_paths_without_xdg_config_home_depend_on_what_is_in_home() {
+        let home = scratch_dir("user-context", "config-home");
+        let home_str = home.to_str().unwrap();
+        let vars = [("HOME", home_str)];
+
+        // Without a ~/.config, the file goes straight in the home directory.
+        let context = resolve(account(None), &[], &vars);
+        assert_eq!(
+            context.config_file_path(),
+            Some(format!("{home_str}/.userserversd_services.json"))
+        );
+        assert_eq!(
+            context.services_dir_path(),
+            Some(format!("{home_str}/.config/userserversd/services.d"))
+        );
+
+        fs::create_dir(home.join(".config")).unwrap();
+        assert_eq!(
+            context.config_file_path(),
+            Some(format!("{home_str}/.config/userserversd_services.json"))
+        );
+
+        // A file in the home directory from before ~/.config existed keeps being used.
+        fs::write(home.join(".userserversd_services.json"), "{}").unwrap();
+        assert_eq!(
+            context.config_file_path(),
+            Some(format!("{home_str}/.userserversd_services.json"))
+        );
+    }
+
+    #[test]
+    fn state_dir_follows_xdg_state_home_unless_empty() {
+        let context = resolve(
+            account(None),
+            &[],
+            &[("HOME", "/home/alice"), ("XDG_STATE_HOME", "/xdg/state")],
+        );
+        assert_eq!(
+            context.state_dir.as_deref(),
+            Some("/xdg/state/userserversd")
+        );
+        assert_eq!(
+            context.history_file_path().as_deref(),
+            Some("/xdg/state/userserversd/history.jsonl")
+        );
+
+        let context = resolve(
+            account(None),
+            &[],
+            &[("HOME", "/home/alice"), ("XDG_STATE_HOME", "")],
+        );
+        assert_eq!(
+            context.state_dir.as_deref(),
+            Some("/home/alice/.local/state/userserversd")
+        );
+
+        let context = resolve(
+            account(None),
+            &[],
+            &[
+                ("HOME", "/home/alice"),
+                ("USERSERVERSD_STATE_DIR", "/state"),
+            ],
+        );
+        assert_eq!(context.state_dir.as_deref(), Some("/state"));
+    }
+
+    #[test]
+    fn xdg_runtime_dir_gets_a_private_subdirectory() {
+        let dir = scratch_dir("user-context", "xdg-runtime");
+        let context = resolve(
+            account(None),
+            &[],
+            &[("XDG_RUNTIME_DIR", dir.to_str().unwrap())],
+        );
+
+        let runtime_dir = dir.join("userserversd");
+        assert_eq!(context.runtime_dir.as_deref(), runtime_dir.to_str());
+        let mode = fs::metadata(&runtime_dir).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o700);
+        assert_eq!(
+            context.socket_path().unwrap(),
+            format!("{}/userserversd.sock", runtime_dir.display())
+        );
+    }
+
+    #[test]
+    fn runtime_dir_probing_skips_bases_where_the_user_dir_cant_be_created() {
+        let dir = scratch_dir("user-context", "probe");
+        let missing = dir.join("missing");
+        // `user` can't be made a directory here, as a file already has its name.
+        let unusable = dir.join("unusable");
+        fs::create_dir(&unusable).unwrap();
+        fs::write(unusable.join("user"), "").unwrap();
+        let usable = dir.join("usable");
+        fs::create_dir(&usable).unwrap();
+
+        let bases = [
+            missing.to_str().unwrap(),
+            unusable.to_str().unwrap(),
+            usable.to_str().unwrap(),
+        ];
+        let uid = unistd::getuid().as_raw();
+        let context = resolve(account(None), &bases, &[("XDG_RUNTIME_DIR", "")]);
+        assert_eq!(
+            context.runtime_dir,
+            Some(format!("{}/user/{uid}", usable.display()))
+        );
+    }
+
+    #[test]
+    fn runtime_dir_probing_distrusts_a_user_dir_owned_by_someone_else() {
+        let dir = scratch_dir("user-context", "probe-owner");
+        let shared = dir.join("shared");
+        let own = dir.join("own");
+        fs::create_dir(&own).unwrap();
+        // Made by the user running the tests, who isn't the one being resolved.
+        let other_uid = unistd::getuid().as_raw() + 1;
+        fs::create_dir_all(shared.join(format!("user/{other_uid}"))).unwrap();
+
+        let someone_else = Account {
+            uid: other_uid,
+            ..account(None)
+        };
+        let bases = [shared.to_str().unwrap(), own.to_str().unwrap()];
+        let context = resolve(someone_else, &bases, &[]);
+        assert_eq!(
+            context.runtime_dir,
+            Some(format!("{}/user/{other_uid}", own.display()))
+        );
+    }
+
+    #[test]
+    fn runtime_dir_probing_lists_every_failure() {
+        let dir = scratch_dir("user-context", "probe-failures");
+        let first = dir.join("first");
+        let second = dir.join("second");
+        for base in [&first, &second] {
+            fs::create_dir(base).unwrap();
+            fs::write(base.join("user"), "").unwrap();
+        }
+
+        let bases = [first.to_str().unwrap(), second.to_str().unwrap()];
+        let context = resolve(account(None), &bases, &[]);
+        assert_eq!(context.runtime_dir, None);
+        let err = context.socket_path().unwrap_err().to_string();
+        assert!(
+            err.contains(&format!("`{}/user/", first.display())),
+            "{err}"
+        );
+        assert!(
+            err.contains(&format!("`{}/user/", second.display())),
+            "{err}"
+        );
+
+        let context = resolve(account(None), &[dir.join("nowhere").to_str().unwrap()], &[]);
+        let err = context.socket_path().unwrap_err().to_string();
+        assert!(err.contains("none of"), "{err}");
+    }
+
+    #[test]
+    fn runtime_dir_from_the_environment_wins() {
+        let context = resolve(
+            account(None),
+            &["/nonexistent"],
+            &[
+                ("USERSERVERSD_RUNTIME_DIR", "/custom/runtime"),
+                ("XDG_RUNTIME_DIR", "/xdg"),
+            ],
+        );
+        assert_eq!(context.runtime_dir.as_deref(), Some("/custom/runtime"));
+        assert_eq!(
+            context.lock_file_path().unwrap(),
+            "/custom/runtime/userserversd.lock"
+        );
+    }
+}
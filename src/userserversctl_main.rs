@@ -1,96 +1,642 @@
-use std::collections::HashMap;
-use std::env;
-use std::os::unix::net::UnixStream;
-use std::path::Path;
-use std::process::exit;
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, IsTerminal, Write};
+use std::ops::{Deref, DerefMut};
+use std::os::unix::process::CommandExt;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant};
+use std::{env, fs};
 
-use serde::de::DeserializeOwned;
+use nix::sys::signal::Signal;
 
-use nix::unistd;
+use userserversd::client::{self, Client, ServiceFilter, Traced};
+use userserversd::ipc::response::{
+    ConfigReload, DaemonEnvironment, DaemonInfo, ResponseStatus, ServiceList, ServiceStatus,
+    SnapshotRestore,
+};
+use userserversd::{ipc, user_context};
 
 mod flag;
-mod ipc;
+#[cfg(test)]
+mod scratch;
+mod unit_file;
 
-use ipc::command::Command;
-use ipc::response::{Response, ResponseKind, ResponseStatus};
+// Set while `apply` runs its commands, which then end their line instead of the process and
+// share a single connection.
+static APPLYING: AtomicBool = AtomicBool::new(false);
+static SHARED_CLIENT: Mutex<Option<Client>> = Mutex::new(None);
+
+/// What a command of `apply` unwinds with instead of exiting.
+struct LineExit(i32);
+
+fn exit(code: i32) -> ! {
+    if APPLYING.load(Ordering::SeqCst) {
+        // Unlike a panic, this doesn't run the panic hook, so nothing gets printed.
+        panic::resume_unwind(Box::new(LineExit(code)));
+    }
+    process::exit(code);
+}
+
+// Printed once the whole command succeeded.
+static WARNINGS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+struct Output {
+    quiet: bool,
+    verbose: bool,
+    spawn_daemon: bool,
+}
+
+// Set from the global flags before any subcommand runs.
+static OUTPUT: OnceLock<Output> = OnceLock::new();
+
+fn output() -> &'static Output {
+    OUTPUT.get().unwrap()
+}
+
+/// Prints the title of what a subcommand displays, unless the output should be kept terse.
+fn print_header(title: &str) {
+    if !output().quiet {
+        println!("{title}:");
+        println!();
+    }
+}
+
+// Documented in the help, so that scripts can tell failures apart.
+const EXIT_FAILURE: i32 = 1;
+const EXIT_USAGE: i32 = 2;
+const EXIT_DAEMON_UNREACHABLE: i32 = 3;
+const EXIT_SERVICE_DOES_NOT_EXIST: i32 = 4;
+const EXIT_SERVICE_ALREADY_EXISTS: i32 = 5;
+const EXIT_SERVICE_NOT_RUNNING: i32 = 6;
+const EXIT_TARGET_ERROR: i32 = 7;
+const EXIT_START_FAILED: i32 = 8;
+const EXIT_TIMED_OUT: i32 = 9;
+const EXIT_INVALID_SERVICE: i32 = 10;
+const EXIT_SERVICE_ALREADY_RUNNING: i32 = 11;
+
+const EXIT_CODES: [(i32, &str); 11] = [
+    (
+        EXIT_FAILURE,
+        "Any other failure, such as the system refusing to spawn a process.",
+    ),
+    (
+        EXIT_USAGE,
+        "The command line arguments are invalid, such as an unknown flag or malformed JSON.",
+    ),
+    (
+        EXIT_DAEMON_UNREACHABLE,
+        "The daemon isn't running, or is too busy, restarting or stopping.",
+    ),
+    (EXIT_SERVICE_DOES_NOT_EXIST, "The service does not exist."),
+    (EXIT_SERVICE_ALREADY_EXISTS, "The service already exists."),
+    (EXIT_SERVICE_NOT_RUNNING, "The service is not running."),
+    (
+        EXIT_TARGET_ERROR,
+        "The target does not exist, or the service is not part of it.",
+    ),
+    (
+        EXIT_START_FAILED,
+        "The service failed to start, because of its command, a hook, its pidfile, its ports or a dependency.",
+    ),
+    (EXIT_TIMED_OUT, "The service wasn't ready in time."),
+    (
+        EXIT_INVALID_SERVICE,
+        "The service definition is invalid, or its dependencies form a cycle.",
+    ),
+    (
+        EXIT_SERVICE_ALREADY_RUNNING,
+        "The service is already running.",
+    ),
+];
+
+// Run by the completion scripts to list what can be completed.
+const COMPLETE_SERVICE_NAMES: &str = "__complete-service-names";
+const COMPLETE_TARGET_NAMES: &str = "__complete-target-names";
+
+fn parse_ports(ports: &[String]) -> Vec<u16> {
+    ports
+        .iter()
+        .filter(|port| !port.is_empty())
+        .map(|port| {
+            port.parse().unwrap_or_else(|_| {
+                eprintln!("ERROR: `{port}` is not a valid port");
+                exit(EXIT_USAGE);
+            })
+        })
+        .collect()
+}
+
+fn parse_watch_paths(paths: &[String]) -> Vec<String> {
+    paths
+        .iter()
+        .filter(|path| !path.is_empty())
+        .cloned()
+        .collect()
+}
+
+fn parse_dependencies(services: &[String]) -> Vec<String> {
+    let mut dependencies = Vec::new();
+    for service in services.iter().filter(|service| !service.is_empty()) {
+        if !dependencies.contains(service) {
+            dependencies.push(service.clone());
+        }
+    }
+    dependencies
+}
+
+fn parse_ready_port(port: &str) -> u16 {
+    match port.parse() {
+        Ok(0) | Err(_) => {
+            eprintln!("ERROR: `{port}` is not a port that can be listened on");
+            exit(EXIT_USAGE);
+        }
+        Ok(port) => port,
+    }
+}
+
+fn check_health_interval(secs: u64) -> u64 {
+    if secs == 0 {
+        eprintln!("ERROR: the health check interval must be a positive number of seconds");
+        exit(EXIT_USAGE);
+    }
+    secs
+}
+
+const DURATION_UNITS: [(&str, u64); 4] = [("d", 86400), ("h", 3600), ("m", 60), ("s", 1)];
+
+fn parse_duration(name: &str, duration: &str) -> u64 {
+    let (number, unit) = match DURATION_UNITS
+        .iter()
+        .find_map(|(unit, secs)| Some((duration.strip_suffix(unit)?, *secs)))
+    {
+        Some(split) => split,
+        None => (duration, 1),
+    };
+
+    match number.parse::<u64>() {
+        Ok(number) if number > 0 && number.checked_mul(unit).is_some() => number * unit,
+        _ => {
+            eprintln!(
+                "ERROR: the {name} must be a positive number of seconds, optionally followed by a unit such as in `30s`, `15m`, `2h` or `1d`"
+            );
+            exit(EXIT_USAGE);
+        }
+    }
+}
+
+fn format_duration(secs: u64) -> String {
+    let (unit, unit_secs) = DURATION_UNITS
+        .iter()
+        .find(|(_, unit_secs)| secs.is_multiple_of(*unit_secs))
+        .unwrap();
+    format!("{}{unit}", secs / unit_secs)
+}
+
+fn format_exit(exit: &ipc::ExitStatus) -> Option<String> {
+    match exit {
+        ipc::ExitStatus {
+            code: Some(code), ..
+        } => Some(format!("exited with code {code}")),
+        ipc::ExitStatus {
+            signal: Some(signal),
+            ..
+        } => Some(format!("killed by signal {signal}")),
+        _ => None,
+    }
+}
+
+/// Formats a span of time with its two largest units, such as in `3h 12m`.
+fn format_elapsed(secs: u64) -> String {
+    let parts = [
+        (secs / 86400, "d"),
+        (secs / 3600 % 24, "h"),
+        (secs / 60 % 60, "m"),
+        (secs % 60, "s"),
+    ];
+    let first = parts
+        .iter()
+        .position(|(value, _)| *value > 0)
+        .unwrap_or(parts.len() - 1);
+    parts[first..]
+        .iter()
+        .take(2)
+        .map(|(value, unit)| format!("{value}{unit}"))
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+
+    if bytes < 1024 {
+        return format!("{bytes} B");
+    }
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit + 1 < UNITS.len() {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{value:.1} {}", UNITS[unit])
+}
 
 fn get_home_directory() -> String {
-    match env::var("HOME") {
-        Ok(path) => path,
-        Err(_) => {
-            let uid = unistd::getuid();
-            match unistd::User::from_uid(uid) {
-                Ok(Some(user)) => {
-                    let home = format!("/home/{}", user.name);
-                    if !Path::new(&home).exists() {
-                        eprintln!("ERROR: failed to get home directory path");
-                        exit(1);
-                    }
-                    home
-                }
-                _ => {
-                    eprintln!("ERROR: failed to get home directory path");
-                    exit(1);
-                }
+    user_context::get().home.clone().unwrap_or_else(|| {
+        eprintln!("ERROR: failed to get home directory path");
+        exit(1);
+    })
+}
+
+fn exit_code(err: &client::Error) -> i32 {
+    match err {
+        client::Error::SocketPath(_) | client::Error::Connect(_) => EXIT_DAEMON_UNREACHABLE,
+        client::Error::Command(status) => match status {
+            ResponseStatus::Busy | ResponseStatus::ShuttingDown | ResponseStatus::Stopping => {
+                EXIT_DAEMON_UNREACHABLE
+            }
+            ResponseStatus::ServiceDoesNotExist => EXIT_SERVICE_DOES_NOT_EXIST,
+            ResponseStatus::ServiceAlreadyExists => EXIT_SERVICE_ALREADY_EXISTS,
+            ResponseStatus::ServiceNotRunning => EXIT_SERVICE_NOT_RUNNING,
+            ResponseStatus::ServiceAlreadyRunning => EXIT_SERVICE_ALREADY_RUNNING,
+            ResponseStatus::TargetDoesNotExist | ResponseStatus::ServiceNotInTarget => {
+                EXIT_TARGET_ERROR
             }
+            ResponseStatus::CommandTimedOut { command, .. } if command == "start" => {
+                EXIT_START_FAILED
+            }
+            ResponseStatus::StartCommandFailed { .. }
+            | ResponseStatus::WorkingDirectoryNotFound { .. }
+            | ResponseStatus::ExecutableNotFound { .. }
+            | ResponseStatus::NotExecutable { .. }
+            | ResponseStatus::PidfileTimeout
+            | ResponseStatus::HookFailed { .. }
+            | ResponseStatus::PortInUse { .. }
+            | ResponseStatus::DependencyFailed { .. } => EXIT_START_FAILED,
+            ResponseStatus::WaitTimedOut => EXIT_TIMED_OUT,
+            ResponseStatus::ValidationFailed { .. } | ResponseStatus::DependencyCycle { .. } => {
+                EXIT_INVALID_SERVICE
+            }
+            ResponseStatus::Ok
+            | ResponseStatus::StopCommandFailed { .. }
+            | ResponseStatus::CommandTimedOut { .. }
+            | ResponseStatus::NoReloadMethod
+            | ResponseStatus::NoKnownProcess
+            | ResponseStatus::ConfigReloadFailed { .. }
+            | ResponseStatus::FlushFailed { .. }
+            | ResponseStatus::SnapshotDoesNotExist
+            | ResponseStatus::SnapshotFailed { .. }
+            | ResponseStatus::ReloadCommandFailed { .. }
+            | ResponseStatus::HistoryFailed { .. }
+            | ResponseStatus::IoError { .. }
+            | ResponseStatus::GroupIsEmpty => EXIT_FAILURE,
+            // Failures of a newer daemon.
+            _ => EXIT_FAILURE,
+        },
+        client::Error::Send(_)
+        | client::Error::Receive(_)
+        | client::Error::ConnectionClosed
+        | client::Error::UnexpectedResponse => EXIT_FAILURE,
+    }
+}
+
+/// Prints the error and exits with the code matching it.
+fn fail(err: client::Error) -> ! {
+    println!("ERROR: {err}");
+    exit(exit_code(&err));
+}
+
+/// A connection to the daemon, which is handed back to `apply` once a command is done with it.
+struct Connection(Option<Client>);
+
+impl Deref for Connection {
+    type Target = Client;
+
+    fn deref(&self) -> &Client {
+        self.0.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for Connection {
+    fn deref_mut(&mut self) -> &mut Client {
+        self.0.as_mut().unwrap()
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        if APPLYING.load(Ordering::SeqCst) {
+            *SHARED_CLIENT.lock().unwrap() = self.0.take();
         }
     }
 }
 
-fn run_command(socket: &mut UnixStream, command: Command) -> Response {
-    command.write_to_stream(socket).unwrap_or_else(|err| {
-        eprintln!("ERROR: failed to send command to server: {err}");
-        exit(1);
-    });
+const SPAWN_DAEMON_VARIABLE: &str = "USERSERVERSCTL_SPAWN_DAEMON";
+const SPAWN_TIMEOUT: Duration = Duration::from_secs(5);
+const SPAWN_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Whether connecting failed because nothing listens on the socket, rather than for another
+/// reason such as a lack of permissions.
+fn is_daemon_missing(err: &client::Error) -> bool {
+    matches!(
+        err,
+        client::Error::Connect(err)
+            if matches!(err.kind(), io::ErrorKind::NotFound | io::ErrorKind::ConnectionRefused)
+    )
+}
 
-    let response = Response::read_from_stream(socket)
+/// Starts the daemon in the background, preferably the one next to this binary, and connects
+/// to it once it listens.
+fn spawn_daemon() -> Result<Client, client::Error> {
+    let program = env::current_exe()
+        .ok()
+        .and_then(|path| Some(path.parent()?.join("userserversd")))
+        .filter(|path| path.exists())
+        .unwrap_or_else(|| "userserversd".into());
+
+    // Kept out of the process group of the terminal, so that the daemon outlives it. It is
+    // only waited for if it exits right away, since it is meant to keep running on its own.
+    #[allow(clippy::zombie_processes)]
+    let mut daemon = process::Command::new(&program)
+        .stdin(process::Stdio::null())
+        .stdout(process::Stdio::null())
+        .stderr(process::Stdio::null())
+        .process_group(0)
+        .spawn()
         .unwrap_or_else(|err| {
-            eprintln!("ERROR: failed to receive response from server: {err}");
-            exit(1);
+            println!("ERROR: failed to start `{}`: {err}", program.display());
+            exit(EXIT_DAEMON_UNREACHABLE);
+        });
+
+    let started_at = Instant::now();
+    loop {
+        match Client::connect() {
+            Err(err) if is_daemon_missing(&err) && started_at.elapsed() < SPAWN_TIMEOUT => {}
+            result => return result,
+        }
+        if let Ok(Some(status)) = daemon.try_wait() {
+            println!("ERROR: userserversd exited right after starting ({status})");
+            exit(EXIT_DAEMON_UNREACHABLE);
+        }
+        thread::sleep(SPAWN_POLL_INTERVAL);
+    }
+}
+
+fn connect() -> Connection {
+    if let Some(client) = SHARED_CLIENT.lock().unwrap().take() {
+        return Connection(Some(client));
+    }
+
+    let result = match Client::connect() {
+        Err(err) if is_daemon_missing(&err) && output().spawn_daemon => spawn_daemon(),
+        result => result,
+    };
+    let mut client = result.unwrap_or_else(|err| {
+        if is_daemon_missing(&err) {
+            println!(
+                "ERROR: userserversd does not appear to be running; start it with `userserversd &` or your session manager, or pass --spawn-daemon"
+            );
+            exit(EXIT_DAEMON_UNREACHABLE);
+        }
+        fail(err)
+    });
+    if output().verbose {
+        client.set_trace(|traced| match traced {
+            Traced::Command(command) => {
+                eprintln!("> {}", serde_json::to_string(command).unwrap());
+            }
+            Traced::Response(response) => {
+                eprintln!("< {}", serde_json::to_string(response).unwrap());
+            }
+        });
+    }
+    Connection(Some(client))
+}
+
+/// Runs a request through the client, exiting if it fails.
+fn run<T>(client: &mut Client, request: impl FnOnce(&mut Client) -> Result<T, client::Error>) -> T {
+    let result = request(client);
+    WARNINGS.lock().unwrap().extend(client.take_warnings());
+    result.unwrap_or_else(|err| fail(err))
+}
+
+/// Takes a command as a single string for shell services, or as a JSON array otherwise.
+fn parse_command(shell: bool, command: &str) -> Vec<String> {
+    if shell {
+        vec![command.to_string()]
+    } else {
+        serde_json::from_str(command).unwrap_or_else(|err| {
+            eprintln!("ERROR: the command must be a JSON array: {err}");
+            exit(EXIT_USAGE);
         })
-        .unwrap_or_else(|| {
-            eprintln!("ERROR: connection with server unexpectedly closed");
-            exit(1);
+    }
+}
+
+fn format_command(shell: bool, command: &[String]) -> String {
+    if shell {
+        command.join(" ")
+    } else {
+        format!("{command:?}")
+    }
+}
+
+enum PatternToken {
+    AnyText,
+    AnyChar,
+    Char(char),
+}
+
+fn parse_pattern(pattern: &str) -> Vec<PatternToken> {
+    let mut tokens = Vec::new();
+    let mut chars = pattern.chars();
+    while let Some(char) = chars.next() {
+        tokens.push(match char {
+            '*' => PatternToken::AnyText,
+            '?' => PatternToken::AnyChar,
+            '\\' => PatternToken::Char(chars.next().unwrap_or('\\')),
+            char => PatternToken::Char(char),
         });
+    }
+    tokens
+}
 
-    if response.status != ResponseStatus::Ok {
-        println!(
-            "ERROR: command execution failed with the following status: {:?}",
-            response.status
-        );
-        exit(1);
+fn pattern_matches(tokens: &[PatternToken], name: &str) -> bool {
+    let name = name.chars().collect::<Vec<char>>();
+    let (mut token, mut char) = (0, 0);
+    // Where to go back to when the rest doesn't match: right after the last `*`, with it
+    // covering one more character.
+    let mut backtrack = None;
+
+    while char < name.len() {
+        match tokens.get(token) {
+            Some(PatternToken::AnyText) => {
+                backtrack = Some((token + 1, char));
+                token += 1;
+                continue;
+            }
+            Some(PatternToken::AnyChar) => {
+                token += 1;
+                char += 1;
+                continue;
+            }
+            Some(PatternToken::Char(expected)) if *expected == name[char] => {
+                token += 1;
+                char += 1;
+                continue;
+            }
+            _ => {}
+        }
+
+        match backtrack {
+            Some((after_star, star_end)) => {
+                backtrack = Some((after_star, star_end + 1));
+                token = after_star;
+                char = star_end + 1;
+            }
+            None => return false,
+        }
     }
 
-    response
+    tokens[token..]
+        .iter()
+        .all(|token| matches!(token, PatternToken::AnyText))
 }
 
-fn connect_to_socket() -> UnixStream {
-    let socket_path = ipc::get_socket_path().unwrap_or_else(|err| {
-        eprintln!("ERROR: failed to get socket path: {err}");
-        exit(1);
-    });
+/// Turns service names and patterns into the names of the services they stand for. Plain names
+/// are kept even if there is no such service, so that the daemon reports it.
+fn resolve_service_names(
+    client: &mut Client,
+    subcommand: &flag::ParsedCommand,
+    names: &[String],
+) -> Vec<String> {
+    let mut service_names = Vec::new();
+    let mut existing_names = None;
 
-    match UnixStream::connect(socket_path) {
-        Ok(sock) => sock,
-        Err(err) => {
-            println!("ERROR: failed to connect to socket: {err}");
-            exit(1);
+    for name in names {
+        let tokens = parse_pattern(name);
+        if tokens
+            .iter()
+            .all(|token| matches!(token, PatternToken::Char(_)))
+        {
+            let name = tokens
+                .iter()
+                .filter_map(|token| match token {
+                    PatternToken::Char(char) => Some(*char),
+                    _ => None,
+                })
+                .collect::<String>();
+            if !service_names.contains(&name) {
+                service_names.push(name);
+            }
+            continue;
+        }
+
+        let existing_names = existing_names.get_or_insert_with(|| {
+            let ServiceList { services, .. } = run(client, |client| {
+                client.list_services(ServiceFilter::default())
+            });
+            let mut names = services.into_keys().collect::<Vec<String>>();
+            names.sort();
+            names
+        });
+        let matches = existing_names
+            .iter()
+            .filter(|existing_name| pattern_matches(&tokens, existing_name))
+            .collect::<Vec<&String>>();
+        if matches.is_empty() && !subcommand.has_flag("allow-empty") {
+            eprintln!("ERROR: no service matches `{name}`");
+            exit(EXIT_SERVICE_DOES_NOT_EXIST);
+        }
+
+        for name in matches {
+            if !service_names.contains(name) {
+                service_names.push(name.clone());
+            }
         }
     }
+
+    service_names
 }
 
-fn from_json<T: DeserializeOwned>(json: &String) -> T {
-    serde_json::from_str(json.as_str()).unwrap_or_else(|err| {
-        eprintln!("ERROR: invalid json was provided via the command line arguments: {err}");
-        exit(1);
-    })
+/// Runs an action on each service. With several services, a failure doesn't keep the action
+/// from running on the others, and the ctl exits with the code of the last failure at the end.
+fn run_for_each(
+    client: &mut Client,
+    names: &[String],
+    mut action: impl FnMut(&mut Client, &str) -> Result<(), client::Error>,
+) {
+    if let [name] = names {
+        run(client, |client| action(client, name));
+        return;
+    }
+
+    let mut failure = None;
+    for name in names {
+        let result = action(client, name);
+        WARNINGS.lock().unwrap().extend(
+            client
+                .take_warnings()
+                .into_iter()
+                .map(|warning| format!("{name}: {warning}")),
+        );
+        match result {
+            Ok(()) => println!("{name}: done"),
+            Err(err) => {
+                println!("{name}: ERROR: {err}");
+                failure = Some(exit_code(&err));
+            }
+        }
+    }
+
+    if let Some(code) = failure {
+        print_warnings();
+        exit(code);
+    }
+}
+
+fn print_warnings() {
+    for warning in WARNINGS.lock().unwrap().drain(..) {
+        eprintln!("WARNING: {warning}");
+    }
+}
+
+const SERVICE_PATTERN_HELP: &str = "The name of the service, or a pattern where `*` matches any text and `?` any single character. Either can be escaped with a backslash.";
+
+fn add_allow_empty_flag(command: &mut flag::Command) {
+    command.add_bool_flag(
+        "ae",
+        "allow-empty",
+        "Succeeds without doing anything when a pattern matches no service.",
+    );
 }
 
 fn cli() -> flag::Command {
     let mut root_command =
         flag::Command::new(None, "Add, remove, edit or query userserversd services.");
+    root_command.add_bool_flag(
+        "q",
+        "quiet",
+        "Leaves out the success message and the titles of what is displayed.",
+    );
+    root_command.add_bool_flag(
+        "v",
+        "verbose",
+        "Prints the commands sent to the daemon and its responses to stderr, as JSON.",
+    );
+    root_command.add_bool_flag(
+        "s",
+        "spawn-daemon",
+        "Starts userserversd in the background if it isn't running, then runs the command. Setting USERSERVERSCTL_SPAWN_DAEMON to anything but `0` does the same.",
+    );
+    root_command.add_version_flag("Prints the version of userserversctl.");
 
-    let mut add_command = flag::Command::new(Some("add"), "Adds a new service.");
+    let mut add_command = flag::Command::new(
+        Some("add"),
+        "Adds a new service. Each `${VAR}` in its commands and working directory is replaced with the variable from the environment of the service when it starts, and `$${` stands for a literal `${`. Shell commands are left for the shell to expand instead.",
+    );
 
     let mut sync_subcommand = flag::Command::new(
         Some("sync"),
@@ -101,9 +647,105 @@ fn cli() -> flag::Command {
     sync_subcommand.add_flag(
         "w",
         "working-directory",
-        "Sets the working directory of the service to the provided argument.",
+        "Sets the working directory of the service to the provided argument. A leading `~` or `~user` is expanded to the matching home directory when the service starts.",
+    );
+    sync_subcommand.add_json_flag::<HashMap<String, String>>("e", "environment", "Overrides the environment variables of the service with the ones specified in the provided argument. The provided argument must be a JSON map.");
+    sync_subcommand.add_json_flag::<Vec<String>>(
+        "rc",
+        "ready-cmd",
+        "Considers the service ready once the provided command succeeds after it starts. The command must be a JSON array.",
+    );
+    sync_subcommand.add_flag(
+        "rt",
+        "ready-tcp",
+        "Considers the service ready once something listens on the provided TCP port after it starts.",
+    );
+    sync_subcommand.add_json_flag::<Vec<String>>(
+        "hc",
+        "health-check",
+        "Periodically runs the provided command while the service is up, marking the service as unhealthy after 3 failures in a row. The command must be a JSON array.",
+    );
+    sync_subcommand.add_int_flag(
+        "hi",
+        "health-interval",
+        "Sets how often, in seconds, the health check runs. Defaults to 30.",
+    );
+    sync_subcommand.add_repeatable_flag(
+        "pt",
+        "port",
+        "Declares a TCP port the service listens on, so that starting it fails early when the port is taken.",
+    );
+    sync_subcommand.add_repeatable_flag(
+        "wp",
+        "watch",
+        "Restarts the service whenever the provided file or directory changes. Relative paths are resolved from the working directory of the service.",
+    );
+    sync_subcommand.add_repeatable_flag(
+        "d",
+        "depends-on",
+        "Makes the service depend on the provided service, which then gets started before it.",
+    );
+    sync_subcommand.add_json_flag::<Vec<String>>(
+        "pre",
+        "pre-start",
+        "Runs the provided command before each start of the service, which is aborted if the command fails. The command must be a JSON array.",
+    );
+    sync_subcommand.add_json_flag::<Vec<String>>(
+        "post",
+        "post-stop",
+        "Runs the provided command after each successful stop of the service. The command must be a JSON array.",
+    );
+    sync_subcommand.add_json_flag::<Vec<String>>(
+        "of",
+        "on-failure",
+        "Runs the provided command whenever the service exits with a failure on its own, with USERSERVERSD_SERVICE, USERSERVERSD_EXIT_CODE and USERSERVERSD_EXIT_SIGNAL set. The command must be a JSON array.",
+    );
+    sync_subcommand.add_flag(
+        "rls",
+        "reload-signal",
+        "Reloads the service by sending the provided signal, such as SIGHUP, to its process.",
+    );
+    sync_subcommand.add_json_flag::<Vec<String>>(
+        "rlc",
+        "reload-cmd",
+        "Reloads the service by running the provided command. The command must be a JSON array.",
+    );
+    sync_subcommand.add_bool_flag(
+        "ce",
+        "clean-env",
+        "Starts the service with only the HOME, PATH, USER and LANG variables of the daemon, plus the environment variable overrides, instead of the whole environment of the daemon.",
+    );
+    sync_subcommand.add_bool_flag(
+        "cw",
+        "create-working-directory",
+        "Creates the working directory, along with its parents, when the service starts if it doesn't exist yet. The working directory then doesn't have to exist when the service is added.",
+    );
+    sync_subcommand.add_bool_flag(
+        "ti",
+        "process-title",
+        "Names the processes of the service `userserversd: <service name>` in `ps`, by setting their argv[0]. Programs that look at their argv[0] may behave differently.",
+    );
+    sync_subcommand.add_bool_flag(
+        "sh",
+        "shell",
+        "Takes the commands as single strings that get run with `sh -c`, such as `foo | tee log`, instead of JSON arrays.",
+    );
+    sync_subcommand.add_bool_flag(
+        "f",
+        "force",
+        "Skips checking that the working directory and the executables of the service exist, such as for services on a mount that isn't up yet.",
+    );
+    sync_subcommand.add_flag(
+        "lr",
+        "log-retention",
+        "Drops the logs of the service once they are older than the provided duration, such as `30m`, `24h` or `7d`.",
+    );
+    sync_subcommand.add_flag("desc", "description", "Describes what the service is for.");
+    sync_subcommand.add_repeatable_flag(
+        "l",
+        "label",
+        "Labels the service with the provided KEY=VALUE pair, which list-services can filter on.",
     );
-    sync_subcommand.add_flag("e", "environment", "Overrides the environment variables of the service with the ones specified in the provided argument. The provided argument must be a JSON map.");
     sync_subcommand.add_flag(
         "g",
         "group",
@@ -120,23 +762,296 @@ fn cli() -> flag::Command {
     async_subcommand.add_flag(
         "w",
         "working-directory",
-        "Sets the working directory of the service to the provided argument.",
+        "Sets the working directory of the service to the provided argument. A leading `~` or `~user` is expanded to the matching home directory when the service starts.",
+    );
+    async_subcommand.add_json_flag::<HashMap<String, String>>("se", "stop-environment", "Runs the stop command with only the environment variable overrides specified in the provided argument instead of the ones of the service. The provided argument must be a JSON map.");
+    async_subcommand.add_flag(
+        "sw",
+        "stop-working-directory",
+        "Runs the stop command in the provided working directory instead of the one of the service.",
+    );
+    async_subcommand.add_flag(
+        "p",
+        "pidfile",
+        "Tracks the service through the PID written to the provided file by the start command, relative to the working directory of the service.",
+    );
+    async_subcommand.add_flag(
+        "ct",
+        "command-timeout",
+        "Kills the start or stop command once it has run for the provided duration, such as `90s` or `5m`, which then fails. Defaults to 60s.",
+    );
+    async_subcommand.add_json_flag::<HashMap<String, String>>("e", "environment", "Overrides the environment variables of the service with the ones specified in the provided argument. The provided argument must be a JSON map.");
+    async_subcommand.add_json_flag::<Vec<String>>(
+        "rc",
+        "ready-cmd",
+        "Considers the service ready once the provided command succeeds after it starts. The command must be a JSON array.",
+    );
+    async_subcommand.add_flag(
+        "rt",
+        "ready-tcp",
+        "Considers the service ready once something listens on the provided TCP port after it starts.",
+    );
+    async_subcommand.add_json_flag::<Vec<String>>(
+        "hc",
+        "health-check",
+        "Periodically runs the provided command while the service is up, marking the service as unhealthy after 3 failures in a row. The command must be a JSON array.",
+    );
+    async_subcommand.add_int_flag(
+        "hi",
+        "health-interval",
+        "Sets how often, in seconds, the health check runs. Defaults to 30.",
+    );
+    async_subcommand.add_repeatable_flag(
+        "pt",
+        "port",
+        "Declares a TCP port the service listens on, so that starting it fails early when the port is taken.",
+    );
+    async_subcommand.add_repeatable_flag(
+        "wp",
+        "watch",
+        "Restarts the service whenever the provided file or directory changes. Relative paths are resolved from the working directory of the service.",
+    );
+    async_subcommand.add_repeatable_flag(
+        "d",
+        "depends-on",
+        "Makes the service depend on the provided service, which then gets started before it.",
+    );
+    async_subcommand.add_json_flag::<Vec<String>>(
+        "pre",
+        "pre-start",
+        "Runs the provided command before each start of the service, which is aborted if the command fails. The command must be a JSON array.",
+    );
+    async_subcommand.add_json_flag::<Vec<String>>(
+        "post",
+        "post-stop",
+        "Runs the provided command after each successful stop of the service. The command must be a JSON array.",
+    );
+    async_subcommand.add_json_flag::<Vec<String>>(
+        "of",
+        "on-failure",
+        "Runs the provided command whenever the service exits with a failure on its own, with USERSERVERSD_SERVICE, USERSERVERSD_EXIT_CODE and USERSERVERSD_EXIT_SIGNAL set. The command must be a JSON array.",
+    );
+    async_subcommand.add_flag(
+        "rls",
+        "reload-signal",
+        "Reloads the service by sending the provided signal, such as SIGHUP, to its process.",
+    );
+    async_subcommand.add_json_flag::<Vec<String>>(
+        "rlc",
+        "reload-cmd",
+        "Reloads the service by running the provided command. The command must be a JSON array.",
+    );
+    async_subcommand.add_bool_flag(
+        "ce",
+        "clean-env",
+        "Starts the service with only the HOME, PATH, USER and LANG variables of the daemon, plus the environment variable overrides, instead of the whole environment of the daemon.",
+    );
+    async_subcommand.add_bool_flag(
+        "cw",
+        "create-working-directory",
+        "Creates the working directory, along with its parents, when the service starts if it doesn't exist yet. The working directory then doesn't have to exist when the service is added.",
+    );
+    async_subcommand.add_bool_flag(
+        "ti",
+        "process-title",
+        "Names the processes of the service `userserversd: <service name>` in `ps`, by setting their argv[0]. Programs that look at their argv[0] may behave differently.",
+    );
+    async_subcommand.add_bool_flag(
+        "sh",
+        "shell",
+        "Takes the commands as single strings that get run with `sh -c`, such as `foo | tee log`, instead of JSON arrays.",
+    );
+    async_subcommand.add_bool_flag(
+        "f",
+        "force",
+        "Skips checking that the working directory and the executables of the service exist, such as for services on a mount that isn't up yet.",
+    );
+    async_subcommand.add_flag(
+        "lr",
+        "log-retention",
+        "Drops the logs of the service once they are older than the provided duration, such as `30m`, `24h` or `7d`.",
+    );
+    async_subcommand.add_flag("desc", "description", "Describes what the service is for.");
+    async_subcommand.add_repeatable_flag(
+        "l",
+        "label",
+        "Labels the service with the provided KEY=VALUE pair, which list-services can filter on.",
     );
-    async_subcommand.add_flag("e", "environment", "Overrides the environment variables of the service with the ones specified in the provided argument. The provided argument must be a JSON map.");
     async_subcommand.add_flag(
         "g",
         "group",
         "Makes the service part of the group specified in the provided argument.",
     );
 
+    let mut oneshot_subcommand = flag::Command::new(
+        Some("oneshot"),
+        "Adds a oneshot service with the specified name that runs the specified command once each time it is started, and counts as completed once the command succeeds. The command must be a JSON array, with each item being a command line argument.",
+    );
+    oneshot_subcommand.add_positional_arg("service name", "The name of the service.");
+    oneshot_subcommand.add_positional_arg("command", "The command that the service will run.");
+    oneshot_subcommand.add_flag(
+        "w",
+        "working-directory",
+        "Sets the working directory of the service to the provided argument. A leading `~` or `~user` is expanded to the matching home directory when the service starts.",
+    );
+    oneshot_subcommand.add_json_flag::<HashMap<String, String>>("e", "environment", "Overrides the environment variables of the service with the ones specified in the provided argument. The provided argument must be a JSON map.");
+    oneshot_subcommand.add_repeatable_flag(
+        "d",
+        "depends-on",
+        "Makes the service depend on the provided service, which then gets started before it.",
+    );
+    oneshot_subcommand.add_json_flag::<Vec<String>>(
+        "pre",
+        "pre-start",
+        "Runs the provided command before each start of the service, which is aborted if the command fails. The command must be a JSON array.",
+    );
+    oneshot_subcommand.add_json_flag::<Vec<String>>(
+        "post",
+        "post-stop",
+        "Runs the provided command after each successful stop of the service. The command must be a JSON array.",
+    );
+    oneshot_subcommand.add_json_flag::<Vec<String>>(
+        "of",
+        "on-failure",
+        "Runs the provided command whenever the service exits with a failure on its own, with USERSERVERSD_SERVICE, USERSERVERSD_EXIT_CODE and USERSERVERSD_EXIT_SIGNAL set. The command must be a JSON array.",
+    );
+    oneshot_subcommand.add_bool_flag(
+        "ce",
+        "clean-env",
+        "Starts the service with only the HOME, PATH, USER and LANG variables of the daemon, plus the environment variable overrides, instead of the whole environment of the daemon.",
+    );
+    oneshot_subcommand.add_bool_flag(
+        "cw",
+        "create-working-directory",
+        "Creates the working directory, along with its parents, when the service starts if it doesn't exist yet. The working directory then doesn't have to exist when the service is added.",
+    );
+    oneshot_subcommand.add_bool_flag(
+        "ti",
+        "process-title",
+        "Names the processes of the service `userserversd: <service name>` in `ps`, by setting their argv[0]. Programs that look at their argv[0] may behave differently.",
+    );
+    oneshot_subcommand.add_bool_flag(
+        "sh",
+        "shell",
+        "Takes the commands as single strings that get run with `sh -c`, such as `foo | tee log`, instead of JSON arrays.",
+    );
+    oneshot_subcommand.add_bool_flag(
+        "f",
+        "force",
+        "Skips checking that the working directory and the executables of the service exist, such as for services on a mount that isn't up yet.",
+    );
+    oneshot_subcommand.add_flag(
+        "lr",
+        "log-retention",
+        "Drops the logs of the service once they are older than the provided duration, such as `30m`, `24h` or `7d`.",
+    );
+    oneshot_subcommand.add_flag("desc", "description", "Describes what the service is for.");
+    oneshot_subcommand.add_repeatable_flag(
+        "l",
+        "label",
+        "Labels the service with the provided KEY=VALUE pair, which list-services can filter on.",
+    );
+    oneshot_subcommand.add_flag(
+        "g",
+        "group",
+        "Makes the service part of the group specified in the provided argument.",
+    );
+
+    let mut timer_subcommand = flag::Command::new(
+        Some("timer"),
+        "Adds a timer service with the specified name that, while started, runs the specified command right away and then once every interval given with --every. The command must be a JSON array, with each item being a command line argument.",
+    );
+    timer_subcommand.add_positional_arg("service name", "The name of the service.");
+    timer_subcommand.add_positional_arg("command", "The command that the service will run.");
+    timer_subcommand.add_flag(
+        "ev",
+        "every",
+        "Sets how often the command runs, as a number of seconds optionally followed by a unit, such as `30s`, `15m`, `2h` or `1d`. Timers are only checked once per watch interval of the daemon, so shorter intervals get rounded up to it. Required.",
+    );
+    timer_subcommand.add_flag(
+        "w",
+        "working-directory",
+        "Sets the working directory of the service to the provided argument. A leading `~` or `~user` is expanded to the matching home directory when the service starts.",
+    );
+    timer_subcommand.add_json_flag::<HashMap<String, String>>("e", "environment", "Overrides the environment variables of the service with the ones specified in the provided argument. The provided argument must be a JSON map.");
+    timer_subcommand.add_repeatable_flag(
+        "d",
+        "depends-on",
+        "Makes the service depend on the provided service, which then gets started before it.",
+    );
+    timer_subcommand.add_json_flag::<Vec<String>>(
+        "pre",
+        "pre-start",
+        "Runs the provided command before each start of the service, which is aborted if the command fails. The command must be a JSON array.",
+    );
+    timer_subcommand.add_json_flag::<Vec<String>>(
+        "post",
+        "post-stop",
+        "Runs the provided command after each successful stop of the service. The command must be a JSON array.",
+    );
+    timer_subcommand.add_json_flag::<Vec<String>>(
+        "of",
+        "on-failure",
+        "Runs the provided command whenever the service exits with a failure on its own, with USERSERVERSD_SERVICE, USERSERVERSD_EXIT_CODE and USERSERVERSD_EXIT_SIGNAL set. The command must be a JSON array.",
+    );
+    timer_subcommand.add_bool_flag(
+        "ce",
+        "clean-env",
+        "Starts the service with only the HOME, PATH, USER and LANG variables of the daemon, plus the environment variable overrides, instead of the whole environment of the daemon.",
+    );
+    timer_subcommand.add_bool_flag(
+        "cw",
+        "create-working-directory",
+        "Creates the working directory, along with its parents, when the service starts if it doesn't exist yet. The working directory then doesn't have to exist when the service is added.",
+    );
+    timer_subcommand.add_bool_flag(
+        "ti",
+        "process-title",
+        "Names the processes of the service `userserversd: <service name>` in `ps`, by setting their argv[0]. Programs that look at their argv[0] may behave differently.",
+    );
+    timer_subcommand.add_bool_flag(
+        "sh",
+        "shell",
+        "Takes the commands as single strings that get run with `sh -c`, such as `foo | tee log`, instead of JSON arrays.",
+    );
+    timer_subcommand.add_bool_flag(
+        "f",
+        "force",
+        "Skips checking that the working directory and the executables of the service exist, such as for services on a mount that isn't up yet.",
+    );
+    timer_subcommand.add_flag(
+        "lr",
+        "log-retention",
+        "Drops the logs of the service once they are older than the provided duration, such as `30m`, `24h` or `7d`.",
+    );
+    timer_subcommand.add_flag("desc", "description", "Describes what the service is for.");
+    timer_subcommand.add_repeatable_flag(
+        "l",
+        "label",
+        "Labels the service with the provided KEY=VALUE pair, which list-services can filter on.",
+    );
+    timer_subcommand.add_flag(
+        "g",
+        "group",
+        "Makes the service part of the group specified in the provided argument.",
+    );
+
     add_command.add_subcommand(sync_subcommand);
     add_command.add_subcommand(async_subcommand);
+    add_command.add_subcommand(oneshot_subcommand);
+    add_command.add_subcommand(timer_subcommand);
 
     let mut remove_command = flag::Command::new(
         Some("remove"),
-        "Removes the service with the specified name.",
+        "Stops and removes the services with the specified names, along with their logs. Asks for confirmation first unless --force is given, which it must be when stdin isn't a terminal.",
+    );
+    remove_command.add_positional_arg("service name", SERVICE_PATTERN_HELP);
+    remove_command.add_variadic_positional_arg(
+        "more service names",
+        "The names of, or patterns matching, other services to remove.",
     );
-    remove_command.add_positional_arg("service name", "The name of the service.");
+    add_allow_empty_flag(&mut remove_command);
+    remove_command.add_bool_flag("f", "force", "Removes the services without asking.");
 
     let mut edit_command =
         flag::Command::new(Some("edit"), "Edits the service with the specified name.");
@@ -159,14 +1074,145 @@ fn cli() -> flag::Command {
     sync_subcommand.add_flag(
         "w",
         "working-directory",
-        "Sets the working directory of the service to the provided argument.",
+        "Sets the working directory of the service to the provided argument. A leading `~` or `~user` is expanded to the matching home directory when the service starts.",
+    );
+    sync_subcommand.add_bool_flag(
+        "nwd",
+        "no-working-directory",
+        "Resets the working directory of the service to the home directory, like when it is added without one.",
+    );
+    sync_subcommand.add_json_flag::<HashMap<String, String>>("e", "environment", "Overrides the environment variables of the service with the ones specified in the provided argument. The provided argument must be a JSON map.");
+    sync_subcommand.add_repeatable_flag(
+        "set",
+        "set-env",
+        "Sets the environment variable given as KEY=VALUE, keeping the other variables of the service.",
+    );
+    sync_subcommand.add_repeatable_flag(
+        "unset",
+        "unset-env",
+        "Removes the provided environment variable from the service, keeping the other ones.",
+    );
+    sync_subcommand.add_bool_flag(
+        "ne",
+        "no-env",
+        "Removes every environment variable of the service, before applying --set-env if given.",
+    );
+    sync_subcommand.add_json_flag::<Vec<String>>(
+        "rc",
+        "ready-cmd",
+        "Changes the readiness command of the service to the provided one, which must be a JSON array. Pass an empty argument to remove it.",
+    );
+    sync_subcommand.add_flag(
+        "rt",
+        "ready-tcp",
+        "Changes the TCP port that must be listened on for the service to be ready. Pass an empty argument to remove it.",
+    );
+    sync_subcommand.add_json_flag::<Vec<String>>(
+        "hc",
+        "health-check",
+        "Changes the health check of the service to the provided command, which must be a JSON array. Pass an empty argument to remove it.",
+    );
+    sync_subcommand.add_int_flag(
+        "hi",
+        "health-interval",
+        "Sets how often, in seconds, the health check runs. Defaults to 30.",
+    );
+    sync_subcommand.add_repeatable_flag(
+        "pt",
+        "port",
+        "Replaces the TCP ports the service declares with the provided ones. Pass an empty argument to remove them.",
+    );
+    sync_subcommand.add_repeatable_flag(
+        "wp",
+        "watch",
+        "Replaces the paths watched for changes with the provided ones. Pass an empty argument to remove them.",
+    );
+    sync_subcommand.add_repeatable_flag(
+        "d",
+        "depends-on",
+        "Replaces the services this service depends on with the provided ones. Pass an empty argument to remove them.",
+    );
+    sync_subcommand.add_choice_flag(
+        "we",
+        "watch-enabled",
+        "Turns restarting on file changes on or off without removing the watched paths. Must be either `true` or `false`.",
+        &["true", "false"],
+    );
+    sync_subcommand.add_json_flag::<Vec<String>>(
+        "pre",
+        "pre-start",
+        "Changes the command run before each start of the service, which is aborted if the command fails. The command must be a JSON array. Pass an empty argument to remove it.",
+    );
+    sync_subcommand.add_json_flag::<Vec<String>>(
+        "post",
+        "post-stop",
+        "Changes the command run after each successful stop of the service. The command must be a JSON array. Pass an empty argument to remove it.",
+    );
+    sync_subcommand.add_json_flag::<Vec<String>>(
+        "of",
+        "on-failure",
+        "Changes the command run whenever the service exits with a failure on its own. The command must be a JSON array. Pass an empty argument to remove it.",
+    );
+    sync_subcommand.add_flag(
+        "rls",
+        "reload-signal",
+        "Changes the signal sent to the process of the service to reload it, replacing the reload command. Pass an empty argument to remove it.",
+    );
+    sync_subcommand.add_json_flag::<Vec<String>>(
+        "rlc",
+        "reload-cmd",
+        "Changes the command run to reload the service, replacing the reload signal. The command must be a JSON array. Pass an empty argument to remove it.",
+    );
+    sync_subcommand.add_choice_flag(
+        "ce",
+        "clean-env",
+        "Turns starting the service with only the HOME, PATH, USER and LANG variables of the daemon, plus the environment variable overrides, on or off. Must be either `true` or `false`.",
+        &["true", "false"],
+    );
+    sync_subcommand.add_choice_flag(
+        "cw",
+        "create-working-directory",
+        "Turns creating the working directory, along with its parents, when the service starts if it doesn't exist yet on or off. Must be either `true` or `false`.",
+        &["true", "false"],
+    );
+    sync_subcommand.add_choice_flag(
+        "ti",
+        "process-title",
+        "Turns naming the processes of the service `userserversd: <service name>` in `ps` on or off, by setting their argv[0]. Must be either `true` or `false`.",
+        &["true", "false"],
+    );
+    sync_subcommand.add_choice_flag(
+        "sh",
+        "shell",
+        "Turns running the commands with `sh -c` on or off. The commands of shell services are single strings instead of JSON arrays. Must be either `true` or `false`.",
+        &["true", "false"],
+    );
+    sync_subcommand.add_bool_flag(
+        "f",
+        "force",
+        "Skips checking that the working directory and the executables of the service exist, such as for services on a mount that isn't up yet.",
+    );
+    sync_subcommand.add_flag(
+        "lr",
+        "log-retention",
+        "Changes how long the logs of the service are kept, such as `30m`, `24h` or `7d`. Pass an empty argument to keep them until the daemon exits.",
+    );
+    sync_subcommand.add_flag(
+        "desc",
+        "description",
+        "Changes the description of the service to the provided one. Pass an empty argument to remove it.",
+    );
+    sync_subcommand.add_repeatable_flag(
+        "l",
+        "label",
+        "Replaces the labels of the service with the provided ones, each given as KEY=VALUE. Pass an empty argument to remove them.",
     );
-    sync_subcommand.add_flag("e", "environment", "Overrides the environment variables of the service with the ones specified in the provided argument. The provided argument must be a JSON map.");
     sync_subcommand.add_flag(
         "g",
         "group",
         "Makes the service part of the group specified in the provided argument.",
     );
+    sync_subcommand.add_bool_flag("ng", "no-group", "Removes the service from its group.");
 
     let mut async_subcommand = flag::Command::new(
         Some("async"),
@@ -188,515 +1234,3065 @@ fn cli() -> flag::Command {
         "stop-command",
         "Changes the stop command of the service to the specified one.",
     );
+    async_subcommand.add_json_flag::<HashMap<String, String>>("se", "stop-environment", "Runs the stop command with only the environment variable overrides specified in the provided argument instead of the ones of the service. The provided argument must be a JSON map.");
+    async_subcommand.add_flag(
+        "sw",
+        "stop-working-directory",
+        "Runs the stop command in the provided working directory instead of the one of the service.",
+    );
     async_subcommand.add_flag(
         "w",
         "working-directory",
-        "Sets the working directory of the service to the provided argument.",
+        "Sets the working directory of the service to the provided argument. A leading `~` or `~user` is expanded to the matching home directory when the service starts.",
+    );
+    async_subcommand.add_bool_flag(
+        "nwd",
+        "no-working-directory",
+        "Resets the working directory of the service to the home directory, like when it is added without one.",
+    );
+    async_subcommand.add_flag(
+        "p",
+        "pidfile",
+        "Tracks the service through the PID written to the provided file by the start command, relative to the working directory of the service.",
+    );
+    async_subcommand.add_flag(
+        "ct",
+        "command-timeout",
+        "Kills the start or stop command once it has run for the provided duration, such as `90s` or `5m`, which then fails. Defaults to 60s.",
+    );
+    async_subcommand.add_json_flag::<HashMap<String, String>>("e", "environment", "Overrides the environment variables of the service with the ones specified in the provided argument. The provided argument must be a JSON map.");
+    async_subcommand.add_repeatable_flag(
+        "set",
+        "set-env",
+        "Sets the environment variable given as KEY=VALUE, keeping the other variables of the service.",
+    );
+    async_subcommand.add_repeatable_flag(
+        "unset",
+        "unset-env",
+        "Removes the provided environment variable from the service, keeping the other ones.",
+    );
+    async_subcommand.add_bool_flag(
+        "ne",
+        "no-env",
+        "Removes every environment variable of the service, before applying --set-env if given.",
+    );
+    async_subcommand.add_json_flag::<Vec<String>>(
+        "rc",
+        "ready-cmd",
+        "Changes the readiness command of the service to the provided one, which must be a JSON array. Pass an empty argument to remove it.",
+    );
+    async_subcommand.add_flag(
+        "rt",
+        "ready-tcp",
+        "Changes the TCP port that must be listened on for the service to be ready. Pass an empty argument to remove it.",
+    );
+    async_subcommand.add_json_flag::<Vec<String>>(
+        "hc",
+        "health-check",
+        "Changes the health check of the service to the provided command, which must be a JSON array. Pass an empty argument to remove it.",
+    );
+    async_subcommand.add_int_flag(
+        "hi",
+        "health-interval",
+        "Sets how often, in seconds, the health check runs. Defaults to 30.",
+    );
+    async_subcommand.add_repeatable_flag(
+        "pt",
+        "port",
+        "Replaces the TCP ports the service declares with the provided ones. Pass an empty argument to remove them.",
+    );
+    async_subcommand.add_repeatable_flag(
+        "wp",
+        "watch",
+        "Replaces the paths watched for changes with the provided ones. Pass an empty argument to remove them.",
+    );
+    async_subcommand.add_repeatable_flag(
+        "d",
+        "depends-on",
+        "Replaces the services this service depends on with the provided ones. Pass an empty argument to remove them.",
+    );
+    async_subcommand.add_choice_flag(
+        "we",
+        "watch-enabled",
+        "Turns restarting on file changes on or off without removing the watched paths. Must be either `true` or `false`.",
+        &["true", "false"],
+    );
+    async_subcommand.add_json_flag::<Vec<String>>(
+        "pre",
+        "pre-start",
+        "Changes the command run before each start of the service, which is aborted if the command fails. The command must be a JSON array. Pass an empty argument to remove it.",
+    );
+    async_subcommand.add_json_flag::<Vec<String>>(
+        "post",
+        "post-stop",
+        "Changes the command run after each successful stop of the service. The command must be a JSON array. Pass an empty argument to remove it.",
+    );
+    async_subcommand.add_json_flag::<Vec<String>>(
+        "of",
+        "on-failure",
+        "Changes the command run whenever the service exits with a failure on its own. The command must be a JSON array. Pass an empty argument to remove it.",
+    );
+    async_subcommand.add_flag(
+        "rls",
+        "reload-signal",
+        "Changes the signal sent to the process of the service to reload it, replacing the reload command. Pass an empty argument to remove it.",
+    );
+    async_subcommand.add_json_flag::<Vec<String>>(
+        "rlc",
+        "reload-cmd",
+        "Changes the command run to reload the service, replacing the reload signal. The command must be a JSON array. Pass an empty argument to remove it.",
+    );
+    async_subcommand.add_choice_flag(
+        "ce",
+        "clean-env",
+        "Turns starting the service with only the HOME, PATH, USER and LANG variables of the daemon, plus the environment variable overrides, on or off. Must be either `true` or `false`.",
+        &["true", "false"],
+    );
+    async_subcommand.add_choice_flag(
+        "cw",
+        "create-working-directory",
+        "Turns creating the working directory, along with its parents, when the service starts if it doesn't exist yet on or off. Must be either `true` or `false`.",
+        &["true", "false"],
+    );
+    async_subcommand.add_choice_flag(
+        "ti",
+        "process-title",
+        "Turns naming the processes of the service `userserversd: <service name>` in `ps` on or off, by setting their argv[0]. Must be either `true` or `false`.",
+        &["true", "false"],
+    );
+    async_subcommand.add_choice_flag(
+        "sh",
+        "shell",
+        "Turns running the commands with `sh -c` on or off. The commands of shell services are single strings instead of JSON arrays. Must be either `true` or `false`.",
+        &["true", "false"],
+    );
+    async_subcommand.add_bool_flag(
+        "f",
+        "force",
+        "Skips checking that the working directory and the executables of the service exist, such as for services on a mount that isn't up yet.",
+    );
+    async_subcommand.add_flag(
+        "lr",
+        "log-retention",
+        "Changes how long the logs of the service are kept, such as `30m`, `24h` or `7d`. Pass an empty argument to keep them until the daemon exits.",
+    );
+    async_subcommand.add_flag(
+        "desc",
+        "description",
+        "Changes the description of the service to the provided one. Pass an empty argument to remove it.",
+    );
+    async_subcommand.add_repeatable_flag(
+        "l",
+        "label",
+        "Replaces the labels of the service with the provided ones, each given as KEY=VALUE. Pass an empty argument to remove them.",
     );
-    async_subcommand.add_flag("e", "environment", "Overrides the environment variables of the service with the ones specified in the provided argument. The provided argument must be a JSON map.");
     async_subcommand.add_flag(
         "g",
         "group",
         "Makes the service part of the group specified in the provided argument.",
     );
+    async_subcommand.add_bool_flag("ng", "no-group", "Removes the service from its group.");
+
+    let mut oneshot_subcommand = flag::Command::new(
+        Some("oneshot"),
+        "Edits the oneshot service with the specified name.",
+    );
+    oneshot_subcommand.add_positional_arg("service name", "The name of the service.");
+    oneshot_subcommand.add_flag(
+        "n",
+        "name",
+        "Changes the name of the service to the specified one.",
+    );
+    oneshot_subcommand.add_flag(
+        "c",
+        "command",
+        "Changes the command of the service to the specified one.",
+    );
+    oneshot_subcommand.add_flag(
+        "w",
+        "working-directory",
+        "Sets the working directory of the service to the provided argument. A leading `~` or `~user` is expanded to the matching home directory when the service starts.",
+    );
+    oneshot_subcommand.add_bool_flag(
+        "nwd",
+        "no-working-directory",
+        "Resets the working directory of the service to the home directory, like when it is added without one.",
+    );
+    oneshot_subcommand.add_json_flag::<HashMap<String, String>>("e", "environment", "Overrides the environment variables of the service with the ones specified in the provided argument. The provided argument must be a JSON map.");
+    oneshot_subcommand.add_repeatable_flag(
+        "set",
+        "set-env",
+        "Sets the environment variable given as KEY=VALUE, keeping the other variables of the service.",
+    );
+    oneshot_subcommand.add_repeatable_flag(
+        "unset",
+        "unset-env",
+        "Removes the provided environment variable from the service, keeping the other ones.",
+    );
+    oneshot_subcommand.add_bool_flag(
+        "ne",
+        "no-env",
+        "Removes every environment variable of the service, before applying --set-env if given.",
+    );
+    oneshot_subcommand.add_repeatable_flag(
+        "d",
+        "depends-on",
+        "Replaces the services this service depends on with the provided ones. Pass an empty argument to remove them.",
+    );
+    oneshot_subcommand.add_json_flag::<Vec<String>>(
+        "pre",
+        "pre-start",
+        "Changes the command run before each start of the service, which is aborted if the command fails. The command must be a JSON array. Pass an empty argument to remove it.",
+    );
+    oneshot_subcommand.add_json_flag::<Vec<String>>(
+        "post",
+        "post-stop",
+        "Changes the command run after each successful stop of the service. The command must be a JSON array. Pass an empty argument to remove it.",
+    );
+    oneshot_subcommand.add_json_flag::<Vec<String>>(
+        "of",
+        "on-failure",
+        "Changes the command run whenever the service exits with a failure on its own. The command must be a JSON array. Pass an empty argument to remove it.",
+    );
+    oneshot_subcommand.add_choice_flag(
+        "ce",
+        "clean-env",
+        "Turns starting the service with only the HOME, PATH, USER and LANG variables of the daemon, plus the environment variable overrides, on or off. Must be either `true` or `false`.",
+        &["true", "false"],
+    );
+    oneshot_subcommand.add_choice_flag(
+        "cw",
+        "create-working-directory",
+        "Turns creating the working directory, along with its parents, when the service starts if it doesn't exist yet on or off. Must be either `true` or `false`.",
+        &["true", "false"],
+    );
+    oneshot_subcommand.add_choice_flag(
+        "ti",
+        "process-title",
+        "Turns naming the processes of the service `userserversd: <service name>` in `ps` on or off, by setting their argv[0]. Must be either `true` or `false`.",
+        &["true", "false"],
+    );
+    oneshot_subcommand.add_choice_flag(
+        "sh",
+        "shell",
+        "Turns running the commands with `sh -c` on or off. The commands of shell services are single strings instead of JSON arrays. Must be either `true` or `false`.",
+        &["true", "false"],
+    );
+    oneshot_subcommand.add_bool_flag(
+        "f",
+        "force",
+        "Skips checking that the working directory and the executables of the service exist, such as for services on a mount that isn't up yet.",
+    );
+    oneshot_subcommand.add_flag(
+        "lr",
+        "log-retention",
+        "Changes how long the logs of the service are kept, such as `30m`, `24h` or `7d`. Pass an empty argument to keep them until the daemon exits.",
+    );
+    oneshot_subcommand.add_flag(
+        "desc",
+        "description",
+        "Changes the description of the service to the provided one. Pass an empty argument to remove it.",
+    );
+    oneshot_subcommand.add_repeatable_flag(
+        "l",
+        "label",
+        "Replaces the labels of the service with the provided ones, each given as KEY=VALUE. Pass an empty argument to remove them.",
+    );
+    oneshot_subcommand.add_flag(
+        "g",
+        "group",
+        "Makes the service part of the group specified in the provided argument.",
+    );
+    oneshot_subcommand.add_bool_flag("ng", "no-group", "Removes the service from its group.");
+
+    let mut timer_subcommand = flag::Command::new(
+        Some("timer"),
+        "Edits the timer service with the specified name.",
+    );
+    timer_subcommand.add_positional_arg("service name", "The name of the service.");
+    timer_subcommand.add_flag(
+        "n",
+        "name",
+        "Changes the name of the service to the specified one.",
+    );
+    timer_subcommand.add_flag(
+        "c",
+        "command",
+        "Changes the command of the service to the specified one.",
+    );
+    timer_subcommand.add_flag(
+        "ev",
+        "every",
+        "Changes how often the command runs, as a number of seconds optionally followed by a unit, such as `30s`, `15m`, `2h` or `1d`.",
+    );
+    timer_subcommand.add_flag(
+        "w",
+        "working-directory",
+        "Sets the working directory of the service to the provided argument. A leading `~` or `~user` is expanded to the matching home directory when the service starts.",
+    );
+    timer_subcommand.add_bool_flag(
+        "nwd",
+        "no-working-directory",
+        "Resets the working directory of the service to the home directory, like when it is added without one.",
+    );
+    timer_subcommand.add_json_flag::<HashMap<String, String>>("e", "environment", "Overrides the environment variables of the service with the ones specified in the provided argument. The provided argument must be a JSON map.");
+    timer_subcommand.add_repeatable_flag(
+        "set",
+        "set-env",
+        "Sets the environment variable given as KEY=VALUE, keeping the other variables of the service.",
+    );
+    timer_subcommand.add_repeatable_flag(
+        "unset",
+        "unset-env",
+        "Removes the provided environment variable from the service, keeping the other ones.",
+    );
+    timer_subcommand.add_bool_flag(
+        "ne",
+        "no-env",
+        "Removes every environment variable of the service, before applying --set-env if given.",
+    );
+    timer_subcommand.add_repeatable_flag(
+        "d",
+        "depends-on",
+        "Replaces the services this service depends on with the provided ones. Pass an empty argument to remove them.",
+    );
+    timer_subcommand.add_json_flag::<Vec<String>>(
+        "pre",
+        "pre-start",
+        "Changes the command run before each start of the service, which is aborted if the command fails. The command must be a JSON array. Pass an empty argument to remove it.",
+    );
+    timer_subcommand.add_json_flag::<Vec<String>>(
+        "post",
+        "post-stop",
+        "Changes the command run after each successful stop of the service. The command must be a JSON array. Pass an empty argument to remove it.",
+    );
+    timer_subcommand.add_json_flag::<Vec<String>>(
+        "of",
+        "on-failure",
+        "Changes the command run whenever the service exits with a failure on its own. The command must be a JSON array. Pass an empty argument to remove it.",
+    );
+    timer_subcommand.add_choice_flag(
+        "ce",
+        "clean-env",
+        "Turns starting the service with only the HOME, PATH, USER and LANG variables of the daemon, plus the environment variable overrides, on or off. Must be either `true` or `false`.",
+        &["true", "false"],
+    );
+    timer_subcommand.add_choice_flag(
+        "cw",
+        "create-working-directory",
+        "Turns creating the working directory, along with its parents, when the service starts if it doesn't exist yet on or off. Must be either `true` or `false`.",
+        &["true", "false"],
+    );
+    timer_subcommand.add_choice_flag(
+        "ti",
+        "process-title",
+        "Turns naming the processes of the service `userserversd: <service name>` in `ps` on or off, by setting their argv[0]. Must be either `true` or `false`.",
+        &["true", "false"],
+    );
+    timer_subcommand.add_choice_flag(
+        "sh",
+        "shell",
+        "Turns running the commands with `sh -c` on or off. The commands of shell services are single strings instead of JSON arrays. Must be either `true` or `false`.",
+        &["true", "false"],
+    );
+    timer_subcommand.add_bool_flag(
+        "f",
+        "force",
+        "Skips checking that the working directory and the executables of the service exist, such as for services on a mount that isn't up yet.",
+    );
+    timer_subcommand.add_flag(
+        "lr",
+        "log-retention",
+        "Changes how long the logs of the service are kept, such as `30m`, `24h` or `7d`. Pass an empty argument to keep them until the daemon exits.",
+    );
+    timer_subcommand.add_flag(
+        "desc",
+        "description",
+        "Changes the description of the service to the provided one. Pass an empty argument to remove it.",
+    );
+    timer_subcommand.add_repeatable_flag(
+        "l",
+        "label",
+        "Replaces the labels of the service with the provided ones, each given as KEY=VALUE. Pass an empty argument to remove them.",
+    );
+    timer_subcommand.add_flag(
+        "g",
+        "group",
+        "Makes the service part of the group specified in the provided argument.",
+    );
+    timer_subcommand.add_bool_flag("ng", "no-group", "Removes the service from its group.");
 
     edit_command.add_subcommand(sync_subcommand);
     edit_command.add_subcommand(async_subcommand);
+    edit_command.add_subcommand(oneshot_subcommand);
+    edit_command.add_subcommand(timer_subcommand);
+
+    let mut edit_json_command = flag::Command::new(
+        Some("edit-json"),
+        "Opens the definition of the service with the specified name in $VISUAL or $EDITOR as JSON, and applies it once the editor exits.",
+    );
+    edit_json_command.add_positional_arg("service name", "The name of the service.");
+    edit_json_command.add_bool_flag(
+        "f",
+        "force",
+        "Applies the changes even if the working directory or executables don't exist.",
+    );
 
-    let mut start_command =
-        flag::Command::new(Some("start"), "Starts the service with the specified name.");
-    start_command.add_positional_arg("service name", "The name of the service.");
+    let mut start_command = flag::Command::new(
+        Some("start"),
+        "Starts the service with the specified name, or every service matching the pattern.",
+    );
+    start_command.add_positional_arg("service name", SERVICE_PATTERN_HELP);
+    add_allow_empty_flag(&mut start_command);
+    start_command.add_bool_flag(
+        "w",
+        "wait",
+        "Waits until the service is ready before exiting.",
+    );
+    start_command.add_flag(
+        "wt",
+        "wait-timeout",
+        "Sets how long, in seconds, to wait for the service to be ready. Defaults to 60.",
+    );
 
-    let mut stop_command =
-        flag::Command::new(Some("stop"), "Stops the service with the specified name.");
-    stop_command.add_positional_arg("service name", "The name of the service.");
+    let mut stop_command = flag::Command::new(
+        Some("stop"),
+        "Stops the service with the specified name, or every service matching the pattern.",
+    );
+    stop_command.add_positional_arg("service name", SERVICE_PATTERN_HELP);
+    add_allow_empty_flag(&mut stop_command);
+    stop_command.add_bool_flag(
+        "wd",
+        "with-dependents",
+        "Also stops the services that depend on the service.",
+    );
 
     let mut restart_command = flag::Command::new(
         Some("restart"),
-        "Restarts the service with the specified name.",
+        "Restarts the service with the specified name, or every service matching the pattern.",
+    );
+    restart_command.add_positional_arg("service name", SERVICE_PATTERN_HELP);
+    add_allow_empty_flag(&mut restart_command);
+    restart_command.add_bool_flag(
+        "w",
+        "wait",
+        "Waits until the service is ready before exiting.",
+    );
+    restart_command.add_flag(
+        "wt",
+        "wait-timeout",
+        "Sets how long, in seconds, to wait for the service to be ready. Defaults to 60.",
+    );
+
+    let mut pause_command = flag::Command::new(
+        Some("pause"),
+        "Freezes the service with the specified name, or every service matching the pattern, with SIGSTOP until it is resumed. Timers and async services without a pidfile can't be paused.",
+    );
+    pause_command.add_positional_arg("service name", SERVICE_PATTERN_HELP);
+    add_allow_empty_flag(&mut pause_command);
+
+    let mut resume_command = flag::Command::new(
+        Some("resume"),
+        "Lets the paused service with the specified name, or every paused service matching the pattern, carry on with SIGCONT.",
+    );
+    resume_command.add_positional_arg("service name", SERVICE_PATTERN_HELP);
+    add_allow_empty_flag(&mut resume_command);
+
+    let mut reload_command = flag::Command::new(
+        Some("reload"),
+        "Has the service with the specified name, or every service matching the pattern, reload its configuration with its reload signal or command.",
     );
-    restart_command.add_positional_arg("service name", "The name of the service.");
+    reload_command.add_positional_arg("service name", SERVICE_PATTERN_HELP);
+    add_allow_empty_flag(&mut reload_command);
 
     let mut status_command = flag::Command::new(
         Some("status"),
-        "Displays the status of the service with the specified name.",
+        "Displays the status of the service with the specified name or of every service matching the pattern, or an overview of every service if no name is given. Exits with an error if a service of the overview failed.",
+    );
+    status_command.add_optional_positional_arg("service name", SERVICE_PATTERN_HELP);
+    status_command.add_int_flag(
+        "lk",
+        "log-kb",
+        "Sets how many kilobytes of the end of the logs are displayed. Defaults to 16. The logs subcommand displays the whole logs with --full.",
+    );
+    add_allow_empty_flag(&mut status_command);
+
+    let mut logs_command = flag::Command::new(
+        Some("logs"),
+        "Displays the logs of the service with the specified name.",
+    );
+    logs_command.add_positional_arg("service name", "The name of the service.");
+    logs_command.add_int_flag(
+        "n",
+        "lines",
+        "Sets how many of the last lines of the logs are displayed. Defaults to 100.",
+    );
+    logs_command.add_bool_flag(
+        "f",
+        "full",
+        "Displays the whole logs, however big they are.",
+    );
+
+    let mut watch_command = flag::Command::new(
+        Some("watch"),
+        "Displays the events of every service as they happen, until interrupted.",
+    );
+    watch_command.add_repeatable_flag(
+        "e",
+        "event",
+        "Only displays the events of the provided kind, which is one of `started`, `stopped`, `failed`, `state-changed`, `added`, `removed` or `edited`. Can be given several times.",
+    );
+    watch_command.add_bool_flag(
+        "j",
+        "json",
+        "Displays each event as a line of JSON instead.",
+    );
+
+    let mut list_services_command = flag::Command::new(Some("list-services"), "List all services.");
+    list_services_command.add_bool_flag(
+        "p",
+        "ports",
+        "Also shows the ports each service declares, warning about ports declared more than once.",
+    );
+    list_services_command.add_flag(
+        "g",
+        "group",
+        "Only lists the services of the specified group.",
+    );
+    list_services_command.add_flag(
+        "fmt",
+        "format",
+        "Displays each service on its own line, following the specified template instead of a table. Each `{field}` is replaced with the field of the service, and `\\t`, `\\n` and `\\\\` stand for a tab, a newline and a backslash. The fields are name, group (empty if there is none), kind, state, command and working_directory.",
+    );
+    list_services_command.add_bool_flag(
+        "fl",
+        "full",
+        "Displays names and commands in full instead of truncating them.",
+    );
+    list_services_command.add_int_flag(
+        "w",
+        "width",
+        "Sets how many characters names and commands are truncated to. Defaults to 40.",
+    );
+    list_services_command.add_repeatable_flag(
+        "l",
+        "label",
+        "Only lists the services that have the provided KEY=VALUE label. Can be given several times, in which case services must have every label.",
+    );
+    list_services_command.add_bool_flag("r", "running", "Only lists the running services.");
+    list_services_command.add_bool_flag(
+        "s",
+        "stopped",
+        "Only lists the services that aren't running.",
+    );
+
+    let mut export_command = flag::Command::new(
+        Some("export"),
+        "Displays the definitions of all services, in a format `import` reads.",
+    );
+    export_command.add_flag(
+        "o",
+        "output",
+        "Writes the definitions to the specified file instead.",
+    );
+    export_command.add_choice_flag(
+        "f",
+        "format",
+        "Writes the definitions in the provided format, either `json` (the default) or `toml`.",
+        &["json", "toml"],
+    );
+
+    let mut export_systemd_command = flag::Command::new(
+        Some("export-systemd"),
+        "Displays the specified services as systemd user units, to hand them over to systemd. The parts of a service systemd has no equivalent for are reported.",
+    );
+    export_systemd_command.add_positional_arg("service name", SERVICE_PATTERN_HELP);
+    export_systemd_command.add_variadic_positional_arg(
+        "more service names",
+        "The names of, or patterns matching, other services to export.",
+    );
+    export_systemd_command.add_flag(
+        "o",
+        "output-dir",
+        "Writes each unit to a file in the specified directory, such as ~/.config/systemd/user, instead.",
+    );
+    export_systemd_command.add_repeatable_flag(
+        "r",
+        "rename",
+        "Names the unit of a service differently, as OLD=NEW. Needed for services whose name isn't a valid unit name, and also applied to dependencies. Can be given several times.",
+    );
+    export_systemd_command.add_bool_flag(
+        "f",
+        "force",
+        "Overwrites the unit files that already exist.",
+    );
+    add_allow_empty_flag(&mut export_systemd_command);
+
+    let mut import_command = flag::Command::new(
+        Some("import"),
+        "Adds the services defined in the specified file, as written by `export`. Files ending in `.toml` are read as TOML, and any other file as JSON. Nothing is changed if any of them is invalid.",
+    );
+    import_command.add_positional_arg("file", "The file to read the definitions from.");
+    import_command.add_bool_flag(
+        "r",
+        "replace",
+        "Replaces the services that already exist, and stops and removes the ones not in the file.",
+    );
+    import_command.add_bool_flag(
+        "m",
+        "merge",
+        "Leaves the services that already exist as they are (the default).",
+    );
+    import_command.add_bool_flag(
+        "f",
+        "force",
+        "Imports the services even if their working directory or executables don't exist.",
+    );
+
+    let mut import_systemd_command = flag::Command::new(
+        Some("import-systemd"),
+        "Adds services translated from the specified systemd user units, after showing what they will be and asking for confirmation. ExecStart=, ExecStop=, WorkingDirectory=, Environment=, EnvironmentFile= and a few other directives are translated, and the ones that can't be are reported.",
+    );
+    import_systemd_command
+        .add_variadic_positional_arg("unit files", "The .service files of the units to import.");
+    import_systemd_command.add_bool_flag(
+        "a",
+        "all",
+        "Imports every unit in the systemd user configuration directory, ~/.config/systemd/user by default.",
+    );
+    import_systemd_command.add_bool_flag(
+        "n",
+        "dry-run",
+        "Only shows what the services will be, without adding them.",
+    );
+    import_systemd_command.add_bool_flag(
+        "y",
+        "yes",
+        "Adds the services without asking, which is needed when stdin isn't a terminal.",
+    );
+    import_systemd_command.add_bool_flag(
+        "f",
+        "force",
+        "Imports the services even if their working directory or executables don't exist.",
     );
-    status_command.add_positional_arg("service name", "The name of the service.");
 
-    let list_services_command = flag::Command::new(Some("list-services"), "List all services.");
+    let mut apply_command = flag::Command::new(
+        Some("apply"),
+        "Runs the commands in the specified file one after the other over a single connection, one per line and written as they would be on the command line, such as `start web`. Quotes and backslashes work like in a shell, and lines starting with `#` are ignored. Exits with the code of the last command that failed.",
+    );
+    apply_command.add_positional_arg(
+        "file",
+        "The file to read the commands from, or `-` to read them from stdin.",
+    );
+    apply_command.add_bool_flag(
+        "s",
+        "stop-on-error",
+        "Stops at the first command that fails instead of going on with the next ones.",
+    );
+
+    let mut target_command = flag::Command::new(Some("target"), "Manages service targets.");
+
+    let mut add_subcommand = flag::Command::new(
+        Some("add"),
+        "Adds the service with the specified name to the specified target, creating the target if it does not exist.",
+    );
+    add_subcommand.add_positional_arg("target name", "The name of the target.");
+    add_subcommand.add_positional_arg("service name", "The name of the service.");
+
+    let mut remove_subcommand = flag::Command::new(
+        Some("remove"),
+        "Removes the service with the specified name from the specified target. Targets with no services left are deleted.",
+    );
+    remove_subcommand.add_positional_arg("target name", "The name of the target.");
+    remove_subcommand.add_positional_arg("service name", "The name of the service.");
+
+    let mut start_subcommand = flag::Command::new(
+        Some("start"),
+        "Starts all services of the target with the specified name.",
+    );
+    start_subcommand.add_positional_arg("target name", "The name of the target.");
+    start_subcommand.add_bool_flag(
+        "x",
+        "exclusive",
+        "Also stops the services that belong to other targets but not to this one.",
+    );
+
+    let mut stop_subcommand = flag::Command::new(
+        Some("stop"),
+        "Stops all services of the target with the specified name.",
+    );
+    stop_subcommand.add_positional_arg("target name", "The name of the target.");
+
+    target_command.add_subcommand(add_subcommand);
+    target_command.add_subcommand(remove_subcommand);
+    target_command.add_subcommand(start_subcommand);
+    target_command.add_subcommand(stop_subcommand);
+
+    let list_targets_command = flag::Command::new(Some("list-targets"), "List all targets.");
+
+    let mut group_command = flag::Command::new(Some("group"), "Manages service groups.");
+
+    let mut describe_subcommand = flag::Command::new(
+        Some("describe"),
+        "Sets the description of the group with the specified name, which is shown by list-groups. An empty description removes it.",
+    );
+    describe_subcommand.add_positional_arg("group name", "The name of the group.");
+    describe_subcommand.add_positional_arg("description", "The description of the group.");
+
+    let mut move_subcommand = flag::Command::new(
+        Some("move"),
+        "Moves every service of the group with the first name to the group with the second name. Services moved to `none` no longer belong to any group.",
+    );
+    move_subcommand.add_positional_arg("group name", "The name of the group.");
+    move_subcommand.add_optional_positional_arg(
+        "new group name",
+        "The name of the group to move the services to.",
+    );
+    move_subcommand.add_bool_flag(
+        "c",
+        "clear",
+        "Removes the services from the group instead of moving them to another one.",
+    );
+
+    group_command.add_subcommand(describe_subcommand);
+    group_command.add_subcommand(move_subcommand);
+
+    let list_groups_command = flag::Command::new(
+        Some("list-groups"),
+        "List all groups, along with how many of their services are running.",
+    );
+
+    let ping_command = flag::Command::new(
+        Some("ping"),
+        "Checks whether the daemon is running and displays information about it.",
+    );
+
+    let daemon_env_command = flag::Command::new(
+        Some("daemon-env"),
+        "Displays the working directory, user and environment the daemon runs with.",
+    );
+
+    let reload_config_command = flag::Command::new(
+        Some("reload-config"),
+        "Has the daemon read its configuration file again, starting the services added to it, stopping the ones removed from it and restarting the running ones that changed. Sending SIGHUP to the daemon does the same.",
+    );
+
+    let flush_command = flag::Command::new(
+        Some("flush"),
+        "Has the daemon write its configuration right away. Changes are otherwise written within a second.",
+    );
+
+    let mut snapshot_command = flag::Command::new(
+        Some("snapshot"),
+        "Saves and restores the services along with which of them are running. Snapshots are kept in the state directory, ~/.local/state/userserversd by default.",
+    );
+
+    let mut save_snapshot_subcommand = flag::Command::new(
+        Some("save"),
+        "Saves the definitions of the services and which of them are running under the specified name, replacing any snapshot with that name.",
+    );
+    save_snapshot_subcommand.add_positional_arg("snapshot name", "The name of the snapshot.");
+
+    let mut restore_snapshot_subcommand = flag::Command::new(
+        Some("restore"),
+        "Puts the services back the way they were in the snapshot with the specified name: the services not in it are stopped and removed, the others are added or changed to match it, and only the ones that were running are left running. Asks for confirmation first unless --force is given, which it must be when stdin isn't a terminal.",
+    );
+    restore_snapshot_subcommand.add_positional_arg("snapshot name", "The name of the snapshot.");
+    restore_snapshot_subcommand.add_bool_flag(
+        "f",
+        "force",
+        "Restores the snapshot without asking for confirmation.",
+    );
+
+    let list_snapshots_subcommand = flag::Command::new(
+        Some("list"),
+        "Lists the snapshots, from the oldest to the newest.",
+    );
+
+    snapshot_command.add_subcommand(save_snapshot_subcommand);
+    snapshot_command.add_subcommand(restore_snapshot_subcommand);
+    snapshot_command.add_subcommand(list_snapshots_subcommand);
+
+    let mut history_command = flag::Command::new(
+        Some("history"),
+        "Displays the last commands that changed something, from the oldest to the newest, along with how they went. The history is kept in the state directory, ~/.local/state/userserversd by default.",
+    );
+    history_command.add_int_flag(
+        "n",
+        "lines",
+        "Sets how many of the last commands are displayed. Defaults to 50.",
+    );
+
+    let daemon_restart_command = flag::Command::new(
+        Some("daemon-restart"),
+        "Restarts the daemon in place, running the current version of its binary, without stopping any services.",
+    );
+
+    let shutdown_daemon_command = flag::Command::new(
+        Some("shutdown-daemon"),
+        "Stops all services and shuts the daemon down.",
+    );
+
+    let mut completions_command = flag::Command::new(
+        Some("completions"),
+        "Displays a script that completes the commands of userserversctl, including the names of services and targets, in the specified shell.",
+    );
+    completions_command.add_positional_arg("shell", "Either `bash`, `zsh` or `fish`.");
 
     let help_command = flag::Command::new(Some("help"), "Prints this help.");
 
     root_command.add_subcommand(add_command);
     root_command.add_subcommand(remove_command);
     root_command.add_subcommand(edit_command);
+    root_command.add_subcommand(edit_json_command);
     root_command.add_subcommand(start_command);
     root_command.add_subcommand(stop_command);
     root_command.add_subcommand(restart_command);
+    root_command.add_subcommand(reload_command);
+    root_command.add_subcommand(pause_command);
+    root_command.add_subcommand(resume_command);
     root_command.add_subcommand(status_command);
+    root_command.add_subcommand(logs_command);
+    root_command.add_subcommand(watch_command);
     root_command.add_subcommand(list_services_command);
+    root_command.add_subcommand(export_command);
+    root_command.add_subcommand(export_systemd_command);
+    root_command.add_subcommand(import_command);
+    root_command.add_subcommand(import_systemd_command);
+    root_command.add_subcommand(apply_command);
+    root_command.add_subcommand(target_command);
+    root_command.add_subcommand(list_targets_command);
+    root_command.add_subcommand(group_command);
+    root_command.add_subcommand(list_groups_command);
+    root_command.add_subcommand(ping_command);
+    root_command.add_subcommand(daemon_env_command);
+    root_command.add_subcommand(reload_config_command);
+    root_command.add_subcommand(flush_command);
+    root_command.add_subcommand(snapshot_command);
+    root_command.add_subcommand(history_command);
+    root_command.add_subcommand(daemon_restart_command);
+    root_command.add_subcommand(shutdown_daemon_command);
+    root_command.add_subcommand(completions_command);
     root_command.add_subcommand(help_command);
 
+    let mut exit_codes = String::from("0: The command succeeded.");
+    for (code, description) in EXIT_CODES {
+        exit_codes.push_str(&format!("\n{code}: {description}"));
+    }
+    root_command.add_help_section("exit codes", &exit_codes);
+
     root_command
 }
 
 fn add_subcommand(subcommand: &flag::ParsedCommand) {
     let subcommand = subcommand.subcommand.as_ref().unwrap();
 
+    let service_name = subcommand
+        .positional_args
+        .get("service name")
+        .unwrap()
+        .clone();
+
     let working_directory = subcommand
         .flags
-        .get(&"working-directory".to_string())
-        .map(|s| s.clone())
-        .or(Some(get_home_directory()))
-        .unwrap();
-    let environment = subcommand
+        .get("working-directory")
+        .cloned()
+        .unwrap_or_else(get_home_directory);
+    let environment = subcommand.get_json("environment").unwrap_or_default();
+    let group = subcommand.flags.get("group").cloned();
+    let ports = parse_ports(subcommand.get_all("port"));
+    let health_check = subcommand.get_json("health-check");
+    let health_check_interval_secs = subcommand
+        .get_int("health-interval")
+        .map(check_health_interval);
+    let ready_command = subcommand.get_json("ready-cmd");
+    let ready_tcp_port = subcommand
         .flags
-        .get(&"environment".to_string())
-        .map(|env| from_json(env))
-        .or(Some(HashMap::new()))
-        .unwrap();
-    let group = subcommand
+        .get("ready-tcp")
+        .map(|port| parse_ready_port(port));
+    let watch_paths = parse_watch_paths(subcommand.get_all("watch"));
+    let depends_on = parse_dependencies(subcommand.get_all("depends-on"));
+    let pre_start = subcommand.get_json("pre-start");
+    let post_stop = subcommand.get_json("post-stop");
+    let on_failure = subcommand.get_json("on-failure");
+    let reload_signal = subcommand
         .flags
-        .get(&"group".to_string())
-        .map(|s| s.clone());
+        .get("reload-signal")
+        .map(|name| parse_signal(name));
+    let reload_command = subcommand.get_json("reload-cmd");
+    let log_retention_secs = subcommand
+        .flags
+        .get("log-retention")
+        .map(|duration| parse_duration("log retention", duration));
 
-    match subcommand.name.as_str() {
+    let shell = subcommand.has_flag("shell");
+    let kind = match subcommand.name.as_str() {
         "sync" => {
-            let service_name = subcommand
-                .positional_args
-                .get(&"service name".to_string())
-                .unwrap()
-                .clone();
-
-            let command = subcommand
-                .positional_args
-                .get(&"command".to_string())
-                .unwrap();
-            let command: Vec<String> = from_json(command);
-
-            let mut socket = connect_to_socket();
-            run_command(
-                &mut socket,
-                Command::AddSynchronousService {
-                    name: service_name,
-                    working_directory,
-                    environment,
-                    group,
-                    command,
-                },
-            );
-        }
+            let command = parse_command(shell, subcommand.positional_args.get("command").unwrap());
 
-        "async" => {
-            let service_name = subcommand
-                .positional_args
-                .get(&"service name".to_string())
-                .unwrap()
-                .clone();
-
-            let start_command = subcommand
-                .positional_args
-                .get(&"start command".to_string())
-                .unwrap();
-            let start_command: Vec<String> = from_json(start_command);
-
-            let stop_command = subcommand
-                .positional_args
-                .get(&"stop command".to_string())
-                .unwrap();
-            let stop_command: Vec<String> = from_json(stop_command);
-
-            let mut socket = connect_to_socket();
-            run_command(
-                &mut socket,
-                Command::AddAsynchronousService {
-                    name: service_name,
-                    working_directory,
-                    environment,
-                    group,
-                    start_command,
-                    stop_command,
-                },
-            );
+            ipc::ServiceKind::Synchronous { command }
         }
 
-        _ => unreachable!(),
-    }
-}
-
-fn remove_subcommand(subcommand: &flag::ParsedCommand) {
-    let service_name = subcommand
-        .positional_args
-        .get(&"service name".to_string())
-        .unwrap()
-        .clone();
-
-    let mut socket = connect_to_socket();
-    run_command(&mut socket, Command::RemoveService { name: service_name });
-}
-
-fn edit_subcommand(subcommand: &flag::ParsedCommand) {
-    let subcommand = subcommand.subcommand.as_ref().unwrap();
-
-    let service_name = subcommand
-        .positional_args
-        .get(&"service name".to_string())
-        .unwrap()
-        .clone();
+        "oneshot" => {
+            let command = parse_command(shell, subcommand.positional_args.get("command").unwrap());
 
-    let mut socket = connect_to_socket();
-    let get_service_response = run_command(
-        &mut socket,
-        Command::GetServiceStatus {
-            name: service_name.clone(),
-        },
-    );
-    let service = match get_service_response.kind {
-        ResponseKind::ServiceStatus { service, .. } => service,
-        _ => {
-            eprintln!("ERROR: got unexpected response from servier");
-            exit(1);
+            ipc::ServiceKind::Oneshot { command }
         }
-    };
 
-    let new_name = subcommand
-        .flags
-        .get(&"name".to_string())
-        .map(|s| s.clone())
-        .or(Some(service_name.clone()))
-        .unwrap();
-    let working_directory = subcommand
-        .flags
-        .get(&"working-directory".to_string())
-        .map(|s| s.clone())
-        .or(Some(service.working_directory))
-        .unwrap();
-    let environment = subcommand
-        .flags
-        .get(&"environment".to_string())
-        .map(|env| from_json(env))
-        .or(Some(service.environment))
-        .unwrap();
-    let group = subcommand
-        .flags
-        .get(&"group".to_string())
-        .map(|s| s.clone())
-        .or(service.group);
+        "timer" => {
+            let command = parse_command(shell, subcommand.positional_args.get("command").unwrap());
 
-    let readd_command = match subcommand.name.as_str() {
-        "sync" => {
-            let old_command = if let ipc::ServiceKind::Synchronous { command } = service.kind {
-                command
-            } else {
-                eprintln!("ERROR: service is not synchronous");
-                exit(1);
+            let interval_secs = match subcommand.flags.get("every") {
+                Some(interval) => parse_duration("timer interval", interval),
+                None => {
+                    eprintln!("ERROR: timer services need an interval, provide one with --every");
+                    exit(EXIT_USAGE);
+                }
             };
 
-            let command = subcommand
-                .flags
-                .get(&"command".to_string())
-                .map(|json| from_json(json))
-                .or(Some(old_command))
-                .unwrap();
-
-            Command::AddSynchronousService {
-                name: new_name,
-                working_directory,
-                environment,
-                group,
+            ipc::ServiceKind::Timer {
                 command,
+                interval_secs,
             }
         }
 
         "async" => {
-            let (old_start_command, old_stop_command) = if let ipc::ServiceKind::Asynchronous {
-                start_command,
-                stop_command,
-            } = service.kind
-            {
-                (start_command, stop_command)
-            } else {
-                eprintln!("ERROR: service is not asynchronous");
-                exit(1);
-            };
+            let start_command = parse_command(
+                shell,
+                subcommand.positional_args.get("start command").unwrap(),
+            );
 
-            let start_command = subcommand
-                .flags
-                .get(&"start-command".to_string())
-                .map(|json| from_json(json))
-                .or(Some(old_start_command))
-                .unwrap();
-            let stop_command = subcommand
+            let stop_command = parse_command(
+                shell,
+                subcommand.positional_args.get("stop command").unwrap(),
+            );
+
+            let stop_environment = subcommand.get_json("stop-environment");
+            let stop_working_directory = subcommand.flags.get("stop-working-directory").cloned();
+            let pidfile = subcommand.flags.get("pidfile").cloned();
+            let command_timeout_secs = subcommand
                 .flags
-                .get(&"stop-command".to_string())
-                .map(|json| from_json(json))
-                .or(Some(old_stop_command))
-                .unwrap();
-
-            Command::AddAsynchronousService {
-                name: new_name,
-                working_directory,
-                environment,
-                group,
+                .get("command-timeout")
+                .map(|duration| parse_duration("command timeout", duration));
+
+            ipc::ServiceKind::Asynchronous {
                 start_command,
                 stop_command,
+                stop_environment,
+                stop_working_directory,
+                pidfile,
+                command_timeout_secs,
             }
         }
 
         _ => unreachable!(),
     };
 
-    run_command(&mut socket, Command::RemoveService { name: service_name });
-    run_command(&mut socket, readd_command);
+    let mut service = ipc::Service::new(working_directory, kind);
+    service.environment = environment;
+    service.group = group;
+    service.ports = ports;
+    service.health_check = health_check;
+    service.health_check_interval_secs = health_check_interval_secs;
+    service.ready_command = ready_command;
+    service.ready_tcp_port = ready_tcp_port;
+    service.watch_paths = watch_paths;
+    service.depends_on = depends_on;
+    service.log_retention_secs = log_retention_secs;
+    service.pre_start = pre_start;
+    service.post_stop = post_stop;
+    service.on_failure = on_failure;
+    service.clean_environment = subcommand.has_flag("clean-env");
+    service.shell = shell;
+    service.description = subcommand.flags.get("description").cloned();
+    service.labels = parse_labels(subcommand.get_all("label"));
+    service.reload_signal = reload_signal;
+    service.reload_command = reload_command;
+    service.create_working_directory = subcommand.has_flag("create-working-directory");
+    service.process_title = subcommand.has_flag("process-title");
+
+    let mut client = connect();
+    run(&mut client, |client| {
+        client.add_service(&service_name, service, subcommand.has_flag("force"))
+    });
 }
 
-fn start_subcommand(subcommand: &flag::ParsedCommand) {
-    let service_name = subcommand
-        .positional_args
-        .get(&"service name".to_string())
-        .unwrap()
-        .clone();
+fn remove_subcommand(subcommand: &flag::ParsedCommand) {
+    let mut names = vec![
+        subcommand
+            .positional_args
+            .get("service name")
+            .unwrap()
+            .clone(),
+    ];
+    names.extend(subcommand.variadic_args.iter().cloned());
 
-    let mut socket = connect_to_socket();
-    run_command(&mut socket, Command::StartService { name: service_name });
-}
+    let mut client = connect();
+    let service_names = resolve_service_names(&mut client, subcommand, &names);
+    if service_names.is_empty() {
+        return;
+    }
+    if !subcommand.has_flag("force") {
+        if !io::stdin().is_terminal() {
+            eprintln!("ERROR: stdin is not a terminal, pass --force to remove without asking");
+            exit(EXIT_USAGE);
+        }
 
-fn stop_subcommand(subcommand: &flag::ParsedCommand) {
-    let service_name = subcommand
-        .positional_args
-        .get(&"service name".to_string())
-        .unwrap()
-        .clone();
+        // Fetched before asking, so that a typo in any name fails before anything is removed.
+        let summaries = service_names
+            .iter()
+            .map(|name| {
+                let ServiceStatus {
+                    service, running, ..
+                } = run(&mut client, |client| {
+                    client.service_status_with_logs(name, 0)
+                });
+                format!(
+                    "{name} ({}, {}, group: {}): {}",
+                    service.kind.name(),
+                    if running { "running" } else { "not running" },
+                    service.group.as_deref().unwrap_or("none"),
+                    format_command(service.shell, service.kind.command()),
+                )
+            })
+            .collect::<Vec<String>>();
 
-    let mut socket = connect_to_socket();
-    run_command(&mut socket, Command::StopService { name: service_name });
-}
+        println!("These services will be stopped and removed, along with their logs:");
+        for summary in summaries {
+            println!("    {summary}");
+        }
+        print!("Remove them? [y/N] ");
+        let _ = io::stdout().flush();
 
-fn restart_subcommand(subcommand: &flag::ParsedCommand) {
-    let service_name = subcommand
-        .positional_args
-        .get(&"service name".to_string())
-        .unwrap()
-        .clone();
+        let mut answer = String::new();
+        let _ = io::stdin().read_line(&mut answer);
+        if !matches!(answer.trim().to_lowercase().as_str(), "y" | "yes") {
+            eprintln!("ERROR: aborted, nothing was removed");
+            exit(EXIT_FAILURE);
+        }
+    }
 
-    let mut socket = connect_to_socket();
-    run_command(&mut socket, Command::RestartService { name: service_name });
+    run_for_each(&mut client, &service_names, |client, name| {
+        client.remove_service(name)
+    });
 }
 
-fn status_subcommand(subcommand: &flag::ParsedCommand) {
+fn edit_subcommand(subcommand: &flag::ParsedCommand) {
+    let subcommand = subcommand.subcommand.as_ref().unwrap();
+
     let service_name = subcommand
         .positional_args
-        .get(&"service name".to_string())
+        .get("service name")
         .unwrap()
         .clone();
 
-    let mut socket = connect_to_socket();
-    let response = run_command(
-        &mut socket,
-        Command::GetServiceStatus {
-            name: service_name.clone(),
-        },
-    );
+    let mut client = connect();
+    let original_service = run(&mut client, |client| {
+        client.service_status_with_logs(&service_name, 0)
+    })
+    .service;
+    let mut service = original_service.clone();
+
+    let new_name = subcommand
+        .flags
+        .get("name")
+        .cloned()
+        .unwrap_or(service_name.clone());
+    apply_edit(subcommand, &mut service);
 
-    if let ResponseKind::ServiceStatus {
+    replace_service(
+        &mut client,
+        &service_name,
+        original_service,
+        &new_name,
         service,
-        running,
-        logs,
-    } = response.kind
+        subcommand.has_flag("force"),
+    );
+}
+
+/// Changes what the flags of an `edit` subcommand ask for, leaving the rest of the service as
+/// it is.
+fn apply_edit(subcommand: &flag::ParsedCommand, service: &mut ipc::Service) {
+    match field_edit(subcommand, "working-directory", "no-working-directory") {
+        FieldEdit::Keep => {}
+        FieldEdit::Set(working_directory) => {
+            service.working_directory = working_directory.clone();
+        }
+        FieldEdit::Clear => service.working_directory = get_home_directory(),
+    }
+    if subcommand.flags.contains_key("environment")
+        && (subcommand.has_flag("set-env") || subcommand.has_flag("unset-env"))
     {
-        println!("Service status:");
-        println!();
-        println!("                 Name: {service_name}");
-        println!("              Running: {running:?}");
-        println!("    Working directory: {}", service.working_directory);
-        println!("          Environment: {:?}", service.environment);
-        if let Some(group) = service.group {
-            println!("                Group: {group}")
-        } else {
-            println!("                Group: none")
+        eprintln!(
+            "ERROR: --environment replaces the whole environment, so it can't be combined with --set-env or --unset-env"
+        );
+        exit(EXIT_USAGE);
+    }
+    match field_edit(subcommand, "environment", "no-env") {
+        FieldEdit::Keep => {}
+        FieldEdit::Set(_) => {
+            service.environment = subcommand.get_json("environment").unwrap_or_default()
         }
-        match service.kind {
-            ipc::ServiceKind::Synchronous { command } => {
-                println!("              Command: {command:?}")
+        FieldEdit::Clear => service.environment.clear(),
+    }
+    edit_environment(
+        &mut service.environment,
+        subcommand.get_all("set-env"),
+        subcommand.get_all("unset-env"),
+    );
+    if let Some(description) = subcommand.flags.get("description") {
+        service.description = (!description.is_empty()).then(|| description.clone());
+    }
+    if subcommand.has_flag("label") {
+        service.labels = parse_labels(subcommand.get_all("label"));
+    }
+    match field_edit(subcommand, "group", "no-group") {
+        FieldEdit::Keep => {}
+        FieldEdit::Set(group) => service.group = Some(group.clone()),
+        FieldEdit::Clear => service.group = None,
+    }
+    if subcommand.has_flag("port") {
+        service.ports = parse_ports(subcommand.get_all("port"));
+    }
+    if subcommand.has_flag("health-check") {
+        service.health_check = subcommand.get_json("health-check");
+    }
+    if subcommand.has_flag("health-interval") {
+        service.health_check_interval_secs = subcommand
+            .get_int("health-interval")
+            .map(check_health_interval);
+    }
+    if subcommand.has_flag("ready-cmd") {
+        service.ready_command = subcommand.get_json("ready-cmd");
+    }
+    if let Some(port) = subcommand.flags.get("ready-tcp") {
+        service.ready_tcp_port = (!port.is_empty()).then(|| parse_ready_port(port));
+    }
+    if subcommand.has_flag("depends-on") {
+        service.depends_on = parse_dependencies(subcommand.get_all("depends-on"));
+    }
+    if subcommand.has_flag("pre-start") {
+        service.pre_start = subcommand.get_json("pre-start");
+    }
+    if subcommand.has_flag("post-stop") {
+        service.post_stop = subcommand.get_json("post-stop");
+    }
+    if subcommand.has_flag("on-failure") {
+        service.on_failure = subcommand.get_json("on-failure");
+    }
+    if let Some(name) = subcommand.flags.get("reload-signal") {
+        service.reload_signal = (!name.is_empty()).then(|| parse_signal(name));
+        service.reload_command = None;
+    }
+    if subcommand.has_flag("reload-cmd") {
+        service.reload_command = subcommand.get_json("reload-cmd");
+        service.reload_signal = None;
+    }
+    if let Some(duration) = subcommand.flags.get("log-retention") {
+        service.log_retention_secs =
+            (!duration.is_empty()).then(|| parse_duration("log retention", duration));
+    }
+    if subcommand.has_flag("watch") {
+        service.watch_paths = parse_watch_paths(subcommand.get_all("watch"));
+    }
+    if let Some(enabled) = subcommand.flags.get("shell") {
+        service.shell = enabled == "true";
+    }
+    if let Some(enabled) = subcommand.flags.get("clean-env") {
+        service.clean_environment = enabled == "true";
+    }
+    if let Some(enabled) = subcommand.flags.get("create-working-directory") {
+        service.create_working_directory = enabled == "true";
+    }
+    if let Some(enabled) = subcommand.flags.get("process-title") {
+        service.process_title = enabled == "true";
+    }
+    if let Some(enabled) = subcommand.flags.get("watch-enabled") {
+        service.watch_disabled = enabled == "false";
+    }
+
+    match (subcommand.name.as_str(), &mut service.kind) {
+        ("sync", ipc::ServiceKind::Synchronous { command }) => {
+            if let Some(new_command) = subcommand.flags.get("command") {
+                *command = parse_command(service.shell, new_command);
             }
+        }
+
+        (
+            "async",
             ipc::ServiceKind::Asynchronous {
                 start_command,
                 stop_command,
-            } => {
-                println!("        Start command: {start_command:?}");
-                println!("         Stop command: {stop_command:?}");
+                stop_environment,
+                stop_working_directory,
+                pidfile,
+                command_timeout_secs,
+            },
+        ) => {
+            if let Some(new_command) = subcommand.flags.get("start-command") {
+                *start_command = parse_command(service.shell, new_command);
+            }
+            if let Some(new_command) = subcommand.flags.get("stop-command") {
+                *stop_command = parse_command(service.shell, new_command);
+            }
+            if subcommand.has_flag("stop-environment") {
+                *stop_environment = subcommand.get_json("stop-environment");
+            }
+            if let Some(directory) = subcommand.flags.get("stop-working-directory") {
+                *stop_working_directory = Some(directory.clone());
+            }
+            if let Some(path) = subcommand.flags.get("pidfile") {
+                *pidfile = (!path.is_empty()).then(|| path.clone());
+            }
+            if let Some(duration) = subcommand.flags.get("command-timeout") {
+                *command_timeout_secs = Some(parse_duration("command timeout", duration));
             }
         }
-        println!();
-        println!("--- Beginning of Logs ---");
-        println!("{logs}");
-        println!("---    End of Logs    ---");
-        println!();
-    } else {
-        eprintln!("ERROR: got unexpected response from server");
-        exit(1);
-    }
-}
 
-fn list_services_subcommand() {
-    let mut socket = connect_to_socket();
-    let response = run_command(&mut socket, Command::ListServices);
+        ("oneshot", ipc::ServiceKind::Oneshot { command }) => {
+            if let Some(new_command) = subcommand.flags.get("command") {
+                *command = parse_command(service.shell, new_command);
+            }
+        }
 
-    let services = if let ResponseKind::ServiceList { services } = response.kind {
-        services
-    } else {
-        eprintln!("ERROR: got unexpected response from server");
-        exit(1);
-    };
+        (
+            "timer",
+            ipc::ServiceKind::Timer {
+                command,
+                interval_secs,
+            },
+        ) => {
+            if let Some(new_command) = subcommand.flags.get("command") {
+                *command = parse_command(service.shell, new_command);
+            }
+            if let Some(interval) = subcommand.flags.get("every") {
+                *interval_secs = parse_duration("timer interval", interval);
+            }
+        }
 
-    // For truncating table values later.
-    fn truncate_string(string: &String) -> String {
-        let max_chars = 40;
-        let truncated_length = max_chars.min(string.len());
-        let mut truncated_string = string[..truncated_length].to_string();
+        ("sync", _) => {
+            eprintln!("ERROR: service is not synchronous");
+            exit(EXIT_USAGE);
+        }
 
-        if truncated_length > 0 && truncated_length < string.len() {
-            truncated_string.pop();
-            truncated_string.push('|');
+        ("oneshot", _) => {
+            eprintln!("ERROR: service is not a oneshot service");
+            exit(EXIT_USAGE);
         }
 
-        truncated_string
-    }
+        ("timer", _) => {
+            eprintln!("ERROR: service is not a timer service");
+            exit(EXIT_USAGE);
+        }
 
-    /*
-     * Separate into groups.
-     */
-    let mut groups = HashMap::<String, HashMap<String, ipc::Service>>::new();
-    for (service_name, service) in services {
-        let group_name = match service.group {
-            Some(ref group_name) => group_name.clone(),
-            None => "none".to_string(),
-        };
+        ("async", _) => {
+            eprintln!("ERROR: service is not asynchronous");
+            exit(EXIT_USAGE);
+        }
 
-        let group = match groups.get_mut(&group_name) {
-            Some(group) => group,
-            None => {
-                groups.insert(group_name.clone(), HashMap::new());
-                groups.get_mut(&group_name).unwrap()
-            }
-        };
+        _ => unreachable!(),
+    }
+}
 
-        group.insert(service_name, service);
+fn replace_service(
+    client: &mut Client,
+    name: &str,
+    original_service: ipc::Service,
+    new_name: &str,
+    service: ipc::Service,
+    force: bool,
+) {
+    run(client, |client| client.remove_service(name));
+    let readd_result = client.add_service(new_name, service, force);
+    if let Err(err @ client::Error::Command(_)) = readd_result {
+        // Put the service back the way it was rather than leaving it removed.
+        let _ = client.add_service(name, original_service, true);
+        fail(err);
     }
+    run(client, |_| readd_result);
+}
 
-    /*
-     * Get each property's displayed length.
-     */
-    let mut name_length = 4;
-    let mut start_command_length = 13;
-    let mut stop_command_length = 12;
+fn edit_json_subcommand(subcommand: &flag::ParsedCommand) {
+    let service_name = subcommand
+        .positional_args
+        .get("service name")
+        .unwrap()
+        .clone();
 
-    for (_, group) in &groups {
-        for (service_name, service) in group {
-            if truncate_string(service_name).len() > name_length {
-                name_length = service_name.len();
-            }
+    let mut client = connect();
+    let original_service = run(&mut client, |client| {
+        client.service_status_with_logs(&service_name, 0)
+    })
+    .service;
 
-            match &service.kind {
-                ipc::ServiceKind::Synchronous { command } => {
-                    let formatted_command = truncate_string(&format!("{command:?}"));
-                    if formatted_command.len() > start_command_length {
-                        start_command_length = formatted_command.len();
-                    }
-                }
+    // The name is written along with the service, like `export` does, so that the file can be
+    // told apart from others open in the editor.
+    let original_json = serde_json::to_string_pretty(&BTreeMap::from([(
+        service_name.as_str(),
+        &original_service,
+    )]))
+    .unwrap()
+        + "\n";
 
-                ipc::ServiceKind::Asynchronous {
-                    start_command,
-                    stop_command,
-                } => {
-                    let formatted_start_command = truncate_string(&format!("{start_command:?}"));
-                    let formatted_stop_command = truncate_string(&format!("{stop_command:?}"));
+    let path = env::temp_dir().join(format!("userserversctl-edit-{}.json", process::id()));
+    let write_result = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&path)
+        .and_then(|mut file| file.write_all(original_json.as_bytes()));
+    if let Err(err) = write_result {
+        eprintln!("ERROR: failed to write `{}`: {err}", path.display());
+        exit(1);
+    }
 
-                    if formatted_start_command.len() > start_command_length {
-                        start_command_length = formatted_start_command.len();
-                    }
+    let editor = env::var("VISUAL")
+        .or_else(|_| env::var("EDITOR"))
+        .unwrap_or_else(|_| "vi".to_string());
+    // Going through the shell lets the editor have arguments, like `code --wait`.
+    let editor_status = process::Command::new("sh")
+        .arg("-c")
+        .arg(format!("{editor} \"$1\""))
+        .arg("sh")
+        .arg(&path)
+        .status();
+    let edited_json = fs::read_to_string(&path);
 
-                    if formatted_stop_command.len() > stop_command_length {
-                        stop_command_length = formatted_stop_command.len();
-                    }
-                }
-            }
+    match editor_status {
+        Ok(status) if status.success() => {}
+        Ok(status) => {
+            let _ = fs::remove_file(&path);
+            eprintln!("ERROR: the editor exited with {status}, so the service was left unchanged");
+            exit(1);
+        }
+        Err(err) => {
+            let _ = fs::remove_file(&path);
+            eprintln!("ERROR: failed to run `{editor}`: {err}");
+            exit(1);
         }
     }
 
-    /*
-     * Display table.
-     */
-    for (group_name, group) in groups {
-        println!("{group_name}:");
-        println!(
-            "    Name{}  Start Command{}  Stop Command{}",
-            " ".repeat(name_length - "Name".len()),
-            " ".repeat(start_command_length - "Start Command".len()),
-            " ".repeat(stop_command_length - "Stop Command".len())
-        );
-        println!(
-            "    {}",
-            "-".repeat(name_length + start_command_length + stop_command_length + 4)
-        );
+    let edited_json = edited_json.unwrap_or_else(|err| {
+        eprintln!("ERROR: failed to read `{}`: {err}", path.display());
+        exit(1);
+    });
+    if edited_json == original_json {
+        let _ = fs::remove_file(&path);
+        println!("The service was left unchanged");
+        return;
+    }
 
-        for (service_name, service) in group {
-            print!(
-                "    {service_name}{padding}  ",
-                service_name = truncate_string(&service_name),
-                padding = " ".repeat(name_length - service_name.len())
+    let edited = serde_json::from_str::<HashMap<String, ipc::Service>>(&edited_json)
+        .unwrap_or_else(|err| {
+            eprintln!("ERROR: invalid service definition: {err}");
+            eprintln!("Your changes were kept in `{}`", path.display());
+            exit(1);
+        });
+    let _ = fs::remove_file(&path);
+
+    let mut edited = edited.into_iter();
+    let service = match (edited.next(), edited.next()) {
+        (Some((name, service)), None) if name == service_name => service,
+        (Some((name, _)), None) => {
+            eprintln!(
+                "ERROR: the service can't be renamed to `{name}` this way, use `edit <kind> {service_name} --name {name}` instead"
             );
+            exit(1);
+        }
+        _ => {
+            eprintln!("ERROR: the file must define exactly one service");
+            exit(1);
+        }
+    };
+    if service == original_service {
+        println!("The service was left unchanged");
+        return;
+    }
 
-            match service.kind {
-                ipc::ServiceKind::Synchronous { command } => {
-                    let formatted_command = truncate_string(&format!("{command:?}"));
-                    println!(
-                        "{formatted_command}{}  ",
-                        " ".repeat(start_command_length - formatted_command.len())
-                    );
-                }
+    replace_service(
+        &mut client,
+        &service_name,
+        original_service,
+        &service_name,
+        service,
+        subcommand.has_flag("force"),
+    );
+}
 
-                ipc::ServiceKind::Asynchronous {
-                    start_command,
-                    stop_command,
-                } => {
-                    let formatted_start_command = truncate_string(&format!("{start_command:?}"));
-                    print!(
-                        "{formatted_start_command}{}  ",
-                        " ".repeat(start_command_length - formatted_start_command.len())
-                    );
+/// How an edit changes a field that can also be reset, through a flag setting it and another
+/// one resetting it.
+enum FieldEdit<'a> {
+    Keep,
+    Set(&'a String),
+    Clear,
+}
 
-                    let formatted_stop_command = truncate_string(&format!("{stop_command:?}"));
-                    println!(
-                        "{formatted_stop_command}{}",
-                        " ".repeat(stop_command_length - formatted_stop_command.len())
-                    );
-                }
-            }
+fn field_edit<'a>(
+    subcommand: &'a flag::ParsedCommand,
+    set_flag: &str,
+    clear_flag: &str,
+) -> FieldEdit<'a> {
+    match (
+        subcommand.flags.get(set_flag),
+        subcommand.has_flag(clear_flag),
+    ) {
+        (Some(_), true) => {
+            eprintln!("ERROR: --{set_flag} and --{clear_flag} can't be used together");
+            exit(EXIT_USAGE);
         }
-        println!();
+        (Some(value), false) => FieldEdit::Set(value),
+        (None, true) => FieldEdit::Clear,
+        (None, false) => FieldEdit::Keep,
     }
 }
 
-fn main() {
-    let cli = cli();
-    let parsed_cli = flag::parse(&cli).unwrap_or_else(|err| {
-        eprintln!("{}", cli.generate_help());
-        eprintln!("ERROR: {err}");
-        exit(1);
-    });
+/// Parses a signal name, with or without its `SIG` prefix and in any case, into its full name.
+fn parse_signal(name: &str) -> String {
+    let name = name.to_uppercase();
+    let name = if name.starts_with("SIG") {
+        name
+    } else {
+        format!("SIG{name}")
+    };
+    if name.parse::<Signal>().is_err() {
+        eprintln!("ERROR: `{name}` is not a signal");
+        exit(EXIT_USAGE);
+    }
+    name
+}
 
-    let subcommand = parsed_cli.subcommand.unwrap();
+/// Parses labels given as KEY=VALUE, ignoring empty arguments so that they can remove every
+/// label.
+fn parse_labels(labels: &[String]) -> HashMap<String, String> {
+    labels
+        .iter()
+        .filter(|label| !label.is_empty())
+        .map(|label| match label.split_once('=') {
+            Some((key, value)) if !key.is_empty() => (key.to_string(), value.to_string()),
+            _ => {
+                eprintln!("ERROR: labels must be given as KEY=VALUE, got `{label}`");
+                exit(EXIT_USAGE);
+            }
+        })
+        .collect()
+}
+
+fn edit_environment(environment: &mut HashMap<String, String>, set: &[String], unset: &[String]) {
+    for assignment in set {
+        let Some((key, value)) = assignment
+            .split_once('=')
+            .filter(|(key, _)| !key.is_empty())
+        else {
+            eprintln!("ERROR: environment variables must be set as KEY=VALUE, got `{assignment}`");
+            exit(EXIT_USAGE);
+        };
+        if unset.iter().any(|unset_key| unset_key == key) {
+            eprintln!("ERROR: the environment variable `{key}` is both set and unset");
+            exit(EXIT_USAGE);
+        }
+
+        environment.insert(key.to_string(), value.to_string());
+    }
+
+    for key in unset {
+        environment.remove(key);
+    }
+}
+
+fn start_subcommand(subcommand: &flag::ParsedCommand) {
+    let mut client = connect();
+    let service_names = resolve_service_names(
+        &mut client,
+        subcommand,
+        std::slice::from_ref(subcommand.positional_args.get("service name").unwrap()),
+    );
+
+    let wait_timeout = wait_timeout(subcommand);
+    run_for_each(&mut client, &service_names, |client, name| {
+        client.start_service(name)?;
+        match wait_timeout {
+            Some(timeout) => client.wait_for_service(name, timeout),
+            None => Ok(()),
+        }
+    });
+}
+
+fn stop_subcommand(subcommand: &flag::ParsedCommand) {
+    let with_dependents = subcommand.has_flag("with-dependents");
+
+    let mut client = connect();
+    let service_names = resolve_service_names(
+        &mut client,
+        subcommand,
+        std::slice::from_ref(subcommand.positional_args.get("service name").unwrap()),
+    );
+
+    run_for_each(&mut client, &service_names, |client, name| {
+        client.stop_service(name, with_dependents)
+    });
+}
+
+fn restart_subcommand(subcommand: &flag::ParsedCommand) {
+    let mut client = connect();
+    let service_names = resolve_service_names(
+        &mut client,
+        subcommand,
+        std::slice::from_ref(subcommand.positional_args.get("service name").unwrap()),
+    );
+
+    let wait_timeout = wait_timeout(subcommand);
+    run_for_each(&mut client, &service_names, |client, name| {
+        client.restart_service(name)?;
+        match wait_timeout {
+            Some(timeout) => client.wait_for_service(name, timeout),
+            None => Ok(()),
+        }
+    });
+}
+
+fn pause_subcommand(subcommand: &flag::ParsedCommand) {
+    let mut client = connect();
+    let service_names = resolve_service_names(
+        &mut client,
+        subcommand,
+        std::slice::from_ref(subcommand.positional_args.get("service name").unwrap()),
+    );
+
+    run_for_each(&mut client, &service_names, |client, name| {
+        client.pause_service(name)
+    });
+}
+
+fn resume_subcommand(subcommand: &flag::ParsedCommand) {
+    let mut client = connect();
+    let service_names = resolve_service_names(
+        &mut client,
+        subcommand,
+        std::slice::from_ref(subcommand.positional_args.get("service name").unwrap()),
+    );
+
+    run_for_each(&mut client, &service_names, |client, name| {
+        client.resume_service(name)
+    });
+}
+
+fn reload_subcommand(subcommand: &flag::ParsedCommand) {
+    let mut client = connect();
+    let service_names = resolve_service_names(
+        &mut client,
+        subcommand,
+        std::slice::from_ref(subcommand.positional_args.get("service name").unwrap()),
+    );
+
+    run_for_each(&mut client, &service_names, |client, name| {
+        client.reload_service(name)
+    });
+}
+
+/// Returns how long to wait for services to be ready, if the subcommand should wait at all.
+fn wait_timeout(subcommand: &flag::ParsedCommand) -> Option<Duration> {
+    if !subcommand.has_flag("wait") {
+        return None;
+    }
+
+    let timeout_secs = match subcommand.flags.get("wait-timeout") {
+        Some(secs) => match secs.parse::<f64>() {
+            Ok(secs) if secs >= 0.0 && secs.is_finite() => secs,
+            _ => {
+                eprintln!("ERROR: the wait timeout must be a non-negative number of seconds");
+                exit(EXIT_USAGE);
+            }
+        },
+        None => 60.0,
+    };
+
+    Some(Duration::try_from_secs_f64(timeout_secs).unwrap_or(Duration::MAX))
+}
+
+fn status_summary_subcommand() {
+    let mut client = connect();
+    let summaries = run(&mut client, Client::service_summaries);
+
+    let mut rows = summaries
+        .into_iter()
+        .map(|(name, summary)| {
+            let group = summary.group.unwrap_or_else(|| "none".to_string());
+            let state = format!("{:?}", summary.state);
+            let uptime = match summary.uptime_secs {
+                Some(secs) => format_elapsed(secs),
+                None => "-".to_string(),
+            };
+            let failed = summary.state == ipc::ServiceState::Failed;
+            (group, name, state, uptime, summary.start_count, failed)
+        })
+        .collect::<Vec<_>>();
+    rows.sort();
+
+    let name_length = rows.iter().map(|row| row.1.len()).fold(4, usize::max);
+    let group_length = rows.iter().map(|row| row.0.len()).fold(5, usize::max);
+    let state_length = "Unhealthy".len();
+    println!(
+        "{:name_length$}  {:group_length$}  {:state_length$}  {:10}  Starts",
+        "Name", "Group", "State", "Uptime"
+    );
+    for (group, name, state, uptime, start_count, _) in &rows {
+        println!(
+            "{name:name_length$}  {group:group_length$}  {state:state_length$}  {uptime:10}  {start_count}"
+        );
+    }
+
+    let failed = rows.iter().filter(|row| row.5).count();
+    if failed > 0 {
+        eprintln!("ERROR: {failed} service(s) failed");
+        exit(1);
+    }
+}
+
+fn status_subcommand(subcommand: &flag::ParsedCommand) {
+    let Some(service_name) = subcommand.positional_args.get("service name") else {
+        status_summary_subcommand();
+        return;
+    };
+
+    let log_bytes = subcommand
+        .get_int("log-kb")
+        .map(|kilobytes| (kilobytes as usize).saturating_mul(1024));
+
+    let mut client = connect();
+    for name in resolve_service_names(&mut client, subcommand, std::slice::from_ref(service_name)) {
+        print_service_status(&mut client, &name, log_bytes);
+    }
+}
+
+fn print_service_status(client: &mut Client, service_name: &str, log_bytes: Option<usize>) {
+    let ServiceStatus {
+        service,
+        state,
+        running,
+        ready,
+        last_watch_trigger,
+        last_exit,
+        timer,
+        oldest_log_secs_ago,
+        pid,
+        memory_bytes,
+        cpu_percent,
+        start_count,
+        runs,
+        logs,
+        logs_truncated,
+        ..
+    } = run(client, |client| match log_bytes {
+        Some(log_bytes) => client.service_status_with_logs(service_name, log_bytes),
+        None => client.service_status(service_name),
+    });
+
+    let exit_status = match last_exit.as_ref().and_then(format_exit) {
+        Some(exit) => exit,
+        None if running => "still running".to_string(),
+        None => "none".to_string(),
+    };
+
+    print_header("Service status");
+    println!("                 Name: {service_name}");
+    match (&service.kind, timer) {
+        (ipc::ServiceKind::Oneshot { .. }, _) => {
+            println!("          Exit status: {exit_status}");
+        }
+        (ipc::ServiceKind::Timer { .. }, Some(timer)) => {
+            println!("            Scheduled: {running:?}");
+            match timer.last_run_secs_ago {
+                // The PID of a started timer is the one of the run in progress.
+                Some(secs_ago) if pid.is_some() => {
+                    println!("             Last run: {secs_ago}s ago, still running")
+                }
+                Some(secs_ago) if last_exit.is_some() => {
+                    println!("             Last run: {secs_ago}s ago, {exit_status}")
+                }
+                Some(secs_ago) => println!("             Last run: {secs_ago}s ago"),
+                None => println!("             Last run: never"),
+            }
+            if let Some(next_run_in_secs) = timer.next_run_in_secs {
+                println!("             Next run: in {next_run_in_secs}s");
+            }
+        }
+        _ => println!("              Running: {running:?}"),
+    }
+    println!("                State: {state:?}");
+    println!("                Ready: {ready:?}");
+    if let Some(pid) = pid {
+        println!("                  PID: {pid}");
+    }
+    if let Some(memory_bytes) = memory_bytes {
+        println!("               Memory: {}", format_bytes(memory_bytes));
+    }
+    if let Some(cpu_percent) = cpu_percent {
+        println!("                  CPU: {cpu_percent:.1}% on average since it started");
+    }
+    println!(
+        "    Working directory: {}{}",
+        service.working_directory,
+        if service.create_working_directory {
+            " (created if missing)"
+        } else {
+            ""
+        }
+    );
+    println!(
+        "          Environment: {:?}{}",
+        service.environment,
+        if service.clean_environment {
+            " (clean)"
+        } else {
+            ""
+        }
+    );
+    if let Some(description) = &service.description {
+        println!("          Description: {description}");
+    }
+    if let Some(group) = service.group {
+        println!("                Group: {group}")
+    } else {
+        println!("                Group: none")
+    }
+    if !service.labels.is_empty() {
+        let mut labels = service
+            .labels
+            .iter()
+            .map(|(key, value)| format!("{key}={value}"))
+            .collect::<Vec<String>>();
+        labels.sort();
+        println!("               Labels: {}", labels.join(", "));
+    }
+    if !service.ports.is_empty() {
+        println!("                Ports: {:?}", service.ports);
+    }
+    if let Some(ready_command) = &service.ready_command {
+        println!("    Readiness command: {ready_command:?}");
+    }
+    if let Some(ready_tcp_port) = service.ready_tcp_port {
+        println!("       Readiness port: {ready_tcp_port}");
+    }
+    if let Some(health_check) = &service.health_check {
+        println!(
+            "         Health check: {health_check:?} (every {}s)",
+            service.health_check_interval_secs.unwrap_or(30)
+        );
+    }
+    if let Some(pre_start) = &service.pre_start {
+        println!("       Pre-start hook: {pre_start:?}");
+    }
+    if let Some(post_stop) = &service.post_stop {
+        println!("       Post-stop hook: {post_stop:?}");
+    }
+    if let Some(on_failure) = &service.on_failure {
+        println!("      On-failure hook: {on_failure:?}");
+    }
+    if let Some(reload_signal) = &service.reload_signal {
+        println!("        Reload signal: {reload_signal}");
+    }
+    if let Some(reload_command) = &service.reload_command {
+        println!("       Reload command: {reload_command:?}");
+    }
+    if service.process_title {
+        println!("        Process title: userserversd: {service_name}");
+    }
+    if !service.depends_on.is_empty() {
+        println!("           Depends on: {:?}", service.depends_on);
+    }
+    if !service.watch_paths.is_empty() {
+        println!(
+            "        Watched paths: {:?}{}",
+            service.watch_paths,
+            if service.watch_disabled {
+                " (disabled)"
+            } else {
+                ""
+            }
+        );
+        if let Some(trigger) = last_watch_trigger {
+            println!(
+                "     Last file change: {} ({}s ago)",
+                trigger.path, trigger.secs_ago
+            );
+        }
+    }
+    let shell = service.shell;
+    match service.kind {
+        ipc::ServiceKind::Synchronous { command } | ipc::ServiceKind::Oneshot { command } => {
+            println!("              Command: {}", format_command(shell, &command))
+        }
+        ipc::ServiceKind::Timer {
+            command,
+            interval_secs,
+        } => {
+            println!("              Command: {}", format_command(shell, &command));
+            println!(
+                "             Interval: every {}",
+                format_duration(interval_secs)
+            );
+        }
+        ipc::ServiceKind::Asynchronous {
+            start_command,
+            stop_command,
+            stop_environment,
+            stop_working_directory,
+            pidfile,
+            command_timeout_secs,
+        } => {
+            println!(
+                "        Start command: {}",
+                format_command(shell, &start_command)
+            );
+            println!(
+                "         Stop command: {}",
+                format_command(shell, &stop_command)
+            );
+            if let Some(stop_environment) = stop_environment {
+                println!("     Stop environment: {stop_environment:?}");
+            }
+            if let Some(stop_working_directory) = stop_working_directory {
+                println!("       Stop directory: {stop_working_directory}");
+            }
+            if let Some(pidfile) = pidfile {
+                println!("              Pidfile: {pidfile}");
+            }
+            if let Some(command_timeout_secs) = command_timeout_secs {
+                println!(
+                    "      Command timeout: {}",
+                    format_duration(command_timeout_secs)
+                );
+            }
+        }
+        kind => println!(
+            "              Command: {}",
+            format_command(shell, kind.command())
+        ),
+    }
+    if let Some(log_retention_secs) = service.log_retention_secs {
+        println!(
+            "        Log retention: {}",
+            format_duration(log_retention_secs)
+        );
+    }
+    if let Some(secs_ago) = oldest_log_secs_ago {
+        println!("      Oldest log line: {secs_ago}s ago");
+    }
+    if start_count > 0 {
+        println!();
+        println!("Started {start_count} time(s), last {} run(s):", runs.len());
+        for run in runs.iter().rev() {
+            let end = match (run.ended_secs_ago, run.end_state) {
+                (Some(secs_ago), Some(state)) => {
+                    let exit = match run.exit.as_ref().and_then(format_exit) {
+                        Some(exit) => format!(", {exit}"),
+                        None => String::new(),
+                    };
+                    format!("ended {secs_ago}s ago as {state:?}{exit}")
+                }
+                _ => "still running".to_string(),
+            };
+            println!("    started {}s ago, {end}", run.started_secs_ago);
+        }
+    }
+    if output().quiet {
+        println!();
+        println!("{logs}");
+    } else {
+        println!();
+        if logs_truncated {
+            println!("--- Earlier Logs Left Out ---");
+        } else {
+            println!("--- Beginning of Logs ---");
+        }
+        println!("{logs}");
+        println!("---    End of Logs    ---");
+        println!();
+    }
+}
+
+fn logs_subcommand(subcommand: &flag::ParsedCommand) {
+    let service_name = subcommand
+        .positional_args
+        .get("service name")
+        .unwrap()
+        .clone();
+
+    let mut client = connect();
+
+    if !subcommand.has_flag("full") {
+        let lines = subcommand
+            .get_int("lines")
+            .map_or(100, |lines| lines as usize);
+
+        print!(
+            "{}",
+            run(&mut client, |client| client
+                .service_logs(&service_name, lines))
+        );
+        return;
+    }
+
+    let chunks = client
+        .stream_service_logs(&service_name)
+        .unwrap_or_else(|err| fail(err));
+    for chunk in chunks {
+        let data = chunk.unwrap_or_else(|err| fail(err));
+        print!("{data}");
+    }
+}
+
+fn watch_subcommand(subcommand: &flag::ParsedCommand) {
+    let kinds = subcommand
+        .get_all("event")
+        .iter()
+        .map(|name| {
+            ipc::EVENT_KINDS
+                .iter()
+                .find(|(kind_name, _)| kind_name == name)
+                .map(|(_, kind)| *kind)
+                .unwrap_or_else(|| {
+                    eprintln!("ERROR: unknown event kind `{name}`");
+                    exit(EXIT_USAGE);
+                })
+        })
+        .collect::<Vec<ipc::EventKind>>();
+    let json = subcommand.has_flag("json");
+
+    let mut client = connect();
+    let events = client.subscribe(&kinds).unwrap_or_else(|err| fail(err));
+    for event in events {
+        let event = event.unwrap_or_else(|err| fail(err));
+        if json {
+            println!("{}", serde_json::to_string(&event).unwrap());
+            continue;
+        }
+
+        let kind_name = ipc::EVENT_KINDS
+            .iter()
+            .find(|(_, kind)| *kind == event.kind)
+            .map(|(name, _)| *name)
+            .unwrap();
+        println!(
+            "{} {} {kind_name} ({:?}, {})",
+            event.timestamp, event.service, event.state, event.detail
+        );
+    }
+}
+
+const DEFAULT_TABLE_WIDTH: usize = 40;
+
+const LIST_TEMPLATE_FIELDS: [&str; 6] = [
+    "name",
+    "group",
+    "kind",
+    "state",
+    "command",
+    "working_directory",
+];
+
+enum TemplatePart {
+    Text(String),
+    Field(&'static str),
+}
+
+fn parse_list_template(template: &str) -> Vec<TemplatePart> {
+    let mut parts = Vec::new();
+    let mut text = String::new();
+    let mut chars = template.chars();
+
+    while let Some(char) = chars.next() {
+        match char {
+            '\\' => match chars.next() {
+                Some('t') => text.push('\t'),
+                Some('n') => text.push('\n'),
+                Some('\\') => text.push('\\'),
+                other => {
+                    text.push('\\');
+                    text.extend(other);
+                }
+            },
+            '{' => {
+                let mut name = String::new();
+                loop {
+                    match chars.next() {
+                        Some('}') => break,
+                        Some(char) => name.push(char),
+                        None => {
+                            eprintln!("ERROR: the format has a `{{` that is never closed");
+                            exit(EXIT_USAGE);
+                        }
+                    }
+                }
+
+                let Some(field) = LIST_TEMPLATE_FIELDS.iter().find(|field| **field == name) else {
+                    eprintln!(
+                        "ERROR: unknown field `{name}` in the format, the valid fields are: {}",
+                        LIST_TEMPLATE_FIELDS.join(", ")
+                    );
+                    exit(EXIT_USAGE);
+                };
+                parts.push(TemplatePart::Text(std::mem::take(&mut text)));
+                parts.push(TemplatePart::Field(field));
+            }
+            char => text.push(char),
+        }
+    }
+    parts.push(TemplatePart::Text(text));
+
+    parts
+}
+
+fn format_list_template(
+    template: &[TemplatePart],
+    name: &str,
+    service: &ipc::Service,
+    state: &ipc::ServiceState,
+) -> String {
+    let mut line = String::new();
+    for part in template {
+        match part {
+            TemplatePart::Text(text) => line.push_str(text),
+            TemplatePart::Field("name") => line.push_str(name),
+            TemplatePart::Field("group") => line.push_str(service.group.as_deref().unwrap_or("")),
+            TemplatePart::Field("kind") => line.push_str(match &service.kind {
+                ipc::ServiceKind::Synchronous { .. } => "sync",
+                ipc::ServiceKind::Asynchronous { .. } => "async",
+                ipc::ServiceKind::Oneshot { .. } => "oneshot",
+                ipc::ServiceKind::Timer { .. } => "timer",
+                kind => kind.name(),
+            }),
+            TemplatePart::Field("state") => line.push_str(&format!("{state:?}")),
+            TemplatePart::Field("command") => {
+                line.push_str(&format_command(service.shell, service.kind.command()))
+            }
+            TemplatePart::Field("working_directory") => line.push_str(&service.working_directory),
+            TemplatePart::Field(_) => unreachable!(),
+        }
+    }
+    line.push('\n');
+    line
+}
+
+/// Cuts a table value down to `max_width` characters, marking that it was cut with a `|`.
+fn truncate_string(string: &str, max_width: Option<usize>) -> String {
+    match max_width {
+        Some(max_width) if string.chars().count() > max_width => {
+            let mut truncated_string = string.chars().take(max_width - 1).collect::<String>();
+            truncated_string.push('|');
+            truncated_string
+        }
+        _ => string.to_string(),
+    }
+}
+
+/// Renders an indented table with a line under its header. Values longer than `max_width`
+/// characters are truncated, and columns are as wide as the widest value once truncated.
+fn render_table(columns: &[&str], rows: &[Vec<String>], max_width: Option<usize>) -> String {
+    let rows = rows
+        .iter()
+        .map(|row| {
+            row.iter()
+                .map(|value| truncate_string(value, max_width))
+                .collect::<Vec<String>>()
+        })
+        .collect::<Vec<Vec<String>>>();
+
+    let widths = columns
+        .iter()
+        .enumerate()
+        .map(|(i, column)| {
+            rows.iter()
+                .map(|row| row[i].chars().count())
+                .fold(column.chars().count(), usize::max)
+        })
+        .collect::<Vec<usize>>();
+
+    let render_row = |values: &mut dyn Iterator<Item = &str>| {
+        let mut line = "   ".to_string();
+        for (value, width) in values.zip(&widths) {
+            line.push_str(&format!(" {value:width$} "));
+        }
+        line.truncate(line.trim_end().len());
+        line + "\n"
+    };
+
+    let mut output = render_row(&mut columns.iter().copied());
+    let total_width = widths.iter().sum::<usize>() + 2 * (widths.len() - 1);
+    output.push_str(&format!("    {}\n", "-".repeat(total_width)));
+    for row in &rows {
+        output.push_str(&render_row(&mut row.iter().map(String::as_str)));
+    }
+    output
+}
+
+fn list_services_subcommand(subcommand: &flag::ParsedCommand) {
+    let show_ports = subcommand.has_flag("ports");
+    let max_width = match (subcommand.has_flag("full"), subcommand.get_int("width")) {
+        (true, _) if subcommand.has_flag("width") => {
+            eprintln!("ERROR: --full and --width can't be used together");
+            exit(EXIT_USAGE);
+        }
+        (true, _) => None,
+        (false, Some(0)) => {
+            eprintln!("ERROR: the width must be a positive integer");
+            exit(EXIT_USAGE);
+        }
+        (false, Some(width)) => Some(width as usize),
+        (false, None) => Some(DEFAULT_TABLE_WIDTH),
+    };
+    let group = subcommand.flags.get("group");
+    let running = match (
+        subcommand.has_flag("running"),
+        subcommand.has_flag("stopped"),
+    ) {
+        (true, true) => {
+            eprintln!("ERROR: --running and --stopped can't be used together");
+            exit(EXIT_USAGE);
+        }
+        (true, false) => Some(true),
+        (false, true) => Some(false),
+        (false, false) => None,
+    };
+
+    // Parsed before connecting so that mistakes in it are reported right away.
+    let template = subcommand
+        .flags
+        .get("format")
+        .map(|format| parse_list_template(format));
+
+    let labels = parse_labels(subcommand.get_all("label"));
+    let filtered = group.is_some() || running.is_some() || !labels.is_empty();
+
+    let mut client = connect();
+    let ServiceList {
+        services, states, ..
+    } = run(&mut client, |client| {
+        client.list_services(ServiceFilter {
+            group: group.cloned(),
+            running,
+            labels,
+        })
+    });
+    if let Some(template) = template {
+        let mut names = services.keys().collect::<Vec<&String>>();
+        names.sort();
+        for name in names {
+            let line = format_list_template(&template, name, &services[name], &states[name]);
+            print!("{line}");
+        }
+        // Anything else written to stdout would get in the way of whatever reads the lines.
+        exit(0);
+    }
+    if services.is_empty() && filtered {
+        println!("No services match the filters");
+        return;
+    }
+
+    /*
+     * Separate into groups.
+     */
+    let mut groups = HashMap::<String, HashMap<String, Arc<ipc::Service>>>::new();
+    for (service_name, service) in services {
+        let group_name = match service.group {
+            Some(ref group_name) => group_name.clone(),
+            None => "none".to_string(),
+        };
+
+        let group = match groups.get_mut(&group_name) {
+            Some(group) => group,
+            None => {
+                groups.insert(group_name.clone(), HashMap::new());
+                groups.get_mut(&group_name).unwrap()
+            }
+        };
+
+        group.insert(service_name, service);
+    }
+
+    /*
+     * Format each row.
+     */
+    fn format_row(service: &ipc::Service) -> (String, String, String) {
+        let (start_command, stop_command) = match &service.kind {
+            ipc::ServiceKind::Synchronous { command } | ipc::ServiceKind::Oneshot { command } => {
+                (format_command(service.shell, command), String::new())
+            }
+            ipc::ServiceKind::Timer {
+                command,
+                interval_secs,
+            } => (
+                format!(
+                    "{} every {}",
+                    format_command(service.shell, command),
+                    format_duration(*interval_secs)
+                ),
+                String::new(),
+            ),
+            ipc::ServiceKind::Asynchronous {
+                start_command,
+                stop_command,
+                ..
+            } => (
+                format_command(service.shell, start_command),
+                format_command(service.shell, stop_command),
+            ),
+            kind => (format_command(service.shell, kind.command()), String::new()),
+        };
+        let ports = service
+            .ports
+            .iter()
+            .map(|port| port.to_string())
+            .collect::<Vec<String>>()
+            .join(", ");
+
+        (start_command, stop_command, ports)
+    }
+
+    /*
+     * Display table.
+     */
+    let mut port_owners = BTreeMap::<u16, Vec<String>>::new();
+
+    let mut columns = vec!["Name", "State", "Start Command", "Stop Command"];
+    if show_ports {
+        columns.push("Ports");
+    }
+    for (group_name, group) in groups {
+        let mut rows = Vec::new();
+        for (service_name, service) in group {
+            let (start_command, stop_command, ports) = format_row(&service);
+            let state = format!(
+                "{:?}",
+                states.get(&service_name).copied().unwrap_or_default()
+            );
+
+            for port in &service.ports {
+                port_owners
+                    .entry(*port)
+                    .or_default()
+                    .push(service_name.clone());
+            }
+
+            let mut row = vec![service_name, state, start_command, stop_command];
+            if show_ports {
+                row.push(ports);
+            }
+            rows.push(row);
+        }
+
+        println!("{group_name}:");
+        print!("{}", render_table(&columns, &rows, max_width));
+        println!();
+    }
+
+    let services_up = states.values().filter(|state| state.is_active()).count();
+    println!("{services_up}/{} services up", states.len());
+
+    if show_ports {
+        for (port, owners) in port_owners {
+            if owners.len() > 1 {
+                println!(
+                    "WARNING: port {port} is declared by several services: {}",
+                    owners.join(", ")
+                );
+            }
+        }
+    }
+}
+
+fn target_subcommand(subcommand: &flag::ParsedCommand) {
+    let subcommand = subcommand.subcommand.as_ref().unwrap();
+
+    let target_name = subcommand
+        .positional_args
+        .get("target name")
+        .unwrap()
+        .clone();
+
+    let mut client = connect();
+    run(&mut client, |client| match subcommand.name.as_str() {
+        "add" => client.add_service_to_target(
+            &target_name,
+            subcommand.positional_args.get("service name").unwrap(),
+        ),
+        "remove" => client.remove_service_from_target(
+            &target_name,
+            subcommand.positional_args.get("service name").unwrap(),
+        ),
+        "start" => client.start_target(&target_name, subcommand.has_flag("exclusive")),
+        "stop" => client.stop_target(&target_name),
+        _ => unreachable!(),
+    });
+}
+
+fn list_targets_subcommand() {
+    let mut client = connect();
+    let targets = run(&mut client, Client::list_targets);
+
+    let mut target_names = targets.keys().collect::<Vec<&String>>();
+    target_names.sort();
+
+    for target_name in target_names {
+        let target = &targets[target_name];
+        println!(
+            "{target_name} ({}/{} running):",
+            target.running,
+            target.services.len()
+        );
+        for service_name in &target.services {
+            println!("    {service_name}");
+        }
+        println!();
+    }
+}
+
+fn group_subcommand(subcommand: &flag::ParsedCommand) {
+    let subcommand = subcommand.subcommand.as_ref().unwrap();
+
+    let group_name = subcommand.positional_args.get("group name").unwrap();
+
+    let mut client = connect();
+    match subcommand.name.as_str() {
+        "describe" => run(&mut client, |client| {
+            client.describe_group(
+                group_name,
+                subcommand.positional_args.get("description").unwrap(),
+            )
+        }),
+        "move" => {
+            let new_group_name = match (
+                subcommand.positional_args.get("new group name"),
+                subcommand.has_flag("clear"),
+            ) {
+                (Some(_), true) => {
+                    eprintln!("ERROR: --clear can't be used along with a new group name");
+                    exit(EXIT_USAGE);
+                }
+                (None, false) => {
+                    eprintln!("ERROR: either a new group name or --clear is required");
+                    exit(EXIT_USAGE);
+                }
+                (Some(name), false) if name != "none" => Some(name.as_str()),
+                _ => None,
+            };
+
+            let moved = run(&mut client, |client| {
+                client.move_group(group_name, new_group_name)
+            });
+            println!("Moved {} service(s): {}", moved.len(), moved.join(", "));
+        }
+        _ => unreachable!(),
+    }
+}
+
+fn reload_config_subcommand() {
+    let mut client = connect();
+    let ConfigReload {
+        added,
+        removed,
+        changed,
+        ..
+    } = run(&mut client, Client::reload_config);
+
+    if added.is_empty() && removed.is_empty() && changed.is_empty() {
+        println!("No service changed");
+    }
+    for (action, services) in [("Added", added), ("Removed", removed), ("Changed", changed)] {
+        if !services.is_empty() {
+            println!(
+                "{action} {} service(s): {}",
+                services.len(),
+                services.join(", ")
+            );
+        }
+    }
+}
+
+fn flush_subcommand() {
+    let mut client = connect();
+    run(&mut client, Client::flush);
+}
+
+fn snapshot_subcommand(subcommand: &flag::ParsedCommand) {
+    let subcommand = subcommand.subcommand.as_ref().unwrap();
+
+    let mut client = connect();
+    match subcommand.name.as_str() {
+        "save" => {
+            let name = subcommand.positional_args.get("snapshot name").unwrap();
+            let snapshot = run(&mut client, |client| client.save_snapshot(name));
+            println!(
+                "Saved snapshot `{}` of {} service(s), {} running",
+                snapshot.name, snapshot.services, snapshot.running
+            );
+        }
+        "restore" => {
+            let name = subcommand.positional_args.get("snapshot name").unwrap();
+            if !subcommand.has_flag("force") {
+                if !io::stdin().is_terminal() {
+                    eprintln!(
+                        "ERROR: stdin is not a terminal, pass --force to restore without asking"
+                    );
+                    exit(EXIT_USAGE);
+                }
+                print!(
+                    "Services not in the snapshot `{name}` will be stopped and removed, and the others changed, started or stopped to match it. Restore it? [y/N] "
+                );
+                let _ = io::stdout().flush();
+
+                let mut answer = String::new();
+                let _ = io::stdin().read_line(&mut answer);
+                if !matches!(answer.trim().to_lowercase().as_str(), "y" | "yes") {
+                    eprintln!("ERROR: aborted, nothing was restored");
+                    exit(EXIT_FAILURE);
+                }
+            }
+
+            let SnapshotRestore {
+                added,
+                removed,
+                changed,
+                started,
+                stopped,
+                ..
+            } = run(&mut client, |client| client.restore_snapshot(name));
+            if [&added, &removed, &changed, &started, &stopped]
+                .iter()
+                .all(|services| services.is_empty())
+            {
+                println!("Nothing had to change");
+            }
+            for (action, services) in [
+                ("Added", added),
+                ("Removed", removed),
+                ("Changed", changed),
+                ("Started", started),
+                ("Stopped", stopped),
+            ] {
+                if !services.is_empty() {
+                    println!(
+                        "{action} {} service(s): {}",
+                        services.len(),
+                        services.join(", ")
+                    );
+                }
+            }
+        }
+        "list" => {
+            let snapshots = run(&mut client, Client::list_snapshots);
+            let rows = snapshots
+                .into_iter()
+                .map(|snapshot| {
+                    vec![
+                        snapshot.name,
+                        snapshot.created,
+                        snapshot.services.to_string(),
+                        snapshot.running.to_string(),
+                    ]
+                })
+                .collect::<Vec<Vec<String>>>();
+            print!(
+                "{}",
+                render_table(&["Snapshot", "Created", "Services", "Running"], &rows, None)
+            );
+        }
+        _ => unreachable!(),
+    }
+}
+
+fn history_subcommand(subcommand: &flag::ParsedCommand) {
+    let limit = subcommand
+        .get_int("lines")
+        .map_or(50, |lines| lines as usize);
+
+    let mut client = connect();
+    let entries = run(&mut client, |client| client.history(limit));
+    let rows = entries
+        .into_iter()
+        .map(|entry| {
+            vec![
+                entry.timestamp,
+                entry.status.to_string(),
+                entry.command.to_string(),
+            ]
+        })
+        .collect::<Vec<Vec<String>>>();
+    print!(
+        "{}",
+        render_table(&["Time", "Status", "Command"], &rows, None)
+    );
+}
+
+fn list_groups_subcommand() {
+    let mut client = connect();
+    let groups = run(&mut client, Client::list_groups);
+
+    let mut rows = groups
+        .into_iter()
+        .map(|(name, group)| {
+            vec![
+                name,
+                group.services.to_string(),
+                group.running.to_string(),
+                group.description.unwrap_or_default(),
+            ]
+        })
+        .collect::<Vec<Vec<String>>>();
+    rows.sort();
+
+    print!(
+        "{}",
+        render_table(
+            &["Group", "Services", "Running", "Description"],
+            &rows,
+            None
+        )
+    );
+}
+
+fn ping_subcommand() {
+    let mut client = connect();
+    let DaemonInfo {
+        version,
+        uptime_secs,
+        config_path,
+        config_save_error,
+        socket_path,
+        watch_mechanism,
+        watch_interval_ms,
+        clients_active,
+        clients_max,
+        services_total,
+        services_running,
+        ..
+    } = run(&mut client, Client::ping);
+
+    if version != userserversd::VERSION {
+        WARNINGS.lock().unwrap().push(format!(
+            "the daemon runs version {version}, but userserversctl is version {}",
+            userserversd::VERSION
+        ));
+    }
+    if let Some(err) = config_save_error {
+        WARNINGS.lock().unwrap().push(format!(
+            "the daemon failed to save its configuration: {err}"
+        ));
+    }
+
+    print_header("Daemon info");
+    println!("     Version: {version}");
+    println!(
+        "      Uptime: {}h {}m {}s",
+        uptime_secs / 3600,
+        uptime_secs / 60 % 60,
+        uptime_secs % 60
+    );
+    println!(
+        " Config file: {}",
+        config_path.unwrap_or_else(|| "none".to_string())
+    );
+    println!("      Socket: {socket_path}");
+    println!(
+        "    Watching: {watch_mechanism} (every {}s)",
+        watch_interval_ms as f64 / 1000.0
+    );
+    println!("     Clients: {clients_active}/{clients_max} connected");
+    println!("    Services: {services_running}/{services_total} running");
+    println!();
+}
+
+fn daemon_env_subcommand() {
+    let mut client = connect();
+    let DaemonEnvironment {
+        working_directory,
+        uid,
+        gid,
+        variables_total,
+        variables,
+        ..
+    } = run(&mut client, Client::daemon_environment);
+
+    print_header("Daemon environment");
+    println!(
+        "    Working directory: {}",
+        working_directory.unwrap_or_else(|| "unknown".to_string())
+    );
+    println!("                 User: uid {uid}, gid {gid}");
+    println!("            Variables: {variables_total}");
+    println!();
+
+    let mut variables = variables.into_iter().collect::<Vec<_>>();
+    variables.sort();
+    for (name, value) in variables {
+        println!("    {name}={value}");
+    }
+    println!();
+}
+
+fn daemon_restart_subcommand() {
+    let mut client = connect();
+    let uptime_secs_before = run(&mut client, Client::ping).uptime_secs;
+    run(&mut client, Client::restart_daemon);
+
+    let DaemonInfo {
+        version,
+        uptime_secs,
+        services_total,
+        services_running,
+        ..
+    } = run(&mut client, Client::ping);
+    // The uptime only starts over if the new instance actually took over.
+    if uptime_secs > uptime_secs_before {
+        eprintln!("ERROR: the daemon failed to restart, see its output for details");
+        exit(1);
+    }
+    println!("Daemon restarted, now running version {version}");
+    println!("{services_running}/{services_total} services running");
+}
+
+fn export_subcommand(subcommand: &flag::ParsedCommand) {
+    let mut client = connect();
+    let ServiceList { services, .. } = run(&mut client, |client| {
+        client.list_services(ServiceFilter::default())
+    });
+
+    let services = services
+        .into_iter()
+        .collect::<BTreeMap<String, Arc<ipc::Service>>>();
+    let definitions = match subcommand.flags.get("format").map(String::as_str) {
+        Some("toml") => toml::to_string_pretty(&services).unwrap_or_else(|err| {
+            eprintln!("ERROR: failed to write the definitions as TOML: {err}");
+            exit(1);
+        }),
+        _ => serde_json::to_string_pretty(&services).unwrap() + "\n",
+    };
+
+    match subcommand.flags.get("output") {
+        Some(path) => fs::write(path, definitions).unwrap_or_else(|err| {
+            eprintln!("ERROR: failed to write `{path}`: {err}");
+            exit(1);
+        }),
+        None => {
+            // Anything else written to stdout would get in the way of the definitions.
+            print!("{definitions}");
+            exit(0);
+        }
+    }
+}
+
+fn export_systemd_subcommand(subcommand: &flag::ParsedCommand) {
+    let mut renames = HashMap::new();
+    for rename in subcommand.get_all("rename") {
+        match rename.split_once('=') {
+            Some((old, new)) if !old.is_empty() && !new.is_empty() => {
+                renames.insert(
+                    old.to_string(),
+                    new.trim_end_matches(".service").to_string(),
+                );
+            }
+            _ => {
+                eprintln!("ERROR: `{rename}` isn't a valid rename, expected OLD=NEW");
+                exit(EXIT_USAGE);
+            }
+        }
+    }
+
+    let mut names = vec![
+        subcommand
+            .positional_args
+            .get("service name")
+            .unwrap()
+            .clone(),
+    ];
+    names.extend(subcommand.variadic_args.iter().cloned());
+
+    let mut client = connect();
+    let service_names = resolve_service_names(&mut client, subcommand, &names);
+    let mut units = Vec::new();
+    for name in &service_names {
+        let status = run(&mut client, |client| {
+            client.service_status_with_logs(name, 0)
+        });
+        let unit = unit_file::export(name, &status.service, &renames).unwrap_or_else(|err| {
+            eprintln!("ERROR: `{name}`: {err}");
+            exit(EXIT_USAGE);
+        });
+        for warning in &unit.warnings {
+            WARNINGS
+                .lock()
+                .unwrap()
+                .push(format!("`{name}`: {warning}"));
+        }
+        units.push(unit);
+    }
+
+    let Some(output_dir) = subcommand.flags.get("output-dir") else {
+        let units = units
+            .iter()
+            .map(|unit| format!("# {}.service\n{}", unit.unit_name, unit.contents))
+            .collect::<Vec<String>>();
+        // Anything else written to stdout would get in the way of the units.
+        print!("{}", units.join("\n"));
+        print_warnings();
+        exit(0);
+    };
+
+    let output_dir = Path::new(output_dir);
+    let paths = units
+        .iter()
+        .map(|unit| output_dir.join(format!("{}.service", unit.unit_name)))
+        .collect::<Vec<PathBuf>>();
+    if !subcommand.has_flag("force")
+        && let Some(path) = paths.iter().find(|path| path.exists())
+    {
+        eprintln!(
+            "ERROR: `{}` already exists, pass --force to overwrite it",
+            path.display()
+        );
+        exit(EXIT_FAILURE);
+    }
+    if let Err(err) = fs::create_dir_all(output_dir) {
+        eprintln!("ERROR: failed to create `{}`: {err}", output_dir.display());
+        exit(EXIT_FAILURE);
+    }
+    for (unit, path) in units.iter().zip(&paths) {
+        if let Err(err) = fs::write(path, &unit.contents) {
+            eprintln!("ERROR: failed to write `{}`: {err}", path.display());
+            exit(EXIT_FAILURE);
+        }
+        println!("Wrote `{}`", path.display());
+    }
+    if !paths.is_empty() {
+        println!("Run `systemctl --user daemon-reload` for systemd to see the new units.");
+    }
+}
+
+fn import_subcommand(subcommand: &flag::ParsedCommand) {
+    let path = subcommand.positional_args.get("file").unwrap();
+    let replace = subcommand.has_flag("replace");
+    if replace && subcommand.has_flag("merge") {
+        eprintln!("ERROR: --replace and --merge can't be used together");
+        exit(EXIT_USAGE);
+    }
+
+    let contents = fs::read_to_string(path).unwrap_or_else(|err| {
+        eprintln!("ERROR: failed to read `{path}`: {err}");
+        exit(1);
+    });
+    let services: Result<HashMap<String, ipc::Service>, String> = if path.ends_with(".toml") {
+        toml::from_str(&contents).map_err(|err| err.message().to_string())
+    } else {
+        serde_json::from_str(&contents).map_err(|err| err.to_string())
+    };
+    let services = services.unwrap_or_else(|err| {
+        eprintln!("ERROR: `{path}` doesn't hold valid service definitions: {err}");
+        exit(1);
+    });
+
+    let mut client = connect();
+    let force = subcommand.has_flag("force");
+    run(&mut client, |client| {
+        client.import_services(services, replace, force)
+    });
+}
+
+/// Lists the units of the systemd user configuration directory, leaving out templates.
+fn systemd_user_units() -> Vec<PathBuf> {
+    let config_home = env::var("XDG_CONFIG_HOME")
+        .ok()
+        .filter(|path| !path.is_empty())
+        .unwrap_or_else(|| format!("{}/.config", get_home_directory()));
+    let directory = format!("{config_home}/systemd/user");
+    let entries = fs::read_dir(&directory).unwrap_or_else(|err| {
+        eprintln!("ERROR: failed to read `{directory}`: {err}");
+        exit(1);
+    });
+
+    let mut units = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| name.ends_with(".service") && !name.ends_with("@.service"))
+        })
+        .collect::<Vec<PathBuf>>();
+    units.sort();
+    units
+}
+
+fn import_systemd_subcommand(subcommand: &flag::ParsedCommand) {
+    let mut paths = subcommand
+        .variadic_args
+        .iter()
+        .map(PathBuf::from)
+        .collect::<Vec<PathBuf>>();
+    if subcommand.has_flag("all") {
+        paths.extend(systemd_user_units());
+    }
+    if paths.is_empty() {
+        eprintln!("ERROR: no unit file was provided, pass some or use --all");
+        exit(EXIT_USAGE);
+    }
+
+    let mut services = HashMap::new();
+    let mut failed = false;
+    for path in &paths {
+        let unit = match unit_file::import(path) {
+            Ok(unit) => unit,
+            Err(err) => {
+                eprintln!("ERROR: `{}`: {err}", path.display());
+                failed = true;
+                continue;
+            }
+        };
+        if services.contains_key(&unit.name) {
+            eprintln!(
+                "ERROR: `{}`: another unit is already imported as `{}`",
+                path.display(),
+                unit.name
+            );
+            failed = true;
+            continue;
+        }
+
+        let service = &unit.service;
+        println!(
+            "{} ({}): {}",
+            unit.name,
+            service.kind.name(),
+            format_command(false, service.kind.command())
+        );
+        if let ipc::ServiceKind::Asynchronous { stop_command, .. } = &service.kind {
+            println!("    Stop command: {}", format_command(false, stop_command));
+        }
+        if let Some(description) = &service.description {
+            println!("    Description: {description}");
+        }
+        println!("    Working directory: {}", service.working_directory);
+        if !service.environment.is_empty() {
+            let mut environment = service
+                .environment
+                .iter()
+                .map(|(key, value)| format!("{key}={value}"))
+                .collect::<Vec<String>>();
+            environment.sort();
+            println!("    Environment: {}", environment.join(" "));
+        }
+        for (label, command) in [
+            ("Pre-start", &service.pre_start),
+            ("Post-stop", &service.post_stop),
+            ("Reload command", &service.reload_command),
+        ] {
+            if let Some(command) = command {
+                println!("    {label}: {}", format_command(false, command));
+            }
+        }
+        for warning in &unit.warnings {
+            println!("    WARNING: {warning}");
+        }
+        services.insert(unit.name, unit.service);
+    }
+
+    if failed {
+        eprintln!("ERROR: nothing was imported, as some units couldn't be translated");
+        exit(EXIT_FAILURE);
+    }
+    if subcommand.has_flag("dry-run") {
+        return;
+    }
+    if !subcommand.has_flag("yes") {
+        if !io::stdin().is_terminal() {
+            eprintln!("ERROR: stdin is not a terminal, pass --yes to import without asking");
+            exit(EXIT_USAGE);
+        }
+        print!(
+            "Add these services? Existing services with the same names are left as they are. [y/N] "
+        );
+        let _ = io::stdout().flush();
+
+        let mut answer = String::new();
+        let _ = io::stdin().read_line(&mut answer);
+        if !matches!(answer.trim().to_lowercase().as_str(), "y" | "yes") {
+            eprintln!("ERROR: aborted, nothing was imported");
+            exit(EXIT_FAILURE);
+        }
+    }
+
+    let mut client = connect();
+    let force = subcommand.has_flag("force");
+    run(&mut client, |client| {
+        client.import_services(services, false, force)
+    });
+}
+
+/// Runs a line of `apply`, returning the code it would have exited with on its own, or `None`
+/// if there was nothing to run.
+fn apply_line(line: &str, cli: &flag::Command) -> Option<i32> {
+    let args = match flag::split_line(line) {
+        Ok(args) if args.is_empty() => return None,
+        Ok(args) => args,
+        Err(err) => {
+            eprintln!("ERROR: {err}");
+            return Some(EXIT_USAGE);
+        }
+    };
+    let argv = [String::from("userserversctl")]
+        .into_iter()
+        .chain(args)
+        .collect();
+    let parsed = match flag::parse_from(cli, argv) {
+        Ok(parsed) => parsed,
+        Err(err) => {
+            eprintln!("ERROR: {err}");
+            return Some(EXIT_USAGE);
+        }
+    };
+
+    let subcommand = parsed.subcommand.unwrap();
+    if subcommand.name == "apply" {
+        eprintln!("ERROR: apply can't be used from within apply");
+        return Some(EXIT_USAGE);
+    }
+
+    match panic::catch_unwind(AssertUnwindSafe(|| run_subcommand(&subcommand, cli))) {
+        Ok(()) => Some(0),
+        Err(payload) => match payload.downcast::<LineExit>() {
+            Ok(line_exit) => Some(line_exit.0),
+            Err(payload) => panic::resume_unwind(payload),
+        },
+    }
+}
+
+fn apply_subcommand(subcommand: &flag::ParsedCommand, cli: &flag::Command) {
+    let path = subcommand.positional_args.get("file").unwrap();
+    let stop_on_error = subcommand.has_flag("stop-on-error");
+
+    let contents = if path == "-" {
+        io::read_to_string(io::stdin())
+    } else {
+        fs::read_to_string(path)
+    };
+    let contents = contents.unwrap_or_else(|err| {
+        eprintln!("ERROR: failed to read `{path}`: {err}");
+        exit(1);
+    });
+
+    APPLYING.store(true, Ordering::SeqCst);
+    let mut failure = None;
+    for (index, line) in contents.lines().enumerate() {
+        let Some(code) = apply_line(line, cli) else {
+            continue;
+        };
+        print_warnings();
+
+        let line_number = index + 1;
+        if code == 0 {
+            println!("Line {line_number}: done");
+            continue;
+        }
+        println!(
+            "Line {line_number}: ERROR: `{}` exited with code {code}",
+            line.trim()
+        );
+        failure = Some(code);
+        if stop_on_error {
+            break;
+        }
+    }
+    APPLYING.store(false, Ordering::SeqCst);
+
+    if let Some(code) = failure {
+        exit(code);
+    }
+}
+
+fn shutdown_daemon_subcommand() {
+    let mut client = connect();
+    run(&mut client, Client::shutdown);
+}
+
+fn completions_subcommand(subcommand: &flag::ParsedCommand, cli: &flag::Command) {
+    let shell_name = subcommand.positional_args.get("shell").unwrap();
+    let Some(shell) = flag::Shell::from_name(shell_name) else {
+        eprintln!("ERROR: unsupported shell `{shell_name}`, expected `bash`, `zsh` or `fish`");
+        exit(EXIT_USAGE);
+    };
+
+    print!(
+        "{}",
+        cli.generate_completions(
+            shell,
+            "userserversctl",
+            &[
+                ("service name", COMPLETE_SERVICE_NAMES),
+                ("target name", COMPLETE_TARGET_NAMES),
+            ],
+        )
+    );
+    // Anything else written to stdout would end up in the script.
+    exit(0);
+}
 
+/// Prints the names the completion scripts offer, one per line. Errors are ignored since there
+/// is nobody to show them to.
+fn complete_names(helper: &str) {
+    let Ok(mut client) = Client::connect() else {
+        exit(1);
+    };
+    let names = if helper == COMPLETE_SERVICE_NAMES {
+        client
+            .list_services(ServiceFilter::default())
+            .map(|list| list.services.into_keys().collect::<Vec<String>>())
+    } else {
+        client
+            .list_targets()
+            .map(|targets| targets.into_keys().collect::<Vec<String>>())
+    };
+    let Ok(mut names) = names else {
+        exit(1);
+    };
+
+    names.sort();
+    for name in names {
+        println!("{name}");
+    }
+    exit(0);
+}
+
+fn run_subcommand(subcommand: &flag::ParsedCommand, cli: &flag::Command) {
     match subcommand.name.as_str() {
-        "add" => add_subcommand(subcommand.as_ref()),
-        "remove" => remove_subcommand(subcommand.as_ref()),
-        "edit" => edit_subcommand(subcommand.as_ref()),
-        "start" => start_subcommand(subcommand.as_ref()),
-        "stop" => stop_subcommand(subcommand.as_ref()),
-        "restart" => restart_subcommand(subcommand.as_ref()),
-        "status" => status_subcommand(subcommand.as_ref()),
-        "list-services" => list_services_subcommand(),
+        "add" => add_subcommand(subcommand),
+        "remove" => remove_subcommand(subcommand),
+        "edit" => edit_subcommand(subcommand),
+        "edit-json" => edit_json_subcommand(subcommand),
+        "start" => start_subcommand(subcommand),
+        "stop" => stop_subcommand(subcommand),
+        "restart" => restart_subcommand(subcommand),
+        "reload" => reload_subcommand(subcommand),
+        "pause" => pause_subcommand(subcommand),
+        "resume" => resume_subcommand(subcommand),
+        "status" => status_subcommand(subcommand),
+        "logs" => logs_subcommand(subcommand),
+        "watch" => watch_subcommand(subcommand),
+        "list-services" => list_services_subcommand(subcommand),
+        "export" => export_subcommand(subcommand),
+        "export-systemd" => export_systemd_subcommand(subcommand),
+        "import-systemd" => import_systemd_subcommand(subcommand),
+        "import" => import_subcommand(subcommand),
+        "target" => target_subcommand(subcommand),
+        "list-targets" => list_targets_subcommand(),
+        "group" => group_subcommand(subcommand),
+        "list-groups" => list_groups_subcommand(),
+        "ping" => ping_subcommand(),
+        "daemon-env" => daemon_env_subcommand(),
+        "reload-config" => reload_config_subcommand(),
+        "snapshot" => snapshot_subcommand(subcommand),
+        "history" => history_subcommand(subcommand),
+        "flush" => flush_subcommand(),
+        "daemon-restart" => daemon_restart_subcommand(),
+        "shutdown-daemon" => shutdown_daemon_subcommand(),
+        "apply" => apply_subcommand(subcommand, cli),
+        "completions" => completions_subcommand(subcommand, cli),
 
         "help" => {
             print!("{}", cli.generate_help());
@@ -705,6 +4301,393 @@ fn main() {
 
         _ => unreachable!(),
     }
+}
+
+fn main() {
+    // The completion helpers are only meant for the scripts, so they are kept out of the help.
+    if let Some(helper) = env::args()
+        .nth(1)
+        .filter(|arg| [COMPLETE_SERVICE_NAMES, COMPLETE_TARGET_NAMES].contains(&arg.as_str()))
+    {
+        complete_names(&helper);
+    }
+
+    let cli = cli();
+    let parsed_cli = flag::parse(&cli).unwrap_or_else(|err| {
+        eprintln!("{}", cli.generate_help());
+        eprintln!("ERROR: {err}");
+        exit(EXIT_USAGE);
+    });
+    if parsed_cli.has_flag("version") {
+        println!("userserversctl {}", userserversd::VERSION);
+        exit(0);
+    }
+
+    let _ = OUTPUT.set(Output {
+        quiet: parsed_cli.has_flag("quiet"),
+        verbose: parsed_cli.has_flag("verbose"),
+        spawn_daemon: parsed_cli.has_flag("spawn-daemon")
+            || env::var(SPAWN_DAEMON_VARIABLE).is_ok_and(|value| !value.is_empty() && value != "0"),
+    });
+
+    let subcommand = parsed_cli.subcommand.unwrap();
+    run_subcommand(&subcommand, &cli);
+
+    if !output().quiet {
+        println!("Command executed successfully!");
+    }
+    print_warnings();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(str::to_string).collect()
+    }
 
-    println!("Command executed successfully!");
+    #[test]
+    fn cli_definition_passes_validation() {
+        // Building the tree panics on any mistake in it, such as two flags of a subcommand
+        // sharing a name, so this catches them whenever a subcommand is added.
+        let cli = cli();
+
+        let parsed = flag::parse_from(&cli, args("userserversctl -q status web")).unwrap();
+        assert!(parsed.has_flag("quiet"));
+        let status = parsed.subcommand.unwrap();
+        assert_eq!(status.name, "status");
+        assert_eq!(
+            status
+                .positional_args
+                .get("service name")
+                .map(String::as_str),
+            Some("web")
+        );
+    }
+
+    #[test]
+    fn bash_completions_of_the_cli_parse() {
+        let script = cli().generate_completions(
+            flag::Shell::Bash,
+            "userserversctl",
+            &[
+                ("service name", COMPLETE_SERVICE_NAMES),
+                ("target name", COMPLETE_TARGET_NAMES),
+            ],
+        );
+        for subcommand in [
+            "start",
+            "stop",
+            "status",
+            "add sync",
+            "add async",
+            "target add",
+        ] {
+            assert!(
+                script.contains(&format!("    '{subcommand}')\n")),
+                "{subcommand}"
+            );
+        }
+
+        let output = process::Command::new("bash")
+            .args(["--norc", "-n", "-c", &script])
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{output:?}");
+    }
+
+    #[test]
+    fn each_failure_exits_with_the_code_of_its_class() {
+        let command = |status| client::Error::Command(status);
+        let cases = [
+            (
+                client::Error::Connect(io::Error::from(io::ErrorKind::NotFound)),
+                EXIT_DAEMON_UNREACHABLE,
+            ),
+            (command(ResponseStatus::Busy), EXIT_DAEMON_UNREACHABLE),
+            (
+                command(ResponseStatus::ShuttingDown),
+                EXIT_DAEMON_UNREACHABLE,
+            ),
+            (
+                command(ResponseStatus::ServiceDoesNotExist),
+                EXIT_SERVICE_DOES_NOT_EXIST,
+            ),
+            (
+                command(ResponseStatus::ServiceAlreadyExists),
+                EXIT_SERVICE_ALREADY_EXISTS,
+            ),
+            (
+                command(ResponseStatus::ServiceNotRunning),
+                EXIT_SERVICE_NOT_RUNNING,
+            ),
+            (
+                command(ResponseStatus::ServiceNotInTarget),
+                EXIT_TARGET_ERROR,
+            ),
+            (
+                command(ResponseStatus::ExecutableNotFound {
+                    program: "server".to_string(),
+                    search_path: None,
+                }),
+                EXIT_START_FAILED,
+            ),
+            (
+                command(ResponseStatus::CommandTimedOut {
+                    command: "start".to_string(),
+                    secs: 60,
+                }),
+                EXIT_START_FAILED,
+            ),
+            (
+                command(ResponseStatus::CommandTimedOut {
+                    command: "stop".to_string(),
+                    secs: 60,
+                }),
+                EXIT_FAILURE,
+            ),
+            (command(ResponseStatus::WaitTimedOut), EXIT_TIMED_OUT),
+            (
+                command(ResponseStatus::DependencyCycle {
+                    services: vec!["a".to_string(), "b".to_string()],
+                }),
+                EXIT_INVALID_SERVICE,
+            ),
+            (
+                command(ResponseStatus::ServiceAlreadyRunning),
+                EXIT_SERVICE_ALREADY_RUNNING,
+            ),
+            (
+                command(ResponseStatus::IoError {
+                    message: "Not a directory (os error 20)".to_string(),
+                }),
+                EXIT_FAILURE,
+            ),
+            (client::Error::ConnectionClosed, EXIT_FAILURE),
+        ];
+        for (err, code) in cases {
+            assert_eq!(exit_code(&err), code, "{err}");
+        }
+    }
+
+    #[test]
+    fn help_documents_every_exit_code() {
+        let help = cli().generate_help();
+        for (code, description) in EXIT_CODES {
+            assert!(help.contains(&format!("{code}: {description}")), "{code}");
+        }
+
+        let mut codes = EXIT_CODES.map(|(code, _)| code);
+        codes.sort();
+        assert_eq!(codes, std::array::from_fn(|index| index as i32 + 1));
+
+        let err = flag::parse_from(&cli(), args("userserversctl strat web")).err();
+        assert!(err.unwrap().contains("did you mean `start`?"));
+    }
+
+    fn row(values: &[&str]) -> Vec<String> {
+        values.iter().map(|value| value.to_string()).collect()
+    }
+
+    #[test]
+    fn truncation_counts_characters_and_marks_the_cut() {
+        assert_eq!(truncate_string("web", Some(5)), "web");
+        assert_eq!(truncate_string("webui", Some(5)), "webui");
+        assert_eq!(truncate_string("webserver", Some(5)), "webs|");
+        assert_eq!(truncate_string("webserver", None), "webserver");
+        // Multibyte characters count once and are never split.
+        assert_eq!(truncate_string("café-crème", Some(6)), "café-|");
+        assert_eq!(truncate_string("日本語のサービス", Some(4)), "日本語|");
+        assert_eq!(truncate_string("x", Some(1)), "x");
+        assert_eq!(truncate_string("xy", Some(1)), "|");
+    }
+
+    #[test]
+    fn columns_are_as_wide_as_their_values_once_truncated() {
+        let rows = [
+            row(&["web", "[\"python3\", \"-m\", \"http.server\"]"]),
+            row(&["a-very-long-service-name", "true"]),
+        ];
+        assert_eq!(
+            render_table(&["NAME", "COMMAND"], &rows, Some(10)),
+            concat!(
+                "    NAME        COMMAND\n",
+                "    ----------------------\n",
+                "    web         [\"python3|\n",
+                "    a-very-lo|  true\n",
+            )
+        );
+    }
+
+    #[test]
+    fn full_tables_keep_every_value_whole() {
+        let rows = [
+            row(&["a-very-long-service-name", "running"]),
+            row(&["db", "stopped"]),
+        ];
+        assert_eq!(
+            render_table(&["NAME", "STATE"], &rows, None),
+            concat!(
+                "    NAME                      STATE\n",
+                "    ---------------------------------\n",
+                "    a-very-long-service-name  running\n",
+                "    db                        stopped\n",
+            )
+        );
+    }
+
+    #[test]
+    fn header_sets_the_width_of_narrow_columns() {
+        let rows = [row(&["é", "1", ""])];
+        assert_eq!(
+            render_table(&["NAME", "PID", "PORTS"], &rows, Some(40)),
+            concat!(
+                "    NAME  PID  PORTS\n",
+                "    ----------------\n",
+                "    é     1\n",
+            )
+        );
+        assert_eq!(
+            render_table(&["NAME"], &[], Some(40)),
+            "    NAME\n    ----\n"
+        );
+    }
+
+    fn web_service() -> ipc::Service {
+        let mut service = ipc::Service::new(
+            "/srv/web",
+            ipc::ServiceKind::Synchronous {
+                command: vec!["./server".to_string()],
+            },
+        );
+        service.group = Some("frontend".to_string());
+        service.environment = HashMap::from([
+            ("PORT".to_string(), "8080".to_string()),
+            ("MODE".to_string(), "production".to_string()),
+        ]);
+        service
+    }
+
+    fn edited(line: &str) -> ipc::Service {
+        let parsed = flag::parse_from(&cli(), args(&format!("userserversctl edit {line}")));
+        let edit = parsed.unwrap().subcommand.unwrap();
+        let mut service = web_service();
+        apply_edit(edit.subcommand.as_ref().unwrap(), &mut service);
+        service
+    }
+
+    #[test]
+    fn resettable_fields_can_be_kept_set_or_cleared() {
+        let original = web_service();
+        let home = user_context::get().home.clone().unwrap();
+        let environment = |pairs: &[(&str, &str)]| {
+            pairs
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect::<HashMap<String, String>>()
+        };
+
+        assert_eq!(edited("sync web"), original);
+
+        assert_eq!(
+            edited("sync web --group backend").group.as_deref(),
+            Some("backend")
+        );
+        assert_eq!(edited("sync web --no-group").group, None);
+
+        assert_eq!(
+            edited("sync web --working-directory /srv/api").working_directory,
+            "/srv/api"
+        );
+        assert_eq!(
+            edited("sync web --no-working-directory").working_directory,
+            home
+        );
+
+        assert_eq!(
+            edited(r#"sync web --environment {"DEBUG":"1"}"#).environment,
+            environment(&[("DEBUG", "1")])
+        );
+        assert_eq!(edited("sync web --no-env").environment, HashMap::new());
+        assert_eq!(
+            edited("sync web --set-env PORT=9090 --unset-env MODE").environment,
+            environment(&[("PORT", "9090")])
+        );
+        // Clearing comes first, so that the environment can be replaced variable by variable.
+        assert_eq!(
+            edited("sync web --no-env --set-env TOKEN=abc").environment,
+            environment(&[("TOKEN", "abc")])
+        );
+    }
+
+    #[test]
+    fn clearing_a_field_leaves_the_others_alone() {
+        for (line, clear) in [
+            (
+                "sync web --no-group",
+                (|service| service.group = None) as fn(&mut ipc::Service),
+            ),
+            ("sync web --no-env", |service| service.environment.clear()),
+            ("sync web --no-env --no-group", |service| {
+                service.environment.clear();
+                service.group = None;
+            }),
+        ] {
+            let mut expected = web_service();
+            clear(&mut expected);
+            assert_eq!(edited(line), expected, "{line}");
+        }
+    }
+
+    fn add_sync(line: &str) -> Result<flag::ParsedCommand, String> {
+        let parsed = flag::parse_from(
+            &cli(),
+            args(&format!(r#"userserversctl add sync api ["./api"] {line}"#)),
+        )?;
+        Ok(*parsed.subcommand.unwrap().subcommand.unwrap())
+    }
+
+    #[test]
+    fn repeatable_flags_keep_every_value_in_order() {
+        let sync =
+            add_sync("-pt 443 -d db --port 80 -l tier=web -d cache --label team=ops").unwrap();
+        assert_eq!(sync.get_all("port"), ["443", "80"]);
+        assert_eq!(sync.get_all("depends-on"), ["db", "cache"]);
+        assert_eq!(sync.get_all("watch"), Vec::<String>::new());
+        assert_eq!(
+            parse_labels(sync.get_all("label")),
+            HashMap::from([
+                ("tier".to_string(), "web".to_string()),
+                ("team".to_string(), "ops".to_string()),
+            ])
+        );
+    }
+
+    #[test]
+    fn later_assignments_of_the_same_variable_win() {
+        let mut environment = HashMap::from([("LEVEL".to_string(), "info".to_string())]);
+        let set = ["LEVEL=debug", "URL=http://a?b=c", "LEVEL=trace"].map(String::from);
+        edit_environment(&mut environment, &set, &[]);
+        assert_eq!(environment["LEVEL"], "trace");
+        // Only the first `=` separates the name from the value.
+        assert_eq!(environment["URL"], "http://a?b=c");
+    }
+
+    #[test]
+    fn flags_taking_one_value_are_refused_when_given_twice() {
+        for (line, flag) in [
+            (r#"-e {"A":"1"} --environment {"B":"2"}"#, "environment"),
+            ("-w /srv/a -w /srv/a", "working-directory"),
+            ("--group web --pre-start [] --group api", "group"),
+        ] {
+            assert_eq!(
+                add_sync(line).err().unwrap(),
+                format!("the flag --{flag} was given more than once"),
+                "{line}"
+            );
+        }
+        // Flags without an argument can't disagree with themselves.
+        assert!(add_sync("--shell --shell").unwrap().has_flag("shell"));
+    }
 }
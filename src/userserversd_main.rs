@@ -1,181 +1,1579 @@
-use std::fs;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufReader, Read, Seek, Write};
+use std::net::Shutdown;
+use std::os::fd::{AsRawFd, FromRawFd};
 use std::os::unix::net::{UnixListener, UnixStream};
-use std::process::exit;
+use std::path::Path;
+use std::process::{self, exit};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::mpsc;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, OnceLock, RwLock};
 use std::thread;
+use std::time::{Duration, Instant};
+
+use nix::errno::Errno;
+use nix::fcntl::{Flock, FlockArg};
+use nix::sys::socket::{self, MsgFlags, getsockopt, sockopt};
+use nix::unistd;
 
 use signal_hook::consts as sigconsts;
 use signal_hook::iterator::Signals;
 
-mod ipc;
+#[macro_use]
+mod log;
+
+mod clock;
+mod events;
+#[allow(dead_code)]
+mod flag;
+mod handover;
+mod history;
+mod logs;
+mod notifier;
+mod scheduler;
+#[cfg(test)]
+mod scratch;
 mod service;
 mod service_manager;
+mod systemd;
+
+use userserversd::{ipc, user_context};
 
+use ipc::EventKind;
 use ipc::command::Command;
-use ipc::response::{Response, ResponseKind, ResponseStatus};
+use ipc::response::{Response, ResponseKind, ResponseStatus, ServiceEvent};
 
-use service_manager::ServiceManager;
+use events::Event;
+use handover::Handover;
+use history::History;
+use notifier::{DesktopNotifier, Webhook};
+use scheduler::Scheduler;
+use service_manager::{ConfigFormat, DaemonState, ServiceManager};
+
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(60);
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+/// How the daemon notices service processes exiting: SIGCHLD for its own children, and a poll
+/// every watch interval for the processes of pidfiles, which aren't.
+const WATCH_MECHANISM: &str = "SIGCHLD and polling";
+
+enum ExitRequest {
+    Exit(i32),
+    Restart,
+}
+
+fn shutting_down_response() -> Response {
+    Response::new(ResponseStatus::ShuttingDown, ResponseKind::None)
+}
+
+fn busy_response() -> Response {
+    Response::new(ResponseStatus::Busy, ResponseKind::None)
+}
+
+fn stopping_response() -> Response {
+    Response::new(ResponseStatus::Stopping, ResponseKind::None)
+}
+
+fn handle_client(
+    stream: UnixStream,
+    service_manager: Arc<ServiceManager>,
+    daemon_state: Arc<DaemonState>,
+    exit_tx: Arc<Mutex<mpsc::Sender<ExitRequest>>>,
+) {
+    let mut reader = BufReader::new(&stream);
+    let mut writer = &stream;
+
+    let client_id = stream.as_raw_fd();
+    debug!(client = client_id; "Client {client_id} connected");
+    if let Ok(clone) = stream.try_clone() {
+        daemon_state
+            .clients
+            .lock()
+            .unwrap()
+            .insert(client_id, clone);
+    }
 
-fn handle_client(stream: &mut UnixStream, service_manager: Arc<Mutex<ServiceManager>>) {
     loop {
-        let command = match Command::read_from_stream(stream) {
+        let command = match Command::read_from_stream(&mut reader) {
             Ok(Some(command)) => command,
             Ok(None) => break,
-            Err(_) => continue,
+            Err(err) if err.kind() == io::ErrorKind::InvalidData => continue,
+            Err(err) => {
+                debug!(client = client_id; "Closing connection with client {client_id}: {err}");
+                break;
+            }
+        };
+
+        info!(client = client_id, command = command.name(); "Received command: {:?}", command);
+
+        // The connection is kept until the client goes away, so this can't hold off a restart
+        // by taking the command lock.
+        if let Command::Subscribe { events } = command {
+            let receiver = service_manager.events().subscribe();
+            stream_events(&stream, receiver, &events, &daemon_state);
+            break;
+        }
+
+        let _command = daemon_state.commands.read().unwrap();
+        if daemon_state.restarting.load(Ordering::SeqCst) {
+            let _ = shutting_down_response().write_to_stream(&mut writer);
+            break;
+        }
+        // Checked under the command lock, which the daemon takes once it starts stopping so that
+        // the commands that got past this are done before anything is stopped.
+        if service_manager.is_stopping() && !command.is_read_only() {
+            if let Err(err) = stopping_response().write_to_stream(&mut writer) {
+                debug!(client = client_id; "Failed to send response to client {client_id}: {err}");
+                break;
+            }
+            continue;
+        }
+        // A reload replaces the services, so the changes that come during one are turned away
+        // for the client to send again rather than acting on what is about to be replaced.
+        let change = !command.is_read_only() && !matches!(command, Command::ReloadConfig);
+        let _change = match change.then(|| service_manager.begin_change()) {
+            Some(None) => {
+                if let Err(err) = busy_response().write_to_stream(&mut writer) {
+                    debug!(client = client_id; "Failed to send response to client {client_id}: {err}");
+                    break;
+                }
+                continue;
+            }
+            change => change.flatten(),
         };
 
-        println!("Received command: {:?}", command);
+        let command_name = command.name();
+        let recorded_command = daemon_state
+            .history
+            .records(&command)
+            .then(|| serde_json::to_value(&command).unwrap());
+        let shutdown_requested = matches!(command, Command::Shutdown);
+        let restart_requested = matches!(command, Command::RestartDaemon);
+        let mut warnings = Vec::new();
 
-        let mut service_manager = service_manager.lock().unwrap();
         let response = match command {
-            Command::AddSynchronousService {
+            Command::AddService {
                 name,
-                working_directory,
-                environment,
-                group,
-                command,
-            } => service_manager.add_synchronous(
-                name,
-                working_directory,
-                environment,
-                group,
-                command,
-            ),
+                service,
+                force,
+            } => service_manager.add(name, *service, force, &mut warnings),
 
-            Command::AddAsynchronousService {
+            Command::RemoveService { name } => service_manager.remove(name),
+            Command::ImportServices {
+                services,
+                replace,
+                force,
+            } => service_manager.import(services, replace, force, &mut warnings),
+
+            Command::StartService { name } => service_manager.start(name, &mut warnings),
+            Command::StopService {
                 name,
-                working_directory,
-                environment,
-                group,
-                start_command,
-                stop_command,
-            } => service_manager.add_asynchronous(
+                with_dependents,
+            } => service_manager.stop(name, with_dependents, &mut warnings),
+            Command::RestartService { name } => service_manager.restart(name, &mut warnings),
+            Command::PauseService { name } => service_manager.pause(name),
+            Command::ResumeService { name } => service_manager.resume(name),
+            Command::ReloadService { name } => service_manager.reload(name),
+            Command::WaitForService { name, timeout_ms } => {
+                service_manager.wait_for(name, timeout_ms, &daemon_state, &mut warnings)
+            }
+
+            Command::GetServiceStatus { name, log_bytes } => service_manager.get_status(
                 name,
-                working_directory,
-                environment,
-                group,
-                start_command,
-                stop_command,
+                log_bytes.unwrap_or(ipc::command::DEFAULT_STATUS_LOG_BYTES),
             ),
+            Command::GetServiceSummaries => service_manager.get_summaries(),
+            Command::GetServiceLogs { name, lines } => service_manager.get_logs(name, lines),
+            Command::StreamServiceLogs { name } => service_manager.stream_logs(name, |data| {
+                let chunk = Response::new(ResponseStatus::Ok, ResponseKind::LogChunk { data });
+                chunk.write_to_stream(&mut writer)
+            }),
+            Command::ListServices {
+                group,
+                running,
+                labels,
+            } => service_manager.list_services(group, running, labels),
 
-            Command::RemoveService { name } => service_manager.remove(name),
+            Command::AddServiceToTarget { target, service } => {
+                service_manager.add_to_target(target, service)
+            }
+            Command::RemoveServiceFromTarget { target, service } => {
+                service_manager.remove_from_target(target, service)
+            }
+            Command::StartTarget { name, exclusive } => {
+                service_manager.start_target(name, exclusive)
+            }
+            Command::StopTarget { name } => service_manager.stop_target(name),
+            Command::ListTargets => service_manager.list_targets(),
 
-            Command::StartService { name } => service_manager.start(name),
-            Command::StopService { name } => service_manager.stop(name),
-            Command::RestartService { name } => service_manager.restart(name),
+            Command::ListGroups => service_manager.list_groups(),
+            Command::DescribeGroup { name, description } => {
+                service_manager.describe_group(name, description)
+            }
+            Command::MoveGroup { from, to } => service_manager.move_group(from, to),
+
+            Command::ReloadConfig => service_manager.reload_config(&mut warnings),
+            Command::Flush => service_manager.flush_now(),
+            Command::SaveSnapshot { name } => service_manager.save_snapshot(name),
+            Command::RestoreSnapshot { name } => {
+                service_manager.restore_snapshot(name, &mut warnings)
+            }
+            Command::ListSnapshots => service_manager.list_snapshots(&mut warnings),
+            Command::GetHistory { limit } => daemon_state.history.read(limit, &mut warnings),
+            Command::Subscribe { .. } => unreachable!(),
 
-            Command::GetServiceStatus { name } => service_manager.get_status(name),
-            Command::ListServices => service_manager.list_services(),
+            Command::Ping => service_manager.get_daemon_info(&daemon_state),
+            Command::GetDaemonEnvironment => service_manager.get_daemon_environment(),
+            Command::RestartDaemon => {
+                info!("Restart requested by client");
+                Ok(ResponseKind::None)
+            }
+            Command::Shutdown => {
+                info!("Shutdown requested by client");
+                Ok(ResponseKind::None)
+            }
+            // The daemon is built along with the library, so it knows every command there is.
+            _ => unreachable!("unknown command `{command_name}`"),
         };
 
         let response = match response {
-            Ok(kind) => Response {
-                status: ResponseStatus::Ok,
-                kind,
-            },
+            Ok(kind) => Response::new(ResponseStatus::Ok, kind).with_warnings(warnings),
             Err(status) => {
-                println!(
+                warn!(
+                    client = client_id, command = command_name;
                     "Command execution failed with the following status: {:?}",
                     status
                 );
 
-                Response {
-                    status,
-                    kind: ResponseKind::None,
+                Response::new(status, ResponseKind::None)
+            }
+        };
+
+        if let Some(command) = recorded_command {
+            daemon_state
+                .history
+                .record(command, response.status.clone());
+        }
+
+        // The Rust runtime ignores SIGPIPE, so a client that went away mid-response makes the
+        // write fail with EPIPE instead of killing the daemon.
+        if let Err(err) = response.write_to_stream(&mut writer) {
+            debug!(client = client_id; "Failed to send response to client {client_id}: {err}");
+            break;
+        }
+
+        if shutdown_requested {
+            request_exit(&exit_tx, 0);
+            break;
+        }
+        // The connection is kept open so that the client can tell when the new instance has
+        // taken over.
+        if restart_requested {
+            let _ = exit_tx.lock().unwrap().send(ExitRequest::Restart);
+        }
+    }
+
+    daemon_state.clients.lock().unwrap().remove(&client_id);
+    debug!(client = client_id; "Client {client_id} disconnected");
+}
+
+/// Sends the events of the kinds asked for, or of any kind if none were, until the client
+/// disconnects or the daemon restarts.
+fn stream_events(
+    stream: &UnixStream,
+    receiver: mpsc::Receiver<Event>,
+    kinds: &[EventKind],
+    daemon_state: &DaemonState,
+) {
+    const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+    loop {
+        if daemon_state.restarting.load(Ordering::SeqCst) {
+            return;
+        }
+
+        let event = match receiver.recv_timeout(POLL_INTERVAL) {
+            Ok(event) => event,
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                // Nothing is ever read from the client again, so anything but having to wait
+                // means it went away.
+                let mut byte = [0];
+                let flags = MsgFlags::MSG_PEEK | MsgFlags::MSG_DONTWAIT;
+                match socket::recv(stream.as_raw_fd(), &mut byte, flags) {
+                    Err(Errno::EAGAIN | Errno::EINTR) => continue,
+                    _ => return,
                 }
             }
+            Err(mpsc::RecvTimeoutError::Disconnected) => return,
         };
 
-        response.write_to_stream(stream).unwrap_or_else(|err| {
-            println!("Failed to send response to client: {err}");
+        let kind = event.kind();
+        if !kinds.is_empty() && !kinds.contains(&kind) {
+            continue;
+        }
+
+        let response = Response::new(
+            ResponseStatus::Ok,
+            ResponseKind::Event(ServiceEvent::new(
+                event.service,
+                kind,
+                event.new_state,
+                event.cause.to_string(),
+                log::format_timestamp(event.timestamp),
+            )),
+        );
+        if response.write_to_stream(&mut &*stream).is_err() {
+            return;
+        }
+    }
+}
+
+fn request_exit(exit_tx: &Mutex<mpsc::Sender<ExitRequest>>, exit_code: i32) {
+    // The main thread only stops listening once it is already exiting.
+    let _ = exit_tx.lock().unwrap().send(ExitRequest::Exit(exit_code));
+}
+
+/// Exits on SIGINT and SIGTERM, reloads the configuration on SIGHUP, and notices the services
+/// whose processes exited on SIGCHLD.
+fn handle_signals(
+    signals: &mut Signals,
+    service_manager: &ServiceManager,
+    daemon_state: &DaemonState,
+    exit_tx: &Mutex<mpsc::Sender<ExitRequest>>,
+) {
+    for signal in signals.forever() {
+        // Each service reaps only its own process, so that the statuses of the other children
+        // the daemon waits for, such as hooks, aren't taken from under them.
+        if signal == sigconsts::SIGCHLD {
+            service_manager.poll_services();
+            continue;
+        }
+        if signal != sigconsts::SIGHUP {
+            request_exit(exit_tx, 0);
+            continue;
+        }
+
+        // Runs like a command would, so that a restart waits for it.
+        let _command = daemon_state.commands.read().unwrap();
+        if service_manager.is_stopping() {
+            continue;
+        }
+        // What went wrong is in the logs already.
+        let _ = service_manager.reload_config(&mut Vec::new());
+    }
+}
+
+/// Replaces the daemon with a new instance of its binary that takes over the running services.
+/// Only returns if that fails.
+fn restart(
+    service_manager: &ServiceManager,
+    daemon_state: &DaemonState,
+    timeout: Duration,
+) -> io::Error {
+    info!("Restarting the daemon...");
+
+    // Turns away the commands that come from now on, and waits for the ones being run to finish.
+    // Waiting for the lock without a deadline would hold up every new client behind a command
+    // that takes long, such as a wait for a service, so one still running at the deadline is
+    // cut short by the new instance.
+    daemon_state.restarting.store(true, Ordering::SeqCst);
+    let deadline = Instant::now() + timeout;
+    let _commands = loop {
+        match daemon_state.commands.try_write() {
+            Ok(commands) => break Some(commands),
+            Err(_) if Instant::now() < deadline => thread::sleep(Duration::from_millis(50)),
+            Err(_) => break None,
+        }
+    };
+
+    // The new instance loads the configuration file, so it must hold the latest changes. What
+    // went wrong is in the logs already.
+    let _ = service_manager.flush(false);
+
+    for stream in daemon_state.clients.lock().unwrap().values() {
+        let _ = shutting_down_response().write_to_stream(&mut &*stream);
+    }
+
+    let err = match daemon_state.listener_fd.get() {
+        Some(listener_fd) => service_manager.hand_over(|services| {
+            handover::exec(&Handover {
+                listener_fd: *listener_fd,
+                socket_activated: daemon_state.socket_activated,
+                services,
+            })
+        }),
+        None => io::Error::other("the daemon is not listening for commands"),
+    };
+
+    // Clients were told the daemon is going away, so don't leave them waiting.
+    for stream in daemon_state.clients.lock().unwrap().values() {
+        let _ = stream.shutdown(Shutdown::Both);
+    }
+    daemon_state.restarting.store(false, Ordering::SeqCst);
+    err
+}
+
+/// Stops every service for the daemon to exit, turning away the commands that would change
+/// anything from now on. Whatever is still running after `timeout` gets killed.
+fn stop(service_manager: &ServiceManager, daemon_state: &DaemonState, timeout: Duration) {
+    let deadline = Instant::now() + timeout;
+    service_manager.begin_stopping();
+    // Waits for the commands already being run, so that none of them starts a service after it
+    // was stopped. A command still running at the deadline is left to end with the daemon.
+    while daemon_state.commands.try_write().is_err() && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(50));
+    }
+    // What went wrong is in the logs already.
+    let _ = service_manager.flush(false);
+    service_manager.stop_all(deadline.saturating_duration_since(Instant::now()));
+}
+
+/// Makes sure this is the only daemon of the user, by holding a lock that is released along with
+/// the process, so a crashed daemon never keeps another one from starting. The lock is also let
+/// go of across the exec of a restart, and taken again by the new instance.
+fn lock_instance() -> Flock<File> {
+    let lock_path = user_context::get().lock_file_path().unwrap_or_else(|err| {
+        eprintln!("ERROR: failed to get lock file path: {err}");
+        exit(1);
+    });
+    let file = File::options()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&lock_path)
+        .unwrap_or_else(|err| {
+            eprintln!("ERROR: failed to open lock file `{lock_path}`: {err}");
+            exit(1);
         });
+
+    let mut lock = match Flock::lock(file, FlockArg::LockExclusiveNonblock) {
+        Ok(lock) => lock,
+        Err((mut file, Errno::EWOULDBLOCK)) => {
+            let mut pid = String::new();
+            let _ = file.read_to_string(&mut pid);
+            eprintln!("userserversd already running (pid {})", pid.trim());
+            exit(1);
+        }
+        Err((_, err)) => {
+            eprintln!("ERROR: failed to lock `{lock_path}`: {err}");
+            exit(1);
+        }
+    };
+
+    let result = lock
+        .set_len(0)
+        .and_then(|_| lock.rewind())
+        .and_then(|_| write!(lock, "{}", process::id()));
+    if let Err(err) = result {
+        warn!("Failed to write the PID to the lock file: {err}");
+    }
+    lock
+}
+
+fn is_transient_accept_error(err: &io::Error) -> bool {
+    let Some(errno) = err.raw_os_error() else {
+        return false;
+    };
+
+    matches!(
+        Errno::from_raw(errno),
+        Errno::EMFILE
+            | Errno::ENFILE
+            | Errno::ENOBUFS
+            | Errno::ENOMEM
+            | Errno::ECONNABORTED
+            | Errno::EINTR
+            | Errno::EPROTO
+    )
+}
+
+fn is_peer_trusted(stream: &UnixStream) -> bool {
+    match getsockopt(stream, sockopt::PeerCredentials) {
+        Ok(credentials) => credentials.uid() == unistd::getuid().as_raw(),
+        Err(err) => {
+            warn!("Failed to get credentials of client: {err}");
+            false
+        }
+    }
+}
+
+// Only ever called while holding the instance lock, so a socket left there belongs to a daemon
+// that died without cleaning up.
+fn remove_stale_socket(socket_path: &str) -> io::Result<()> {
+    match fs::remove_file(socket_path) {
+        Ok(()) => {
+            info!("Removed the stale socket `{socket_path}`");
+            Ok(())
+        }
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(err) => Err(err),
     }
 }
 
 fn server(
-    socket_path: String,
-    service_manager: Arc<Mutex<ServiceManager>>,
-    exit_code_tx: Arc<Mutex<mpsc::Sender<i32>>>,
+    service_manager: Arc<ServiceManager>,
+    daemon_state: Arc<DaemonState>,
+    exit_tx: Arc<Mutex<mpsc::Sender<ExitRequest>>>,
 ) {
-    let listener = UnixListener::bind(&socket_path).unwrap_or_else(|err| {
-        eprintln!("ERROR: failed to bind socket: {err}");
-        exit_code_tx.lock().unwrap().send(1).unwrap();
-        loop {}
-    });
+    let socket_path = &daemon_state.socket_path;
+    let listener = match daemon_state.listener_fd.get() {
+        // SAFETY: the listener was handed over by the previous instance or by systemd, and this
+        // instance owns it now.
+        Some(listener_fd) => unsafe { UnixListener::from_raw_fd(*listener_fd) },
+        None => {
+            match remove_stale_socket(socket_path).and_then(|_| UnixListener::bind(socket_path)) {
+                Ok(listener) => listener,
+                Err(err) => {
+                    error!("Failed to bind socket: {err}");
+                    request_exit(&exit_tx, 1);
+                    return;
+                }
+            }
+        }
+    };
+    let _ = daemon_state.listener_fd.set(listener.as_raw_fd());
 
-    println!("Listening for commands on socket `{socket_path}`");
+    if daemon_state.socket_activated {
+        info!("Listening for commands on the socket passed by systemd");
+    } else {
+        info!("Listening for commands on socket `{socket_path}`");
+    }
+    // Services were started before the server, so everything is up by now.
+    systemd::notify("READY=1");
 
     for stream in listener.incoming() {
-        let mut stream = stream.unwrap_or_else(|err| {
-            eprintln!("ERROR: failed to accept connection: {err}");
-            exit_code_tx.lock().unwrap().send(1).unwrap();
-            loop {}
-        });
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(err) if is_transient_accept_error(&err) => {
+                warn!("Failed to accept connection: {err}");
+                // Errors like EMFILE persist until something else frees resources, so back
+                // off instead of retrying immediately.
+                thread::sleep(ACCEPT_RETRY_DELAY);
+                continue;
+            }
+            Err(err) => {
+                error!("Failed to accept connection: {err}");
+                request_exit(&exit_tx, 1);
+                return;
+            }
+        };
+
+        if !is_peer_trusted(&stream) {
+            warn!("Rejected connection from a client owned by another user");
+            continue;
+        }
+
+        if daemon_state.restarting.load(Ordering::SeqCst) {
+            let _ = shutting_down_response().write_to_stream(&mut &stream);
+            continue;
+        }
+
+        if let Err(err) = stream
+            .set_read_timeout(Some(CLIENT_TIMEOUT))
+            .and_then(|_| stream.set_write_timeout(Some(CLIENT_TIMEOUT)))
+        {
+            warn!("Failed to set timeouts on client connection: {err}");
+            continue;
+        }
+
+        let active_clients = daemon_state.active_clients.fetch_add(1, Ordering::SeqCst);
+        if active_clients >= daemon_state.max_clients {
+            daemon_state.active_clients.fetch_sub(1, Ordering::SeqCst);
+            warn!("Rejected connection: too many clients ({active_clients} connected)");
+
+            let _ = busy_response().write_to_stream(&mut &stream);
+            continue;
+        }
 
         let handle_client_services = service_manager.clone();
-        thread::spawn(move || handle_client(&mut stream, handle_client_services));
+        let handle_client_daemon_state = daemon_state.clone();
+        let handle_client_exit_tx = exit_tx.clone();
+        let spawn_result = thread::Builder::new().spawn(move || {
+            handle_client(
+                stream,
+                handle_client_services,
+                handle_client_daemon_state.clone(),
+                handle_client_exit_tx,
+            );
+            handle_client_daemon_state
+                .active_clients
+                .fetch_sub(1, Ordering::SeqCst);
+        });
+
+        if let Err(err) = spawn_result {
+            daemon_state.active_clients.fetch_sub(1, Ordering::SeqCst);
+            error!("Failed to spawn client handler thread: {err}");
+        }
     }
 }
 
+fn cli() -> flag::Command {
+    let mut root_command = flag::Command::new(None, "A daemon for managing user services.");
+    root_command.add_version_flag("Prints the version of the daemon.");
+    root_command.add_flag(
+        "t",
+        "default-target",
+        "Only starts the services of the specified target when the daemon starts.",
+    );
+    root_command.add_bool_flag(
+        "n",
+        "no-start-services",
+        "Loads the services without starting any of them, including the services of the default target and the ones added to the configuration file while the daemon runs.",
+    );
+    root_command.add_bool_flag(
+        "F",
+        "fresh",
+        "Starts every service, or the services of the default target, instead of the ones that were running when the daemon last stopped.",
+    );
+    root_command.add_choice_flag(
+        "f",
+        "config-format",
+        "Reads and writes the configuration in the provided format, either `json` or `toml`. The TOML configuration file is userserversd_services.toml, next to where the JSON one would be. Defaults to TOML if only that file exists, and to JSON otherwise.",
+        &["json", "toml"],
+    );
+    root_command.add_bool_flag(
+        "d",
+        "drop-ins",
+        "Keeps each service in its own file in the userserversd/services.d directory of the configuration directory, moving the services of the configuration file there. Once that directory exists, it is used without this flag.",
+    );
+    root_command.add_flag(
+        "i",
+        "watch-interval",
+        "Sets how often, in seconds, periodic work such as timers, file watches and checks on processes found through pidfiles runs. Defaults to 2.",
+    );
+    root_command.add_flag(
+        "s",
+        "shutdown-timeout",
+        "Sets how long, in seconds, the services get to stop when the daemon exits before whatever is left of them gets killed, which is also how long a restart waits for the commands being run. Defaults to 90.",
+    );
+    root_command.add_flag(
+        "c",
+        "max-clients",
+        "Sets how many clients can be connected at the same time. Defaults to 64.",
+    );
+    root_command.add_bool_flag(
+        "v",
+        "verbose",
+        "Also logs debug messages, such as about client connections.",
+    );
+    root_command.add_bool_flag("q", "quiet", "Only logs warnings and errors.");
+    root_command.add_flag(
+        "l",
+        "log-file",
+        "Appends the logs to the provided file instead of writing them to stdout and stderr.",
+    );
+    root_command.add_flag(
+        "wh",
+        "webhook",
+        "Posts a JSON object to the provided http:// URL when a service fails, holding the name of the service, the kind of event, its new state, what caused it, a timestamp and the exit code. Services with the `notify=false` label are left out.",
+    );
+    root_command.add_repeatable_flag(
+        "we",
+        "webhook-event",
+        "Posts to the webhook on events of the provided kind instead of on failures, which is one of `started`, `stopped`, `failed`, `state-changed`, `added`, `removed` or `edited`. Can be given several times.",
+    );
+    root_command.add_bool_flag(
+        "dn",
+        "desktop-notifications",
+        "Shows a desktop notification when a service fails or becomes unhealthy on its own, at most once a minute for each service. Services with the `notify=false` label are left out.",
+    );
+    root_command.add_json_flag::<Vec<String>>(
+        "nc",
+        "notify-command",
+        "Sets the command that shows desktop notifications, which gets a summary and a body as its last two arguments. The command must be a JSON array. Defaults to `[\"notify-send\"]`.",
+    );
+    root_command.add_bool_flag(
+        "H",
+        "history-all",
+        "Also records the commands that only look at the daemon, such as status and list, in the command history.",
+    );
+    root_command.add_choice_flag(
+        "L",
+        "log-format",
+        "Writes the logs of the daemon in the provided format, either `text` or `json`, which puts each line in a JSON object along with fields such as the service it is about. The output of services isn't affected. Defaults to text.",
+        &["text", "json"],
+    );
+
+    root_command
+}
+
 fn main() {
-    let service_manager = Arc::new(Mutex::new(ServiceManager::new()));
+    let started_at = Instant::now();
+    // Taken before anything can change the environment of the daemon, so that services get it
+    // as it was when the daemon started.
+    service::base_environment();
+
+    let cli = cli();
+    let parsed_cli = flag::parse(&cli).unwrap_or_else(|err| {
+        eprintln!("{}", cli.generate_help());
+        eprintln!("ERROR: {err}");
+        exit(1);
+    });
+    if parsed_cli.has_flag("version") {
+        println!("userserversd {}", userserversd::VERSION);
+        exit(0);
+    }
+
+    let default_target = parsed_cli.flags.get("default-target").cloned();
+    let watch_interval = match parsed_cli.flags.get("watch-interval") {
+        Some(interval) => match interval.parse::<f64>() {
+            Ok(secs) if secs > 0.0 && secs.is_finite() => Duration::from_secs_f64(secs),
+            _ => {
+                eprintln!("{}", cli.generate_help());
+                eprintln!("ERROR: the watch interval must be a positive number of seconds");
+                exit(1);
+            }
+        },
+        None => Duration::from_secs(2),
+    };
+    let max_clients = match parsed_cli.flags.get("max-clients") {
+        Some(max_clients) => match max_clients.parse::<usize>() {
+            Ok(max_clients) if max_clients > 0 => max_clients,
+            _ => {
+                eprintln!("{}", cli.generate_help());
+                eprintln!("ERROR: the maximum number of clients must be a positive integer");
+                exit(1);
+            }
+        },
+        None => 64,
+    };
+    let shutdown_timeout = match parsed_cli.flags.get("shutdown-timeout") {
+        Some(timeout) => match timeout.parse::<f64>() {
+            Ok(secs) if secs > 0.0 && secs.is_finite() => Duration::from_secs_f64(secs),
+            _ => {
+                eprintln!("{}", cli.generate_help());
+                eprintln!("ERROR: the shutdown timeout must be a positive number of seconds");
+                exit(1);
+            }
+        },
+        None => Duration::from_secs(90),
+    };
+
+    let webhook_kinds = match parsed_cli.get_all("webhook-event") {
+        [] => vec![EventKind::Failed],
+        names => names
+            .iter()
+            .map(|name| {
+                ipc::EVENT_KINDS
+                    .iter()
+                    .find(|(kind_name, _)| kind_name == name)
+                    .map(|(_, kind)| *kind)
+                    .unwrap_or_else(|| {
+                        eprintln!("{}", cli.generate_help());
+                        eprintln!("ERROR: unknown event kind `{name}`");
+                        exit(1);
+                    })
+            })
+            .collect(),
+    };
+    let webhook = parsed_cli.flags.get("webhook").map(|url| {
+        Webhook::new(url, webhook_kinds).unwrap_or_else(|err| {
+            eprintln!("{}", cli.generate_help());
+            eprintln!("ERROR: invalid webhook URL `{url}`: {err}");
+            exit(1);
+        })
+    });
+
+    let desktop_notifier = parsed_cli.has_flag("desktop-notifications").then(|| {
+        let command = parsed_cli
+            .get_json::<Vec<String>>("notify-command")
+            .unwrap_or_else(|| vec!["notify-send".to_string()]);
+        if command.is_empty() {
+            eprintln!("{}", cli.generate_help());
+            eprintln!("ERROR: the notification command can't be empty");
+            exit(1);
+        }
+        DesktopNotifier::new(command)
+    });
+
+    let instance_lock = lock_instance();
+
+    let log_level = match (parsed_cli.has_flag("verbose"), parsed_cli.has_flag("quiet")) {
+        (true, true) => {
+            eprintln!("{}", cli.generate_help());
+            eprintln!("ERROR: --verbose and --quiet can't be used together");
+            exit(1);
+        }
+        (true, false) => log::Level::Debug,
+        (false, true) => log::Level::Warn,
+        (false, false) => log::Level::Info,
+    };
+    let log_file = parsed_cli.flags.get("log-file").map(|path| {
+        File::options()
+            .append(true)
+            .create(true)
+            .open(path)
+            .unwrap_or_else(|err| {
+                eprintln!("ERROR: failed to open log file `{path}`: {err}");
+                exit(1);
+            })
+    });
+    let log_format = match parsed_cli.flags.get("log-format").map(String::as_str) {
+        Some("json") => log::Format::Json,
+        _ => log::Format::Text,
+    };
+    log::init(log_level, log_format, log_file);
+
+    let systemd_listener_fd = systemd::take_listener_fd();
+    let handover = handover::take();
+    let listener_fd = OnceLock::new();
+    let (handover, socket_activated) = match handover {
+        Some(handover) => {
+            let _ = listener_fd.set(handover.listener_fd);
+            (Some(handover.services), handover.socket_activated)
+        }
+        None => match systemd_listener_fd {
+            Some(fd) => {
+                let _ = listener_fd.set(fd);
+                (None, true)
+            }
+            None => (None, false),
+        },
+    };
 
-    let (exit_code_tx, exit_code_rx) = mpsc::channel();
-    let exit_code_tx = Arc::new(Mutex::new(exit_code_tx));
+    let config_format = match parsed_cli.flags.get("config-format").map(String::as_str) {
+        Some("toml") => ConfigFormat::Toml,
+        Some(_) => ConfigFormat::Json,
+        None => {
+            let exists = |format| {
+                service_manager::config_file_path(user_context::get(), format)
+                    .is_some_and(|path| Path::new(&path).exists())
+            };
+            if exists(ConfigFormat::Toml) && !exists(ConfigFormat::Json) {
+                ConfigFormat::Toml
+            } else {
+                if exists(ConfigFormat::Toml) {
+                    warn!(
+                        "Both a JSON and a TOML configuration file exist, the JSON one is used unless --config-format toml is passed"
+                    );
+                }
+                ConfigFormat::Json
+            }
+        }
+    };
+    let drop_ins = parsed_cli.has_flag("drop-ins")
+        || user_context::get()
+            .services_dir_path()
+            .is_some_and(|path| Path::new(&path).is_dir());
+    let service_manager = Arc::new(
+        ServiceManager::new(
+            user_context::get(),
+            default_target,
+            !parsed_cli.has_flag("no-start-services"),
+            config_format,
+            drop_ins,
+            parsed_cli.has_flag("fresh"),
+            handover,
+        )
+        .unwrap_or_else(|err| {
+            error!("Refusing to load the configuration: {err}");
+            exit(1);
+        }),
+    );
+
+    let (exit_tx, exit_rx) = mpsc::channel();
+    let exit_tx = Arc::new(Mutex::new(exit_tx));
 
     /*
      * Setup server thread.
      */
 
-    let socket_path = ipc::get_socket_path().unwrap_or_else(|err| {
-        eprintln!("ERROR: failed to get socket path: {err}");
+    let socket_path = user_context::get().socket_path().unwrap_or_else(|err| {
+        error!("Failed to get socket path: {err}");
         exit(1);
     });
 
+    let daemon_state = Arc::new(DaemonState {
+        started_at,
+        socket_path: socket_path.clone(),
+        watch_interval,
+        watch_mechanism: WATCH_MECHANISM,
+        max_clients,
+        active_clients: AtomicUsize::new(0),
+        listener_fd,
+        socket_activated,
+        commands: RwLock::new(()),
+        clients: Mutex::new(HashMap::new()),
+        restarting: AtomicBool::new(false),
+        history: History::new(user_context::get(), parsed_cli.has_flag("history-all")),
+    });
+
     let server_service_manager = service_manager.clone();
-    let server_exit_code_tx = exit_code_tx.clone();
-    let server_socket_path = socket_path.clone();
+    let server_daemon_state = daemon_state.clone();
+    let server_exit_tx = exit_tx.clone();
+    thread::spawn(move || server(server_service_manager, server_daemon_state, server_exit_tx));
+
+    /*
+     * Setup event logger thread.
+     */
+
+    let events = service_manager.events().subscribe();
     thread::spawn(move || {
-        server(
-            server_socket_path,
-            server_service_manager,
-            server_exit_code_tx,
-        )
+        for event in events {
+            // Adding, removing and editing services is already logged along with the command.
+            if matches!(
+                event.kind(),
+                EventKind::Added | EventKind::Removed | EventKind::Edited
+            ) {
+                continue;
+            }
+            info!(
+                service = event.service;
+                "Service `{}` went from {:?} to {:?} ({})",
+                event.service, event.old_state, event.new_state, event.cause
+            );
+        }
     });
 
     /*
-     * Setup signal handler thread.
+     * Setup on-failure hook thread.
      */
 
-    let mut signals =
-        Signals::new(&[sigconsts::SIGINT, sigconsts::SIGTERM]).unwrap_or_else(|err| {
-            eprintln!("ERROR: failed to set up signal handlers: {err}");
-            exit(1);
+    let failure_events = service_manager.events().subscribe();
+    let failure_service_manager = service_manager.clone();
+    thread::spawn(move || {
+        for event in failure_events {
+            failure_service_manager.run_failure_hook(&event);
+        }
+    });
+
+    /*
+     * Setup webhook thread.
+     */
+
+    if let Some(webhook) = webhook {
+        let webhook_events = service_manager.events().subscribe();
+        let webhook_service_manager = service_manager.clone();
+        let webhook = webhook.spawn();
+        thread::spawn(move || {
+            for event in webhook_events {
+                if webhook_service_manager.notifies_about(&event.service) {
+                    webhook.notify(&event);
+                }
+            }
         });
+    }
+
+    /*
+     * Setup desktop notification thread.
+     */
+
+    if let Some(mut desktop_notifier) = desktop_notifier {
+        let notification_events = service_manager.events().subscribe();
+        let notification_service_manager = service_manager.clone();
+        thread::spawn(move || {
+            for event in notification_events {
+                if notification_service_manager.notifies_about(&event.service) {
+                    desktop_notifier.notify(&event);
+                }
+            }
+        });
+    }
+
+    /*
+     * Setup scheduler thread.
+     */
+
+    let mut scheduler = Scheduler::new(watch_interval);
+
+    let poll_service_manager = service_manager.clone();
+    scheduler.add_task(move || poll_service_manager.poll_services());
+
+    let readiness_service_manager = service_manager.clone();
+    scheduler.add_task(move || readiness_service_manager.probe_readiness());
 
-    let signal_handler_exit_code_tx = exit_code_tx.clone();
+    let watch_service_manager = service_manager.clone();
+    scheduler.add_task(move || watch_service_manager.check_watched_paths());
+
+    let timer_service_manager = service_manager.clone();
+    scheduler.add_task(move || timer_service_manager.run_timers());
+
+    let log_service_manager = service_manager.clone();
+    scheduler.add_task(move || log_service_manager.prune_logs());
+
+    let health_check_service_manager = service_manager.clone();
+    scheduler.add_task(move || health_check_service_manager.run_health_checks());
+
+    scheduler.spawn();
+
+    /*
+     * Setup config flushing thread.
+     */
+
+    // Changes to the configuration are written at most once a second, away from the commands
+    // that make them, so that a slow disk doesn't hold them up.
+    let flush_service_manager = service_manager.clone();
     thread::spawn(move || {
-        for _ in signals.forever() {
-            signal_handler_exit_code_tx.lock().unwrap().send(0).unwrap();
+        loop {
+            thread::sleep(Duration::from_secs(1));
+            // What went wrong is in the logs and the daemon info already.
+            let _ = flush_service_manager.flush(false);
         }
     });
 
+    /*
+     * Setup signal handler thread.
+     */
+
+    let mut signals = Signals::new([
+        sigconsts::SIGINT,
+        sigconsts::SIGTERM,
+        sigconsts::SIGHUP,
+        sigconsts::SIGCHLD,
+    ])
+    .unwrap_or_else(|err| {
+        error!("Failed to set up signal handlers: {err}");
+        exit(1);
+    });
+
+    let signal_handler_exit_tx = exit_tx.clone();
+    let signal_handler_service_manager = service_manager.clone();
+    let signal_handler_daemon_state = daemon_state.clone();
+    thread::spawn(move || {
+        handle_signals(
+            &mut signals,
+            &signal_handler_service_manager,
+            &signal_handler_daemon_state,
+            &signal_handler_exit_tx,
+        )
+    });
+
     /*
      * Listen to receiver channel.
      */
 
-    loop {
-        let exit_code = exit_code_rx.recv().unwrap();
+    let exit_code = loop {
+        match exit_rx.recv().unwrap() {
+            ExitRequest::Exit(exit_code) => break exit_code,
+            ExitRequest::Restart => {
+                let err = restart(&service_manager, &daemon_state, shutdown_timeout);
+                error!("Failed to restart the daemon: {err}");
+            }
+        }
+    };
 
-        service_manager.lock().unwrap().stop_all();
-        if exit_code == 0 {
-            fs::remove_file(socket_path).unwrap_or_else(|err| {
-                eprintln!("ERROR: failed to remove socket file: {err}");
-                exit(1);
+    systemd::notify("STOPPING=1");
+    stop(&service_manager, &daemon_state, shutdown_timeout);
+    // A socket passed by systemd is for systemd to remove.
+    if exit_code == 0 && !socket_activated {
+        fs::remove_file(socket_path).unwrap_or_else(|err| {
+            error!("Failed to remove socket file: {err}");
+            exit(1);
+        });
+    }
+    drop(instance_lock);
+
+    exit(exit_code);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, Read};
+
+    use super::*;
+    use events::EventCause;
+    use ipc::ServiceState;
+    use service_manager::tests::TestDaemon;
+
+    #[test]
+    fn cli_definition_passes_validation() {
+        // Building the tree panics on any mistake in it.
+        let cli = cli();
+        let args = ["userserversd", "--fresh", "--config-format", "toml"];
+        let parsed = flag::parse_from(&cli, args.map(str::to_string).to_vec()).unwrap();
+        assert!(parsed.has_flag("fresh"));
+        assert_eq!(
+            parsed.values.get("config-format"),
+            Some(&flag::FlagValue::Choice("toml".to_string()))
+        );
+    }
+
+    struct Connections {
+        service_manager: Arc<ServiceManager>,
+        daemon_state: Arc<DaemonState>,
+        exit_tx: Arc<Mutex<mpsc::Sender<ExitRequest>>>,
+        exit_rx: mpsc::Receiver<ExitRequest>,
+    }
+
+    impl Connections {
+        fn new(daemon: &TestDaemon) -> Self {
+            Self::listening_on(daemon, String::new())
+        }
+
+        fn listening_on(daemon: &TestDaemon, socket_path: String) -> Self {
+            let (exit_tx, exit_rx) = mpsc::channel();
+            Self {
+                service_manager: Arc::new(daemon.manager()),
+                daemon_state: Arc::new(DaemonState {
+                    started_at: Instant::now(),
+                    socket_path,
+                    watch_interval: Duration::from_secs(1),
+                    watch_mechanism: WATCH_MECHANISM,
+                    max_clients: 8,
+                    active_clients: AtomicUsize::new(0),
+                    listener_fd: OnceLock::new(),
+                    socket_activated: false,
+                    commands: RwLock::new(()),
+                    clients: Mutex::new(HashMap::new()),
+                    restarting: AtomicBool::new(false),
+                    history: History::new(daemon.context, false),
+                }),
+                exit_tx: Arc::new(Mutex::new(exit_tx)),
+                exit_rx,
+            }
+        }
+
+        /// Hands one end of a new connection to `handle_client` on a thread of its own, whose
+        /// reads time out after `timeout`.
+        fn connect(&self, timeout: Duration) -> (UnixStream, thread::JoinHandle<()>) {
+            let (client, server) = UnixStream::pair().unwrap();
+            server.set_read_timeout(Some(timeout)).unwrap();
+            let service_manager = self.service_manager.clone();
+            let daemon_state = self.daemon_state.clone();
+            let exit_tx = self.exit_tx.clone();
+            let handler = thread::spawn(move || {
+                handle_client(server, service_manager, daemon_state, exit_tx)
             });
+            (client, handler)
+        }
+    }
+
+    fn ping(client: &UnixStream) -> Response {
+        Command::Ping.write_to_stream(&mut &*client).unwrap();
+        Response::read_from_stream(&mut BufReader::new(client))
+            .unwrap()
+            .unwrap()
+    }
+
+    // Sends the command, and again for as long as the daemon is too busy for it.
+    fn run(client: &UnixStream, command: &Command) -> Response {
+        loop {
+            command.write_to_stream(&mut &*client).unwrap();
+            let response = Response::read_from_stream(&mut BufReader::new(client))
+                .unwrap()
+                .unwrap();
+            if response.status != ResponseStatus::Busy {
+                return response;
+            }
+            thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    fn wait_for_exit(handler: &thread::JoinHandle<()>, within: Duration) {
+        let deadline = Instant::now() + within;
+        while !handler.is_finished() {
+            assert!(Instant::now() < deadline, "the thread is still running");
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn a_socket_that_cant_be_bound_makes_the_daemon_exit() {
+        let daemon = TestDaemon::new("unbindable-socket");
+        let socket_path = daemon.dir.join("nowhere/userserversd.sock");
+        let connections = Connections::listening_on(&daemon, socket_path.display().to_string());
+
+        let serving = {
+            let service_manager = connections.service_manager.clone();
+            let daemon_state = connections.daemon_state.clone();
+            let exit_tx = connections.exit_tx.clone();
+            thread::spawn(move || server(service_manager, daemon_state, exit_tx))
+        };
+        match connections.exit_rx.recv_timeout(Duration::from_secs(5)) {
+            Ok(ExitRequest::Exit(1)) => {}
+            _ => panic!("the daemon wasn't asked to exit with 1"),
+        }
+        wait_for_exit(&serving, Duration::from_secs(1));
+    }
+
+    #[test]
+    fn only_accept_errors_that_pass_are_retried() {
+        let transient = [
+            Errno::EMFILE,
+            Errno::ENFILE,
+            Errno::ENOBUFS,
+            Errno::ENOMEM,
+            Errno::ECONNABORTED,
+            Errno::EINTR,
+            Errno::EPROTO,
+        ];
+        for errno in transient {
+            let err = io::Error::from_raw_os_error(errno as i32);
+            assert!(is_transient_accept_error(&err), "{errno}");
+        }
+        for errno in [Errno::EBADF, Errno::EINVAL, Errno::ENOTSOCK] {
+            let err = io::Error::from_raw_os_error(errno as i32);
+            assert!(!is_transient_accept_error(&err), "{errno}");
+        }
+        assert!(!is_transient_accept_error(&io::Error::other("closed")));
+    }
+
+    #[test]
+    fn a_client_that_stalls_mid_command_is_dropped() {
+        let daemon = TestDaemon::new("client-stalls");
+        let connections = Connections::new(&daemon);
+
+        let (stalled, stalled_handler) = connections.connect(Duration::from_millis(200));
+        (&stalled)
+            .write_all(b"{\"GetServiceStatus\":{\"na")
+            .unwrap();
+        // Others are still served while it holds its connection open.
+        let (other, _) = connections.connect(CLIENT_TIMEOUT);
+        assert_eq!(ping(&other).status, ResponseStatus::Ok);
+
+        wait_for_exit(&stalled_handler, Duration::from_secs(5));
+        let mut rest = Vec::new();
+        (&stalled).read_to_end(&mut rest).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(connections.daemon_state.clients.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn a_command_over_the_size_limit_drops_the_connection() {
+        let daemon = TestDaemon::new("client-oversized");
+        let connections = Connections::new(&daemon);
+
+        let (flooding, flooding_handler) = connections.connect(CLIENT_TIMEOUT);
+        let writer = flooding.try_clone().unwrap();
+        let flood = thread::spawn(move || {
+            let chunk = vec![b'a'; 1024 * 1024];
+            let mut written = 0;
+            // Fails once the daemon hangs up, which it does before reading all of it.
+            while (&writer).write_all(&chunk).is_ok() {
+                written += chunk.len() as u64;
+            }
+            written
+        });
+
+        wait_for_exit(&flooding_handler, Duration::from_secs(10));
+        let written = flood.join().unwrap();
+        assert!(written <= ipc::command::MAX_COMMAND_SIZE + 1024 * 1024);
+
+        let (other, _) = connections.connect(CLIENT_TIMEOUT);
+        assert_eq!(ping(&other).status, ResponseStatus::Ok);
+    }
+
+    #[test]
+    fn clients_hanging_up_on_a_large_response_are_dropped() {
+        let daemon = TestDaemon::new("client-hangs-up");
+        let connections = Connections::new(&daemon);
+        let service_manager = &connections.service_manager;
+        service_manager
+            .add(
+                "chatty".to_string(),
+                daemon.script("seq 1 300000; exec sleep 1000"),
+                false,
+                &mut Vec::new(),
+            )
+            .unwrap();
+        // About 2 MB of logs, much more than a socket buffers.
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while !matches!(
+            service_manager.get_logs("chatty".to_string(), 1),
+            Ok(ResponseKind::ServiceLogs { logs }) if logs.trim() == "300000"
+        ) {
+            assert!(Instant::now() < deadline, "the service didn't log enough");
+            thread::sleep(Duration::from_millis(50));
         }
 
-        exit(exit_code);
+        for attempt in 0..40 {
+            let (client, handler) = connections.connect(CLIENT_TIMEOUT);
+            let command = if attempt % 2 == 0 {
+                Command::GetServiceLogs {
+                    name: "chatty".to_string(),
+                    lines: 1_000_000,
+                }
+            } else {
+                Command::StreamServiceLogs {
+                    name: "chatty".to_string(),
+                }
+            };
+            command.write_to_stream(&mut &client).unwrap();
+            drop(client);
+            wait_for_exit(&handler, Duration::from_secs(5));
+        }
+
+        let (client, _) = connections.connect(CLIENT_TIMEOUT);
+        assert_eq!(ping(&client).status, ResponseStatus::Ok);
+        assert_eq!(connections.daemon_state.clients.lock().unwrap().len(), 1);
+        service_manager
+            .stop("chatty".to_string(), false, &mut Vec::new())
+            .unwrap();
+    }
+
+    #[test]
+    fn malformed_commands_are_skipped() {
+        let daemon = TestDaemon::new("client-malformed");
+        let connections = Connections::new(&daemon);
+
+        let (client, handler) = connections.connect(CLIENT_TIMEOUT);
+        (&client)
+            .write_all(b"not json\xff{\"NoSuchCommand\":{}}\xff")
+            .unwrap();
+        assert_eq!(ping(&client).status, ResponseStatus::Ok);
+
+        client.shutdown(Shutdown::Write).unwrap();
+        wait_for_exit(&handler, Duration::from_secs(5));
+        // Nothing was sent back for the malformed commands.
+        let mut reader = BufReader::new(&client);
+        assert!(reader.fill_buf().unwrap().is_empty());
+    }
+
+    #[test]
+    fn exits_are_noticed_on_sigchld_without_waiting_for_a_poll() {
+        let daemon = TestDaemon::new("sigchld");
+        let connections = Connections::new(&daemon);
+        let service_manager = &connections.service_manager;
+        // Nothing polls the services in tests, so only the signal can make the exit noticed.
+        let mut signals = Signals::new([sigconsts::SIGCHLD]).unwrap();
+        let handle = signals.handle();
+        let signal_handler_service_manager = service_manager.clone();
+        let signal_handler_daemon_state = connections.daemon_state.clone();
+        let signal_handler_exit_tx = connections.exit_tx.clone();
+        let signal_handler = thread::spawn(move || {
+            handle_signals(
+                &mut signals,
+                &signal_handler_service_manager,
+                &signal_handler_daemon_state,
+                &signal_handler_exit_tx,
+            )
+        });
+
+        let events = service_manager.events().subscribe();
+        service_manager
+            .add(
+                "short-lived".to_string(),
+                daemon.script("sleep 0.2; exit 3"),
+                false,
+                &mut Vec::new(),
+            )
+            .unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let exit = loop {
+            let event = events
+                .recv_timeout(deadline.saturating_duration_since(Instant::now()))
+                .expect("the exit wasn't noticed");
+            if event.service == "short-lived" && event.new_state == ServiceState::Failed {
+                break event;
+            }
+        };
+        assert!(matches!(
+            exit.cause,
+            EventCause::Exited {
+                code: Some(3),
+                signal: None
+            }
+        ));
+
+        handle.close();
+        signal_handler.join().unwrap();
+    }
+
+    /// Whether a process exists and hasn't exited, as a zombie waiting to be reaped has.
+    fn is_alive(pid: &str) -> bool {
+        fs::read_to_string(format!("/proc/{pid}/stat")).is_ok_and(|stat| {
+            !stat
+                .rsplit_once(") ")
+                .is_some_and(|(_, rest)| rest.starts_with('Z'))
+        })
+    }
+
+    #[test]
+    fn services_started_while_the_daemon_stops_dont_outlive_it() {
+        let daemon = TestDaemon::new("start-while-stopping");
+        let connections = Connections::new(&daemon);
+        let names = (0..4)
+            .map(|index| format!("web-{index}"))
+            .collect::<Vec<_>>();
+        for name in &names {
+            connections
+                .service_manager
+                .add(
+                    name.clone(),
+                    daemon.script("echo $$ >> pids; exec sleep 1000"),
+                    false,
+                    &mut Vec::new(),
+                )
+                .unwrap();
+        }
+
+        thread::scope(|scope| {
+            let clients = names
+                .iter()
+                .map(|name| {
+                    let (client, _) = connections.connect(CLIENT_TIMEOUT);
+                    scope.spawn(move || {
+                        let name = name.clone();
+                        // Starts and stops the service until the daemon turns it away.
+                        loop {
+                            let start = Command::StartService { name: name.clone() };
+                            if run(&client, &start).status == ResponseStatus::Stopping {
+                                return;
+                            }
+                            let stop = Command::StopService {
+                                name: name.clone(),
+                                with_dependents: false,
+                            };
+                            if run(&client, &stop).status == ResponseStatus::Stopping {
+                                return;
+                            }
+                        }
+                    })
+                })
+                .collect::<Vec<_>>();
+
+            thread::sleep(Duration::from_millis(300));
+            stop(
+                &connections.service_manager,
+                &connections.daemon_state,
+                Duration::from_secs(10),
+            );
+            for client in clients {
+                client.join().unwrap();
+            }
+        });
+
+        let pids = fs::read_to_string(daemon.dir.join("pids")).unwrap();
+        assert!(pids.lines().count() > names.len());
+        for pid in pids.lines() {
+            assert!(!is_alive(pid), "process {pid} outlived the daemon");
+        }
+
+        // Looking at the services is still allowed.
+        let (client, _) = connections.connect(CLIENT_TIMEOUT);
+        let status = Command::GetServiceStatus {
+            name: names[0].clone(),
+            log_bytes: None,
+        };
+        assert_eq!(run(&client, &status).status, ResponseStatus::Ok);
+    }
+
+    #[test]
+    fn a_restart_cuts_waits_for_services_short() {
+        let daemon = TestDaemon::new("restart-waits");
+        let connections = Connections::new(&daemon);
+        let mut service = daemon.script("exec sleep 1000");
+        service.ready_command = Some(vec!["false".to_string()]);
+        let manager = &connections.service_manager;
+        manager
+            .add("web".to_string(), service, false, &mut Vec::new())
+            .unwrap();
+
+        let (client, _) = connections.connect(CLIENT_TIMEOUT);
+        let waiting = thread::spawn(move || {
+            let wait = Command::WaitForService {
+                name: "web".to_string(),
+                timeout_ms: 60_000,
+            };
+            run(&client, &wait).status
+        });
+        thread::sleep(Duration::from_millis(300));
+
+        let asked_at = Instant::now();
+        // There is no listener to hand over in a test, so the restart fails once it gets to it.
+        restart(manager, &connections.daemon_state, Duration::from_secs(30));
+        assert!(asked_at.elapsed() < Duration::from_secs(5));
+        assert_eq!(waiting.join().unwrap(), ResponseStatus::ShuttingDown);
+        manager
+            .stop("web".to_string(), false, &mut Vec::new())
+            .unwrap();
+    }
+
+    #[test]
+    fn a_restart_stuck_behind_a_command_turns_new_clients_away() {
+        let daemon = TestDaemon::new("restart-stuck");
+        let connections = Connections::new(&daemon);
+        let state = &connections.daemon_state;
+
+        thread::scope(|scope| {
+            // Stands for a command that runs past the deadline.
+            let command = state.commands.read().unwrap();
+            let restarting = scope.spawn(|| {
+                let asked_at = Instant::now();
+                restart(
+                    &connections.service_manager,
+                    state,
+                    Duration::from_millis(500),
+                );
+                asked_at.elapsed()
+            });
+            while !state.restarting.load(Ordering::SeqCst) {
+                thread::sleep(Duration::from_millis(10));
+            }
+
+            let (client, handler) = connections.connect(CLIENT_TIMEOUT);
+            let asked_at = Instant::now();
+            assert_eq!(ping(&client).status, ResponseStatus::ShuttingDown);
+            assert!(asked_at.elapsed() < Duration::from_millis(400));
+            wait_for_exit(&handler, Duration::from_secs(1));
+
+            let waited = restarting.join().unwrap();
+            assert!(waited >= Duration::from_millis(500));
+            assert!(waited < Duration::from_secs(5));
+            drop(command);
+        });
+    }
+
+    #[test]
+    fn reloads_interleaved_with_adds_lose_neither() {
+        let daemon = TestDaemon::new("reload-adds");
+        let connections = Connections::new(&daemon);
+        // Added behind the back of the daemon, as if by hand.
+        let other = daemon.manager();
+        other
+            .add(
+                "by-hand".to_string(),
+                daemon.script("true"),
+                false,
+                &mut Vec::new(),
+            )
+            .unwrap();
+        other.flush(true).unwrap();
+        let (client, _) = connections.connect(CLIENT_TIMEOUT);
+        assert_eq!(
+            run(&client, &Command::ReloadConfig).status,
+            ResponseStatus::Ok
+        );
+
+        let (adding_client, _) = connections.connect(CLIENT_TIMEOUT);
+        thread::scope(|scope| {
+            let adding = scope.spawn(|| {
+                let client = adding_client;
+                for index in 0..30 {
+                    let command = Command::AddService {
+                        name: format!("added-{index}"),
+                        service: Box::new(daemon.script("true")),
+                        force: false,
+                    };
+                    assert_eq!(run(&client, &command).status, ResponseStatus::Ok);
+                }
+            });
+            while !adding.is_finished() {
+                let response = run(&client, &Command::ReloadConfig);
+                assert_eq!(response.status, ResponseStatus::Ok);
+            }
+        });
+
+        // What ends up in the file holds both as well.
+        assert_eq!(
+            run(&client, &Command::ReloadConfig).status,
+            ResponseStatus::Ok
+        );
+        let ResponseKind::ServiceList(list) = connections
+            .service_manager
+            .list_services(None, None, HashMap::new())
+            .unwrap()
+        else {
+            panic!("not a service list");
+        };
+        let mut names = list.services.into_keys().collect::<Vec<String>>();
+        names.sort();
+        let mut expected = (0..30)
+            .map(|index| format!("added-{index}"))
+            .chain(["by-hand".to_string()])
+            .collect::<Vec<String>>();
+        expected.sort();
+        assert_eq!(names, expected);
     }
 }